@@ -1,10 +1,45 @@
 use std::env;
 use std::path::PathBuf;
 
+/// Link the librrd pkg-config finds statically, with the static libraries it needs itself, for a
+/// self-contained binary
+///
+/// librrd is not built here, pkg-config has to find a static one. It only links the archives
+/// statically if they are not in a system library directory, so build rrdtool with its own prefix
+/// and point PKG_CONFIG_PATH there. rrdtool configured with --disable-rrd_graph keeps the
+/// dependencies down to glib and libxml2. libqb is linked statically too if pkg-config finds a
+/// static one, otherwise the binary still needs the shared libqb.
+fn link_static() -> Vec<String> {
+    let librrd = pkg_config::Config::new()
+        .statik(true)
+        .probe("librrd")
+        .unwrap_or_else(|err| panic!("Unable to find a static librrd: {err}"));
+    if pkg_config::Config::new()
+        .statik(true)
+        .probe("libqb")
+        .is_err()
+    {
+        println!("cargo:warning=no static libqb found, linking the shared one");
+        println!("cargo:rustc-link-lib=qb");
+    }
+    librrd
+        .include_paths
+        .iter()
+        .map(|path| format!("-I{}", path.display()))
+        .collect()
+}
+
 fn main() {
-    println!("cargo:rustc-link-lib=rrd");
+    let clang_args = if env::var_os("CARGO_FEATURE_STATIC_RRD").is_some() {
+        link_static()
+    } else {
+        println!("cargo:rustc-link-lib=rrd");
+        println!("cargo:rustc-link-lib=qb");
+        Vec::new()
+    };
 
     println!("cargo:rerun-if-changed=wrapper.h");
+    println!("cargo:rerun-if-env-changed=PROXMOX_RRD_MIGRATION_TOOL_PATH");
     // The bindgen::Builder is the main entry point
     // to bindgen, and lets you build up options for
     // the resulting bindings.
@@ -13,6 +48,8 @@ fn main() {
         // The input header we would like to generate
         // bindings for.
         .header("wrapper.h")
+        // Where the static build has its rrd.h
+        .clang_args(clang_args)
         // Tell cargo to invalidate the built crate whenever any of the
         // included header files changed.
         .parse_callbacks(Box::new(bindgen::CargoCallbacks::new()))
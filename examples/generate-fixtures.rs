@@ -0,0 +1,107 @@
+//! Generate pve2 source RRD files with known data, for test scenarios that the binary fixtures in
+//! tests/resources do not cover
+//!
+//! cargo run --example generate-fixtures -- <DIR> [--end <SECONDS>] [--hours <N>] [--guests <N>]
+//!     [--corrupt]
+//!
+//! Writes the files of node testnode, guests 100 and 400 and storage iso of testnode below DIR, in
+//! the layout of /var/lib/rrdcached/db. Every value follows from its data source and the time of
+//! the update, so two runs with the same options write the same data.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error};
+
+use proxmox_rrd_migration_tool::migrate::{self, ResourceType, RRD_STEP_SIZE};
+
+/// Last update of the fixtures by default, that of the files in tests/resources/compare
+const DEFAULT_END: i64 = 1753999190;
+const DEFAULT_HOURS: i64 = 2;
+/// VMID of the first of the additional --guests
+const FIRST_EXTRA_GUEST: u32 = 1000;
+
+/// The update at 'time' of a file with the schema 'rrd_def', 'seed' tells files apart
+fn update(rrd_def: &[&std::ffi::CStr], time: i64, seed: u32) -> String {
+    let step = time / RRD_STEP_SIZE as i64;
+    let mut update = time.to_string();
+    let data_sources = rrd_def
+        .iter()
+        .filter_map(|def| def.to_str().ok()?.strip_prefix("DS:"));
+    for (index, def) in data_sources.enumerate() {
+        let index = index as i64 + 1;
+        let value = if def.contains(":DERIVE:") {
+            // counters only go up
+            step * index * 1024
+        } else {
+            (step + i64::from(seed)) % 100 * index
+        };
+        update.push_str(&format!(":{value}"));
+    }
+    update
+}
+
+fn generate(path: &Path, kind: ResourceType, end: i64, hours: i64, seed: u32) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context(format!("cannot create {dir:?}"))?;
+    }
+    let _ = fs::remove_file(path);
+    let rrd_def = kind.legacy_rrd_def();
+    let step = RRD_STEP_SIZE as i64;
+    let start = end - hours * 3600;
+    migrate::create_file(path, rrd_def, start - step)?;
+    let updates: Vec<String> = (start..=end)
+        .step_by(RRD_STEP_SIZE)
+        .map(|time| update(rrd_def, time, seed))
+        .collect();
+    migrate::update_file(path, &updates)?;
+    Ok(())
+}
+
+/// Cut 'path' to half its length, like a file that was not written completely
+fn truncate(path: &Path) -> Result<(), Error> {
+    let len = fs::metadata(path)?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(path)?
+        .set_len(len / 2)?;
+    Ok(())
+}
+
+fn main() -> Result<(), Error> {
+    let mut pargs = pico_args::Arguments::from_env();
+    let end: i64 = pargs.opt_value_from_str("--end")?.unwrap_or(DEFAULT_END);
+    let hours: i64 = pargs
+        .opt_value_from_str("--hours")?
+        .unwrap_or(DEFAULT_HOURS);
+    let guests: u32 = pargs.opt_value_from_str("--guests")?.unwrap_or(0);
+    let corrupt = pargs.contains("--corrupt");
+    let dir: PathBuf = pargs
+        .free_from_str()
+        .context("missing the target directory")?;
+
+    generate(
+        &dir.join("pve2-node/testnode"),
+        ResourceType::Node,
+        end,
+        hours,
+        0,
+    )?;
+    for vmid in [100, 400] {
+        let path = dir.join(format!("pve2-vm/{vmid}"));
+        generate(&path, ResourceType::Guest, end, hours, vmid)?;
+    }
+    for vmid in FIRST_EXTRA_GUEST..FIRST_EXTRA_GUEST + guests {
+        let path = dir.join(format!("pve2-vm/{vmid}"));
+        generate(&path, ResourceType::Guest, end, hours, vmid)?;
+    }
+    let storage = dir.join("pve2-storage/testnode/iso");
+    generate(&storage, ResourceType::Storage, end, hours, 0)?;
+
+    if corrupt {
+        let path = dir.join("pve2-vm/200");
+        generate(&path, ResourceType::Guest, end, hours, 200)?;
+        truncate(&path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,45 @@
+//! Collecting the files of a directory must not panic on any names and sizes of its entries
+//!
+//! Each line of the input is a file name, the length of the line decides the size of the file,
+//! so that some are too small to be RRD files.
+
+#![no_main]
+
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+use libfuzzer_sys::fuzz_target;
+
+use proxmox_rrd_migration_tool::migrate::{self, MIN_RRD_SIZE, OLD_SUFFIX};
+
+fn scratch_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("rrd-migration-fuzz-{}", std::process::id()))
+}
+
+fuzz_target!(|data: &[u8]| {
+    let dir = scratch_dir();
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("create scratch directory");
+    for line in data.split(|byte| *byte == b'\n') {
+        if line.is_empty() || line.contains(&b'/') || line.contains(&0) {
+            continue;
+        }
+        let name = OsStr::from_bytes(line);
+        if name == "." || name == ".." {
+            continue;
+        }
+        let len = line.len() as u64 * MIN_RRD_SIZE / 16;
+        let _ = fs::write(dir.join(name), vec![0u8; len as usize]);
+    }
+
+    for file in migrate::collect_rrd_files(&dir).expect("collect files") {
+        assert!(!file.1.is_empty());
+    }
+    let _ = migrate::collect_unusable_rrd_files(&dir).expect("collect unusable files");
+    for file in migrate::collect_old_rrd_files(&dir, OLD_SUFFIX).expect("collect old files") {
+        assert!(!file.1.is_empty());
+    }
+    let _ = fs::remove_dir_all(&dir);
+});
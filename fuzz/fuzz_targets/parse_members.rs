@@ -0,0 +1,11 @@
+//! The .members parsing must not panic on whatever pmxcfs, or a broken file, contains
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use proxmox_rrd_migration_tool::migrate;
+
+fuzz_target!(|data: &str| {
+    let _ = migrate::parse_members(data);
+});
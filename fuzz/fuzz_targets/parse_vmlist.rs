@@ -0,0 +1,13 @@
+//! The .vmlist parsing must not panic on whatever pmxcfs, or a broken file, contains
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use proxmox_rrd_migration_tool::migrate;
+
+fuzz_target!(|data: &str| {
+    for id in migrate::parse_guest_ids(data) {
+        assert!(!id.is_empty());
+    }
+});
@@ -0,0 +1,24 @@
+//! Classifying file names must not panic on any bytes a directory entry can have
+
+#![no_main]
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+use libfuzzer_sys::fuzz_target;
+
+use proxmox_rrd_migration_tool::migrate::{self, ResourceType};
+
+fuzz_target!(|data: &[u8]| {
+    let name = OsStr::from_bytes(data);
+    for kind in [
+        ResourceType::Guest,
+        ResourceType::Node,
+        ResourceType::Storage,
+    ] {
+        if migrate::is_valid_resource_name(kind, name) {
+            assert!(!data.is_empty());
+        }
+    }
+    let _ = migrate::is_target_backup(name);
+});
@@ -0,0 +1,1301 @@
+//! The command line options of the migration, layered over the environment and the config file
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error};
+use serde::Deserialize;
+
+use crate::config::{self, Config};
+use crate::logging::Verbosity;
+use crate::pattern::PathPattern;
+use crate::plan::OutputFormat;
+use crate::remigrate::FromOld;
+use crate::symlinks::SymlinkPolicy;
+use crate::{RemoteGuests, EXIT_SUCCESS};
+
+pub const HELP: &str = "\
+proxmox-rrd-migration tool
+
+Migrates existing RRD metrics data to the new format.
+
+Use this only in the process of upgrading from Proxmox VE 8 to 9 according to the upgrade guide!
+
+USAGE:
+    proxmox-rrd-migration [OPTIONS]
+    proxmox-rrd-migration restore --from <FILE> [-y] [--source <DIR>] [--target <DIR>]
+        [--old-suffix <SUFFIX>] [--archive-dir <DIR>]
+    proxmox-rrd-migration create <guest|node|storage> <NAME> [--node <NODE>] [--force]
+        [--target <DIR>] [--timestamp <TIME>]
+    proxmox-rrd-migration inspect <FILE> [--json]
+    proxmox-rrd-migration diff <FILE> <FILE> [--data [--samples <N>]]
+
+    FLAGS:
+        -h, --help              Prints this help information
+
+    OPTIONS:
+        --migrate               Start the migration. Without it, only a dry run will be done,
+                                which shows with --verbose how the data sources and RRAs of each
+                                file would change, and warns about source files that do not have
+                                the schema Proxmox VE 8 creates.
+
+        --force                 Migrate, even if the target already exists.
+                                This will overwrite any migrated RRD files! On a terminal, asks for
+                                confirmation first if there are any. The overwritten targets are
+                                kept as <NAME>.bak.<TIMESTAMP> next to the new ones. Targets an
+                                interrupted run left incomplete are replaced without it. Also
+                                migrates source directories another host migrated already, as
+                                recorded in their .migrated-by file, which happens if they are on
+                                shared storage or were copied from another node.
+
+        --incremental           Migrate the sources that were modified after their existing target
+                                again, updating the target, and skip those whose target is up to
+                                date. Keeps repeated runs cheap while the sources are still
+                                written to. Cannot be combined with --force.
+
+        -y, --yes               Do not ask before overwriting existing targets with --force, or
+                                before going on after --canary.
+
+        --service               Run as the systemd oneshot service of the shipped unit file:
+                                implies --migrate and --yes, logs to the journal, reports the
+                                progress to systemd and keeps the state of the run and the list
+                                of --failed-files in $STATE_DIRECTORY, so that the next start of
+                                the unit resumes an interrupted run. Exits with 0 also if there
+                                was nothing to do, with 1 if files could not be migrated and with
+                                6 if the checks before the migration failed, like systemd expects.
+                                Default state directory: /var/lib/proxmox-rrd-migration
+
+        --plan                  Instead of the dry run, print for each resource type which RRD
+                                files would be migrated, overwritten with --force, updated with
+                                --incremental, skipped as already migrated or marked as old, with
+                                their target paths and the data sources and RRAs of the new
+                                format. Nothing is changed.
+
+        --needs-migration       Only check whether there are RRD files left to migrate and print a
+                                single line about it. Exits with 10 if there are, see EXIT STATUS,
+                                and with 1 if that cannot be determined.
+
+        --plan-format <FORMAT>  'text' or 'json' for the output of --plan, printed on stdout.
+                                Default: text
+
+        --fsck                  Only check the whole RRD tree for anomalies and print them: targets
+                                of guests, nodes or storages that are not configured, guests and
+                                nodes without any RRD file, empty files and targets with wrong
+                                permissions. Nothing is changed. Exits with 11 if there are any,
+                                see EXIT STATUS.
+
+        --fsck-format <FORMAT>  'text' or 'json' for the output of --fsck, printed on stdout.
+                                Default: text
+
+        --dump-schema           Only print the data sources and RRAs the migrated RRD files of each
+                                resource type are created with, as arguments to rrdtool create and
+                                as tables with the resolution and retention of each RRA.
+
+        --list-leftovers        List the paths of the old source files and of the targets without
+                                a configured resource in the summary at the end of the run, not
+                                only how many there are.
+
+        --sample N              Only convert the first N RRD files of each resource type into a
+                                scratch directory and verify them, to try out librrd on this host
+                                and estimate how long the whole migration takes. The source files
+                                and the target directory are left alone.
+
+        --sample-dir <DIR>      Scratch directory for --sample, kept afterwards.
+                                Default: a temporary directory that is removed again
+
+        --render-samples N      After the migration, render PNG graphs of the last 30 days of the
+                                CPU of N random guests and of the memory of this node from their
+                                migrated files, as a quick visual check that their history
+                                survived. Needs --migrate. Not available if built with the
+                                'static-rrd' feature.
+
+        --render-dir <DIR>      Directory for the graphs of --render-samples.
+                                Default: <AUDIT DIR>/graphs-<RUN ID>
+
+        --cluster               Run the tool with the same options on every online node in
+                                .members, over SSH as root, and print a summary of the outcome on
+                                each. The PROXMOX_RRD_MIGRATION_* environment variables are passed
+                                on, a --config file is not, each node reads its default one. The
+                                output of the nodes is prefixed with their name. Exits with 3 if it
+                                did not succeed on all of them.
+
+        --estimate              Report how much space the migrated files need and how long the
+                                migration takes, from converting two RRD files of each resource
+                                type into a temporary directory. Nothing else is migrated. Exits
+                                with 13 if there is not enough space left in the target.
+
+        --benchmark             Convert some guest RRD files into a temporary directory with 1, 2,
+                                4, … threads up to the number of CPUs, print the throughput of each
+                                and recommend a value for --threads. Nothing else is migrated.
+
+        --benchmark-files N     Number of guest RRD files to convert per run of --benchmark.
+                                Default: 20
+
+        --canary                First migrate and verify a single node, guest and storage file,
+                                then ask whether to go on with the others. Needs --migrate.
+
+        --backup <FILE>         Before changing anything, archive the pve2-* source directories
+                                to the tar file FILE, compressed with zstd if it ends in .zst,
+                                and record it in the --audit-dir. The old files can be recovered
+                                from it even after the .old files were removed. Needs --migrate.
+
+        --archive-dir <DIR>     Move migrated source files and those of resources that are gone
+                                below DIR, in the same directories as in the source base
+                                directory, instead of renaming them to .old next to the others.
+
+        --symlinks <POLICY>     What to do with source files that are symbolic links: 'follow'
+                                migrates the file they point to and marks the link as old, 'skip'
+                                leaves them alone and 'replicate' creates the same link in the
+                                target directory, following those that point to another directory.
+                                Default: follow
+
+        --break-hardlinks       Copy source files that have other hard links, for example into a
+                                snapshot of the RRD directory, instead of only renaming them when
+                                marking them as old, so that the old file does not share its data
+                                with the other names. Without it, such files are warned about.
+
+        --skip-stale DAYS       Do not migrate source files that were last updated more than DAYS
+                                days ago, for example those of guests that were removed long ago,
+                                but mark them as old like those of resources that are gone.
+
+        --timestamp <TIME>      Create the targets as of TIME, in seconds since the epoch or like
+                                2025-08-01T00:00:00Z, in local time without an offset. Their last
+                                update is TIME instead of 10 seconds before the current time, to
+                                reproduce the targets of another migration exactly.
+
+        --quarantine-dir <DIR>  Move source files that librrd cannot read below DIR, in the same
+                                directories as in the source base directory, and go on with the
+                                others. They are reported as corrupt sources at the end.
+                                Default: <SOURCE>/corrupt
+
+        --try-repair            Try to repair source files that librrd cannot read, by dumping
+                                what can still be read of them, cutting off a truncated end and
+                                restoring the dump, before migrating them. The damaged files are
+                                kept in the quarantine directory, those that cannot be repaired,
+                                also because whole RRAs would be lost, are reported as corrupt
+                                sources.
+
+        --migrate-orphans       Also migrate the RRD files of guests that are not in .vmlist, for
+                                example because they were only removed temporarily or are on
+                                another cluster, instead of renaming them to .old.
+
+        --remote-guests <POLICY>
+                                What to do with the RRD files of guests that .vmlist lists on
+                                another cluster node, often leftovers from before they were moved:
+                                'all' migrates them like those of the local guests, 'local' leaves
+                                them alone and 'archive' marks them as old.
+                                Default: all
+
+        --prune-removed-storages
+                                Rename the RRD files of storages that are not in storage.cfg in
+                                the --resources directory to .old, like those of removed guests,
+                                instead of migrating them.
+
+        --keep-removed-storages Migrate the RRD files of all storages, also of those that are not
+                                configured anymore. This is the default.
+
+        --node <NAME>           Only migrate the storage RRD files of node NAME, in
+                                pve2-storage/<NAME>, the others belong to the other cluster nodes.
+                                Default: this node, as named in .members
+
+        --all-nodes             Migrate the storage RRD files of all nodes.
+
+        --storage <ID>[,<ID>...]
+                                Only migrate the RRD files of these storages, on all nodes, for
+                                example to migrate a big storage separately. The guest and node
+                                files are migrated as usual.
+
+        --from-old <all|LIST>   Migrate the old RRD files again instead of the current ones, to
+                                recreate broken targets after the sources were renamed to .old or
+                                moved to the --archive-dir. Either all of them or only those of the
+                                comma separated VMIDs, nodes or storages in LIST. The old files are
+                                left in place, the replaced targets are kept like with --force.
+
+        --reconcile             Look for the states an interrupted run can leave behind and report
+                                how to fix them, or fix them with --migrate: sources next to a
+                                verified target are marked as old, partial targets next to their
+                                source are deleted, and missing or broken targets of old sources
+                                are migrated again from them. Complete targets with other data
+                                than their source are only reported. Nothing else is migrated.
+
+        --verify                Only verify each migrated target against its source, or its old
+                                source once marked as old, like --verify-after-migrate does right
+                                after creating it. Nothing is changed. Exits with 12 if any target
+                                fails that.
+
+        --verify-threads N      Number of parallel threads for --verify. Default: --threads
+
+        --keep-source           Leave migrated source files as they are, instead of renaming them to
+                                .old. Those of resources that are gone are left alone too.
+
+        --delete-source         Delete each source file once its target was migrated and verified.
+                                Those of resources that are gone are still renamed to .old.
+
+        --old-suffix <SUFFIX>   Rename old source files by appending SUFFIX instead of '.old'.
+                                Files ending in it are never migrated.
+
+        --compress-old          Compress each old source file with zstd, to <NAME>.old.zst, once
+                                its target was verified. Most of the space the old files take is
+                                reclaimed that way, while still keeping them for a rollback. An
+                                old file zstd fails on is kept uncompressed. --verify, --reconcile
+                                and --from-old read the compressed old files as well.
+
+        --verify-after-migrate  Check each new file right after creating it: that librrd can read
+                                it, that it has the expected data sources, RRAs and rows, and that
+                                it holds the data of its source. A file failing that is removed
+                                and reported as failed, its source is left as it is.
+
+        --threads <THREADS|auto-tune>
+                                Number of paralell threads. With 'auto-tune', convert a dozen
+                                guest RRD files into a temporary directory with 1, 2, 4, …
+                                threads before migrating, like --benchmark, and use the fastest
+                                thread count. Default: a quarter of the CPUs, between 1 and 6
+
+        --max-threads THREADS   Automatically scale the number of guest migration threads up to
+                                THREADS, depending on how quickly the host keeps up, but not
+                                below --threads. See also SIGNALS below.
+
+        --io-threads THREADS    Read the guest RRD files ahead of their conversion with THREADS
+                                separate threads, so that the storage is kept busy while the
+                                conversion threads use the CPU. Helps on network file systems.
+                                Default: read by the conversion threads
+
+        --low-memory            Keep the memory usage down on small nodes: use at most 2 threads
+                                for each of --threads, --max-threads and --io-threads, keep at
+                                most 8 guest files queued for the threads, and list the storage
+                                files node by node while migrating them instead of all at once.
+                                The peak memory usage is printed at the end.
+
+        --batch-size N          Send the guest RRD files to the threads in batches of N, instead
+                                of one by one, which saves time on hosts with a lot of small
+                                files. Cannot be combined with --file-timeout, which would give
+                                up on whole batches. Default: 1
+
+        --stall-timeout SECONDS Warn about guest RRD files that take longer than SECONDS to migrate.
+                                Default: 300
+
+        --file-timeout SECONDS  Give up on an RRD file that takes longer than SECONDS to migrate,
+                                mark it as failed and continue with the next one.
+                                Default: no limit
+
+        --retries N             Retry migrating failed RRD files up to N times at the end of each
+                                phase, waiting exponentially longer between the attempts.
+                                Default: 0
+
+        --fail-fast             Abort the migration on the first RRD file that fails to migrate.
+
+        --max-errors N          Abort the migration once N RRD files failed to migrate, a file
+                                failing again on a retry counts once.
+
+        --failed-files <FILE>   If some RRD files could not be migrated, write their source paths
+                                to FILE, one per line with the error in a comment above it.
+
+        --files-from <FILE>     Only migrate the RRD files whose source paths are listed in FILE,
+                                for example to retry the ones written by --failed-files.
+
+        --match <PATTERN>       Only migrate the RRD files whose path relative to the source
+                                directory matches the glob PATTERN, like pve2-vm/1?? or
+                                pve2-storage/*/local-zfs. * and ? do not match a /, ** does. A
+                                PATTERN starting with re: is a regular expression instead, which
+                                only needs to match part of the path.
+
+        --log-file <FILE>       Append a timestamped line for every RRD file to FILE, recording
+                                whether it was migrated, skipped, overwritten or failed and why.
+
+        --audit-dir <DIR>       Append a record of this run, with its arguments, start and end time,
+                                counts and failures, to DIR/runs.log. The ID of the run is also
+                                written to the --log-file and --failed-files.
+                                Default: /var/log/proxmox-rrd-migration
+
+        --report <FILE>         Write a report of the run as JSON to FILE: the result, the number
+                                of files per outcome and per resource type, the files not
+                                migrated, the durations, versions and arguments. Also written by
+                                dry runs if given.
+                                Default: <TARGET>/migration-report.json, with --migrate
+
+        --status-file <FILE>    While running, keep the phase, the number of files done, to do
+                                and failed, and the PID in FILE as JSON, rewritten every few
+                                seconds, for pve-manager to show the progress. Its state is
+                                'finished' with the exit code once the run ended. Also written by
+                                dry runs if given.
+                                Default: /run/proxmox-rrd-migration/status.json, with --service
+
+        --notify-webhook <URL>  When the run ends, post its summary as JSON to URL with curl: the
+                                result (success, partial or failed), the number of files per
+                                outcome and the duration.
+
+        --notify-email <ADDRESS>
+                                When the run ends, mail its summary to ADDRESS with sendmail.
+
+        --metrics-listen <ADDR> Serve metrics about the progress in the Prometheus text format on
+                                http://ADDR/metrics while the run lasts, like 127.0.0.1:9199:
+                                the files per outcome, the size of the migrated source files, the
+                                progress of each phase and how many threads are busy.
+
+        --progress-fd N         Write progress events as JSON lines to the already open file
+                                descriptor N, for example for frontends. Human readable output
+                                stays on stderr.
+
+        --progress-every <N[%]> Print how many RRD files of a phase were migrated so far every N
+                                files, or every N percent of them with a trailing '%'. 0 disables
+                                it. Once known, with the estimated time left, from how quickly
+                                the last 50 files were migrated by their size. Default: 10
+
+        -q, --quiet             Only print warnings and errors, besides the final summary.
+
+        -v, --verbose           Also print a line for each RRD file. Given twice (-vv), print debug
+                                messages too, prefixed with their level and the phase and file
+                                they belong to. RUST_LOG takes precedence if set.
+
+        --legacy-output         Print messages on stdout and the summary of files not migrated on
+                                stderr, like earlier versions did. By default all messages go to
+                                stderr and only the final summary to stdout.
+
+        --no-color              Do not color the output, also if NO_COLOR is set. By default
+                                migrated files are shown in green, skipped ones in yellow and
+                                failures in red, if printing to a terminal.
+
+        --tui                   Show a dashboard with the progress of each phase, the files being
+                                migrated and the latest output instead of printing it. Only
+                                available if built with the 'tui' feature.
+
+        --dbus                  Own org.proxmox.RRDMigration1 on the system bus during the run.
+                                Its object /org/proxmox/RRDMigration1 has the properties RunId,
+                                State, Phase, Done, Total, Failed and Percent, and the methods
+                                Pause, which holds back the next files, Resume and Abort. Only
+                                available if built with the 'dbus' feature.
+
+        --log-target <TARGET>   Where to send the per-file log entries besides the console and
+                                --log-file: 'console' for nowhere else, 'journald' for the
+                                journal with RESOURCE_TYPE, FILE and RESULT fields, or 'auto' for
+                                the journal when running as systemd service.
+                                Default: auto
+
+        --source <SOURCE DIR>   Source base directory. Mainly for tests!
+                                Default: /var/lib/rrdcached/db
+
+        --target <TARGET DIR>   Target base directory. Mainly for tests!
+                                Default: /var/lib/rrdcached/db
+
+        --config <FILE>         Read defaults for the options from the TOML file FILE, options
+                                given on the command line take precedence. The keys are named
+                                like the long options, for example 'threads = 4' or
+                                'verbosity = \"verbose\"'. --migrate, --service, --force, --yes
+                                and --cluster can only be given on the command line. A switch
+                                turned on in the file or the environment is turned off with its
+                                --no-* option, like --no-keep-source, and --no-color with --color.
+                                Default: /etc/proxmox-rrd-migration.conf, if it exists
+
+        --resources <DIR>       Directory that contains .vmlist and .member files, and storage.cfg
+                                for --prune-removed-storages. Mainly for tests!
+                                Default: /etc/pve
+
+        --ignore-quorum         Migrate even if the cluster is not quorate. Its guest list may be
+                                stale then, so that the files of existing guests are marked as old
+                                like those of removed ones.
+
+        --resources-from-ipc    Query the live guest and node lists from pmxcfs over its IPC
+                                instead of reading .vmlist and .members, for example when
+                                /etc/pve is mounted elsewhere. storage.cfg is still read from
+                                --resources.
+
+    RESTORE:
+        Puts the source files from FILE, created with --backup, back into place, overwriting
+        the current ones, and removes their migrated targets and their old files, so that the
+        migration can be started over. The old files are found by --old-suffix and
+        --archive-dir, like above, also compressed with --compress-old. Asks for confirmation
+        first unless -y is given, which is required if not run on a terminal. --source and
+        --target are the base directories, like above.
+
+    CREATE:
+        Creates an empty RRD file in the new format for the guest, node or storage NAME below
+        --target, with the data sources and RRAs the migration creates. Storage files go below
+        the directory of NODE, by default the local node. An existing file is only replaced
+        with --force, it is kept as <NAME>.bak.<TIMESTAMP> then. --timestamp is the time of its
+        last update, like above.
+
+    INSPECT:
+        Prints the version, step, data sources, RRAs and last update of the RRD file FILE, for
+        example a migrated target, without needing rrdtool. --json prints them as JSON.
+
+    DIFF:
+        Compares the version, step, last update, data sources and RRAs of two RRD files, for
+        example a source .old file and its migrated target, and prints what differs, lines of
+        the first file with a '-' and those of the second with a '+'. With --data, also compares
+        the values of the data sources both have at N points in time (default 10) of the last day
+        before the earlier last update, for each consolidation function both have. Exits with 1
+        if the files differ.
+
+    All options can also be set in PROXMOX_RRD_MIGRATION_* environment variables, named like the
+    long options in upper case, for example PROXMOX_RRD_MIGRATION_MAX_THREADS=4 or
+    PROXMOX_RRD_MIGRATION_KEEP_SOURCE=1, except for those that can only be given on the command
+    line, see --config. Options on the command line take precedence over the
+    environment, which takes precedence over the config file. PROXMOX_RRD_MIGRATION_VERBOSITY, like
+    the 'verbosity' key, is one of quiet, normal, verbose or debug (-vv).
+
+    SIGNALS:
+        SIGUSR1                 Add a guest migration thread.
+        SIGUSR2                 Remove a guest migration thread, down to one.
+
+        Both are handled for the whole run, signals received before the guest migration apply
+        once it starts. Not available if built with the 'rayon' feature, the signals terminate
+        the run then.
+
+    EXIT STATUS:
+        0                       All RRD files were migrated, or there were none. For the checks
+                                below, they found nothing.
+        1                       A migration phase failed or was aborted, or a check could not run.
+        2                       Invalid command line.
+        3                       Some RRD files could not be migrated.
+        4                       Checks before the migration failed, nothing was changed.
+        5                       Nothing to do, all RRD files were migrated already. Not for a dry
+                                run, it reports them as usual.
+        10                      --needs-migration found RRD files left to migrate.
+        11                      --fsck found anomalies.
+        12                      --verify found targets that fail the verification.
+        13                      --estimate found not enough space left in the target.
+
+";
+
+/// How often to print the number of files migrated so far in a phase
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "ProgressIntervalValue")]
+pub enum ProgressInterval {
+    /// every N files
+    Files(usize),
+    /// every N percent of the files of the phase
+    Percent(usize),
+}
+
+impl ProgressInterval {
+    /// Whether to print the progress once 'done' of 'total' files are migrated
+    pub(crate) fn is_due(self, done: usize, total: usize) -> bool {
+        match self {
+            ProgressInterval::Files(0) | ProgressInterval::Percent(0) => false,
+            ProgressInterval::Files(files) => done > 0 && done % files == 0,
+            ProgressInterval::Percent(percent) => {
+                let step = |done: usize| done * 100 / total.max(1) / percent;
+                done > 0 && step(done) != step(done - 1)
+            }
+        }
+    }
+}
+
+impl FromStr for ProgressInterval {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.strip_suffix('%') {
+            Some(percent) => match percent.parse::<usize>()? {
+                percent @ 0..=100 => Ok(ProgressInterval::Percent(percent)),
+                _ => bail!("percentage must be between 0 and 100"),
+            },
+            None => Ok(ProgressInterval::Files(value.parse()?)),
+        }
+    }
+}
+
+/// A progress interval in the config file, a plain number or a string like on the command line
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProgressIntervalValue {
+    Files(usize),
+    Text(String),
+}
+
+impl TryFrom<ProgressIntervalValue> for ProgressInterval {
+    type Error = Error;
+
+    fn try_from(value: ProgressIntervalValue) -> Result<Self, Self::Error> {
+        match value {
+            ProgressIntervalValue::Files(files) => Ok(ProgressInterval::Files(files)),
+            ProgressIntervalValue::Text(text) => text.parse(),
+        }
+    }
+}
+
+/// The time the targets are created as of, in seconds since the epoch, see --timestamp
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "TimestampValue")]
+pub struct Timestamp(pub i64);
+
+impl FromStr for Timestamp {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Ok(seconds) = value.parse() {
+            return Ok(Timestamp(seconds));
+        }
+        match parse_iso_time(value) {
+            Some(seconds) => Ok(Timestamp(seconds)),
+            None => bail!(
+                "'{value}' is neither seconds since the epoch nor a time like 2025-08-01T00:00:00Z"
+            ),
+        }
+    }
+}
+
+/// The number of guest migration threads, see --threads
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "ThreadsValue")]
+pub enum Threads {
+    Count(usize),
+    /// the fastest count in a calibration run before migrating
+    AutoTune,
+}
+
+impl FromStr for Threads {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "auto-tune" => Ok(Threads::AutoTune),
+            _ => match value.parse() {
+                Ok(threads) => Ok(Threads::Count(threads)),
+                Err(_) => bail!("'{value}' is neither a number of threads nor 'auto-tune'"),
+            },
+        }
+    }
+}
+
+/// A thread count in the config file, either a plain number or a string like on the command line
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ThreadsValue {
+    Count(usize),
+    Text(String),
+}
+
+impl TryFrom<ThreadsValue> for Threads {
+    type Error = Error;
+
+    fn try_from(value: ThreadsValue) -> Result<Self, Self::Error> {
+        match value {
+            ThreadsValue::Count(threads) => Ok(Threads::Count(threads)),
+            ThreadsValue::Text(text) => text.parse(),
+        }
+    }
+}
+
+/// A timestamp in the config file, either a plain number or a string like on the command line
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TimestampValue {
+    Seconds(i64),
+    Text(String),
+}
+
+impl TryFrom<TimestampValue> for Timestamp {
+    type Error = Error;
+
+    fn try_from(value: TimestampValue) -> Result<Self, Self::Error> {
+        match value {
+            TimestampValue::Seconds(seconds) => Ok(Timestamp(seconds)),
+            TimestampValue::Text(text) => text.parse(),
+        }
+    }
+}
+
+/// Seconds of a UTC offset like Z, +02:00 or -0130
+fn parse_utc_offset(offset: &str) -> Option<i64> {
+    if offset == "Z" {
+        return Some(0);
+    }
+    let sign = if offset.starts_with('-') { -1 } else { 1 };
+    let digits: String = offset.get(1..)?.chars().filter(|c| *c != ':').collect();
+    if digits.len() != 4 || !digits.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i64 = digits[..2].parse().ok()?;
+    let minutes: i64 = digits[2..].parse().ok()?;
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Seconds since the epoch of an ISO 8601 time, in local time without an offset
+fn parse_iso_time(value: &str) -> Option<i64> {
+    let (date, time) = value.split_once(['T', ' '])?;
+    let (time, offset) = match time.find(['Z', '+', '-']) {
+        Some(index) => (&time[..index], Some(parse_utc_offset(&time[index..])?)),
+        None => (time, None),
+    };
+    let mut date = date.splitn(3, '-').map(str::parse::<i32>);
+    let mut clock = time.splitn(3, ':').map(str::parse::<i32>);
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = date.next()?.ok()? - 1900;
+    tm.tm_mon = date.next()?.ok()? - 1;
+    tm.tm_mday = date.next()?.ok()?;
+    tm.tm_hour = clock.next()?.ok()?;
+    tm.tm_min = clock.next()?.ok()?;
+    tm.tm_sec = clock.next().unwrap_or(Ok(0)).ok()?;
+    if !(0..12).contains(&tm.tm_mon)
+        || !(1..=31).contains(&tm.tm_mday)
+        || !(0..24).contains(&tm.tm_hour)
+        || !(0..60).contains(&tm.tm_min)
+        || !(0..=60).contains(&tm.tm_sec)
+    {
+        return None;
+    }
+    match offset {
+        Some(offset) => Some(unsafe { libc::timegm(&mut tm) } - offset),
+        None => {
+            tm.tm_isdst = -1;
+            let seconds = unsafe { libc::mktime(&mut tm) };
+            (seconds != -1).then_some(seconds)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Args {
+    pub migrate: bool,
+    pub service: bool,
+    pub force: bool,
+    pub incremental: bool,
+    pub fail_fast: bool,
+    pub verbosity: Option<Verbosity>,
+    pub legacy_output: bool,
+    pub no_color: bool,
+    pub yes: bool,
+    pub backup: Option<PathBuf>,
+    pub archive_dir: Option<PathBuf>,
+    pub quarantine_dir: Option<PathBuf>,
+    pub try_repair: bool,
+    pub skip_stale: Option<u64>,
+    pub timestamp: Option<Timestamp>,
+    pub symlinks: Option<SymlinkPolicy>,
+    pub break_hardlinks: bool,
+    pub keep_source: bool,
+    pub delete_source: bool,
+    pub old_suffix: Option<String>,
+    pub compress_old: bool,
+    pub verify_after_migrate: bool,
+    pub migrate_orphans: bool,
+    pub remote_guests: Option<RemoteGuests>,
+    pub prune_removed_storages: bool,
+    pub keep_removed_storages: bool,
+    pub node: Option<String>,
+    pub all_nodes: bool,
+    pub storage: Option<String>,
+    pub from_old: Option<FromOld>,
+    pub reconcile: bool,
+    pub verify: bool,
+    pub verify_threads: Option<usize>,
+    pub plan: bool,
+    pub needs_migration: bool,
+    pub plan_format: Option<OutputFormat>,
+    pub fsck: bool,
+    pub cluster: bool,
+    pub fsck_format: Option<OutputFormat>,
+    pub dump_schema: bool,
+    pub list_leftovers: bool,
+    pub canary: bool,
+    pub sample: Option<usize>,
+    pub sample_dir: Option<PathBuf>,
+    pub render_samples: Option<usize>,
+    pub render_dir: Option<PathBuf>,
+    pub estimate: bool,
+    pub benchmark: bool,
+    pub benchmark_files: Option<usize>,
+    pub tui: bool,
+    pub dbus: bool,
+    pub max_errors: Option<usize>,
+    pub threads: Option<Threads>,
+    pub max_threads: Option<usize>,
+    pub io_threads: Option<usize>,
+    pub low_memory: bool,
+    pub batch_size: Option<usize>,
+    pub stall_timeout: Option<u64>,
+    pub file_timeout: Option<u64>,
+    pub retries: Option<u32>,
+    pub failed_files: Option<PathBuf>,
+    pub files_from: Option<PathBuf>,
+    pub path_match: Option<PathPattern>,
+    pub log_file: Option<PathBuf>,
+    pub log_target: Option<String>,
+    pub audit_dir: Option<PathBuf>,
+    pub notify_webhook: Option<String>,
+    pub notify_email: Option<String>,
+    pub metrics_listen: Option<SocketAddr>,
+    pub report: Option<PathBuf>,
+    pub status_file: Option<PathBuf>,
+    pub progress_fd: Option<i32>,
+    pub progress_every: Option<ProgressInterval>,
+    pub source: Option<String>,
+    pub target: Option<String>,
+    pub resources: Option<String>,
+    pub resources_from_ipc: bool,
+    pub ignore_quorum: bool,
+}
+
+impl Args {
+    /// Take the switches from 'config' and the options not given on the command line
+    fn apply_config(&mut self, config: Config) {
+        self.migrate = config.migrate.unwrap_or(false);
+        self.service = config.service.unwrap_or(false);
+        self.force = config.force.unwrap_or(false);
+        self.incremental = config.incremental.unwrap_or(false);
+        self.yes = config.yes.unwrap_or(false);
+        self.backup = self.backup.take().or(config.backup);
+        self.archive_dir = self.archive_dir.take().or(config.archive_dir);
+        self.quarantine_dir = self.quarantine_dir.take().or(config.quarantine_dir);
+        self.try_repair = config.try_repair.unwrap_or(false);
+        self.skip_stale = self.skip_stale.or(config.skip_stale);
+        self.timestamp = self.timestamp.or(config.timestamp);
+        self.symlinks = self.symlinks.or(config.symlinks);
+        self.break_hardlinks = config.break_hardlinks.unwrap_or(false);
+        self.keep_source = config.keep_source.unwrap_or(false);
+        self.delete_source = config.delete_source.unwrap_or(false);
+        self.old_suffix = self.old_suffix.take().or(config.old_suffix);
+        self.compress_old = config.compress_old.unwrap_or(false);
+        self.verify_after_migrate = config.verify_after_migrate.unwrap_or(false);
+        self.migrate_orphans = config.migrate_orphans.unwrap_or(false);
+        self.remote_guests = self.remote_guests.or(config.remote_guests);
+        self.from_old = self.from_old.take().or(config.from_old);
+        self.reconcile = config.reconcile.unwrap_or(false);
+        self.verify = config.verify.unwrap_or(false);
+        self.verify_threads = self.verify_threads.or(config.verify_threads);
+        // each pair is taken from the same layer, see Config::or
+        self.prune_removed_storages = config.prune_removed_storages.unwrap_or(false);
+        self.keep_removed_storages = config.keep_removed_storages.unwrap_or(false);
+        self.node = config.node;
+        self.all_nodes = config.all_nodes.unwrap_or(false);
+        self.storage = self.storage.take().or(config.storage);
+        self.threads = self.threads.or(config.threads);
+        self.max_threads = self.max_threads.or(config.max_threads);
+        self.io_threads = self.io_threads.or(config.io_threads);
+        self.low_memory = config.low_memory.unwrap_or(false);
+        self.batch_size = self.batch_size.or(config.batch_size);
+        self.stall_timeout = self.stall_timeout.or(config.stall_timeout);
+        self.file_timeout = self.file_timeout.or(config.file_timeout);
+        self.retries = self.retries.or(config.retries);
+        self.fail_fast = config.fail_fast.unwrap_or(false);
+        self.max_errors = self.max_errors.or(config.max_errors);
+        self.failed_files = self.failed_files.take().or(config.failed_files);
+        self.path_match = self.path_match.take().or(config.path_match);
+        self.log_file = self.log_file.take().or(config.log_file);
+        self.log_target = self.log_target.take().or(config.log_target);
+        self.audit_dir = self.audit_dir.take().or(config.audit_dir);
+        self.notify_webhook = self.notify_webhook.take().or(config.notify_webhook);
+        self.notify_email = self.notify_email.take().or(config.notify_email);
+        self.metrics_listen = self.metrics_listen.or(config.metrics_listen);
+        self.report = self.report.take().or(config.report);
+        self.status_file = self.status_file.take().or(config.status_file);
+        self.progress_every = self.progress_every.or(config.progress_every);
+        self.verbosity = self.verbosity.or(config.verbosity);
+        self.legacy_output = config.legacy_output.unwrap_or(false);
+        self.no_color = config.no_color.unwrap_or(false);
+        self.plan = config.plan.unwrap_or(false);
+        self.needs_migration = config.needs_migration.unwrap_or(false);
+        self.plan_format = self.plan_format.or(config.plan_format);
+        self.fsck = config.fsck.unwrap_or(false);
+        self.fsck_format = self.fsck_format.or(config.fsck_format);
+        self.list_leftovers = config.list_leftovers.unwrap_or(false);
+        self.canary = config.canary.unwrap_or(false);
+        self.sample = self.sample.or(config.sample);
+        self.sample_dir = self.sample_dir.take().or(config.sample_dir);
+        self.render_samples = self.render_samples.or(config.render_samples);
+        self.render_dir = self.render_dir.take().or(config.render_dir);
+        self.estimate = config.estimate.unwrap_or(false);
+        self.benchmark = config.benchmark.unwrap_or(false);
+        self.benchmark_files = self.benchmark_files.or(config.benchmark_files);
+        self.source = self.source.take().or(config.source);
+        self.target = self.target.take().or(config.target);
+        self.resources = self.resources.take().or(config.resources);
+        self.resources_from_ipc = config.resources_from_ipc.unwrap_or(false);
+        self.ignore_quorum = config.ignore_quorum.unwrap_or(false);
+    }
+
+    /// Check the combination of options, returns why it is invalid
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        if self.tui && !cfg!(feature = "tui") {
+            bail!("--tui is not available, built without the 'tui' feature");
+        }
+        if self.dbus && !cfg!(feature = "dbus") {
+            bail!("--dbus is not available, built without the 'dbus' feature");
+        }
+        if cfg!(feature = "rayon") {
+            // the rayon pool can neither scale, give up on files, read ahead nor batch
+            let unsupported = [
+                ("--max-threads", self.max_threads.is_some()),
+                ("--file-timeout", self.file_timeout.is_some()),
+                ("--io-threads", self.io_threads.is_some()),
+                ("--batch-size", self.batch_size.is_some_and(|size| size > 1)),
+            ];
+            for (option, given) in unsupported {
+                if given {
+                    bail!("{option} is not available, built with the 'rayon' feature");
+                }
+            }
+        }
+        if self.service
+            && (self.tui
+                || self.plan
+                || self.needs_migration
+                || self.fsck
+                || self.verify
+                || self.cluster)
+        {
+            bail!(
+                "--service migrates unattended, do not give --tui, --plan, --needs-migration, \
+                --fsck, --verify or --cluster"
+            );
+        }
+        if self.plan && self.migrate {
+            bail!("--plan only shows what --migrate would do, do not give both");
+        }
+        if self.needs_migration && self.migrate {
+            bail!("--needs-migration only checks, do not give --migrate");
+        }
+        if self.fsck && self.migrate {
+            bail!("--fsck only checks, do not give --migrate");
+        }
+        if self.verify && self.migrate {
+            bail!("--verify only checks, do not give --migrate");
+        }
+        if self.verify_threads == Some(0) {
+            bail!("--verify-threads must be at least 1");
+        }
+        if self.cluster && (self.progress_fd.is_some() || self.tui) {
+            bail!("--cluster reads the progress of the nodes, do not give --progress-fd or --tui");
+        }
+        if self.batch_size == Some(0) {
+            bail!("--batch-size must be at least 1");
+        }
+        if self.batch_size.is_some_and(|size| size > 1) && self.file_timeout.is_some() {
+            bail!("--batch-size cannot be combined with --file-timeout");
+        }
+        if self.backup.is_some() && !self.migrate {
+            bail!("--backup needs --migrate, a dry run does not change anything");
+        }
+        if [
+            self.archive_dir.is_some(),
+            self.keep_source,
+            self.delete_source,
+        ]
+        .iter()
+        .filter(|set| **set)
+        .count()
+            > 1
+        {
+            bail!("only one of --archive-dir, --keep-source and --delete-source can be given");
+        }
+        if self.prune_removed_storages && self.keep_removed_storages {
+            bail!("--prune-removed-storages and --keep-removed-storages exclude each other");
+        }
+        if self
+            .storage
+            .as_deref()
+            .is_some_and(|ids| ids.split(',').any(str::is_empty))
+        {
+            bail!("--storage needs a comma-separated list of storage IDs");
+        }
+        if self.node.is_some() && self.all_nodes {
+            bail!("--node and --all-nodes exclude each other");
+        }
+        if self.compress_old && (self.keep_source || self.delete_source) {
+            bail!("--compress-old does not go with --keep-source or --delete-source");
+        }
+        if self
+            .old_suffix
+            .as_deref()
+            .is_some_and(|suffix| suffix.is_empty() || suffix.contains('/'))
+        {
+            bail!("--old-suffix must not be empty or contain a '/'");
+        }
+        if self.canary && !self.migrate {
+            bail!("--canary needs --migrate");
+        }
+        if self.render_samples.is_some() && cfg!(feature = "static-rrd") {
+            bail!("--render-samples is not available, built with the 'static-rrd' feature");
+        }
+        if self.render_samples.is_some() && !self.migrate {
+            bail!("--render-samples needs --migrate, a dry run creates no files to render");
+        }
+        if self
+            .notify_webhook
+            .as_deref()
+            .is_some_and(|url| !url.starts_with("http://") && !url.starts_with("https://"))
+        {
+            bail!("--notify-webhook needs an http:// or https:// URL");
+        }
+        if self
+            .notify_email
+            .as_deref()
+            .is_some_and(|address| address.starts_with('-') || !address.contains('@'))
+        {
+            bail!("--notify-email needs a mail address");
+        }
+        if self.render_dir.is_some() && self.render_samples.is_none() {
+            bail!("--render-dir needs --render-samples");
+        }
+        if self.tui && self.canary && !self.yes {
+            bail!("--canary with --tui needs --yes, the dashboard cannot ask to go on");
+        }
+        if self.force && self.incremental {
+            bail!("--force overwrites all targets, --incremental only outdated ones");
+        }
+        if self.tui && self.force && self.migrate && !self.yes {
+            bail!("--force with --tui needs --yes, the dashboard cannot ask before overwriting");
+        }
+        Ok(())
+    }
+}
+
+/// A switch turned on with 'on' or off with 'off' on the command line, none if neither was given
+fn switch(
+    pargs: &mut pico_args::Arguments,
+    on: impl Into<pico_args::Keys>,
+    off: &'static str,
+) -> Result<Option<bool>, Error> {
+    match (pargs.contains(on), pargs.contains(off)) {
+        (true, true) => bail!("{off} contradicts the switch it turns off"),
+        (true, false) => Ok(Some(true)),
+        (false, true) => Ok(Some(false)),
+        (false, false) => Ok(None),
+    }
+}
+
+pub(crate) fn parse_args() -> Result<Args, Error> {
+    let mut pargs = pico_args::Arguments::from_env();
+
+    // Help has a higher priority and should be handled separately.
+    if pargs.contains(["-h", "--help"]) {
+        print!("{HELP}");
+        std::process::exit(EXIT_SUCCESS);
+    }
+
+    let config: Option<PathBuf> = pargs
+        .opt_value_from_str("--config")
+        .context("Could not parse --config parameter")?;
+
+    // the switches are merged with the environment and the config file before taking them
+    let mut switches = Config::default();
+    let mut args = Args {
+        migrate: false,
+        service: false,
+        threads: pargs
+            .opt_value_from_str("--threads")
+            .context("Could not parse --threads parameter")?,
+        max_threads: pargs
+            .opt_value_from_str("--max-threads")
+            .context("Could not parse --max-threads parameter")?,
+        io_threads: pargs
+            .opt_value_from_str("--io-threads")
+            .context("Could not parse --io-threads parameter")?,
+        low_memory: false,
+        batch_size: pargs
+            .opt_value_from_str("--batch-size")
+            .context("Could not parse --batch-size parameter")?,
+        verify_threads: pargs
+            .opt_value_from_str("--verify-threads")
+            .context("Could not parse --verify-threads parameter")?,
+        stall_timeout: pargs
+            .opt_value_from_str("--stall-timeout")
+            .context("Could not parse --stall-timeout parameter")?,
+        file_timeout: pargs
+            .opt_value_from_str("--file-timeout")
+            .context("Could not parse --file-timeout parameter")?,
+        retries: pargs
+            .opt_value_from_str("--retries")
+            .context("Could not parse --retries parameter")?,
+        force: false,
+        incremental: false,
+        fail_fast: false,
+        verbosity: None,
+        legacy_output: false,
+        no_color: false,
+        yes: false,
+        backup: pargs
+            .opt_value_from_str("--backup")
+            .context("Could not parse --backup parameter")?,
+        archive_dir: pargs
+            .opt_value_from_str("--archive-dir")
+            .context("Could not parse --archive-dir parameter")?,
+        quarantine_dir: pargs
+            .opt_value_from_str("--quarantine-dir")
+            .context("Could not parse --quarantine-dir parameter")?,
+        try_repair: false,
+        skip_stale: pargs
+            .opt_value_from_str("--skip-stale")
+            .context("Could not parse --skip-stale parameter")?,
+        timestamp: pargs
+            .opt_value_from_str("--timestamp")
+            .context("Could not parse --timestamp parameter")?,
+        symlinks: pargs
+            .opt_value_from_str("--symlinks")
+            .context("Could not parse --symlinks parameter")?,
+        break_hardlinks: false,
+        keep_source: false,
+        delete_source: false,
+        old_suffix: pargs
+            .opt_value_from_str("--old-suffix")
+            .context("Could not parse --old-suffix parameter")?,
+        compress_old: false,
+        verify_after_migrate: false,
+        migrate_orphans: false,
+        remote_guests: pargs
+            .opt_value_from_str("--remote-guests")
+            .context("Could not parse --remote-guests parameter")?,
+        prune_removed_storages: false,
+        keep_removed_storages: false,
+        node: pargs
+            .opt_value_from_str("--node")
+            .context("Could not parse --node parameter")?,
+        all_nodes: false,
+        storage: pargs
+            .opt_value_from_str("--storage")
+            .context("Could not parse --storage parameter")?,
+        from_old: pargs
+            .opt_value_from_str("--from-old")
+            .context("Could not parse --from-old parameter")?,
+        reconcile: false,
+        verify: false,
+        plan: false,
+        needs_migration: false,
+        plan_format: pargs
+            .opt_value_from_str("--plan-format")
+            .context("Could not parse --plan-format parameter")?,
+        fsck: false,
+        cluster: false,
+        fsck_format: pargs
+            .opt_value_from_str("--fsck-format")
+            .context("Could not parse --fsck-format parameter")?,
+        dump_schema: false,
+        list_leftovers: false,
+        canary: false,
+        sample: pargs
+            .opt_value_from_str("--sample")
+            .context("Could not parse --sample parameter")?,
+        sample_dir: pargs
+            .opt_value_from_str("--sample-dir")
+            .context("Could not parse --sample-dir parameter")?,
+        render_samples: pargs
+            .opt_value_from_str("--render-samples")
+            .context("Could not parse --render-samples parameter")?,
+        render_dir: pargs
+            .opt_value_from_str("--render-dir")
+            .context("Could not parse --render-dir parameter")?,
+        estimate: false,
+        benchmark: false,
+        benchmark_files: pargs
+            .opt_value_from_str("--benchmark-files")
+            .context("Could not parse --benchmark-files parameter")?,
+        tui: false,
+        dbus: false,
+        max_errors: pargs
+            .opt_value_from_str("--max-errors")
+            .context("Could not parse --max-errors parameter")?,
+        failed_files: pargs
+            .opt_value_from_str("--failed-files")
+            .context("Could not parse --failed-files parameter")?,
+        files_from: pargs
+            .opt_value_from_str("--files-from")
+            .context("Could not parse --files-from parameter")?,
+        path_match: pargs
+            .opt_value_from_str("--match")
+            .context("Could not parse --match parameter")?,
+        log_file: pargs
+            .opt_value_from_str("--log-file")
+            .context("Could not parse --log-file parameter")?,
+        log_target: pargs
+            .opt_value_from_str("--log-target")
+            .context("Could not parse --log-target parameter")?,
+        audit_dir: pargs
+            .opt_value_from_str("--audit-dir")
+            .context("Could not parse --audit-dir parameter")?,
+        notify_webhook: pargs
+            .opt_value_from_str("--notify-webhook")
+            .context("Could not parse --notify-webhook parameter")?,
+        notify_email: pargs
+            .opt_value_from_str("--notify-email")
+            .context("Could not parse --notify-email parameter")?,
+        metrics_listen: pargs
+            .opt_value_from_str("--metrics-listen")
+            .context("Could not parse --metrics-listen parameter")?,
+        report: pargs
+            .opt_value_from_str("--report")
+            .context("Could not parse --report parameter")?,
+        status_file: pargs
+            .opt_value_from_str("--status-file")
+            .context("Could not parse --status-file parameter")?,
+        progress_fd: pargs
+            .opt_value_from_str("--progress-fd")
+            .context("Could not parse --progress-fd parameter")?,
+        progress_every: pargs
+            .opt_value_from_str("--progress-every")
+            .context("Could not parse --progress-every parameter")?,
+        source: pargs
+            .opt_value_from_str("--source")
+            .context("Could not parse --source parameter")?,
+        target: pargs
+            .opt_value_from_str("--target")
+            .context("Could not parse --target parameter")?,
+        resources: pargs
+            .opt_value_from_str("--resources")
+            .context("Could not parse --resources parameter")?,
+        resources_from_ipc: false,
+        ignore_quorum: false,
+    };
+
+    switches.migrate = switch(&mut pargs, "--migrate", "--no-migrate")?;
+    switches.service = switch(&mut pargs, "--service", "--no-service")?;
+    switches.force = switch(&mut pargs, "--force", "--no-force")?;
+    switches.incremental = switch(&mut pargs, "--incremental", "--no-incremental")?;
+    switches.try_repair = switch(&mut pargs, "--try-repair", "--no-try-repair")?;
+    switches.resources_from_ipc = switch(
+        &mut pargs,
+        "--resources-from-ipc",
+        "--no-resources-from-ipc",
+    )?;
+    switches.ignore_quorum = switch(&mut pargs, "--ignore-quorum", "--no-ignore-quorum")?;
+    switches.break_hardlinks = switch(&mut pargs, "--break-hardlinks", "--no-break-hardlinks")?;
+    switches.fail_fast = switch(&mut pargs, "--fail-fast", "--no-fail-fast")?;
+    if pargs.contains(["-q", "--quiet"]) {
+        args.verbosity = Some(Verbosity::Quiet);
+    } else if pargs.contains("-vv") {
+        args.verbosity = Some(Verbosity::Debug);
+    } else if pargs.contains(["-v", "--verbose"]) {
+        args.verbosity = Some(if pargs.contains(["-v", "--verbose"]) {
+            Verbosity::Debug
+        } else {
+            Verbosity::Verbose
+        });
+    }
+    switches.legacy_output = switch(&mut pargs, "--legacy-output", "--no-legacy-output")?;
+    switches.no_color = switch(&mut pargs, "--no-color", "--color")?;
+    switches.yes = switch(&mut pargs, ["-y", "--yes"], "--no-yes")?;
+    switches.plan = switch(&mut pargs, "--plan", "--no-plan")?;
+    switches.needs_migration = switch(&mut pargs, "--needs-migration", "--no-needs-migration")?;
+    switches.fsck = switch(&mut pargs, "--fsck", "--no-fsck")?;
+    if pargs.contains("--cluster") {
+        args.cluster = true;
+    }
+    args.dump_schema = switch(&mut pargs, "--dump-schema", "--no-dump-schema")?.unwrap_or(false);
+    switches.list_leftovers = switch(&mut pargs, "--list-leftovers", "--no-list-leftovers")?;
+    switches.keep_source = switch(&mut pargs, "--keep-source", "--no-keep-source")?;
+    switches.delete_source = switch(&mut pargs, "--delete-source", "--no-delete-source")?;
+    switches.migrate_orphans = switch(&mut pargs, "--migrate-orphans", "--no-migrate-orphans")?;
+    switches.prune_removed_storages = switch(
+        &mut pargs,
+        "--prune-removed-storages",
+        "--no-prune-removed-storages",
+    )?;
+    switches.keep_removed_storages = switch(
+        &mut pargs,
+        "--keep-removed-storages",
+        "--no-keep-removed-storages",
+    )?;
+    switches.all_nodes = switch(&mut pargs, "--all-nodes", "--no-all-nodes")?;
+    // taken together with --all-nodes from the layer that sets either
+    switches.node = args.node.take();
+    switches.reconcile = switch(&mut pargs, "--reconcile", "--no-reconcile")?;
+    switches.verify = switch(&mut pargs, "--verify", "--no-verify")?;
+    switches.compress_old = switch(&mut pargs, "--compress-old", "--no-compress-old")?;
+    switches.verify_after_migrate = switch(
+        &mut pargs,
+        "--verify-after-migrate",
+        "--no-verify-after-migrate",
+    )?;
+    switches.low_memory = switch(&mut pargs, "--low-memory", "--no-low-memory")?;
+    switches.estimate = switch(&mut pargs, "--estimate", "--no-estimate")?;
+    switches.benchmark = switch(&mut pargs, "--benchmark", "--no-benchmark")?;
+    switches.canary = switch(&mut pargs, "--canary", "--no-canary")?;
+    args.tui = switch(&mut pargs, "--tui", "--no-tui")?.unwrap_or(false);
+    args.dbus = switch(&mut pargs, "--dbus", "--no-dbus")?.unwrap_or(false);
+
+    // It's up to the caller what to do with the remaining arguments.
+    let remaining = pargs.finish();
+    if !remaining.is_empty() {
+        bail!(format!("Warning: unused arguments left: {:?}", remaining));
+    }
+
+    let config = match config {
+        Some(config) => Some(config),
+        None => config::env("CONFIG")?,
+    };
+    // the nodes could not read it, they take their own
+    if args.cluster && config.is_some() {
+        bail!("--cluster does not pass a --config file on to the nodes, give its options directly");
+    }
+    let config = switches
+        .or(Config::from_env()?)
+        .or(Config::load(config.as_deref())?);
+    args.apply_config(config);
+
+    // the unit file only gives --service
+    if args.service {
+        args.migrate = true;
+        args.yes = true;
+        args.log_target
+            .get_or_insert_with(|| "journald".to_string());
+    }
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_interval() {
+        let due = |interval: ProgressInterval, total: usize| {
+            (0..=total)
+                .filter(|done| interval.is_due(*done, total))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(due(ProgressInterval::Files(3), 10), [3, 6, 9]);
+        assert!(due(ProgressInterval::Files(20), 10).is_empty());
+        assert_eq!(due(ProgressInterval::Percent(25), 8), [2, 4, 6, 8]);
+        assert_eq!(due(ProgressInterval::Percent(50), 5), [3, 5]);
+        // more steps than files
+        assert_eq!(due(ProgressInterval::Percent(10), 3), [1, 2, 3]);
+        assert!(due(ProgressInterval::Percent(100), 0).is_empty());
+        assert!(due(ProgressInterval::Files(0), 10).is_empty());
+        assert!(due(ProgressInterval::Percent(0), 10).is_empty());
+
+        assert_eq!(
+            "25".parse::<ProgressInterval>().unwrap(),
+            ProgressInterval::Files(25)
+        );
+        assert_eq!(
+            "25%".parse::<ProgressInterval>().unwrap(),
+            ProgressInterval::Percent(25)
+        );
+        assert!("101%".parse::<ProgressInterval>().is_err());
+        assert!("-1".parse::<ProgressInterval>().is_err());
+        assert!("%".parse::<ProgressInterval>().is_err());
+    }
+
+    #[test]
+    fn iso_time() {
+        assert_eq!(parse_iso_time("2025-08-01T00:00:00Z"), Some(1754006400));
+        assert_eq!(parse_iso_time("2025-08-01 00:00Z"), Some(1754006400));
+        assert_eq!(
+            parse_iso_time("2025-08-01T00:00:00+02:00"),
+            Some(1753999200)
+        );
+        assert_eq!(parse_iso_time("2025-08-01T00:00:00-0130"), Some(1754011800));
+        assert_eq!(parse_iso_time("2024-02-29T23:59:60Z"), Some(1709251200));
+        // local time without an offset
+        assert!(parse_iso_time("2025-08-01T00:00:00").is_some());
+
+        for invalid in [
+            "2025-08-01",
+            "2025-08-01T",
+            "2025-08-01T00",
+            "2025-13-01T00:00Z",
+            "2025-08-32T00:00Z",
+            "2025-08-01T24:00Z",
+            "2025-08-01T00:60Z",
+            "2025-08-01T00:00:61Z",
+            "2025-08-01T00:00:00+2",
+            "2025-08-01T00:00:00+02:0x",
+            "2025-08-01T00:00:00:00Z",
+            "2025/08/01T00:00Z",
+        ] {
+            assert_eq!(parse_iso_time(invalid), None, "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! Per-file audit log to a file and/or the journal, written independently of what is printed
+//! on the console
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tracing::warn;
+
+use crate::journal::{Journal, Priority};
+use crate::progress::Progress;
+use crate::report::ErrorReport;
+
+/// Default directory for the records of every run
+pub const AUDIT_DIR: &str = "/var/log/proxmox-rrd-migration";
+const AUDIT_FILE: &str = "runs.log";
+
+/// Outcome of handling a single RRD file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Migrated,
+    /// migrated, overwriting an existing target
+    Forced,
+    Skipped,
+    /// resource not present anymore, the file was marked as old
+    MarkedOld,
+    Failed,
+}
+
+impl Outcome {
+    pub const ALL: [Outcome; 5] = [
+        Outcome::Migrated,
+        Outcome::Forced,
+        Outcome::Skipped,
+        Outcome::MarkedOld,
+        Outcome::Failed,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Migrated => "migrated",
+            Outcome::Forced => "forced",
+            Outcome::Skipped => "skipped",
+            Outcome::MarkedOld => "marked-old",
+            Outcome::Failed => "failed",
+        }
+    }
+
+    fn priority(self) -> Priority {
+        match self {
+            Outcome::Migrated | Outcome::Forced => Priority::Info,
+            Outcome::Skipped | Outcome::MarkedOld => Priority::Notice,
+            Outcome::Failed => Priority::Error,
+        }
+    }
+}
+
+/// Records one entry per decision in the log file and the journal, for those that are set
+#[derive(Clone, Debug, Default)]
+pub struct AuditLog {
+    run_id: Arc<str>,
+    file: Option<Arc<Mutex<File>>>,
+    journal: Option<Arc<Journal>>,
+    /// number of files per outcome, indexed by the outcome
+    counts: Arc<[AtomicUsize; Outcome::ALL.len()]>,
+    progress: Progress,
+}
+
+impl AuditLog {
+    /// Stamp all entries with the ID of this run
+    pub fn set_run_id(&mut self, run_id: &str) {
+        self.run_id = run_id.into();
+    }
+
+    /// Also emit a progress event for every entry
+    pub fn set_progress(&mut self, progress: Progress) {
+        self.progress = progress;
+    }
+
+    /// Number of files recorded with the given outcome so far
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.counts[outcome as usize].load(Ordering::SeqCst)
+    }
+
+    /// Number of files per outcome, as space separated 'outcome=count' pairs
+    pub fn counts(&self) -> String {
+        Outcome::ALL
+            .iter()
+            .map(|outcome| format!("{}={}", outcome.as_str(), self.count(*outcome)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Append to 'path', creating it if needed
+    pub fn open_file(&mut self, path: &Path) -> std::io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.file = Some(Arc::new(Mutex::new(file)));
+        Ok(())
+    }
+
+    /// Also send entries with structured fields to the journal
+    pub fn connect_journal(&mut self) -> std::io::Result<()> {
+        self.journal = Some(Arc::new(Journal::connect()?));
+        Ok(())
+    }
+
+    /// Log the outcome for 'file' of the given resource type, with a free-form message
+    pub fn record(
+        &self,
+        resource_type: impl std::fmt::Display,
+        file: &str,
+        outcome: Outcome,
+        message: &str,
+    ) {
+        self.counts[outcome as usize].fetch_add(1, Ordering::SeqCst);
+        self.progress.file(
+            &resource_type,
+            file,
+            outcome.as_str(),
+            outcome == Outcome::Failed,
+        );
+        // a failing audit log must not fail the migration itself
+        if let Some(log) = &self.file {
+            let line = format!(
+                "{} run={} {resource_type} {file} {}: {message}\n",
+                timestamp(),
+                self.run_id,
+                outcome.as_str()
+            );
+            let _ = log.lock().unwrap().write_all(line.as_bytes());
+        }
+        if let Some(journal) = &self.journal {
+            let resource_type = resource_type.to_string();
+            let _ = journal.send(
+                outcome.priority(),
+                &format!("{resource_type} {file} {}: {message}", outcome.as_str()),
+                &[
+                    ("RESOURCE_TYPE", &resource_type),
+                    ("FILE", file),
+                    ("RESULT", outcome.as_str()),
+                    ("RUN_ID", &self.run_id),
+                ],
+            );
+        }
+    }
+}
+
+/// Unique ID of this invocation, to correlate the records of multiple attempts
+pub fn new_run_id() -> String {
+    match std::fs::read_to_string("/proc/sys/kernel/random/uuid") {
+        Ok(uuid) => uuid.trim().to_string(),
+        Err(_) => format!(
+            "{:x}-{:x}",
+            unsafe { libc::time(std::ptr::null_mut()) },
+            std::process::id()
+        ),
+    }
+}
+
+/// Append-only record of every invocation: arguments, start and end time, counts and failures
+pub struct RunAudit {
+    run_id: String,
+    file: Option<File>,
+}
+
+impl RunAudit {
+    /// Record the start of the run in 'dir', only warns if that is not possible
+    pub fn start(dir: &Path, run_id: &str) -> Self {
+        let file = std::fs::create_dir_all(dir).and_then(|()| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join(AUDIT_FILE))
+        });
+        let file = match file {
+            Ok(file) => Some(file),
+            Err(err) => {
+                warn!("cannot write audit record to {dir:?}, continuing without - {err}");
+                None
+            }
+        };
+        let mut audit = Self {
+            run_id: run_id.to_string(),
+            file,
+        };
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        audit.write(&format!("start pid={} args={args:?}", std::process::id()));
+        audit
+    }
+
+    /// Record where the sources were backed up to before the migration
+    pub fn backup(&mut self, path: &Path) {
+        self.write(&format!("backup {path:?}"));
+    }
+
+    /// Record the end of the run with the number of files per outcome and all failures
+    pub fn finish(mut self, exit_code: i32, log: &AuditLog, report: &ErrorReport) {
+        for (cause, resource, detail) in report.entries() {
+            match detail {
+                Some(detail) => self.write(&format!("not-migrated {resource} ({cause}): {detail}")),
+                None => self.write(&format!("not-migrated {resource} ({cause})")),
+            }
+        }
+        self.write(&format!("end exit={exit_code} {}", log.counts()));
+    }
+
+    fn write(&mut self, message: &str) {
+        if let Some(file) = &mut self.file {
+            let line = format!("{} run={} {message}\n", timestamp(), self.run_id);
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+}
+
+/// Current local time in RFC 3339 format
+pub(crate) fn timestamp() -> String {
+    format_time(unsafe { libc::time(std::ptr::null_mut()) })
+}
+
+/// 'time', in seconds since the epoch, as local time in RFC 3339 format
+pub(crate) fn format_time(time: libc::time_t) -> String {
+    let mut buf = [0u8; 64];
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return time.to_string();
+        }
+        let len = libc::strftime(
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+            c"%Y-%m-%dT%H:%M:%S%z".as_ptr(),
+            &tm,
+        );
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+}
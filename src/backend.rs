@@ -0,0 +1,239 @@
+//! The librrd and file system operations the migration of a single file needs, behind a trait so
+//! that the skip, force and update logic can run against an in-memory fake instead of real RRD
+//! files
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsStr};
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::definition;
+use crate::error::MigrationError;
+use crate::migrate::{self, RRD_STEP_SIZE};
+use crate::{rrd_create_r2, rrd_get_error};
+
+pub trait RrdBackend: fmt::Debug + Send + Sync {
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Time of the last modification of 'path'
+    fn modified(&self, path: &Path) -> Result<SystemTime, MigrationError>;
+
+    /// Check that 'path' is a complete RRD file, [`MigrationError::Corrupt`] if not
+    fn check(&self, path: &Path, resource: &OsStr) -> Result<(), MigrationError>;
+
+    /// Create 'target' with the schema 'rrd_def' and the data of 'source'
+    fn create(&self, source: &Path, target: &Path, rrd_def: &[&CStr])
+        -> Result<(), MigrationError>;
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), MigrationError>;
+
+    fn remove(&self, path: &Path) -> Result<(), MigrationError>;
+}
+
+/// The real files, created with librrd
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Librrd {
+    /// Time of the last update of the created targets, in seconds since the epoch, instead of
+    /// 10 seconds before the current time like librrd does by default
+    pub start: Option<i64>,
+}
+
+impl RrdBackend for Librrd {
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn modified(&self, path: &Path) -> Result<SystemTime, MigrationError> {
+        fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(|err| MigrationError::io(path, err))
+    }
+
+    fn check(&self, path: &Path, resource: &OsStr) -> Result<(), MigrationError> {
+        migrate::check_file(path, resource)
+    }
+
+    fn create(
+        &self,
+        source: &Path,
+        target: &Path,
+        rrd_def: &[&CStr],
+    ) -> Result<(), MigrationError> {
+        definition::validate(rrd_def)?;
+        let source = CString::new(source.as_os_str().as_bytes()).unwrap();
+        let mut sources: [*const i8; 2] = [source.as_ptr(), std::ptr::null()];
+        let target_path = CString::new(target.as_os_str().as_bytes()).unwrap();
+
+        unsafe {
+            migrate::clear_rrd_error();
+            let res = rrd_create_r2(
+                target_path.as_ptr(),
+                RRD_STEP_SIZE as u64,
+                self.start.unwrap_or(0),
+                0,
+                sources.as_mut_ptr(),
+                std::ptr::null(),
+                rrd_def.len() as i32,
+                rrd_def
+                    .iter()
+                    .map(|v| v.as_ptr())
+                    .collect::<Vec<_>>()
+                    .as_mut_ptr(),
+            );
+            if res != 0 {
+                return Err(MigrationError::Rrd {
+                    resource: target.file_name().unwrap_or_default().to_os_string(),
+                    message: CStr::from_ptr(rrd_get_error())
+                        .to_string_lossy()
+                        .into_owned(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), MigrationError> {
+        migrate::move_file(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), MigrationError> {
+        fs::remove_file(path).map_err(|err| MigrationError::io(path, err))
+    }
+}
+
+/// A file of the [`FakeBackend`]
+#[derive(Clone, Debug)]
+pub struct FakeFile {
+    pub modified: SystemTime,
+    /// fails the check like a truncated file
+    pub corrupt: bool,
+    /// the schema it was created with, [`None`] for the sources added by the test
+    pub rrd_def: Option<Vec<String>>,
+}
+
+/// Files only kept in memory, for tests of the migration logic without librrd
+#[derive(Debug, Default)]
+pub struct FakeBackend {
+    files: Mutex<HashMap<PathBuf, FakeFile>>,
+    /// how long creating a file takes
+    create_delay: Mutex<Duration>,
+}
+
+impl FakeBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the source file 'path', last modified at 'modified'
+    pub fn add_source(&self, path: impl Into<PathBuf>, modified: SystemTime) {
+        let file = FakeFile {
+            modified,
+            corrupt: false,
+            rrd_def: None,
+        };
+        self.files.lock().unwrap().insert(path.into(), file);
+    }
+
+    /// Add the file 'path', which fails the check
+    pub fn add_corrupt(&self, path: impl Into<PathBuf>) {
+        let file = FakeFile {
+            modified: SystemTime::now(),
+            corrupt: true,
+            rrd_def: None,
+        };
+        self.files.lock().unwrap().insert(path.into(), file);
+    }
+
+    /// Make creating a file take 'delay', like the conversion of a large one
+    pub fn slow_create(&self, delay: Duration) {
+        *self.create_delay.lock().unwrap() = delay;
+    }
+
+    pub fn file(&self, path: &Path) -> Option<FakeFile> {
+        self.files.lock().unwrap().get(path).cloned()
+    }
+
+    /// The paths of all files, sorted
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.files.lock().unwrap().keys().cloned().collect();
+        paths.sort();
+        paths
+    }
+}
+
+fn not_found(path: &Path) -> MigrationError {
+    MigrationError::io(path, ErrorKind::NotFound.into())
+}
+
+impl RrdBackend for FakeBackend {
+    fn exists(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().contains_key(path)
+    }
+
+    fn modified(&self, path: &Path) -> Result<SystemTime, MigrationError> {
+        self.file(path)
+            .map(|file| file.modified)
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn check(&self, path: &Path, resource: &OsStr) -> Result<(), MigrationError> {
+        match self.file(path) {
+            Some(file) if file.corrupt => Err(MigrationError::Corrupt {
+                resource: resource.to_os_string(),
+                message: "corrupt fake file".to_string(),
+            }),
+            Some(_) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn create(
+        &self,
+        source: &Path,
+        target: &Path,
+        rrd_def: &[&CStr],
+    ) -> Result<(), MigrationError> {
+        definition::validate(rrd_def)?;
+        std::thread::sleep(*self.create_delay.lock().unwrap());
+        let mut files = self.files.lock().unwrap();
+        if files.get(source).is_none_or(|file| file.corrupt) {
+            return Err(MigrationError::Rrd {
+                resource: target.file_name().unwrap_or_default().to_os_string(),
+                message: format!("opening '{}': No such file or directory", source.display()),
+            });
+        }
+        let file = FakeFile {
+            modified: SystemTime::now(),
+            corrupt: false,
+            rrd_def: Some(
+                rrd_def
+                    .iter()
+                    .map(|def| def.to_string_lossy().into_owned())
+                    .collect(),
+            ),
+        };
+        files.insert(target.to_owned(), file);
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), MigrationError> {
+        let mut files = self.files.lock().unwrap();
+        let file = files.remove(from).ok_or_else(|| not_found(from))?;
+        files.insert(to.to_owned(), file);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), MigrationError> {
+        self.files
+            .lock()
+            .unwrap()
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(path))
+    }
+}
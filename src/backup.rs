@@ -0,0 +1,54 @@
+//! Backup of the source directories as tar archive before the migration changes anything, so that
+//! the old files can be recovered even after the .old files were cleaned up
+//!
+//! The archive is written by tar(1), compressed with zstd if its name ends in .zst or .tzst.
+
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, format_err, Context, Error};
+
+/// The directories below the source base directory that are backed up
+pub const SOURCE_SUBDIRS: [&str; 3] = ["pve2-node", "pve2-vm", "pve2-storage"];
+
+/// Whether the archive at 'path' is compressed, judging by its name
+pub fn is_compressed(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext == "zst" || ext == "tzst")
+}
+
+/// Archive the existing source directories in 'source_base' to 'path', which must not exist yet
+///
+/// The archive is written next to 'path' first and only renamed once complete.
+pub fn create(path: &Path, source_base: &Path) -> Result<(), Error> {
+    if path.exists() {
+        bail!("backup {path:?} exists already");
+    }
+    let dirs: Vec<&str> = SOURCE_SUBDIRS
+        .into_iter()
+        .filter(|dir| source_base.join(dir).exists())
+        .collect();
+    if dirs.is_empty() {
+        bail!("no source directories to back up in {source_base:?}");
+    }
+
+    let mut tmp = OsString::from(path);
+    tmp.push(".tmp");
+    let mut tar = Command::new("tar");
+    tar.arg("--create").arg("--file").arg(&tmp);
+    if is_compressed(path) {
+        tar.arg("--zstd");
+    }
+    tar.arg("--directory").arg(source_base).args(&dirs);
+    let output = tar.output().context("could not run tar")?;
+    if !output.status.success() {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(format_err!(
+            "tar failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    std::fs::rename(&tmp, path).context(format!("could not rename backup to {path:?}"))
+}
@@ -0,0 +1,202 @@
+//! Benchmark converting guest files with different numbers of threads, to find out what this host
+//! keeps up with
+//!
+//! Each run converts the same files into its own scratch directory, which is removed afterwards.
+//! The same measurement picks the thread count for --threads auto-tune.
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::{bail, format_err, Error};
+use tracing::{error, info, info_span, warn};
+
+use proxmox_rrd_migration_tool::migrate::{self, RRDFile, ResourceType};
+
+use crate::parallel_handler::ParallelHandler;
+use crate::sample;
+use crate::{MigrationOptions, EXIT_FAILURE, EXIT_SUCCESS};
+
+/// A thread count counts as good as the best if its throughput is at most this much lower
+const RECOMMEND_TOLERANCE: f64 = 0.1;
+/// Number of guest files converted with each thread count by --threads auto-tune
+const CALIBRATION_FILES: usize = 12;
+
+/// The first 'count' guest files in 'source_dir' by name, read once so that the first
+/// measurement does not pay for filling the page cache alone
+fn sample_files(
+    count: usize,
+    source_dir: &Path,
+    options: &MigrationOptions,
+) -> Result<Vec<RRDFile>, Error> {
+    let mut files = migrate::collect_rrd_files_with(&*options.fs, source_dir, &options.old_suffix)?;
+    files.retain(|file| options.is_selected(file));
+    files.sort_by(|a, b| a.1.cmp(&b.1));
+    files.truncate(count);
+    if files.is_empty() {
+        bail!("no guest RRD files to benchmark with in {source_dir:?}");
+    }
+    for file in &files {
+        let _ = std::fs::read(OsStr::from_bytes(file.0.as_bytes()));
+    }
+    Ok(files)
+}
+
+/// 1, 2, 4, … threads, up to the number of CPUs
+fn thread_counts() -> Vec<usize> {
+    let cpus = std::thread::available_parallelism().map_or(1, |cpus| cpus.get());
+    let mut thread_counts = vec![1];
+    while let Some(&last) = thread_counts.last() {
+        let next = (last * 2).min(cpus);
+        if next == last {
+            break;
+        }
+        thread_counts.push(next);
+    }
+    thread_counts
+}
+
+/// Convert 'files' into 'scratch' with each of 'thread_counts' threads
+///
+/// Returns the throughput of each thread count in files per second. 'scratch' is removed
+/// afterwards.
+fn measure(
+    files: &[RRDFile],
+    thread_counts: &[usize],
+    scratch: &Path,
+    options: &MigrationOptions,
+) -> Result<Vec<(usize, f64)>, Error> {
+    let mut results = Vec::new();
+    for &threads in thread_counts {
+        let elapsed = match measure_threads(files, threads, scratch, options) {
+            Ok(elapsed) => elapsed,
+            Err(err) => {
+                let _ = std::fs::remove_dir_all(scratch);
+                bail!("benchmark with {threads} thread(s) failed: {err}");
+            }
+        };
+        let throughput = files.len() as f64 / elapsed;
+        info!("{threads} thread(s): {elapsed:.2}s, {throughput:.1} files/s");
+        results.push((threads, throughput));
+    }
+    let _ = std::fs::remove_dir_all(scratch);
+    Ok(results)
+}
+
+/// Convert 'files' with 'threads' threads, returning how many seconds that took
+fn measure_threads(
+    files: &[RRDFile],
+    threads: usize,
+    scratch: &Path,
+    options: &MigrationOptions,
+) -> Result<f64, Error> {
+    let target = scratch.join(threads.to_string());
+    if let Err(err) = std::fs::create_dir(&target) {
+        bail!("cannot create {target:?}: {err}");
+    }
+    let worker_target = target.clone();
+    let pool = ParallelHandler::new("benchmark", threads, move |file| {
+        migrate::migrate_file(
+            &file,
+            &worker_target,
+            ResourceType::Guest.rrd_def(),
+            true,
+            true,
+        )?;
+        Ok::<(), Error>(())
+    });
+    pool.thread_init(migrate::init_rrd_thread);
+
+    let started = Instant::now();
+    let mut result = Ok(());
+    for file in files {
+        options.notifier.watchdog_ping();
+        if let Err(err) = pool.send(file.clone()) {
+            result = Err(err);
+            break;
+        }
+    }
+    let result = result.and(pool.complete());
+    let elapsed = started.elapsed().as_secs_f64();
+    if let Err(err) = std::fs::remove_dir_all(&target) {
+        warn!("could not remove {target:?} - {err}");
+    }
+    result.map(|()| elapsed)
+}
+
+/// The lowest thread count about as fast as the fastest one
+fn recommend(results: &[(usize, f64)]) -> Option<usize> {
+    let best = results
+        .iter()
+        .map(|(_, throughput)| *throughput)
+        .fold(0.0, f64::max);
+    results
+        .iter()
+        .find(|(_, throughput)| *throughput >= best * (1.0 - RECOMMEND_TOLERANCE))
+        .map(|(threads, _)| *threads)
+}
+
+/// Convert the first 'count' files in 'source_dir' with 1, 2, 4, … threads, up to the number
+/// of CPUs, and recommend a thread count
+///
+/// Returns the exit code.
+pub(crate) fn run(
+    count: usize,
+    source_dir: &Path,
+    run_id: &str,
+    options: &MigrationOptions,
+) -> i32 {
+    let _phase = info_span!("phase", name = "benchmark").entered();
+    let files = match sample_files(count, source_dir, options) {
+        Ok(files) => files,
+        Err(err) => {
+            error!("Error: {err}");
+            return EXIT_FAILURE;
+        }
+    };
+
+    let thread_counts = thread_counts();
+    info!(
+        "Converting {} guest file(s) with up to {} thread(s)…",
+        files.len(),
+        thread_counts.last().copied().unwrap_or(1)
+    );
+
+    let scratch = match sample::create_scratch("benchmark", run_id) {
+        Ok(scratch) => scratch,
+        Err(err) => {
+            error!("Error: {err:#}");
+            return EXIT_FAILURE;
+        }
+    };
+    let results = match measure(&files, &thread_counts, &scratch, options) {
+        Ok(results) => results,
+        Err(err) => {
+            error!("Error: {err}");
+            return EXIT_FAILURE;
+        }
+    };
+    if let Some(threads) = recommend(&results) {
+        info!("Recommended: --threads {threads}");
+    }
+    EXIT_SUCCESS
+}
+
+/// Pick the thread count for --threads auto-tune, by converting a few files in 'source_dir'
+/// like [`run`] does
+pub(crate) fn calibrate(
+    source_dir: &Path,
+    run_id: &str,
+    options: &MigrationOptions,
+) -> Result<usize, Error> {
+    let _phase = info_span!("phase", name = "calibration").entered();
+    let files = sample_files(CALIBRATION_FILES, source_dir, options)?;
+    info!(
+        "Calibrating the number of threads with {} guest file(s)…",
+        files.len()
+    );
+    let scratch = sample::create_scratch("calibration", run_id)?;
+    let results = measure(&files, &thread_counts(), &scratch, options)?;
+    recommend(&results).ok_or_else(|| format_err!("no thread count was measured"))
+}
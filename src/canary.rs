@@ -0,0 +1,90 @@
+//! Migrating and verifying one file of each resource type before all the others, see --canary
+
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Error;
+use tracing::{error, info, info_span};
+
+use proxmox_rrd_migration_tool::migrate::{self, RRDFile, ResourceType};
+
+use crate::file::{do_rrd_migration, finish_source, report_failure, source_path};
+use crate::guest_pool::remote_guest_node;
+use crate::{MigrationDir, MigrationOptions};
+
+/// The first file of each resource type that would be migrated, with the directory to migrate it to
+pub(crate) fn files(
+    dirs: &[MigrationDir],
+    options: &MigrationOptions,
+) -> Result<Vec<(ResourceType, RRDFile, PathBuf)>, Error> {
+    let mut canaries: Vec<(ResourceType, RRDFile, PathBuf)> = Vec::new();
+    for MigrationDir {
+        kind,
+        source,
+        target,
+    } in dirs
+    {
+        let kind = *kind;
+        if canaries
+            .iter()
+            .any(|(canary_kind, _, _)| *canary_kind == kind)
+        {
+            continue;
+        }
+        let mut files = migrate::collect_rrd_files_with(&*options.fs, source, &options.old_suffix)?;
+        files.sort_by(|a, b| a.1.cmp(&b.1));
+        for file in files {
+            if !options.is_selected(&file) || (target.join(&file.1).exists() && !options.force) {
+                continue;
+            }
+            let resource = file.1.to_string_lossy();
+            if options.resources.contains(kind, &resource)?
+                && (kind != ResourceType::Guest || remote_guest_node(&resource, options)?.is_none())
+            {
+                canaries.push((kind, file, target.clone()));
+                break;
+            }
+        }
+    }
+    Ok(canaries)
+}
+
+/// Migrate and verify the canary files, returns whether all of them succeeded
+pub(crate) fn run(
+    files: Vec<(ResourceType, RRDFile, PathBuf)>,
+    options: &MigrationOptions,
+) -> bool {
+    let _phase = info_span!("phase", name = "canary").entered();
+    info!("Migrating {} canary file(s) first…", files.len());
+    let mut success = true;
+    for (kind, file, target_dir) in files {
+        let resource = file.1.to_string_lossy().into_owned();
+        let result = fs::create_dir_all(&target_dir)
+            .map_err(Error::from)
+            .and_then(|()| do_rrd_migration(file.clone(), &target_dir, kind, options, &|| true))
+            .and_then(|()| {
+                finish_source(source_path(&file), &target_dir.join(&file.1), kind, options)
+            })
+            .and_then(|old| match old {
+                Some(old) => Ok(migrate::verify_file(
+                    &old,
+                    &target_dir.join(&file.1),
+                    kind.rrd_def(),
+                )?),
+                // verified before deleting or compressing it
+                None => Ok(()),
+            });
+        match result {
+            Ok(()) => info!(
+                status = "migrated",
+                "{kind} '{resource}': migrated and verified"
+            ),
+            Err(err) => {
+                error!(status = "failed", "{kind} '{resource}': {err}");
+                report_failure(&options.report, resource, &file.0, &err);
+                success = false;
+            }
+        }
+    }
+    success
+}
@@ -0,0 +1,282 @@
+//! Running the migration on all cluster nodes from one of them, over SSH like the other
+//! cluster-wide tasks, and summarizing their outcomes
+//!
+//! Each node runs the tool with the same arguments and PROXMOX_RRD_MIGRATION_* environment
+//! variables and writes its progress events to the SSH session, its messages are passed on
+//! prefixed with the node name.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::io::{BufRead, BufReader};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Error};
+use serde::Deserialize;
+use tracing::{error, info, warn};
+
+use crate::config::ENV_PREFIX;
+use crate::{MigrationOptions, EXIT_FAILURE, EXIT_NOTHING_TO_DO, EXIT_PARTIAL, EXIT_SUCCESS};
+
+/// Exit status of ssh itself if it could not connect or authenticate
+const SSH_FAILED: i32 = 255;
+/// Where the tool is installed on the nodes, as passed by the Makefile that installs it there
+const TOOL_PATH: &str = match option_env!("PROXMOX_RRD_MIGRATION_TOOL_PATH") {
+    Some(path) => path,
+    None => "/usr/libexec/proxmox/proxmox-rrd-migration-tool",
+};
+
+/// The progress events of the remote runs needed for the summary
+#[derive(Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+enum Event {
+    File {
+        file: String,
+        result: String,
+    },
+    #[serde(rename_all = "kebab-case")]
+    Finished {
+        exit_code: i32,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// What the run on a node did
+#[derive(Debug, Default)]
+struct NodeOutcome {
+    /// the last result of each file, a retry can change it
+    files: HashMap<String, String>,
+    exit_code: Option<i32>,
+    /// why there was no run, or why it did not finish
+    error: Option<String>,
+}
+
+impl NodeOutcome {
+    fn failed(error: String) -> Self {
+        Self {
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+
+    fn succeeded(&self) -> bool {
+        matches!(self.exit_code, Some(EXIT_SUCCESS | EXIT_NOTHING_TO_DO))
+    }
+
+    /// Number of files per result, like the counts of the audit log
+    fn counts(&self) -> String {
+        let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+        for result in self.files.values() {
+            *counts.entry(result).or_default() += 1;
+        }
+        let counts: Vec<String> = counts
+            .iter()
+            .map(|(result, count)| format!("{result}={count}"))
+            .collect();
+        counts.join(" ")
+    }
+}
+
+/// 'arg' quoted for the shell on the nodes, byte for byte, as it need not be valid UTF-8
+fn shell_quote(arg: &OsStr) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &byte in arg.as_bytes() {
+        match byte {
+            b'\'' => quoted.extend_from_slice(b"'\\''"),
+            byte => quoted.push(byte),
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+/// The PROXMOX_RRD_MIGRATION_* environment variables, which the nodes get as well
+///
+/// Names the shell could not take as such are left out, they could not be read anyway.
+pub(crate) fn forwarded_env() -> Vec<(OsString, OsString)> {
+    std::env::vars_os()
+        .filter(|(name, _)| {
+            name.as_bytes().starts_with(ENV_PREFIX.as_bytes())
+                && name
+                    .as_bytes()
+                    .iter()
+                    .all(|byte| byte.is_ascii_alphanumeric() || *byte == b'_')
+        })
+        .collect()
+}
+
+/// The command line run on each node, with the progress events on stdout and the rest on stderr
+fn remote_command(args: &[OsString], env: &[(OsString, OsString)]) -> OsString {
+    let mut command: Vec<Vec<u8>> = env
+        .iter()
+        .map(|(name, value)| [name.as_bytes(), b"=".as_slice(), &shell_quote(value)].concat())
+        .collect();
+    command.push(shell_quote(OsStr::new(TOOL_PATH)));
+    command.extend(args.iter().map(|arg| shell_quote(arg)));
+    command.push(b"--progress-fd 3 3>&1 1>&2".to_vec());
+    OsString::from_vec(command.join(&b' '))
+}
+
+fn run_on(node: &str, command: &OsStr) -> Result<NodeOutcome, Error> {
+    let mut child = Command::new("ssh")
+        .args(["-o", "BatchMode=yes", "-T"])
+        .arg(format!("root@{node}"))
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("could not run ssh")?;
+
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let prefix = node.to_string();
+    let forward = std::thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            eprintln!("[{prefix}] {line}");
+        }
+    });
+
+    let mut outcome = NodeOutcome::default();
+    let stdout = child.stdout.take().expect("stdout is piped");
+    for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+        match serde_json::from_str(&line) {
+            Ok(Event::File { file, result }) => {
+                outcome.files.insert(file, result);
+            }
+            Ok(Event::Finished { exit_code }) => outcome.exit_code = Some(exit_code),
+            Ok(Event::Other) | Err(_) => {}
+        }
+    }
+    let status = child.wait().context("could not wait for ssh")?;
+    let _ = forward.join();
+    // modes like --fsck exit before they write any events
+    if outcome.exit_code.is_none() && status.code() != Some(SSH_FAILED) {
+        outcome.exit_code = status.code();
+    }
+    if outcome.exit_code.is_none() {
+        outcome.error = Some(format!("did not finish, ssh {status}"));
+    }
+    Ok(outcome)
+}
+
+fn print_summary(outcomes: &BTreeMap<String, NodeOutcome>) {
+    println!("Cluster summary:");
+    for (node, outcome) in outcomes {
+        match (&outcome.error, outcome.exit_code) {
+            (Some(error), _) => println!("  {node}: {error}"),
+            (None, Some(exit_code)) => {
+                println!("  {node}: exit={exit_code} {}", outcome.counts())
+            }
+            (None, None) => println!("  {node}: no result"),
+        }
+    }
+}
+
+/// Run the migration with 'args' on every online node in .members, in parallel
+///
+/// Returns the exit code, [`EXIT_PARTIAL`] if it did not succeed on some of the nodes.
+pub(crate) fn run(args: Vec<OsString>, options: &MigrationOptions) -> i32 {
+    let nodes = match options.resources.nodes() {
+        Ok(nodes) => nodes.clone(),
+        Err(err) => {
+            error!("Error: cannot read the cluster nodes: {err}");
+            return EXIT_FAILURE;
+        }
+    };
+    let command = remote_command(&args, &forwarded_env());
+    info!(
+        "Running on {} node(s): {}",
+        nodes.len(),
+        command.to_string_lossy()
+    );
+
+    let mut outcomes = BTreeMap::new();
+    let mut runs = Vec::new();
+    for (node, online) in nodes {
+        if !online {
+            warn!("Node '{node}' is offline, skipping it");
+            outcomes.insert(node, NodeOutcome::failed("offline, skipped".to_string()));
+            continue;
+        }
+        let command = command.clone();
+        let handle = std::thread::spawn({
+            let node = node.clone();
+            move || run_on(&node, &command)
+        });
+        runs.push((node, handle));
+    }
+    for (node, handle) in runs {
+        let outcome = match handle.join() {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(err)) => NodeOutcome::failed(format!("{err:#}")),
+            Err(_) => NodeOutcome::failed("panicked".to_string()),
+        };
+        outcomes.insert(node, outcome);
+    }
+
+    print_summary(&outcomes);
+    let succeeded = outcomes
+        .values()
+        .filter(|outcome| outcome.succeeded())
+        .count();
+    if succeeded == outcomes.len() {
+        EXIT_SUCCESS
+    } else if succeeded == 0 && outcomes.values().all(|outcome| outcome.error.is_some()) {
+        EXIT_FAILURE
+    } else {
+        EXIT_PARTIAL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_command_quoting() {
+        let args = [
+            OsString::from("--match"),
+            OsString::from("pve2-vm/1*"),
+            OsString::from("--old-suffix"),
+            OsString::from(".it's old"),
+            OsString::from_vec(b"--source=/tmp/\xff".to_vec()),
+        ];
+        let env = [(
+            OsString::from("PROXMOX_RRD_MIGRATION_THREADS"),
+            OsString::from("4; reboot"),
+        )];
+        let command = remote_command(&args, &env);
+        let expected = [
+            b"PROXMOX_RRD_MIGRATION_THREADS='4; reboot' '".as_slice(),
+            TOOL_PATH.as_bytes(),
+            b"' '--match' 'pve2-vm/1*' '--old-suffix' '.it'\\''s old' '--source=/tmp/\xff' \
+              --progress-fd 3 3>&1 1>&2"
+                .as_slice(),
+        ]
+        .concat();
+        assert_eq!(command.as_bytes(), expected);
+    }
+
+    #[test]
+    fn remote_command_through_shell() {
+        let args = [OsString::from("a'b"), OsString::from("$HOME `id` \"c\"")];
+        let command = remote_command(&args, &[]);
+        // what the shell on a node passes on, with the tool replaced by printf
+        let command = command
+            .as_bytes()
+            .strip_prefix(shell_quote(OsStr::new(TOOL_PATH)).as_slice())
+            .expect("starts with the tool")
+            .strip_suffix(b" --progress-fd 3 3>&1 1>&2")
+            .expect("ends with the redirections")
+            .to_vec();
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(OsString::from_vec(
+                [b"printf '%s\\n'".as_slice(), command.as_slice()].concat(),
+            ))
+            .output()
+            .expect("run sh");
+        assert_eq!(output.stdout, b"a'b\n$HOME `id` \"c\"\n");
+    }
+}
@@ -0,0 +1,190 @@
+//! Notification about the end of the run by webhook and mail, for long migrations that finish
+//! while nobody watches the console
+//!
+//! The webhook is posted with curl(1) and the mail is handed to sendmail(8), like the other tools
+//! on a Proxmox VE host send theirs.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use anyhow::{format_err, Context, Error};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::audit::{AuditLog, Outcome};
+use crate::{marker, EXIT_NOTHING_TO_DO, EXIT_PARTIAL, EXIT_SUCCESS};
+
+/// Time the webhook may take to answer, the run is over anyway
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+const SENDMAIL: &str = "/usr/sbin/sendmail";
+
+/// The summary of a run, posted as JSON to the webhook
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Summary {
+    pub run_id: String,
+    pub host: String,
+    /// success, partial or failed
+    pub result: &'static str,
+    pub exit_code: i32,
+    /// number of files per audit log outcome
+    pub counts: BTreeMap<&'static str, usize>,
+    /// files in the summary of those not migrated
+    pub not_migrated: usize,
+    /// in seconds
+    pub duration: u64,
+    /// most memory the process used at once, in KiB
+    pub peak_memory: u64,
+}
+
+/// The maximum resident set size of the process so far, in KiB
+fn peak_memory() -> u64 {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return 0;
+    }
+    let usage = unsafe { usage.assume_init() };
+    u64::try_from(usage.ru_maxrss).unwrap_or(0)
+}
+
+impl Summary {
+    pub fn new(
+        run_id: &str,
+        exit_code: i32,
+        log: &AuditLog,
+        not_migrated: usize,
+        duration: Duration,
+    ) -> Self {
+        let result = match exit_code {
+            EXIT_SUCCESS | EXIT_NOTHING_TO_DO => "success",
+            EXIT_PARTIAL => "partial",
+            _ => "failed",
+        };
+        Self {
+            run_id: run_id.to_string(),
+            host: marker::hostname(),
+            result,
+            exit_code,
+            counts: Outcome::ALL
+                .iter()
+                .map(|outcome| (outcome.as_str(), log.count(*outcome)))
+                .collect(),
+            not_migrated,
+            duration: duration.as_secs(),
+            peak_memory: peak_memory(),
+        }
+    }
+
+    /// The summary as plain text, for the mail
+    fn text(&self) -> String {
+        let counts: Vec<String> = self
+            .counts
+            .iter()
+            .map(|(outcome, count)| format!("{outcome}={count}"))
+            .collect();
+        format!(
+            "The RRD migration on {} finished.\n\n\
+            Result:       {} (exit code {})\n\
+            Files:        {}\n\
+            Not migrated: {}\n\
+            Duration:     {}s\n\
+            Peak memory:  {} MiB\n\
+            Run ID:       {}\n",
+            self.host,
+            self.result,
+            self.exit_code,
+            counts.join(" "),
+            self.not_migrated,
+            self.duration,
+            self.peak_memory / 1024,
+            self.run_id,
+        )
+    }
+}
+
+/// Where to send the summary at the end of the run, nowhere by default
+#[derive(Debug, Default)]
+pub struct Completion {
+    pub webhook: Option<String>,
+    pub email: Option<String>,
+}
+
+impl Completion {
+    /// Send the summary to the webhook and mail address that are set, failures are only warned
+    /// about
+    pub fn send(&self, summary: &Summary) {
+        if let Some(ref url) = self.webhook {
+            match post_webhook(url, summary) {
+                Ok(()) => info!("Posted the summary to the webhook {url}"),
+                Err(err) => warn!("could not post the summary to the webhook {url} - {err:#}"),
+            }
+        }
+        if let Some(ref address) = self.email {
+            match send_mail(address, summary) {
+                Ok(()) => info!("Mailed the summary to {address}"),
+                Err(err) => warn!("could not mail the summary to {address} - {err:#}"),
+            }
+        }
+    }
+}
+
+/// Run 'command' with 'input' on its stdin and fail with its stderr if it does not succeed
+fn run_with_input(command: &mut Command, input: &[u8]) -> Result<(), Error> {
+    let name = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(format!("could not run {name}"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(input)
+            .context(format!("could not write to {name}"))?;
+    }
+    let output = child
+        .wait_with_output()
+        .context(format!("could not wait for {name}"))?;
+    if !output.status.success() {
+        return Err(format_err!(
+            "{name} failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+fn post_webhook(url: &str, summary: &Summary) -> Result<(), Error> {
+    let body = serde_json::to_vec(summary)?;
+    run_with_input(
+        Command::new("curl")
+            .args(["--silent", "--show-error", "--fail"])
+            .arg("--max-time")
+            .arg(WEBHOOK_TIMEOUT.as_secs().to_string())
+            .args(["--header", "Content-Type: application/json"])
+            .args(["--data-binary", "@-"])
+            .arg("--")
+            .arg(url),
+        &body,
+    )
+}
+
+fn send_mail(address: &str, summary: &Summary) -> Result<(), Error> {
+    let message = format!(
+        "To: {address}\n\
+        Subject: RRD migration on {}: {}\n\
+        Content-Type: text/plain; charset=utf-8\n\
+        \n\
+        {}",
+        summary.host,
+        summary.result,
+        summary.text()
+    );
+    run_with_input(
+        Command::new(SENDMAIL).arg("--").arg(address),
+        message.as_bytes(),
+    )
+}
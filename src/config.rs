@@ -0,0 +1,329 @@
+//! Defaults for the command line options from a TOML file, for sites that want the behavior
+//! pinned by their configuration management, or from environment variables for wrapper scripts
+//!
+//! Keys are named like the long options without the leading dashes, the environment variables
+//! like them in upper case with a PROXMOX_RRD_MIGRATION_ prefix, for example
+//! PROXMOX_RRD_MIGRATION_MAX_THREADS. Options given on the command line take precedence over the
+//! environment, which takes precedence over the file, a switch set in either can be turned off
+//! on the command line with its --no-* option. Whether to actually migrate or overwrite files,
+//! and whether to ask before the latter, can only be decided on the command line, like running as
+//! service, which implies migrating, and --cluster, so that the runs it starts on the nodes, with
+//! their own configuration, do not start more.
+
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, format_err, Context, Error};
+use serde::Deserialize;
+
+use crate::args::{ProgressInterval, Threads, Timestamp};
+use crate::logging::Verbosity;
+use crate::pattern::PathPattern;
+use crate::plan::OutputFormat;
+use crate::remigrate::FromOld;
+use crate::symlinks::SymlinkPolicy;
+use crate::RemoteGuests;
+
+pub const CONFIG_FILE: &str = "/etc/proxmox-rrd-migration.conf";
+pub const ENV_PREFIX: &str = "PROXMOX_RRD_MIGRATION_";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    #[serde(skip)]
+    pub migrate: Option<bool>,
+    #[serde(skip)]
+    pub service: Option<bool>,
+    #[serde(skip)]
+    pub force: Option<bool>,
+    #[serde(skip)]
+    pub yes: Option<bool>,
+    pub incremental: Option<bool>,
+    pub backup: Option<PathBuf>,
+    pub archive_dir: Option<PathBuf>,
+    pub quarantine_dir: Option<PathBuf>,
+    pub try_repair: Option<bool>,
+    pub skip_stale: Option<u64>,
+    pub timestamp: Option<Timestamp>,
+    pub symlinks: Option<SymlinkPolicy>,
+    pub break_hardlinks: Option<bool>,
+    pub keep_source: Option<bool>,
+    pub delete_source: Option<bool>,
+    pub old_suffix: Option<String>,
+    pub compress_old: Option<bool>,
+    pub verify_after_migrate: Option<bool>,
+    pub migrate_orphans: Option<bool>,
+    pub remote_guests: Option<RemoteGuests>,
+    pub from_old: Option<FromOld>,
+    pub reconcile: Option<bool>,
+    pub verify: Option<bool>,
+    pub verify_threads: Option<usize>,
+    pub prune_removed_storages: Option<bool>,
+    pub keep_removed_storages: Option<bool>,
+    pub node: Option<String>,
+    pub all_nodes: Option<bool>,
+    pub storage: Option<String>,
+    pub threads: Option<Threads>,
+    pub max_threads: Option<usize>,
+    pub io_threads: Option<usize>,
+    pub low_memory: Option<bool>,
+    pub batch_size: Option<usize>,
+    pub stall_timeout: Option<u64>,
+    pub file_timeout: Option<u64>,
+    pub retries: Option<u32>,
+    pub fail_fast: Option<bool>,
+    pub max_errors: Option<usize>,
+    pub failed_files: Option<PathBuf>,
+    #[serde(rename = "match")]
+    pub path_match: Option<PathPattern>,
+    pub log_file: Option<PathBuf>,
+    pub log_target: Option<String>,
+    pub audit_dir: Option<PathBuf>,
+    pub notify_webhook: Option<String>,
+    pub notify_email: Option<String>,
+    pub metrics_listen: Option<SocketAddr>,
+    pub report: Option<PathBuf>,
+    pub status_file: Option<PathBuf>,
+    pub progress_every: Option<ProgressInterval>,
+    pub verbosity: Option<Verbosity>,
+    pub legacy_output: Option<bool>,
+    pub no_color: Option<bool>,
+    pub plan: Option<bool>,
+    pub needs_migration: Option<bool>,
+    pub plan_format: Option<OutputFormat>,
+    pub fsck: Option<bool>,
+    pub fsck_format: Option<OutputFormat>,
+    pub list_leftovers: Option<bool>,
+    pub canary: Option<bool>,
+    pub sample: Option<usize>,
+    pub sample_dir: Option<PathBuf>,
+    pub render_samples: Option<usize>,
+    pub render_dir: Option<PathBuf>,
+    pub estimate: Option<bool>,
+    pub benchmark: Option<bool>,
+    pub benchmark_files: Option<usize>,
+    pub source: Option<String>,
+    pub target: Option<String>,
+    pub resources: Option<String>,
+    pub resources_from_ipc: Option<bool>,
+    pub ignore_quorum: Option<bool>,
+}
+
+impl Config {
+    /// Read the config from 'path', if given it has to exist, otherwise the default one may not
+    pub fn load(path: Option<&Path>) -> Result<Self, Error> {
+        let (path, required) = match path {
+            Some(path) => (path, true),
+            None => (Path::new(CONFIG_FILE), false),
+        };
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if !required && err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default())
+            }
+            Err(err) => return Err(err).context(format!("Could not read config file {path:?}")),
+        };
+        toml::from_str(&content).context(format!("Could not parse config file {path:?}"))
+    }
+
+    /// The values of 'self', and those of 'lower' for what is not set in it
+    ///
+    /// Options that go the other way round, like --prune-removed-storages and
+    /// --keep-removed-storages, are taken together from the layer that sets any of them.
+    pub fn or(self, lower: Config) -> Config {
+        let (prune_removed_storages, keep_removed_storages) =
+            if self.prune_removed_storages.is_some() || self.keep_removed_storages.is_some() {
+                (self.prune_removed_storages, self.keep_removed_storages)
+            } else {
+                (lower.prune_removed_storages, lower.keep_removed_storages)
+            };
+        let (node, all_nodes) = if self.node.is_some() || self.all_nodes.is_some() {
+            (self.node, self.all_nodes)
+        } else {
+            (lower.node, lower.all_nodes)
+        };
+        Config {
+            migrate: self.migrate.or(lower.migrate),
+            service: self.service.or(lower.service),
+            force: self.force.or(lower.force),
+            yes: self.yes.or(lower.yes),
+            incremental: self.incremental.or(lower.incremental),
+            backup: self.backup.or(lower.backup),
+            archive_dir: self.archive_dir.or(lower.archive_dir),
+            quarantine_dir: self.quarantine_dir.or(lower.quarantine_dir),
+            try_repair: self.try_repair.or(lower.try_repair),
+            skip_stale: self.skip_stale.or(lower.skip_stale),
+            timestamp: self.timestamp.or(lower.timestamp),
+            symlinks: self.symlinks.or(lower.symlinks),
+            break_hardlinks: self.break_hardlinks.or(lower.break_hardlinks),
+            keep_source: self.keep_source.or(lower.keep_source),
+            delete_source: self.delete_source.or(lower.delete_source),
+            old_suffix: self.old_suffix.or(lower.old_suffix),
+            compress_old: self.compress_old.or(lower.compress_old),
+            verify_after_migrate: self.verify_after_migrate.or(lower.verify_after_migrate),
+            migrate_orphans: self.migrate_orphans.or(lower.migrate_orphans),
+            remote_guests: self.remote_guests.or(lower.remote_guests),
+            from_old: self.from_old.or(lower.from_old),
+            reconcile: self.reconcile.or(lower.reconcile),
+            verify: self.verify.or(lower.verify),
+            verify_threads: self.verify_threads.or(lower.verify_threads),
+            prune_removed_storages,
+            keep_removed_storages,
+            node,
+            all_nodes,
+            storage: self.storage.or(lower.storage),
+            threads: self.threads.or(lower.threads),
+            max_threads: self.max_threads.or(lower.max_threads),
+            io_threads: self.io_threads.or(lower.io_threads),
+            low_memory: self.low_memory.or(lower.low_memory),
+            batch_size: self.batch_size.or(lower.batch_size),
+            stall_timeout: self.stall_timeout.or(lower.stall_timeout),
+            file_timeout: self.file_timeout.or(lower.file_timeout),
+            retries: self.retries.or(lower.retries),
+            fail_fast: self.fail_fast.or(lower.fail_fast),
+            max_errors: self.max_errors.or(lower.max_errors),
+            failed_files: self.failed_files.or(lower.failed_files),
+            path_match: self.path_match.or(lower.path_match),
+            log_file: self.log_file.or(lower.log_file),
+            log_target: self.log_target.or(lower.log_target),
+            audit_dir: self.audit_dir.or(lower.audit_dir),
+            notify_webhook: self.notify_webhook.or(lower.notify_webhook),
+            notify_email: self.notify_email.or(lower.notify_email),
+            metrics_listen: self.metrics_listen.or(lower.metrics_listen),
+            report: self.report.or(lower.report),
+            status_file: self.status_file.or(lower.status_file),
+            progress_every: self.progress_every.or(lower.progress_every),
+            verbosity: self.verbosity.or(lower.verbosity),
+            legacy_output: self.legacy_output.or(lower.legacy_output),
+            no_color: self.no_color.or(lower.no_color),
+            plan: self.plan.or(lower.plan),
+            needs_migration: self.needs_migration.or(lower.needs_migration),
+            plan_format: self.plan_format.or(lower.plan_format),
+            fsck: self.fsck.or(lower.fsck),
+            fsck_format: self.fsck_format.or(lower.fsck_format),
+            list_leftovers: self.list_leftovers.or(lower.list_leftovers),
+            canary: self.canary.or(lower.canary),
+            sample: self.sample.or(lower.sample),
+            sample_dir: self.sample_dir.or(lower.sample_dir),
+            render_samples: self.render_samples.or(lower.render_samples),
+            render_dir: self.render_dir.or(lower.render_dir),
+            estimate: self.estimate.or(lower.estimate),
+            benchmark: self.benchmark.or(lower.benchmark),
+            benchmark_files: self.benchmark_files.or(lower.benchmark_files),
+            source: self.source.or(lower.source),
+            target: self.target.or(lower.target),
+            resources: self.resources.or(lower.resources),
+            resources_from_ipc: self.resources_from_ipc.or(lower.resources_from_ipc),
+            ignore_quorum: self.ignore_quorum.or(lower.ignore_quorum),
+        }
+    }
+
+    /// Read the config from the PROXMOX_RRD_MIGRATION_* environment variables
+    ///
+    /// Whether to migrate or overwrite files and whether to ask first are left to the command line.
+    pub fn from_env() -> Result<Self, Error> {
+        Ok(Self {
+            migrate: None,
+            service: None,
+            force: None,
+            yes: None,
+            incremental: env_bool("INCREMENTAL")?,
+            backup: env("BACKUP")?,
+            archive_dir: env("ARCHIVE_DIR")?,
+            quarantine_dir: env("QUARANTINE_DIR")?,
+            try_repair: env_bool("TRY_REPAIR")?,
+            skip_stale: env("SKIP_STALE")?,
+            timestamp: env("TIMESTAMP")?,
+            symlinks: env("SYMLINKS")?,
+            break_hardlinks: env_bool("BREAK_HARDLINKS")?,
+            keep_source: env_bool("KEEP_SOURCE")?,
+            delete_source: env_bool("DELETE_SOURCE")?,
+            old_suffix: env("OLD_SUFFIX")?,
+            compress_old: env_bool("COMPRESS_OLD")?,
+            verify_after_migrate: env_bool("VERIFY_AFTER_MIGRATE")?,
+            migrate_orphans: env_bool("MIGRATE_ORPHANS")?,
+            remote_guests: env("REMOTE_GUESTS")?,
+            from_old: env("FROM_OLD")?,
+            reconcile: env_bool("RECONCILE")?,
+            verify: env_bool("VERIFY")?,
+            verify_threads: env("VERIFY_THREADS")?,
+            prune_removed_storages: env_bool("PRUNE_REMOVED_STORAGES")?,
+            keep_removed_storages: env_bool("KEEP_REMOVED_STORAGES")?,
+            node: env("NODE")?,
+            all_nodes: env_bool("ALL_NODES")?,
+            storage: env("STORAGE")?,
+            threads: env("THREADS")?,
+            max_threads: env("MAX_THREADS")?,
+            io_threads: env("IO_THREADS")?,
+            low_memory: env_bool("LOW_MEMORY")?,
+            batch_size: env("BATCH_SIZE")?,
+            stall_timeout: env("STALL_TIMEOUT")?,
+            file_timeout: env("FILE_TIMEOUT")?,
+            retries: env("RETRIES")?,
+            fail_fast: env_bool("FAIL_FAST")?,
+            max_errors: env("MAX_ERRORS")?,
+            failed_files: env("FAILED_FILES")?,
+            path_match: env("MATCH")?,
+            log_file: env("LOG_FILE")?,
+            log_target: env("LOG_TARGET")?,
+            audit_dir: env("AUDIT_DIR")?,
+            notify_webhook: env("NOTIFY_WEBHOOK")?,
+            notify_email: env("NOTIFY_EMAIL")?,
+            metrics_listen: env("METRICS_LISTEN")?,
+            report: env("REPORT")?,
+            status_file: env("STATUS_FILE")?,
+            progress_every: env("PROGRESS_EVERY")?,
+            verbosity: env("VERBOSITY")?,
+            legacy_output: env_bool("LEGACY_OUTPUT")?,
+            no_color: env_bool("NO_COLOR")?,
+            plan: env_bool("PLAN")?,
+            needs_migration: env_bool("NEEDS_MIGRATION")?,
+            plan_format: env("PLAN_FORMAT")?,
+            fsck: env_bool("FSCK")?,
+            fsck_format: env("FSCK_FORMAT")?,
+            list_leftovers: env_bool("LIST_LEFTOVERS")?,
+            canary: env_bool("CANARY")?,
+            sample: env("SAMPLE")?,
+            sample_dir: env("SAMPLE_DIR")?,
+            render_samples: env("RENDER_SAMPLES")?,
+            render_dir: env("RENDER_DIR")?,
+            estimate: env_bool("ESTIMATE")?,
+            benchmark: env_bool("BENCHMARK")?,
+            benchmark_files: env("BENCHMARK_FILES")?,
+            source: env("SOURCE")?,
+            target: env("TARGET")?,
+            resources: env("RESOURCES")?,
+            resources_from_ipc: env_bool("RESOURCES_FROM_IPC")?,
+            ignore_quorum: env_bool("IGNORE_QUORUM")?,
+        })
+    }
+}
+
+/// The value of PROXMOX_RRD_MIGRATION_'name', treating an empty one like an unset one
+pub fn env<T>(name: &str) -> Result<Option<T>, Error>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let name = format!("{ENV_PREFIX}{name}");
+    match std::env::var(&name) {
+        Ok(value) if value.is_empty() => Ok(None),
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|err| format_err!("Could not parse {name} - {err}")),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(err) => bail!("Could not read {name} - {err}"),
+    }
+}
+
+fn env_bool(name: &str) -> Result<Option<bool>, Error> {
+    match env::<String>(name)?.as_deref() {
+        None => Ok(None),
+        Some("1" | "yes" | "true") => Ok(Some(true)),
+        Some("0" | "no" | "false") => Ok(Some(false)),
+        Some(other) => bail!("Could not parse {ENV_PREFIX}{name} - '{other}' is not a boolean"),
+    }
+}
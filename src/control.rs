@@ -0,0 +1,84 @@
+//! Pausing, resuming and aborting a running migration from outside, like over D-Bus
+//!
+//! The phases check in before each file they hand out. A pause lets the files being migrated
+//! finish and holds back the next ones, an abort fails the phase like --max-errors does.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Error};
+use tracing::{info, warn};
+
+use crate::notify::Notifier;
+
+/// How often to ping the watchdog while paused
+const PAUSE_POLL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default)]
+struct Inner {
+    paused: Mutex<bool>,
+    changed: Condvar,
+    aborted: AtomicBool,
+}
+
+/// Shared by the phases and whoever controls the run
+#[derive(Clone, Debug, Default)]
+pub struct Control {
+    inner: Arc<Inner>,
+}
+
+impl Control {
+    /// Hold back the next files until resumed
+    pub fn pause(&self) {
+        let mut paused = self.inner.paused.lock().unwrap();
+        if !*paused {
+            info!("Pausing the migration once the files being migrated are done");
+            *paused = true;
+        }
+    }
+
+    pub fn resume(&self) {
+        let mut paused = self.inner.paused.lock().unwrap();
+        if *paused {
+            info!("Resuming the migration");
+            *paused = false;
+            self.inner.changed.notify_all();
+        }
+    }
+
+    /// Stop handing out files, also if paused
+    pub fn abort(&self) {
+        if !self.inner.aborted.swap(true, Ordering::SeqCst) {
+            warn!("Aborting the migration on request");
+        }
+        let _paused = self.inner.paused.lock().unwrap();
+        self.inner.changed.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.inner.paused.lock().unwrap()
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.inner.aborted.load(Ordering::SeqCst)
+    }
+
+    /// Wait while paused, returns an error if the migration was aborted
+    pub fn checkpoint(&self, notifier: &Notifier) -> Result<(), Error> {
+        let mut paused = self.inner.paused.lock().unwrap();
+        while *paused && !self.is_aborted() {
+            notifier.watchdog_ping();
+            paused = self
+                .inner
+                .changed
+                .wait_timeout(paused, PAUSE_POLL)
+                .unwrap()
+                .0;
+        }
+        if self.is_aborted() {
+            bail!("migration aborted on request");
+        }
+        Ok(())
+    }
+}
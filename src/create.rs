@@ -0,0 +1,159 @@
+//! The create subcommand, writing an empty RRD file in the new format for a guest, node or
+//! storage, for example to provision the metrics of a restored guest or for tools that need a
+//! file with the exact schema the migration creates
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Error};
+use tracing::{error, info};
+
+use proxmox_rrd_migration_tool::migrate::{self, ResourceType};
+
+use crate::args::{Timestamp, HELP};
+use crate::logging::{self, Verbosity};
+use crate::{
+    marker, BASE_DIR, EXIT_FAILURE, EXIT_SUCCESS, EXIT_USAGE, TARGET_SUBDIR_GUEST,
+    TARGET_SUBDIR_NODE, TARGET_SUBDIR_STORAGE,
+};
+
+#[derive(Debug)]
+struct CreateArgs {
+    kind: ResourceType,
+    name: String,
+    target: Option<String>,
+    /// node of a storage file, the local one if not given
+    node: Option<String>,
+    force: bool,
+    timestamp: Option<Timestamp>,
+}
+
+fn parse_kind(value: &str) -> Result<ResourceType, String> {
+    match value {
+        "guest" | "vm" => Ok(ResourceType::Guest),
+        "node" => Ok(ResourceType::Node),
+        "storage" => Ok(ResourceType::Storage),
+        _ => Err(format!(
+            "unknown resource type '{value}', expected guest, node or storage"
+        )),
+    }
+}
+
+fn parse_args() -> Result<CreateArgs, Error> {
+    let mut pargs = pico_args::Arguments::from_env();
+    // the subcommand itself
+    let _ = pargs.subcommand()?;
+
+    let force = pargs.contains("--force");
+    let target = pargs
+        .opt_value_from_str("--target")
+        .context("Could not parse --target parameter")?;
+    let node: Option<String> = pargs
+        .opt_value_from_str("--node")
+        .context("Could not parse --node parameter")?;
+    let timestamp = pargs
+        .opt_value_from_str("--timestamp")
+        .context("Could not parse --timestamp parameter")?;
+    let kind = pargs
+        .free_from_fn(parse_kind)
+        .context("Could not parse the resource type")?;
+    let name: String = pargs
+        .free_from_str()
+        .context("Could not parse the resource name")?;
+
+    let remaining = pargs.finish();
+    if !remaining.is_empty() {
+        bail!(format!("Warning: unused arguments left: {:?}", remaining));
+    }
+    if node.is_some() && kind != ResourceType::Storage {
+        bail!("--node is only used for storages");
+    }
+    if !migrate::is_valid_resource_name(kind, OsStr::new(&name)) {
+        bail!("'{name}' is not a valid {kind} name");
+    }
+    if let Some(ref node) = node {
+        if !migrate::is_valid_resource_name(ResourceType::Node, OsStr::new(node)) {
+            bail!("'{node}' is not a valid node name");
+        }
+    }
+    Ok(CreateArgs {
+        kind,
+        name,
+        target,
+        node,
+        force,
+        timestamp,
+    })
+}
+
+/// Where the file of 'args' goes below the target base directory 'base'
+fn target_path(args: &CreateArgs, base: &Path) -> PathBuf {
+    match args.kind {
+        ResourceType::Guest => base.join(TARGET_SUBDIR_GUEST).join(&args.name),
+        ResourceType::Node => base.join(TARGET_SUBDIR_NODE).join(&args.name),
+        ResourceType::Storage => {
+            let node = match args.node {
+                Some(ref node) => node.clone(),
+                None => {
+                    let hostname = marker::hostname();
+                    hostname.split('.').next().unwrap_or_default().to_string()
+                }
+            };
+            base.join(TARGET_SUBDIR_STORAGE).join(node).join(&args.name)
+        }
+    }
+}
+
+/// Create the empty file at 'path', keeping an existing one as backup with --force
+fn create(args: &CreateArgs, path: &Path) -> Result<(), Error> {
+    if path.exists() {
+        if !args.force {
+            bail!("{path:?} exists already, use --force to replace it");
+        }
+        let backup = migrate::mv_bak(path)?;
+        info!("Kept the existing file as {backup:?}");
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).context(format!("could not create {dir:?}"))?;
+    }
+    // librrd starts at the current time for 0
+    let start = args.timestamp.map(|timestamp| timestamp.0).unwrap_or(0);
+    migrate::create_file(path, args.kind.rrd_def(), start)?;
+    info!("Created {path:?}");
+    Ok(())
+}
+
+/// Run the create subcommand, returns the exit code
+pub(crate) fn run() -> i32 {
+    if std::env::args().any(|arg| arg == "-h" || arg == "--help") {
+        print!("{HELP}");
+        return EXIT_SUCCESS;
+    }
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("Error: {err}.");
+            return EXIT_USAGE;
+        }
+    };
+    let console = logging::init(
+        Verbosity::Normal,
+        None,
+        false,
+        logging::use_color(false, false),
+    );
+
+    let base = Path::new(args.target.as_deref().unwrap_or(BASE_DIR));
+    let path = target_path(&args, base);
+    let exit_code = match create(&args, &path) {
+        Ok(()) => EXIT_SUCCESS,
+        Err(err) => {
+            error!("Error: could not create the {} file: {err:#}", args.kind);
+            EXIT_FAILURE
+        }
+    };
+    if let Some(ref console) = console {
+        console.flush();
+    }
+    exit_code
+}
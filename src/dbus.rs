@@ -0,0 +1,119 @@
+//! D-Bus service exposing the progress of the run, and methods to pause, resume and abort it, for
+//! GUIs and other services that should not have to parse the output
+//!
+//! Owns [`BUS_NAME`] on the system bus for as long as the run takes. The properties are read from
+//! the progress on each request, there are no change signals, clients poll them.
+
+use anyhow::{Context, Error};
+use zbus::blocking::{connection, Connection};
+use zbus::interface;
+
+use crate::control::Control;
+use crate::progress::Progress;
+
+pub const BUS_NAME: &str = "org.proxmox.RRDMigration1";
+pub const OBJECT_PATH: &str = "/org/proxmox/RRDMigration1";
+
+struct Migration {
+    run_id: String,
+    progress: Progress,
+    control: Control,
+}
+
+impl Migration {
+    /// Files done and to do over all phases started so far
+    fn counts(&self) -> (usize, usize, usize) {
+        let snapshot = self.progress.snapshot();
+        snapshot.phases.iter().fold((0, 0, 0), |acc, phase| {
+            (
+                acc.0 + phase.done,
+                acc.1 + phase.total,
+                acc.2 + phase.failed,
+            )
+        })
+    }
+}
+
+#[interface(name = "org.proxmox.RRDMigration1")]
+impl Migration {
+    /// Hold back the next files once those being migrated are done
+    fn pause(&self) {
+        self.control.pause();
+    }
+
+    fn resume(&self) {
+        self.control.resume();
+    }
+
+    /// Stop migrating, the run ends as failed
+    fn abort(&self) {
+        self.control.abort();
+    }
+
+    #[zbus(property)]
+    fn run_id(&self) -> String {
+        self.run_id.clone()
+    }
+
+    /// 'running', 'paused' or 'aborted'
+    #[zbus(property)]
+    fn state(&self) -> String {
+        if self.control.is_aborted() {
+            "aborted".to_string()
+        } else if self.control.is_paused() {
+            "paused".to_string()
+        } else {
+            "running".to_string()
+        }
+    }
+
+    /// The phase that started last, empty before the first one
+    #[zbus(property)]
+    fn phase(&self) -> String {
+        self.progress
+            .snapshot()
+            .phases
+            .pop()
+            .map(|phase| phase.name)
+            .unwrap_or_default()
+    }
+
+    #[zbus(property)]
+    fn done(&self) -> u64 {
+        self.counts().0 as u64
+    }
+
+    #[zbus(property)]
+    fn total(&self) -> u64 {
+        self.counts().1 as u64
+    }
+
+    #[zbus(property)]
+    fn failed(&self) -> u64 {
+        self.counts().2 as u64
+    }
+
+    /// Percentage of the files of the phases started so far that are done
+    #[zbus(property)]
+    fn percent(&self) -> f64 {
+        match self.counts() {
+            (_, 0, _) => 0.0,
+            (done, total, _) => done as f64 * 100.0 / total as f64,
+        }
+    }
+}
+
+/// Register the service of run 'run_id' on the system bus, it is served until the returned
+/// connection is dropped
+pub fn start(run_id: &str, progress: Progress, control: Control) -> Result<Connection, Error> {
+    let migration = Migration {
+        run_id: run_id.to_string(),
+        progress,
+        control,
+    };
+    connection::Builder::system()?
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, migration)?
+        .build()
+        .context(format!("cannot register {BUS_NAME} on the system bus"))
+}
@@ -0,0 +1,155 @@
+//! Parsing and validating the DS and RRA definitions of a schema like rrdtool create takes them,
+//! so that a malformed one is rejected with a precise message before librrd sees it
+
+use std::collections::HashSet;
+use std::ffi::CStr;
+
+use crate::error::MigrationError;
+use crate::migrate::{DataSourceInfo, RraInfo};
+
+/// Data source types that can be created, COMPUTE needs an RPN expression instead of limits
+const DS_TYPES: [&str; 6] = [
+    "GAUGE", "COUNTER", "DERIVE", "DCOUNTER", "DDERIVE", "ABSOLUTE",
+];
+const CONSOLIDATION_FUNCTIONS: [&str; 4] = ["AVERAGE", "MIN", "MAX", "LAST"];
+/// Longest data source name librrd accepts
+const DS_NAME_MAX: usize = 19;
+
+/// A single definition of a schema
+#[derive(Clone, Debug, PartialEq)]
+pub enum Definition {
+    /// DS:<name>:<type>:<heartbeat>:<min>:<max>
+    DataSource(DataSourceInfo),
+    /// RRA:<cf>:<xff>:<steps>:<rows>
+    Rra(RraInfo),
+}
+
+fn parse_count(value: &str, what: &str) -> Result<u64, String> {
+    match value.parse::<u64>() {
+        Ok(0) => Err(format!("{what} must be at least 1")),
+        Ok(count) => Ok(count),
+        Err(_) => Err(format!("{what} '{value}' is not a positive integer")),
+    }
+}
+
+/// A limit of a data source, U for none
+fn parse_limit(value: &str, what: &str) -> Result<Option<f64>, String> {
+    if value == "U" {
+        return Ok(None);
+    }
+    match value.parse::<f64>() {
+        Ok(limit) if limit.is_finite() => Ok(Some(limit)),
+        _ => Err(format!("{what} '{value}' is neither a number nor U")),
+    }
+}
+
+fn parse_data_source(fields: &[&str]) -> Result<DataSourceInfo, String> {
+    let [name, kind, heartbeat, min, max] = fields else {
+        return Err(format!(
+            "expected 5 fields after DS, <name>:<type>:<heartbeat>:<min>:<max>, got {}",
+            fields.len()
+        ));
+    };
+    if name.is_empty() || name.len() > DS_NAME_MAX {
+        return Err(format!(
+            "data source name '{name}' must have 1 to {DS_NAME_MAX} characters"
+        ));
+    }
+    if !name
+        .bytes()
+        .all(|byte| byte.is_ascii_alphanumeric() || byte == b'_')
+    {
+        return Err(format!(
+            "data source name '{name}' may only contain letters, digits and '_'"
+        ));
+    }
+    if !DS_TYPES.contains(kind) {
+        return Err(format!(
+            "unknown data source type '{kind}' of '{name}', use one of {}",
+            DS_TYPES.join(", ")
+        ));
+    }
+    let heartbeat = parse_count(heartbeat, "heartbeat")?;
+    let min = parse_limit(min, "minimum")?;
+    let max = parse_limit(max, "maximum")?;
+    if let (Some(min), Some(max)) = (min, max) {
+        if min > max {
+            return Err(format!(
+                "minimum {min} of '{name}' is larger than its maximum {max}"
+            ));
+        }
+    }
+    Ok(DataSourceInfo {
+        name: name.to_string(),
+        kind: kind.to_string(),
+        heartbeat,
+        min,
+        max,
+    })
+}
+
+fn parse_rra(fields: &[&str]) -> Result<RraInfo, String> {
+    let [cf, xff, steps, rows] = fields else {
+        return Err(format!(
+            "expected 4 fields after RRA, <cf>:<xff>:<steps>:<rows>, got {}",
+            fields.len()
+        ));
+    };
+    if !CONSOLIDATION_FUNCTIONS.contains(cf) {
+        return Err(format!(
+            "unknown consolidation function '{cf}', use one of {}",
+            CONSOLIDATION_FUNCTIONS.join(", ")
+        ));
+    }
+    let xff = match xff.parse::<f64>() {
+        Ok(xff) if (0.0..1.0).contains(&xff) => xff,
+        _ => return Err(format!("xff '{xff}' must be a number from 0 to below 1")),
+    };
+    Ok(RraInfo {
+        cf: cf.to_string(),
+        xff,
+        pdp_per_row: parse_count(steps, "steps per row")?,
+        rows: parse_count(rows, "rows")?,
+    })
+}
+
+/// Parse a single DS or RRA definition
+pub fn parse(def: &str) -> Result<Definition, String> {
+    let fields: Vec<&str> = def.split(':').collect();
+    match fields[..] {
+        ["DS", ref rest @ ..] => parse_data_source(rest).map(Definition::DataSource),
+        ["RRA", ref rest @ ..] => parse_rra(rest).map(Definition::Rra),
+        _ => Err("does not start with DS: or RRA:".to_string()),
+    }
+}
+
+/// Check that 'rrd_def' consists of valid definitions, with at least one data source and RRA and
+/// no data source defined twice
+pub fn validate(rrd_def: &[&CStr]) -> Result<(), MigrationError> {
+    let invalid = |message: String| MigrationError::InvalidSchema { message };
+    let mut names = HashSet::new();
+    let mut rras = 0;
+    for def in rrd_def {
+        let Ok(def) = def.to_str() else {
+            return Err(invalid(format!("{def:?} is not valid UTF-8")));
+        };
+        match parse(def).map_err(|err| invalid(format!("'{def}': {err}")))? {
+            Definition::DataSource(ds) => {
+                if !names.insert(ds.name.clone()) {
+                    return Err(invalid(format!(
+                        "'{def}': data source '{}' is defined twice",
+                        ds.name
+                    )));
+                }
+            }
+            Definition::Rra(_) => rras += 1,
+        }
+    }
+    if names.is_empty() {
+        return Err(invalid("no data source defined".to_string()));
+    }
+    if rras == 0 {
+        return Err(invalid("no RRA defined".to_string()));
+    }
+    Ok(())
+}
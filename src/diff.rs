@@ -0,0 +1,308 @@
+//! The diff subcommand, comparing the schema and optionally sampled values of two RRD files, for
+//! example a source .old file and its migrated target, to chase discrepancies after a migration
+//!
+//! The dry run uses the same comparison to show how the schema of each source file changes.
+
+use std::collections::BTreeSet;
+use std::ffi::CStr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Error};
+use tracing::{debug, error, warn};
+
+use proxmox_rrd_migration_tool::migrate::{self, FetchedData, ResourceType, RrdInfo};
+
+use crate::args::HELP;
+use crate::inspect::limit;
+use crate::logging::{self, Verbosity};
+use crate::{audit, EXIT_FAILURE, EXIT_SUCCESS, EXIT_USAGE};
+
+/// Points in time compared with --data by default
+const DEFAULT_SAMPLES: usize = 10;
+/// Time range the values are compared in with --data, before the earlier last update
+const DATA_RANGE: i64 = 24 * 3600;
+/// Relative difference up to which two values count as equal
+const TOLERANCE: f64 = 1e-6;
+
+#[derive(Debug)]
+struct DiffArgs {
+    first: PathBuf,
+    second: PathBuf,
+    data: bool,
+    samples: usize,
+}
+
+fn parse_args() -> Result<DiffArgs, Error> {
+    let mut pargs = pico_args::Arguments::from_env();
+    // the subcommand itself
+    let _ = pargs.subcommand()?;
+
+    let data = pargs.contains("--data");
+    let samples: Option<usize> = pargs
+        .opt_value_from_str("--samples")
+        .context("Could not parse --samples parameter")?;
+    let first = pargs
+        .free_from_str()
+        .context("Could not parse the first file name")?;
+    let second = pargs
+        .free_from_str()
+        .context("Could not parse the second file name")?;
+
+    let remaining = pargs.finish();
+    if !remaining.is_empty() {
+        bail!(format!("Warning: unused arguments left: {:?}", remaining));
+    }
+    if samples == Some(0) {
+        bail!("--samples has to be at least 1");
+    }
+    if samples.is_some() && !data {
+        bail!("--samples is only used with --data");
+    }
+    Ok(DiffArgs {
+        first,
+        second,
+        data,
+        samples: samples.unwrap_or(DEFAULT_SAMPLES),
+    })
+}
+
+/// The parts of a schema that are compared, each as a key and a line describing it
+fn schema_lines(info: &RrdInfo) -> Vec<(String, String)> {
+    let mut lines = vec![
+        ("version".to_string(), format!("version {}", info.version)),
+        ("step".to_string(), format!("step {}s", info.step)),
+        (
+            "last_update".to_string(),
+            format!(
+                "last update {} ({})",
+                info.last_update,
+                audit::format_time(info.last_update)
+            ),
+        ),
+    ];
+    for ds in &info.data_sources {
+        lines.push((
+            format!("ds {}", ds.name),
+            format!(
+                "data source {}: {}, heartbeat {}, min {}, max {}",
+                ds.name,
+                ds.kind,
+                ds.heartbeat,
+                limit(ds.min),
+                limit(ds.max)
+            ),
+        ));
+    }
+    for rra in &info.rras {
+        lines.push((
+            format!("rra {} {}", rra.cf, rra.pdp_per_row),
+            format!(
+                "RRA {}: {} steps/row, {} rows, xff {}",
+                rra.cf, rra.pdp_per_row, rra.rows, rra.xff
+            ),
+        ));
+    }
+    lines
+}
+
+/// How the step, data sources and RRAs of 'info' differ from those of 'rrd_def'
+fn schema_diff(info: &RrdInfo, rrd_def: &[&CStr]) -> Vec<String> {
+    let (data_sources, rras) = migrate::def_schema(rrd_def);
+    let expected = RrdInfo {
+        step: migrate::RRD_STEP_SIZE as u64,
+        data_sources,
+        rras,
+        ..info.clone()
+    };
+    // the version and last update are kept by the migration
+    let layout = |info: &RrdInfo| -> Vec<(String, String)> {
+        schema_lines(info)
+            .into_iter()
+            .filter(|(key, _)| key != "version" && key != "last_update")
+            .collect()
+    };
+    diff_lines(&layout(info), &layout(&expected))
+}
+
+/// Show what migrating the source file 'path' of 'kind' changes in its schema, for the dry run,
+/// and warn if it does not have the schema Proxmox VE 8 creates
+pub(crate) fn preview_migration(path: &Path, kind: ResourceType) {
+    let info = match migrate::inspect_file(path) {
+        Ok(info) => info,
+        Err(err) => {
+            warn!("could not read the schema of {} - {err}", path.display());
+            return;
+        }
+    };
+    let unexpected = schema_diff(&info, kind.legacy_rrd_def());
+    if !unexpected.is_empty() {
+        warn!(
+            "{} does not have the schema of a {kind} file of Proxmox VE 8, \
+            compared to it:\n  {}",
+            path.display(),
+            unexpected.join("\n  ")
+        );
+    }
+    let changes = schema_diff(&info, kind.rrd_def());
+    debug!(
+        "migrating {} would change its schema:\n  {}",
+        path.display(),
+        changes.join("\n  ")
+    );
+}
+
+/// The differences of two lists of keyed lines, like diff -u without context
+///
+/// Lines of the same key that differ are shown next to each other, in the order of 'first'.
+fn diff_lines(first: &[(String, String)], second: &[(String, String)]) -> Vec<String> {
+    let find = |lines: &[(String, String)], key: &str| {
+        lines
+            .iter()
+            .find(|(other, _)| other == key)
+            .map(|(_, line)| line.clone())
+    };
+    let mut diff = Vec::new();
+    for (key, line) in first {
+        match find(second, key) {
+            Some(other) if other == *line => {}
+            Some(other) => {
+                diff.push(format!("- {line}"));
+                diff.push(format!("+ {other}"));
+            }
+            None => diff.push(format!("- {line}")),
+        }
+    }
+    for (key, line) in second {
+        if find(first, key).is_none() {
+            diff.push(format!("+ {line}"));
+        }
+    }
+    diff
+}
+
+fn same_value(first: f64, second: f64) -> bool {
+    if first.is_nan() || second.is_nan() {
+        return first.is_nan() && second.is_nan();
+    }
+    (first - second).abs() <= TOLERANCE * first.abs().max(second.abs()).max(1.0)
+}
+
+/// The sampled values of the data sources in 'names', by a description of what was sampled
+fn samples(
+    fetched: &FetchedData,
+    times: &[i64],
+    cf: &str,
+    names: &BTreeSet<&str>,
+) -> Vec<(String, f64)> {
+    let mut samples = Vec::new();
+    for &time in times {
+        let Some(row) = fetched.row_at(time) else {
+            continue;
+        };
+        for (name, value) in fetched.data_sources.iter().zip(row) {
+            if names.contains(name.as_str()) {
+                samples.push((format!("{cf} of {name} at {time}"), *value));
+            }
+        }
+    }
+    samples
+}
+
+/// The differences of the values of 'first' and 'second', at --samples points in time of the
+/// last day both files have data for
+fn diff_data(args: &DiffArgs, first: &RrdInfo, second: &RrdInfo) -> Result<Vec<String>, Error> {
+    let end = first.last_update.min(second.last_update);
+    let start = end - DATA_RANGE;
+    let cfs: BTreeSet<&str> = first.rras.iter().map(|rra| rra.cf.as_str()).collect();
+    let other_cfs: BTreeSet<&str> = second.rras.iter().map(|rra| rra.cf.as_str()).collect();
+    let names: BTreeSet<&str> = first
+        .data_sources
+        .iter()
+        .map(|ds| ds.name.as_str())
+        .filter(|name| second.data_sources.iter().any(|ds| ds.name == *name))
+        .collect();
+
+    let mut diff = Vec::new();
+    for cf in cfs.intersection(&other_cfs) {
+        let fetched = migrate::fetch_file(&args.first, cf, start, end)?;
+        let other = migrate::fetch_file(&args.second, cf, start, end)?;
+        // evenly spread over the rows of the first file, at the end of their steps
+        let rows = fetched.rows.len();
+        let count = args.samples.min(rows);
+        let times: Vec<i64> = (0..count)
+            .map(|sample| sample * rows / count)
+            .map(|row| fetched.start + (row as i64 + 1) * fetched.step as i64)
+            .collect();
+
+        let other_samples = samples(&other, &times, cf, &names);
+        for (sample, value) in samples(&fetched, &times, cf, &names) {
+            match other_samples.iter().find(|(other, _)| *other == sample) {
+                Some((_, other)) if same_value(value, *other) => {}
+                Some((_, other)) => {
+                    diff.push(format!("- {sample}: {value}"));
+                    diff.push(format!("+ {sample}: {other}"));
+                }
+                None => diff.push(format!("- {sample}: {value}")),
+            }
+        }
+    }
+    Ok(diff)
+}
+
+/// Compare the files of 'args', returns the lines of the differences
+fn diff(args: &DiffArgs) -> Result<Vec<String>, Error> {
+    let first = migrate::inspect_file(&args.first)?;
+    let second = migrate::inspect_file(&args.second)?;
+    let mut diff = diff_lines(&schema_lines(&first), &schema_lines(&second));
+    if args.data {
+        diff.extend(diff_data(args, &first, &second)?);
+    }
+    Ok(diff)
+}
+
+fn print_header(first: &Path, second: &Path) {
+    println!("--- {}", first.display());
+    println!("+++ {}", second.display());
+}
+
+/// Run the diff subcommand, returns the exit code
+///
+/// Like diff(1), [`EXIT_FAILURE`] means that the files differ, or that they could not be compared.
+pub(crate) fn run() -> i32 {
+    if std::env::args().any(|arg| arg == "-h" || arg == "--help") {
+        print!("{HELP}");
+        return EXIT_SUCCESS;
+    }
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("Error: {err}.");
+            return EXIT_USAGE;
+        }
+    };
+    let console = logging::init(
+        Verbosity::Normal,
+        None,
+        false,
+        logging::use_color(false, false),
+    );
+
+    let exit_code = match diff(&args) {
+        Ok(diff) if diff.is_empty() => EXIT_SUCCESS,
+        Ok(diff) => {
+            print_header(&args.first, &args.second);
+            for line in diff {
+                println!("{line}");
+            }
+            EXIT_FAILURE
+        }
+        Err(err) => {
+            error!("Error: cannot compare the files: {err:#}");
+            EXIT_FAILURE
+        }
+    };
+    if let Some(ref console) = console {
+        console.flush();
+    }
+    exit_code
+}
@@ -0,0 +1,111 @@
+//! Errors returned by the migration functions of the library
+
+use std::ffi::OsString;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Why a single RRD file was not migrated
+#[derive(Debug)]
+pub enum MigrationError {
+    /// Only a dry run was requested, nothing was changed
+    DryRun { resource: OsString },
+    /// The target already exists and overwriting it was not requested
+    AlreadyMigrated { resource: OsString },
+    /// The target was modified after the source, so there is nothing new to migrate
+    UpToDate { resource: OsString },
+    /// The guest or node is not in .vmlist or .members anymore
+    ResourceMissing { resource: String },
+    /// librrd cannot read the source file
+    Corrupt { resource: OsString, message: String },
+    /// librrd failed to create the migrated file
+    Rrd { resource: OsString, message: String },
+    /// A DS or RRA definition of the schema is malformed
+    InvalidSchema { message: String },
+    /// A resource list of pmxcfs, like .vmlist, cannot be parsed
+    InvalidResourceList { list: &'static str, message: String },
+    /// The migrated file does not look like expected
+    Verification { resource: OsString, message: String },
+    /// The migration was given up on before it finished, its target was discarded
+    Abandoned { resource: OsString },
+    /// Accessing a file or directory failed
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+impl MigrationError {
+    /// Whether nothing failed and the file was not migrated on purpose
+    pub fn is_skip(&self) -> bool {
+        matches!(
+            self,
+            MigrationError::DryRun { .. }
+                | MigrationError::AlreadyMigrated { .. }
+                | MigrationError::UpToDate { .. }
+        )
+    }
+
+    /// Whether a file or directory does not exist
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            MigrationError::Io { source, .. } if source.kind() == std::io::ErrorKind::NotFound
+        )
+    }
+
+    pub(crate) fn io(path: impl Into<PathBuf>, source: std::io::Error) -> Self {
+        MigrationError::Io {
+            path: path.into(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::DryRun { resource } => {
+                write!(f, "skipping migration of metrics for {resource:?} - dry-run mode")
+            }
+            MigrationError::AlreadyMigrated { resource } => write!(
+                f,
+                "refusing to migrate metrics for {resource:?} - target already exists and 'force' not set!"
+            ),
+            MigrationError::UpToDate { resource } => {
+                write!(f, "skipping migration of metrics for {resource:?} - target is up to date")
+            }
+            MigrationError::ResourceMissing { resource } => {
+                write!(f, "'{resource}' not present")
+            }
+            MigrationError::Corrupt { resource, message } => {
+                write!(f, "source {resource:?} is corrupt: {message}")
+            }
+            MigrationError::Rrd { message, .. } => {
+                write!(f, "RRD create-migrated error: {message}")
+            }
+            MigrationError::InvalidSchema { message } => {
+                write!(f, "invalid schema definition: {message}")
+            }
+            MigrationError::InvalidResourceList { list, message } => {
+                write!(f, "cannot parse resource list {list}: {message}")
+            }
+            MigrationError::Verification { resource, message } => {
+                write!(f, "verification of {resource:?} failed: {message}")
+            }
+            MigrationError::Abandoned { resource } => write!(
+                f,
+                "discarded the migrated metrics for {resource:?} - given up on before it finished"
+            ),
+            MigrationError::Io { path, source } => write!(f, "{path:?}: {source}"),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MigrationError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,691 @@
+//! Migrating a single file and dealing with its source, shared by all phases
+
+use std::ffi::{CStr, OsStr};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Error, Result};
+use crossbeam_channel::RecvTimeoutError;
+use tracing::{debug, debug_span, error, info, trace, warn};
+
+use proxmox_rrd_migration_tool::migrate::{self, RRDFile, ResourceType};
+use proxmox_rrd_migration_tool::MigrationError;
+
+use crate::audit::Outcome;
+use crate::diff;
+use crate::parallel_handler::{ItemState, PanicError};
+use crate::report::{ErrorCause, ErrorReport};
+use crate::{MigrationOptions, SourceHandling, RETRY_BACKOFF, SECONDS_PER_DAY};
+
+/// Migrating a file took longer than the file timeout
+#[derive(Debug)]
+pub(crate) struct TimedOut {
+    pub(crate) resource: String,
+    pub(crate) after: Duration,
+}
+
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "giving up on migrating metrics for {} - took longer than {}s",
+            self.resource,
+            self.after.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Print why a file was not migrated, files skipped on purpose are only shown with --verbose
+pub(crate) fn log_file_error(err: &Error) {
+    if is_skip(err) {
+        debug!(status = "skipped", "{err}");
+    } else {
+        error!(status = "failed", "{err}");
+    }
+}
+
+/// Whether the file was not migrated on purpose
+pub(crate) fn is_skip(err: &Error) -> bool {
+    matches!(err.downcast_ref::<MigrationError>(), Some(err) if err.is_skip())
+}
+
+/// Whether the target of a failed file verifies against it, so that only its source is left
+pub(crate) fn is_migrated(
+    file: &RRDFile,
+    target: &Path,
+    kind: ResourceType,
+    options: &MigrationOptions,
+) -> bool {
+    options.migrate
+        && source_path(file).exists()
+        && target.exists()
+        && migrate::verify_file(source_path(file), target, kind.rrd_def()).is_ok()
+}
+
+/// Whether trying to migrate the file again could succeed
+pub(crate) fn is_retryable(err: &Error) -> bool {
+    !is_skip(err)
+        && !matches!(
+            err.downcast_ref::<MigrationError>(),
+            Some(
+                MigrationError::Corrupt { .. }
+                    | MigrationError::InvalidSchema { .. }
+                    | MigrationError::InvalidResourceList { .. }
+            )
+        )
+}
+
+/// Add a file that failed to migrate to the report by the cause of the error, except dry-run skips
+pub(crate) fn report_failure(
+    report: &ErrorReport,
+    resource: impl Into<String>,
+    source: &CStr,
+    err: &Error,
+) {
+    let (cause, detail) = if let Some(err) = err.downcast_ref::<MigrationError>() {
+        match err {
+            MigrationError::DryRun { .. } | MigrationError::UpToDate { .. } => return,
+            MigrationError::AlreadyMigrated { .. } => (ErrorCause::TargetExists, None),
+            MigrationError::ResourceMissing { .. } => (ErrorCause::NotPresent, None),
+            MigrationError::Corrupt { message, .. } => (ErrorCause::Corrupt, Some(message.clone())),
+            MigrationError::Rrd { message, .. } => (ErrorCause::Librrd, Some(message.clone())),
+            MigrationError::InvalidSchema { message } => {
+                (ErrorCause::InvalidSchema, Some(message.clone()))
+            }
+            MigrationError::Verification { message, .. } => {
+                (ErrorCause::Verification, Some(message.clone()))
+            }
+            MigrationError::InvalidResourceList { .. } => {
+                (ErrorCause::Other, Some(err.to_string()))
+            }
+            MigrationError::Abandoned { .. } => (ErrorCause::Timeout, None),
+            MigrationError::Io { .. } => (ErrorCause::Io, Some(err.to_string())),
+        }
+    } else if let Some(err) = err.downcast_ref::<TimedOut>() {
+        (
+            ErrorCause::Timeout,
+            Some(format!("after {}s", err.after.as_secs())),
+        )
+    } else if let Some(err) = err.downcast_ref::<PanicError>() {
+        (ErrorCause::Panic, err.message.clone())
+    } else if let Some(err) = err.downcast_ref::<std::io::Error>() {
+        (ErrorCause::Io, Some(err.to_string()))
+    } else {
+        (ErrorCause::Other, Some(format!("{err:#}")))
+    };
+    // corrupt sources are in the quarantine directory, migrating them again cannot work anyway
+    let source =
+        (cause != ErrorCause::Corrupt).then(|| PathBuf::from(OsStr::from_bytes(source.to_bytes())));
+    report.add(cause, resource, source, detail);
+}
+
+/// How long to wait before the retry 'attempt', starting at 1
+fn retry_backoff(attempt: u32) -> Duration {
+    RETRY_BACKOFF.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+}
+
+/// Sleep with exponential backoff before the next retry
+pub(crate) fn wait_before_retry(count: usize, attempt: u32, options: &MigrationOptions) {
+    let backoff = retry_backoff(attempt);
+    info!(
+        "Retrying {count} failed file(s) in {}s (attempt {attempt} of {})",
+        backoff.as_secs(),
+        options.retries,
+    );
+    // in steps, as the backoff can exceed the systemd watchdog interval
+    let until = std::time::Instant::now() + backoff;
+    while let Some(remaining) = until.checked_duration_since(std::time::Instant::now()) {
+        options.notifier.watchdog_ping();
+        std::thread::sleep(remaining.min(Duration::from_secs(1)));
+    }
+}
+
+/// Retry the failed files as configured, reports and returns the number of those still failing
+pub(crate) fn retry_failed_files(
+    mut failed: Vec<(RRDFile, PathBuf, Error)>,
+    kind: ResourceType,
+    options: &MigrationOptions,
+) -> usize {
+    for attempt in 1..=options.retries {
+        if failed.is_empty() {
+            break;
+        }
+        wait_before_retry(failed.len(), attempt, options);
+
+        failed.retain_mut(|(file, target_location, last_err)| {
+            let target = target_location.join(&file.1);
+            // migrating it again would only find the target and skip it
+            let result = if is_migrated(file, &target, kind, options) {
+                finish_source(source_path(file), &target, kind, options)
+            } else {
+                do_rrd_migration_with_timeout(file.clone(), target_location, kind, options)
+                    .and_then(|()| finish_source(source_path(file), &target, kind, options))
+            };
+            match result {
+                Ok(_) => false,
+                Err(err) => {
+                    log_file_error(&err);
+                    *last_err = err;
+                    true
+                }
+            }
+        });
+    }
+    for (file, _, err) in &failed {
+        report_failure(&options.report, file.1.to_string_lossy(), &file.0, err);
+    }
+    failed.len()
+}
+
+/// Does the actual migration for the given file, unless 'commit' gives up on it once complete
+pub(crate) fn do_rrd_migration(
+    file: RRDFile,
+    target_location: &Path,
+    kind: ResourceType,
+    options: &MigrationOptions,
+    commit: &dyn Fn() -> bool,
+) -> Result<()> {
+    let _file = debug_span!("file", %kind, resource = ?file.1).entered();
+    let backend = &*options.backend;
+    let target_path = target_location.join(&file.1);
+    let target_exists = backend.exists(&target_path);
+    trace!(
+        "migrating {} to {}",
+        file.0.to_string_lossy(),
+        target_path.display()
+    );
+    let source = file.0.to_string_lossy();
+    match backend.check(source_path(&file), &file.1) {
+        Ok(()) => {}
+        Err(err @ MigrationError::Corrupt { .. }) if options.try_repair && options.migrate => {
+            repair_source(&file, kind, err, options)?;
+        }
+        Err(err @ MigrationError::Corrupt { .. }) => {
+            quarantine(source_path(&file), kind, &err, options)?;
+            return Err(err.into());
+        }
+        Err(err) => {
+            options
+                .log
+                .record(kind, &source, Outcome::Failed, &err.to_string());
+            return Err(err.into());
+        }
+    }
+    if !options.migrate {
+        diff::preview_migration(source_path(&file), kind);
+    }
+    // a target an interrupted run left half-written is of no use, replace it without --force
+    let incomplete = if target_exists && !options.force {
+        backend
+            .check(&target_path, &file.1)
+            .err()
+            .filter(|err| matches!(err, MigrationError::Corrupt { .. }))
+    } else {
+        None
+    };
+    if let Some(ref reason) = incomplete {
+        warn!(
+            "existing target {} is incomplete, replacing it: {reason}",
+            target_path.display()
+        );
+    }
+    // with --incremental, only targets the source was modified after are updated
+    let update = target_exists && options.incremental && !options.force && incomplete.is_none();
+    if update && migrate::is_up_to_date_with(backend, source_path(&file), &target_path)? {
+        let err = MigrationError::UpToDate {
+            resource: file.1.clone(),
+        };
+        options
+            .log
+            .record(kind, &source, Outcome::Skipped, &err.to_string());
+        return Err(err.into());
+    }
+    let replace = update || incomplete.is_some();
+    let overwrite = options.force || replace;
+    if target_exists && !overwrite {
+        debug!(
+            status = "skipped",
+            "already migrated, use --force to overwrite target file: {}",
+            target_path.display()
+        );
+    }
+
+    options.progress.file_started(&source);
+    // the old target may still be the best copy there is, keep it, but only until an update
+    // from a newer source or the replacement of an incomplete one succeeded
+    let result = if options.migrate {
+        migrate::replace_file_with(
+            backend,
+            &file,
+            target_location,
+            kind.rrd_def(),
+            overwrite,
+            !replace,
+            |target| {
+                // a broken target is removed again, the source stays for the next run
+                if options.verify_after_migrate {
+                    migrate::verify_file(source_path(&file), target, kind.rrd_def())?;
+                }
+                // given up on meanwhile, the caller already moved on without it
+                if !commit() {
+                    return Err(MigrationError::Abandoned {
+                        resource: file.1.clone(),
+                    });
+                }
+                Ok(())
+            },
+        )
+    } else {
+        migrate::migrate_file_with(
+            backend,
+            &file,
+            target_location,
+            kind.rrd_def(),
+            false,
+            overwrite,
+        )
+        .map(|()| None)
+    };
+    // once given up on, it was recorded as failed already
+    if result.is_err() && !commit() {
+        return result.map(|_| ()).map_err(Error::from);
+    }
+    if let (Ok(Some(backup)), true) = (&result, replace) {
+        warn!("could not remove {}", backup.display());
+    }
+    match &result {
+        Ok(_) if incomplete.is_some() => options.log.record(
+            kind,
+            &source,
+            Outcome::Forced,
+            &format!("replaced incomplete target {}", target_path.display()),
+        ),
+        Ok(_) if update => options.log.record(
+            kind,
+            &source,
+            Outcome::Forced,
+            &format!(
+                "updated target {} from the newer source",
+                target_path.display()
+            ),
+        ),
+        Ok(backup) if target_exists => options.log.record(
+            kind,
+            &source,
+            Outcome::Forced,
+            &match backup {
+                Some(backup) => format!(
+                    "overwrote existing target {}, kept it as {}",
+                    target_path.display(),
+                    backup.display()
+                ),
+                None => format!("overwrote existing target {}", target_path.display()),
+            },
+        ),
+        Ok(_) => options.log.record(
+            kind,
+            &source,
+            Outcome::Migrated,
+            &format!("to {}", target_path.display()),
+        ),
+        Err(err) if err.is_skip() => {
+            options
+                .log
+                .record(kind, &source, Outcome::Skipped, &err.to_string())
+        }
+        Err(err) => options
+            .log
+            .record(kind, &source, Outcome::Failed, &err.to_string()),
+    }
+    result?;
+    if let Ok(metadata) = options.fs.metadata(source_path(&file)) {
+        options.progress.bytes_processed(kind, metadata.len);
+    }
+    debug!(status = "migrated", "migrated {}", file.0.to_string_lossy());
+    Ok(())
+}
+
+/// Move the corrupt source file into the quarantine directory, unless in dry-run mode
+fn quarantine(
+    path: &Path,
+    kind: ResourceType,
+    err: &MigrationError,
+    options: &MigrationOptions,
+) -> Result<()> {
+    let file = &path.to_string_lossy();
+    if !options.migrate {
+        let message = format!("{err} - would quarantine it, but in dry-run mode");
+        options.log.record(kind, file, Outcome::Failed, &message);
+        return Ok(());
+    }
+    match migrate::mv_archive(path, &options.source_base, &options.quarantine) {
+        Ok(quarantined) => {
+            let message = format!("{err} - moved it to {}", quarantined.display());
+            options.log.record(kind, file, Outcome::Failed, &message);
+            Ok(())
+        }
+        Err(mv_err) => {
+            let message = format!("{err} - could not quarantine it: {mv_err}");
+            options.log.record(kind, file, Outcome::Failed, &message);
+            Err(mv_err.into())
+        }
+    }
+}
+
+/// Replace the corrupt source file by a repaired copy, the damaged one goes to the quarantine
+fn repair_source(
+    file: &RRDFile,
+    kind: ResourceType,
+    err: MigrationError,
+    options: &MigrationOptions,
+) -> Result<()> {
+    let source = file.0.to_string_lossy();
+    let repaired = source_path(file);
+    let damaged = match migrate::mv_archive(repaired, &options.source_base, &options.quarantine) {
+        Ok(damaged) => damaged,
+        Err(mv_err) => {
+            let message = format!("{err} - could not quarantine it: {mv_err}");
+            options.log.record(kind, &source, Outcome::Failed, &message);
+            return Err(mv_err.into());
+        }
+    };
+    match migrate::repair(&damaged, repaired).and_then(|()| migrate::check_source(file)) {
+        Ok(()) => {
+            info!(
+                "{kind} '{}': repaired the corrupt source, kept the damaged file as {}",
+                file.1.to_string_lossy(),
+                damaged.display()
+            );
+            Ok(())
+        }
+        Err(repair_err) => {
+            // nothing but the repair can have put a file there since the damaged one was moved
+            let _ = fs::remove_file(repaired);
+            let message = format!(
+                "{err} - could not repair it: {repair_err} - moved it to {}",
+                damaged.display()
+            );
+            options.log.record(kind, &source, Outcome::Failed, &message);
+            Err(err.into())
+        }
+    }
+}
+
+/// Rename the source file to old or move it to the archive directory, returns where it is now
+pub(crate) fn mv_old(
+    file: &Path,
+    kind: ResourceType,
+    options: &MigrationOptions,
+) -> Result<PathBuf> {
+    trace!("marking {} as old", file.display());
+    if options.sources != SourceHandling::Keep {
+        check_hard_links(file, kind, options)?;
+    }
+    let result = match options.sources {
+        SourceHandling::Archive(ref archive) => {
+            migrate::mv_archive_with(&*options.fs, file, &options.source_base, archive)
+        }
+        SourceHandling::Keep => Ok(file.to_path_buf()),
+        SourceHandling::MarkOld | SourceHandling::Delete => {
+            migrate::mv_old_with(&*options.fs, file, &options.old_suffix)
+        }
+    };
+    match result {
+        Ok(old) => Ok(old),
+        Err(err) => {
+            let message = format!("could not mark as old: {err}");
+            let file = file.to_string_lossy();
+            options.log.record(kind, &file, Outcome::Failed, &message);
+            Err(err.into())
+        }
+    }
+}
+
+/// Warn about a file with other hard links before marking it as old, or copy it if configured
+fn check_hard_links(path: &Path, kind: ResourceType, options: &MigrationOptions) -> Result<()> {
+    let links = migrate::hard_links(path)?;
+    if links <= 1 {
+        return Ok(());
+    }
+    let file = path.to_string_lossy();
+    if !options.break_hardlinks {
+        warn!(
+            "{file} has {links} hard links, marking it as old affects the data seen through all \
+            of them, use --break-hardlinks to copy it first"
+        );
+        return Ok(());
+    }
+    debug!("{file} has {links} hard links, replacing it by a copy");
+    if let Err(err) = migrate::break_hardlink(path) {
+        let message = format!("could not break its hard links: {err}");
+        options.log.record(kind, &file, Outcome::Failed, &message);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Deal with the source once migrated to 'target', returns where it is unless deleted or compressed
+pub(crate) fn finish_source(
+    path: &Path,
+    target: &Path,
+    kind: ResourceType,
+    options: &MigrationOptions,
+) -> Result<Option<PathBuf>> {
+    let file = &path.to_string_lossy();
+    if options.sources != SourceHandling::Delete {
+        let old = mv_old(path, kind, options)?;
+        if !options.compress_old {
+            return Ok(Some(old));
+        }
+        trace!("compressing {}", old.display());
+        if let Err(err) = migrate::verify_file(&old, target, kind.rrd_def()) {
+            let message = format!("kept the old file uncompressed: {err}");
+            options.log.record(kind, file, Outcome::Failed, &message);
+            return Err(err.into());
+        }
+        // the target is fine, only the space is not saved
+        return match migrate::compress(&old) {
+            Ok(_) => Ok(None),
+            Err(err) => {
+                warn!("could not compress {} - {err}", old.display());
+                Ok(Some(old))
+            }
+        };
+    }
+    trace!("deleting {file}");
+    let result = migrate::verify_file(path, target, kind.rrd_def())
+        .map_err(Error::from)
+        .and_then(|()| Ok(fs::remove_file(path)?));
+    if let Err(ref err) = result {
+        let message = format!("kept the source, could not delete it: {err}");
+        options.log.record(kind, file, Outcome::Failed, &message);
+    }
+    result.map(|()| None)
+}
+
+/// Record that the resource of the file is gone, marking the file as old unless in dry-run mode
+pub(crate) fn mark_not_present(
+    file: &Path,
+    list: &str,
+    kind: ResourceType,
+    options: &MigrationOptions,
+) -> Result<()> {
+    mark_as_old(file, &format!("not present in {list}"), kind, options)
+}
+
+/// The path of the source file, which need not be valid UTF-8
+pub(crate) fn source_path(file: &RRDFile) -> &Path {
+    Path::new(OsStr::from_bytes(file.0.as_bytes()))
+}
+
+/// How many days ago the file was last updated, if that was longer ago than --skip-stale
+pub(crate) fn stale_for(file: &RRDFile, options: &MigrationOptions) -> Option<u64> {
+    let stale_after = options.skip_stale?;
+    let last_update = migrate::last_update(source_path(file)).ok()?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let age = Duration::from_secs(now.saturating_sub(last_update).max(0) as u64);
+    (age > stale_after).then_some(age.as_secs() / SECONDS_PER_DAY)
+}
+
+/// Whether the file is stale, then it is reported and marked as old unless in dry-run mode
+pub(crate) fn skip_stale(
+    file: &RRDFile,
+    kind: ResourceType,
+    options: &MigrationOptions,
+) -> Result<bool> {
+    let Some(days) = stale_for(file, options) else {
+        return Ok(false);
+    };
+    let resource = file.1.to_string_lossy();
+    let message = format!("last updated {days} days ago");
+    debug!("{kind} '{resource}': {message}, not migrating it");
+    options
+        .report
+        .add(ErrorCause::Stale, resource, None, Some(message.clone()));
+    mark_as_old(source_path(file), &message, kind, options)?;
+    Ok(true)
+}
+
+/// Report the source files in 'dir' that are too small to be RRD files, they are skipped
+pub(crate) fn report_unusable(
+    dir: &Path,
+    kind: ResourceType,
+    options: &MigrationOptions,
+) -> Result<()> {
+    for (file, len) in
+        migrate::collect_unusable_rrd_files_with(&*options.fs, dir, &options.old_suffix)?
+    {
+        if !options.is_selected(&file) {
+            continue;
+        }
+        let resource = file.1.to_string_lossy();
+        let source = file.0.to_string_lossy();
+        let message = format!("{len} bytes, too small for an RRD file");
+        warn!("{kind} '{resource}': skipping {source}, {message}");
+        options
+            .log
+            .record(kind, &source, Outcome::Skipped, &message);
+        options
+            .report
+            .add(ErrorCause::Unusable, resource, None, Some(message));
+    }
+    Ok(())
+}
+
+/// Take the files not named like a resource of 'kind' out of 'files', reporting them as skipped
+pub(crate) fn take_invalid_names(
+    files: &mut Vec<RRDFile>,
+    kind: ResourceType,
+    options: &MigrationOptions,
+) {
+    files.retain(|file| {
+        if migrate::is_valid_resource_name(kind, &file.1) {
+            return true;
+        }
+        let resource = file.1.to_string_lossy();
+        let source = file.0.to_string_lossy();
+        let message = format!("not a valid {kind} name");
+        warn!("{kind} '{resource}': skipping {source}, {message}");
+        options
+            .log
+            .record(kind, &source, Outcome::Skipped, &message);
+        options
+            .report
+            .add(ErrorCause::InvalidName, resource, None, Some(message));
+        false
+    });
+}
+
+/// Record why the file is not migrated, marking it as old unless in dry-run mode
+pub(crate) fn mark_as_old(
+    path: &Path,
+    message: &str,
+    kind: ResourceType,
+    options: &MigrationOptions,
+) -> Result<()> {
+    let file = &path.to_string_lossy();
+    if options.migrate {
+        mv_old(path, kind, options)?;
+        options.log.record(kind, file, Outcome::MarkedOld, message);
+    } else {
+        options.log.record(
+            kind,
+            file,
+            Outcome::Skipped,
+            &format!("{message} - dry-run mode"),
+        );
+    }
+    Ok(())
+}
+
+/// Does the migration for the given file, giving up on it after the configured file timeout
+pub(crate) fn do_rrd_migration_with_timeout(
+    file: RRDFile,
+    target_location: &Path,
+    kind: ResourceType,
+    options: &MigrationOptions,
+) -> Result<()> {
+    let Some(timeout) = options.file_timeout else {
+        return do_rrd_migration(file, target_location, kind, options, &|| true);
+    };
+
+    let resource = file.1.clone();
+    let source = file.0.to_string_lossy().into_owned();
+    let target_location = target_location.to_path_buf();
+    let options = options.clone();
+    let state = Arc::new(ItemState::default());
+    let state2 = Arc::clone(&state);
+    let (result_tx, result_rx) = crossbeam_channel::bounded(1);
+    let log = options.log.clone();
+
+    // librrd cannot be interrupted, a target it still creates after giving up is discarded
+    std::thread::Builder::new()
+        .name(format!("rrd migration {resource:?}"))
+        .spawn(move || {
+            let result =
+                do_rrd_migration(file, &target_location, kind, &options, &|| state2.commit());
+            let _ = result_tx.send(result);
+        })?;
+
+    match result_rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) if state.abandon() => {
+            let message = format!("took longer than {}s", timeout.as_secs());
+            log.record(kind, &source, Outcome::Failed, &message);
+            Err(TimedOut {
+                resource: format!("{resource:?}"),
+                after: timeout,
+            }
+            .into())
+        }
+        // it committed to its target just now, only the source is left to deal with
+        Err(RecvTimeoutError::Timeout) => result_rx
+            .recv()
+            .unwrap_or_else(|_| bail!("migration of {resource:?} stopped unexpectedly")),
+        Err(RecvTimeoutError::Disconnected) => {
+            bail!("migration of {resource:?} stopped unexpectedly")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_backoff_doubles() {
+        assert_eq!(retry_backoff(1), RETRY_BACKOFF);
+        assert_eq!(retry_backoff(2), RETRY_BACKOFF * 2);
+        assert_eq!(retry_backoff(4), RETRY_BACKOFF * 8);
+        // no overflow for large numbers of retries
+        assert_eq!(retry_backoff(100), RETRY_BACKOFF * u32::MAX);
+    }
+}
@@ -0,0 +1,257 @@
+//! The directory operations of the migration, behind a trait so that collecting the source
+//! files, marking them as old and creating the target directories can run against an in-memory
+//! file system that fails on demand
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::error::MigrationError;
+use crate::migrate;
+
+/// What [`Filesystem::metadata`] returns, the part of [`fs::Metadata`] the migration looks at
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub is_file: bool,
+    pub is_dir: bool,
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+pub trait Filesystem: fmt::Debug + Send + Sync {
+    /// The paths of the entries of 'dir', in no particular order
+    fn read_dir(&self, dir: &Path) -> Result<Vec<PathBuf>, MigrationError>;
+
+    /// Rename 'from' to 'to', replacing a file at 'to'
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), MigrationError>;
+
+    /// Create the directory 'dir', its parent has to exist
+    fn create_dir(&self, dir: &Path) -> Result<(), MigrationError>;
+
+    /// The metadata of 'path', following symbolic links
+    fn metadata(&self, path: &Path) -> Result<Metadata, MigrationError>;
+}
+
+/// The real file system
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdFilesystem;
+
+impl Filesystem for StdFilesystem {
+    fn read_dir(&self, dir: &Path) -> Result<Vec<PathBuf>, MigrationError> {
+        let entries = fs::read_dir(dir).map_err(|err| MigrationError::io(dir, err))?;
+        // entries that vanish while reading are left out, like with a later read
+        Ok(entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .collect())
+    }
+
+    /// Copies the file and removes 'from' if 'to' is on another file system
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), MigrationError> {
+        migrate::move_file(from, to)
+    }
+
+    fn create_dir(&self, dir: &Path) -> Result<(), MigrationError> {
+        fs::create_dir(dir).map_err(|err| MigrationError::io(dir, err))
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Metadata, MigrationError> {
+        let metadata = fs::metadata(path).map_err(|err| MigrationError::io(path, err))?;
+        Ok(Metadata {
+            is_file: metadata.is_file(),
+            is_dir: metadata.is_dir(),
+            len: metadata.len(),
+            modified: metadata
+                .modified()
+                .map_err(|err| MigrationError::io(path, err))?,
+        })
+    }
+}
+
+/// An operation of the [`Filesystem`], to make the [`MemoryFilesystem`] fail it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Operation {
+    ReadDir,
+    Rename,
+    CreateDir,
+    Metadata,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Entry {
+    File { len: u64, modified: SystemTime },
+    Dir,
+}
+
+/// Files and directories only kept in memory, for tests of the migration logic
+///
+/// Parents of added entries are created implicitly, the root and relative paths without a
+/// parent always exist.
+#[derive(Debug, Default)]
+pub struct MemoryFilesystem {
+    entries: Mutex<BTreeMap<PathBuf, Entry>>,
+    /// the operations to fail, by the path they are called with, 'from' for renames
+    failures: Mutex<HashMap<(Operation, PathBuf), ErrorKind>>,
+}
+
+impl MemoryFilesystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn add(&self, path: &Path, entry: Entry) {
+        let mut entries = self.entries.lock().unwrap();
+        for parent in path.ancestors().skip(1).filter(|dir| !is_root(dir)) {
+            entries.insert(parent.to_owned(), Entry::Dir);
+        }
+        entries.insert(path.to_owned(), entry);
+    }
+
+    /// Add the file 'path' with 'len' bytes, last modified now
+    pub fn add_file(&self, path: impl AsRef<Path>, len: u64) {
+        let modified = SystemTime::now();
+        self.add(path.as_ref(), Entry::File { len, modified });
+    }
+
+    pub fn add_dir(&self, path: impl AsRef<Path>) {
+        self.add(path.as_ref(), Entry::Dir);
+    }
+
+    /// Make 'operation' on 'path' fail with 'kind' from now on
+    pub fn fail(&self, operation: Operation, path: impl Into<PathBuf>, kind: ErrorKind) {
+        let mut failures = self.failures.lock().unwrap();
+        failures.insert((operation, path.into()), kind);
+    }
+
+    pub fn is_file(&self, path: &Path) -> bool {
+        let entries = self.entries.lock().unwrap();
+        matches!(entries.get(path), Some(Entry::File { .. }))
+    }
+
+    pub fn is_dir(&self, path: &Path) -> bool {
+        let entries = self.entries.lock().unwrap();
+        is_root(path) || matches!(entries.get(path), Some(Entry::Dir))
+    }
+
+    /// The paths of all files and directories, sorted
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.entries.lock().unwrap().keys().cloned().collect()
+    }
+
+    fn check(&self, operation: Operation, path: &Path) -> Result<(), MigrationError> {
+        let failures = self.failures.lock().unwrap();
+        match failures.get(&(operation, path.to_owned())) {
+            Some(kind) => Err(error(path, (*kind).into())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Whether 'path' has no parent that could be missing
+fn is_root(path: &Path) -> bool {
+    path.parent().is_none() || path.as_os_str().is_empty()
+}
+
+fn error(path: &Path, err: io::Error) -> MigrationError {
+    MigrationError::io(path, err)
+}
+
+fn not_found(path: &Path) -> MigrationError {
+    error(path, ErrorKind::NotFound.into())
+}
+
+fn os_error(path: &Path, code: i32) -> MigrationError {
+    error(path, io::Error::from_raw_os_error(code))
+}
+
+impl Filesystem for MemoryFilesystem {
+    fn read_dir(&self, dir: &Path) -> Result<Vec<PathBuf>, MigrationError> {
+        self.check(Operation::ReadDir, dir)?;
+        if !self.is_dir(dir) {
+            if self.is_file(dir) {
+                return Err(os_error(dir, libc::ENOTDIR));
+            }
+            return Err(not_found(dir));
+        }
+        let entries = self.entries.lock().unwrap();
+        Ok(entries
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    /// Moves the entries below a directory along with it
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), MigrationError> {
+        self.check(Operation::Rename, from)?;
+        let mut entries = self.entries.lock().unwrap();
+        let Some(&entry) = entries.get(from) else {
+            return Err(not_found(from));
+        };
+        if let Some(parent) = to.parent().filter(|dir| !is_root(dir)) {
+            match entries.get(parent) {
+                Some(Entry::Dir) => {}
+                Some(Entry::File { .. }) => return Err(os_error(to, libc::ENOTDIR)),
+                None => return Err(not_found(to)),
+            }
+        }
+        match (entry, entries.get(to)) {
+            (Entry::File { .. }, Some(Entry::Dir)) => return Err(os_error(to, libc::EISDIR)),
+            (Entry::Dir, Some(Entry::File { .. })) => return Err(os_error(to, libc::ENOTDIR)),
+            _ => {}
+        }
+        let moved: Vec<PathBuf> = entries
+            .keys()
+            .filter(|path| path.starts_with(from))
+            .cloned()
+            .collect();
+        for path in moved {
+            if let Some(entry) = entries.remove(&path) {
+                let relative = path.strip_prefix(from).unwrap_or(Path::new(""));
+                entries.insert(to.join(relative), entry);
+            }
+        }
+        Ok(())
+    }
+
+    fn create_dir(&self, dir: &Path) -> Result<(), MigrationError> {
+        self.check(Operation::CreateDir, dir)?;
+        if self.is_dir(dir) || self.is_file(dir) {
+            return Err(error(dir, ErrorKind::AlreadyExists.into()));
+        }
+        if let Some(parent) = dir.parent().filter(|dir| !is_root(dir)) {
+            if !self.is_dir(parent) {
+                return Err(not_found(dir));
+            }
+        }
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(dir.to_owned(), Entry::Dir);
+        Ok(())
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Metadata, MigrationError> {
+        self.check(Operation::Metadata, path)?;
+        let entries = self.entries.lock().unwrap();
+        match entries.get(path) {
+            Some(&Entry::File { len, modified }) => Ok(Metadata {
+                is_file: true,
+                is_dir: false,
+                len,
+                modified,
+            }),
+            Some(Entry::Dir) => Ok(Metadata {
+                is_file: false,
+                is_dir: true,
+                len: 0,
+                modified: SystemTime::UNIX_EPOCH,
+            }),
+            None => Err(not_found(path)),
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Read-only consistency check of the whole RRD tree, cross-checking the source, old and target
+//! files against each other and against the configured guests, nodes and storages
+
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Error;
+use serde::Serialize;
+use tracing::error;
+
+use proxmox_rrd_migration_tool::migrate::{self, RRDFile, ResourceType};
+
+use crate::plan::OutputFormat;
+use crate::remigrate::{self, FromOld};
+use crate::{
+    MigrationDir, MigrationOptions, ResourceLists, EXIT_ANOMALIES, EXIT_FAILURE, EXIT_SUCCESS,
+    STORAGE_CONFIG,
+};
+
+/// rrdcached needs to read and write the targets, nobody else should write them
+const OWNER_RW: u32 = 0o600;
+const GROUP_OTHER_W: u32 = 0o022;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Anomaly {
+    /// a target of a guest, node or storage that is not configured
+    UnknownResource,
+    /// a configured guest or node without a source, old or target file
+    NoFiles,
+    /// empty or too small to be an RRD file
+    Empty,
+    Permissions,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Anomaly::UnknownResource => "unknown-resource",
+            Anomaly::NoFiles => "no-files",
+            Anomaly::Empty => "empty",
+            Anomaly::Permissions => "permissions",
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Finding {
+    resource_type: String,
+    resource: String,
+    path: PathBuf,
+    anomaly: Anomaly,
+    detail: String,
+}
+
+/// The configured resources of each type, [`None`] for storages if there is no storage.cfg
+pub(crate) struct Configured {
+    guests: HashSet<String>,
+    nodes: HashSet<String>,
+    storages: Option<HashSet<String>>,
+}
+
+impl Configured {
+    pub(crate) fn read(resources: &str, lists: &ResourceLists) -> Result<Self, Error> {
+        let storage_config = format!("{resources}/{STORAGE_CONFIG}");
+        Ok(Self {
+            guests: lists.guests()?.clone(),
+            nodes: lists.nodes()?.keys().cloned().collect(),
+            storages: if Path::new(&storage_config).exists() {
+                Some(migrate::read_storage_ids(&storage_config)?)
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Storages always count as configured without a storage.cfg
+    pub(crate) fn contains(&self, kind: ResourceType, resource: &str) -> bool {
+        match kind {
+            ResourceType::Guest => self.guests.contains(resource),
+            ResourceType::Node => self.nodes.contains(resource),
+            ResourceType::Storage => self
+                .storages
+                .as_ref()
+                .is_none_or(|storages| storages.contains(resource)),
+        }
+    }
+}
+
+fn path(file: &RRDFile) -> PathBuf {
+    PathBuf::from(OsStr::from_bytes(file.0.as_bytes()))
+}
+
+/// Check all files of 'dir', adding the resources that have files to 'seen'
+fn check_dir(
+    dir: &MigrationDir,
+    configured: &Configured,
+    options: &MigrationOptions,
+    seen: &mut HashSet<String>,
+    findings: &mut Vec<Finding>,
+) -> Result<(), Error> {
+    let sources = migrate::collect_rrd_files_with(&*options.fs, &dir.source, &options.old_suffix)?;
+    let old = remigrate::old_files(dir, &FromOld::All, options)?;
+    let mut targets = migrate::collect_rrd_files(&dir.target)?;
+    targets.retain(|file| !migrate::is_target_backup(&file.1));
+
+    let mut finding = |file: &RRDFile, anomaly, detail: String| {
+        findings.push(Finding {
+            resource_type: dir.kind.to_string(),
+            resource: file.1.to_string_lossy().into_owned(),
+            path: path(file),
+            anomaly,
+            detail,
+        })
+    };
+    for file in sources.iter().chain(&old).chain(&targets) {
+        seen.insert(file.1.to_string_lossy().into_owned());
+        if std::fs::metadata(path(file))?.len() == 0 {
+            finding(file, Anomaly::Empty, "zero bytes".to_string());
+        }
+    }
+    // too small to be collected with the others
+    for dir in [&dir.source, &dir.target] {
+        for (file, len) in
+            migrate::collect_unusable_rrd_files_with(&*options.fs, dir, &options.old_suffix)?
+        {
+            seen.insert(file.1.to_string_lossy().into_owned());
+            finding(&file, Anomaly::Empty, format!("{len} bytes"));
+        }
+    }
+    for file in &targets {
+        let resource = file.1.to_string_lossy();
+        if !configured.contains(dir.kind, &resource) {
+            finding(file, Anomaly::UnknownResource, "target only".to_string());
+        }
+        let mode = std::fs::metadata(path(file))?.permissions().mode() & 0o777;
+        if mode & OWNER_RW != OWNER_RW || mode & GROUP_OTHER_W != 0 {
+            finding(file, Anomaly::Permissions, format!("mode {mode:04o}"));
+        }
+    }
+    Ok(())
+}
+
+fn check(
+    dirs: &[MigrationDir],
+    resources: &str,
+    options: &MigrationOptions,
+) -> Result<Vec<Finding>, Error> {
+    let configured = Configured::read(resources, &options.resources)?;
+    let mut findings = Vec::new();
+    let mut seen_guests = HashSet::new();
+    let mut seen_nodes = HashSet::new();
+    for dir in dirs {
+        let mut seen = HashSet::new();
+        check_dir(dir, &configured, options, &mut seen, &mut findings)?;
+        match dir.kind {
+            ResourceType::Guest => seen_guests.extend(seen),
+            ResourceType::Node => seen_nodes.extend(seen),
+            ResourceType::Storage => {}
+        }
+    }
+
+    // storages can be limited to some of the nodes, so only guests and nodes always have files
+    let mut missing = Vec::new();
+    for (kind, configured, seen) in [
+        (ResourceType::Guest, &configured.guests, &seen_guests),
+        (ResourceType::Node, &configured.nodes, &seen_nodes),
+    ] {
+        let dir = dirs.iter().find(|dir| dir.kind == kind);
+        for resource in configured.difference(seen) {
+            missing.push(Finding {
+                resource_type: kind.to_string(),
+                resource: resource.clone(),
+                path: dir.map(|dir| dir.target.join(resource)).unwrap_or_default(),
+                anomaly: Anomaly::NoFiles,
+                detail: "no source, old or target file".to_string(),
+            });
+        }
+    }
+    missing.sort_by(|a, b| a.resource.cmp(&b.resource));
+    findings.extend(missing);
+    Ok(findings)
+}
+
+fn print_table(findings: &[Finding]) {
+    if findings.is_empty() {
+        println!("No anomalies found.");
+        return;
+    }
+    let header = ["TYPE", "RESOURCE", "ANOMALY", "PATH", "DETAIL"];
+    let rows: Vec<[String; 5]> = findings
+        .iter()
+        .map(|finding| {
+            [
+                finding.resource_type.clone(),
+                finding.resource.clone(),
+                finding.anomaly.to_string(),
+                finding.path.display().to_string(),
+                finding.detail.clone(),
+            ]
+        })
+        .collect();
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let print_row = |cells: [&str; 5]| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(header);
+    for row in &rows {
+        print_row(row.each_ref().map(String::as_str));
+    }
+}
+
+/// Print the anomalies in the RRD tree on stdout, without changing anything
+///
+/// Returns the exit code, [`EXIT_ANOMALIES`] if there are any.
+pub(crate) fn run(
+    dirs: &[MigrationDir],
+    resources: &str,
+    format: OutputFormat,
+    options: &MigrationOptions,
+) -> i32 {
+    let findings = match check(dirs, resources, options) {
+        Ok(findings) => findings,
+        Err(err) => {
+            error!("Error: cannot check the RRD files: {err}");
+            return EXIT_FAILURE;
+        }
+    };
+    match format {
+        OutputFormat::Text => print_table(&findings),
+        OutputFormat::Json => match serde_json::to_string_pretty(&findings) {
+            Ok(json) => println!("{json}"),
+            Err(err) => {
+                error!("Error: cannot serialize the findings: {err}");
+                return EXIT_FAILURE;
+            }
+        },
+    }
+    if findings.is_empty() {
+        EXIT_SUCCESS
+    } else {
+        EXIT_ANOMALIES
+    }
+}
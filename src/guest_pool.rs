@@ -0,0 +1,484 @@
+//! Migrating the guest files in parallel, on the [`ParallelHandler`] unless built with rayon
+//!
+//! [`ParallelHandler`]: crate::parallel_handler::ParallelHandler
+
+use std::collections::HashMap;
+#[cfg(not(feature = "rayon"))]
+use std::ffi::CString;
+use std::ffi::OsString;
+use std::path::Path;
+#[cfg(not(feature = "rayon"))]
+use std::path::PathBuf;
+#[cfg(not(feature = "rayon"))]
+use std::sync::atomic::AtomicIsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(not(feature = "rayon"))]
+use std::sync::Arc;
+#[cfg(not(feature = "rayon"))]
+use std::time::Duration;
+
+use anyhow::{Error, Result};
+#[cfg(not(feature = "rayon"))]
+use crossbeam_channel::Receiver;
+use tracing::{debug, info};
+#[cfg(not(feature = "rayon"))]
+use tracing::{trace, warn};
+
+#[cfg(not(feature = "rayon"))]
+use proxmox_rrd_migration_tool::migrate;
+use proxmox_rrd_migration_tool::migrate::{RRDFile, ResourceType};
+
+use crate::audit::Outcome;
+use crate::file::{
+    do_rrd_migration, finish_source, is_migrated, is_retryable, is_skip, log_file_error,
+    mark_as_old, mark_not_present, skip_stale, source_path,
+};
+#[cfg(not(feature = "rayon"))]
+use crate::file::{wait_before_retry, TimedOut};
+use crate::notify::Notifier;
+use crate::parallel_handler::{self, PanicError};
+#[cfg(not(feature = "rayon"))]
+use crate::parallel_handler::{Batch, BatchSender, ParallelHandler};
+use crate::phase::eta_suffix;
+use crate::report::ErrorCause;
+#[cfg(not(feature = "rayon"))]
+use crate::LOW_MEMORY_QUEUE_DEPTH;
+use crate::{ErrorCounter, MigrationOptions, RemoteGuests};
+
+/// Number of worker threads to add or remove, as requested via SIGUSR1 and SIGUSR2
+#[cfg(not(feature = "rayon"))]
+static THREAD_ADJUSTMENT: AtomicIsize = AtomicIsize::new(0);
+
+/// Scale the guest migration pool via SIGUSR1 (+1) and SIGUSR2 (-1), registered for the whole run
+#[cfg(not(feature = "rayon"))]
+pub(crate) fn register_thread_signals() -> Result<(), Error> {
+    for (signal, step) in [(libc::SIGUSR1, 1), (libc::SIGUSR2, -1)] {
+        // only updates an atomic, which is safe in a signal handler
+        unsafe {
+            signal_hook::low_level::register(signal, move || {
+                THREAD_ADJUSTMENT.fetch_add(step, Ordering::SeqCst);
+            })
+        }?;
+    }
+    Ok(())
+}
+
+/// Apply thread count changes requested via signals since the last call
+#[cfg(not(feature = "rayon"))]
+fn apply_thread_signals<I: Send + std::fmt::Debug + 'static>(pool: &ParallelHandler<I>) {
+    let adjustment = THREAD_ADJUSTMENT.swap(0, Ordering::SeqCst);
+    if adjustment == 0 {
+        return;
+    }
+    let threads = (pool.threads() as isize + adjustment).max(1) as usize;
+    info!("Scaling to {threads} thread(s)");
+    pool.set_threads(threads);
+}
+
+/// Failed migration of a single guest RRD file
+#[derive(Debug)]
+pub(crate) struct FileError {
+    /// The file that failed, if it can be retried
+    pub(crate) file: Option<RRDFile>,
+    pub(crate) resource: String,
+    pub(crate) error: Error,
+}
+
+impl From<PanicError> for FileError {
+    fn from(err: PanicError) -> Self {
+        Self {
+            file: None,
+            resource: err.item.clone(),
+            error: err.into(),
+        }
+    }
+}
+
+/// Collects the outcome of the guest migration as it comes in from the workers
+pub(crate) struct GuestResults {
+    pub(crate) migrated: Vec<OsString>,
+    pub(crate) failed: Vec<FileError>,
+    /// number of files sent to the workers for which no outcome arrived yet
+    pub(crate) outstanding: usize,
+    errors: ErrorCounter,
+    /// set once too many files failed
+    pub(crate) aborted: Option<Error>,
+    /// pinged while waiting for the workers
+    pub(crate) notifier: Notifier,
+    /// what completing the workers returned, their panics are reported after the summary
+    pub(crate) completion: Result<(), Error>,
+}
+
+impl GuestResults {
+    pub(crate) fn new(errors: ErrorCounter, notifier: Notifier) -> Self {
+        Self {
+            migrated: Vec::new(),
+            failed: Vec::new(),
+            outstanding: 0,
+            errors,
+            aborted: None,
+            notifier,
+            completion: Ok(()),
+        }
+    }
+
+    pub(crate) fn handle(&mut self, result: Result<OsString, FileError>) {
+        self.outstanding = self.outstanding.saturating_sub(1);
+        self.notifier.watchdog_ping();
+        self.record(result);
+    }
+
+    fn record(&mut self, result: Result<OsString, FileError>) {
+        match result {
+            Ok(resource) => self.migrated.push(resource),
+            Err(err) => {
+                log_file_error(&err.error);
+                if !is_skip(&err.error) && self.aborted.is_none() {
+                    // the ones without a file are only known by their resource
+                    let file = match &err.file {
+                        Some(file) => file.0.as_bytes(),
+                        None => err.resource.as_bytes(),
+                    };
+                    if let Err(abort) = self.errors.record(file) {
+                        self.aborted = Some(abort);
+                    }
+                }
+                self.failed.push(err);
+            }
+        }
+    }
+
+    /// Handle all outcomes that already arrived
+    #[cfg(not(feature = "rayon"))]
+    fn collect(
+        &mut self,
+        results: &Receiver<Result<OsString, FileError>>,
+        timeouts: &Receiver<FileError>,
+    ) {
+        self.notifier.watchdog_ping();
+        results.try_iter().for_each(|result| self.handle(result));
+        timeouts.try_iter().for_each(|err| self.handle(Err(err)));
+    }
+
+    /// Wait until the outcome of all files sent to the workers arrived
+    #[cfg(not(feature = "rayon"))]
+    fn wait<I: Send + std::fmt::Debug + 'static>(
+        &mut self,
+        results: &Receiver<Result<OsString, FileError>>,
+        timeouts: &Receiver<FileError>,
+        pool: &ParallelHandler<I>,
+    ) {
+        self.wait_until(0, results, timeouts, pool);
+    }
+
+    /// Wait until no more than 'outstanding' files sent to the workers have no outcome yet
+    #[cfg(not(feature = "rayon"))]
+    fn wait_until<I: Send + std::fmt::Debug + 'static>(
+        &mut self,
+        outstanding: usize,
+        results: &Receiver<Result<OsString, FileError>>,
+        timeouts: &Receiver<FileError>,
+        pool: &ParallelHandler<I>,
+    ) {
+        while self.outstanding > outstanding {
+            crossbeam_channel::select! {
+                recv(results) -> result => if let Ok(result) = result {
+                    self.handle(result);
+                },
+                recv(timeouts) -> err => if let Ok(err) = err {
+                    self.handle(Err(err));
+                },
+                default(Duration::from_millis(500)) => {
+                    self.notifier.watchdog_ping();
+                    apply_thread_signals(pool);
+                }
+            }
+        }
+    }
+
+    /// Take out the failed files which are worth another try
+    pub(crate) fn take_retryable(&mut self) -> Vec<RRDFile> {
+        let (retry, failed) = self
+            .failed
+            .drain(..)
+            .partition(|err: &FileError| err.file.is_some() && is_retryable(&err.error));
+        self.failed = failed;
+        retry.into_iter().filter_map(|err| err.file).collect()
+    }
+
+    /// Finish the sources of the files to retry whose targets were migrated, returns the others
+    pub(crate) fn retry_sources(
+        &mut self,
+        retry: Vec<RRDFile>,
+        target_dir: &Path,
+        options: &MigrationOptions,
+    ) -> Vec<RRDFile> {
+        retry
+            .into_iter()
+            .filter(|file| {
+                let target = target_dir.join(&file.1);
+                if !is_migrated(file, &target, ResourceType::Guest, options) {
+                    return true;
+                }
+                let result =
+                    finish_source(source_path(file), &target, ResourceType::Guest, options)
+                        .map(|_| file.1.clone())
+                        .map_err(|error| FileError {
+                            file: Some(file.clone()),
+                            resource: file.1.to_string_lossy().into_owned(),
+                            error,
+                        });
+                self.record(result);
+                false
+            })
+            .collect()
+    }
+}
+
+/// Migrate a single guest file and deal with its source, 'done' out of 'total' counts the progress
+pub(crate) fn migrate_guest(
+    file: RRDFile,
+    target_dir: &Path,
+    done: &AtomicUsize,
+    total: usize,
+    options: &MigrationOptions,
+) -> Result<OsString, FileError> {
+    let resource = file.1.clone();
+
+    // an item the watchdog gave up on fails here, the existing target is restored then
+    if let Err(error) = do_rrd_migration(
+        file.clone(),
+        target_dir,
+        ResourceType::Guest,
+        options,
+        &parallel_handler::commit_item,
+    ) {
+        return Err(FileError {
+            resource: resource.to_string_lossy().into_owned(),
+            file: Some(file),
+            error,
+        });
+    }
+    let target = target_dir.join(&resource);
+    if let Err(error) = finish_source(source_path(&file), &target, ResourceType::Guest, options) {
+        return Err(FileError {
+            resource: resource.to_string_lossy().into_owned(),
+            file: Some(file),
+            error,
+        });
+    }
+
+    let current = done.fetch_add(1, Ordering::SeqCst) + 1;
+    if options.progress_every.is_due(current, total) {
+        info!(
+            "migrated metrics for {current} out of {total} guests{}.",
+            eta_suffix(ResourceType::Guest, options)
+        );
+    }
+    Ok(resource)
+}
+
+/// The other node hosting the guest, if --remote-guests keeps its files from being migrated here
+pub(crate) fn remote_guest_node(guest: &str, options: &MigrationOptions) -> Result<Option<String>> {
+    if options.remote_guests == RemoteGuests::All {
+        return Ok(None);
+    }
+    let Some(node) = options.resources.guest_node(guest)? else {
+        return Ok(None);
+    };
+    if node == options.resources.local_node() {
+        return Ok(None);
+    }
+    Ok(Some(node.to_string()))
+}
+
+/// Whether --remote-guests keeps the guest file from being migrated, then it is reported
+fn skip_remote_guest(file: &RRDFile, options: &MigrationOptions) -> Result<bool> {
+    let guest = file.1.to_string_lossy();
+    let Some(node) = remote_guest_node(&guest, options)? else {
+        return Ok(false);
+    };
+    let message = format!("hosted on node {node}");
+    options.report.add(
+        ErrorCause::OtherNode,
+        guest.as_ref(),
+        None,
+        Some(message.clone()),
+    );
+    if options.remote_guests == RemoteGuests::Archive {
+        debug!("VMID: '{guest}' {message}, marking it as old");
+        mark_as_old(source_path(file), &message, ResourceType::Guest, options)?;
+    } else {
+        debug!(status = "skipped", "VMID: '{guest}' {message}, skipping it");
+        let source = source_path(file).to_string_lossy();
+        options
+            .log
+            .record(ResourceType::Guest, &source, Outcome::Skipped, &message);
+    }
+    Ok(true)
+}
+
+/// Whether the guest file is to be migrated, those of gone, remote or stale guests are reported
+pub(crate) fn is_guest_dispatched(file: &RRDFile, options: &MigrationOptions) -> Result<bool> {
+    let guest = file.1.to_string_lossy().into_owned();
+    let present = options.resources.contains(ResourceType::Guest, &guest)?;
+    if !present && options.migrate_orphans {
+        debug!("VMID: '{guest}' not present, migrating it anyway.");
+    } else if !present {
+        options
+            .report
+            .add(ErrorCause::NotPresent, guest.as_str(), None, None);
+        if options.migrate {
+            debug!(
+                status = "marked-old",
+                "VMID: '{guest}' not present. Skip and mark as old."
+            );
+        } else {
+            debug!(status = "skipped", "VMID: '{guest}' not present. Would mark as old, but in dry-run mode, so just skip.");
+        }
+        mark_not_present(source_path(file), ".vmlist", ResourceType::Guest, options)?;
+        return Ok(false);
+    }
+    if present && skip_remote_guest(file, options)? {
+        return Ok(false);
+    }
+    Ok(!skip_stale(file, ResourceType::Guest, options)?)
+}
+
+/// Migrate the guest files with the [`ParallelHandler`], the outcomes end up in 'results'
+#[cfg(not(feature = "rayon"))]
+pub(crate) fn run(
+    files: Vec<RRDFile>,
+    target_dir: PathBuf,
+    options: &MigrationOptions,
+    results: &mut GuestResults,
+    dispatched: &mut HashMap<String, RRDFile>,
+) -> Result<(), Error> {
+    let worker_options = options.clone();
+    let worker_target = target_dir.clone();
+    let total = files.len();
+    let done = Arc::new(AtomicUsize::new(0));
+    let (mut migration_pool, migration_results) = ParallelHandler::with_batched_results(
+        "guest rrd migration",
+        options.threads,
+        move |file: (CString, OsString)| -> Result<OsString, FileError> {
+            migrate_guest(file, &worker_target, &done, total, &worker_options)
+        },
+    );
+    migration_pool.thread_init(migrate::init_rrd_thread);
+    if let Some(max_threads) = options.max_threads {
+        migration_pool.autoscale(options.threads, max_threads);
+    }
+    let (timeout_tx, timeout_rx) = crossbeam_channel::unbounded();
+    let log = options.log.clone();
+    migration_pool.watchdog(
+        options.stall_timeout,
+        options.file_timeout,
+        move |stalled| {
+            if stalled.skipped {
+                let message = format!("took longer than {}s", stalled.elapsed.as_secs());
+                log.record(
+                    ResourceType::Guest,
+                    &stalled.item,
+                    Outcome::Failed,
+                    &message,
+                );
+                let _ = timeout_tx.send(FileError {
+                    // the stuck conversion might still write the target, so don't retry it
+                    file: None,
+                    resource: stalled.item.clone(),
+                    error: TimedOut {
+                        resource: stalled.item.clone(),
+                        after: stalled.elapsed,
+                    }
+                    .into(),
+                });
+            } else {
+                warn!(
+                    "migration of {} is still running after {}s in {}",
+                    stalled.item,
+                    stalled.elapsed.as_secs(),
+                    stalled.thread,
+                );
+            }
+        },
+    );
+    // the reading threads queue the files for the conversion once they are in the page cache
+    let read_pool = options.io_threads.map(|threads| {
+        let conversion = migration_pool.channel();
+        ParallelHandler::new(
+            "guest rrd reading",
+            threads,
+            move |batch: Batch<RRDFile>| {
+                for file in &batch.0 {
+                    if let Err(err) = migrate::read_ahead(source_path(file)) {
+                        // the conversion fails on it too and reports it
+                        trace!("could not read ahead: {err}");
+                    }
+                }
+                conversion.send(batch)
+            },
+        )
+    });
+    let mut queue = match read_pool {
+        Some(ref read_pool) => BatchSender::new(read_pool.channel(), options.batch_size),
+        None => BatchSender::new(migration_pool.channel(), options.batch_size),
+    };
+
+    for file in files {
+        if let Err(abort) = options.control.checkpoint(&options.notifier) {
+            results.aborted = Some(abort);
+            break;
+        }
+        if !is_guest_dispatched(&file, options)? {
+            continue;
+        }
+        if options.low_memory {
+            queue.flush()?;
+            results.wait_until(
+                LOW_MEMORY_QUEUE_DEPTH - 1,
+                &migration_results,
+                &timeout_rx,
+                &migration_pool,
+            );
+        }
+        dispatched.insert(format!("{file:?}"), file.clone());
+        queue.send(file)?;
+        results.outstanding += 1;
+
+        results.collect(&migration_results, &timeout_rx);
+        apply_thread_signals(&migration_pool);
+        if results.aborted.is_some() {
+            break;
+        }
+    }
+
+    for attempt in 1..=options.retries {
+        if results.aborted.is_some() {
+            break;
+        }
+        queue.flush()?;
+        results.wait(&migration_results, &timeout_rx, &migration_pool);
+        let retry = results.take_retryable();
+        if retry.is_empty() {
+            break;
+        }
+        wait_before_retry(retry.len(), attempt, options);
+        for file in results.retry_sources(retry, &target_dir, options) {
+            queue.send(file)?;
+            results.outstanding += 1;
+        }
+    }
+
+    queue.flush()?;
+    drop(queue);
+    if let Some(read_pool) = read_pool {
+        read_pool.complete()?;
+    }
+    // waiting for the last files here keeps the signals honoured until the end
+    results.wait(&migration_results, &timeout_rx, &migration_pool);
+    // only returned after the summary, once the failed guests are reported
+    results.completion = migration_pool.complete();
+    results.collect(&migration_results, &timeout_rx);
+    Ok(())
+}
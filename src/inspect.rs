@@ -0,0 +1,120 @@
+//! The inspect subcommand, printing the step, data sources, RRAs and last update of an RRD file,
+//! to look at what the migration produced without rrdtool
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Error};
+use tracing::error;
+
+use proxmox_rrd_migration_tool::migrate::{self, RrdInfo};
+
+use crate::args::HELP;
+use crate::logging::{self, Verbosity};
+use crate::{audit, EXIT_FAILURE, EXIT_SUCCESS, EXIT_USAGE};
+
+#[derive(Debug)]
+struct InspectArgs {
+    file: PathBuf,
+    json: bool,
+}
+
+fn parse_args() -> Result<InspectArgs, Error> {
+    let mut pargs = pico_args::Arguments::from_env();
+    // the subcommand itself
+    let _ = pargs.subcommand()?;
+
+    let json = pargs.contains("--json");
+    let file = pargs
+        .free_from_str()
+        .context("Could not parse the file name")?;
+
+    let remaining = pargs.finish();
+    if !remaining.is_empty() {
+        bail!(format!("Warning: unused arguments left: {:?}", remaining));
+    }
+    Ok(InspectArgs { file, json })
+}
+
+/// A limit of a data source like rrdtool create takes it, U for none
+pub(crate) fn limit(value: Option<f64>) -> String {
+    value.map_or_else(|| "U".to_string(), |value| value.to_string())
+}
+
+fn print_text(file: &Path, info: &RrdInfo) {
+    println!("File:         {}", file.display());
+    println!("Version:      {}", info.version);
+    println!("Step:         {}s", info.step);
+    println!(
+        "Last update:  {} ({})",
+        info.last_update,
+        audit::format_time(info.last_update)
+    );
+    println!("Header size:  {} bytes", info.header_size);
+    println!();
+    println!("Data sources:");
+    for ds in &info.data_sources {
+        println!(
+            "    {:<16}{:<10}heartbeat {:<6}min {:<6}max {}",
+            ds.name,
+            ds.kind,
+            ds.heartbeat,
+            limit(ds.min),
+            limit(ds.max)
+        );
+    }
+    println!();
+    println!("RRAs:");
+    for (index, rra) in info.rras.iter().enumerate() {
+        let span = info.step * rra.pdp_per_row * rra.rows;
+        println!(
+            "    {index:<4}{:<9}{:>6} steps/row {:>6} rows  xff {}  covers {}s",
+            rra.cf, rra.pdp_per_row, rra.rows, rra.xff, span
+        );
+    }
+}
+
+/// Run the inspect subcommand, returns the exit code
+pub(crate) fn run() -> i32 {
+    if std::env::args().any(|arg| arg == "-h" || arg == "--help") {
+        print!("{HELP}");
+        return EXIT_SUCCESS;
+    }
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("Error: {err}.");
+            return EXIT_USAGE;
+        }
+    };
+    let console = logging::init(
+        Verbosity::Normal,
+        None,
+        false,
+        logging::use_color(false, false),
+    );
+
+    let exit_code = match migrate::inspect_file(&args.file) {
+        Ok(info) if args.json => match serde_json::to_string_pretty(&info) {
+            Ok(json) => {
+                println!("{json}");
+                EXIT_SUCCESS
+            }
+            Err(err) => {
+                error!("Error: cannot serialize the metadata: {err}");
+                EXIT_FAILURE
+            }
+        },
+        Ok(info) => {
+            print_text(&args.file, &info);
+            EXIT_SUCCESS
+        }
+        Err(err) => {
+            error!("Error: cannot inspect {:?}: {err}", args.file);
+            EXIT_FAILURE
+        }
+    };
+    if let Some(ref console) = console {
+        console.flush();
+    }
+    exit_code
+}
@@ -0,0 +1,67 @@
+//! Minimal client for the native journald protocol, to log entries with structured fields
+
+use std::io;
+use std::os::unix::net::UnixDatagram;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_IDENTIFIER: &str = "proxmox-rrd-migration";
+
+/// Syslog priorities as used by the journal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    Error = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    socket: UnixDatagram,
+}
+
+impl Journal {
+    pub fn connect() -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNAL_SOCKET)?;
+        Ok(Self { socket })
+    }
+
+    /// Whether our output is connected to the journal already, as when started by systemd
+    pub fn is_output_connected() -> bool {
+        std::env::var_os("JOURNAL_STREAM").is_some()
+    }
+
+    /// Send an entry with the given message and additional fields
+    ///
+    /// Field names must be upper case and may only contain letters, digits and underscores.
+    pub fn send(
+        &self,
+        priority: Priority,
+        message: &str,
+        fields: &[(&str, &str)],
+    ) -> io::Result<()> {
+        let mut entry = Vec::new();
+        append_field(&mut entry, "MESSAGE", message);
+        append_field(&mut entry, "PRIORITY", &(priority as u8).to_string());
+        append_field(&mut entry, "SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER);
+        for (name, value) in fields {
+            append_field(&mut entry, name, value);
+        }
+        self.socket.send(&entry)?;
+        Ok(())
+    }
+}
+
+/// Values containing a newline need to be sent length-prefixed
+fn append_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
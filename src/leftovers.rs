@@ -0,0 +1,148 @@
+//! What a run leaves behind for the admin to clean up: the old source files, and the targets of
+//! guests, nodes and storages that are not configured anymore
+
+use std::ffi::{CStr, OsStr};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Error;
+
+use proxmox_rrd_migration_tool::migrate;
+
+use crate::audit::Outcome;
+use crate::fsck::Configured;
+use crate::remigrate::{self, FromOld};
+use crate::{MigrationDir, MigrationOptions, SourceHandling};
+
+#[derive(Debug)]
+pub(crate) struct Leftovers {
+    /// old source files, renamed or archived by this or an earlier run
+    old: Vec<PathBuf>,
+    /// files of resources that are gone, marked as old by this run
+    marked_old: usize,
+    /// targets of resources that are not configured
+    unmatched: Vec<PathBuf>,
+    /// the directories containing old files, for the cleanup command
+    old_dirs: Vec<PathBuf>,
+    /// print every path, not only the counts
+    list: bool,
+    sources: SourceHandling,
+    old_suffix: String,
+}
+
+impl Leftovers {
+    pub(crate) fn collect(
+        dirs: &[MigrationDir],
+        resources: &str,
+        list: bool,
+        options: &MigrationOptions,
+    ) -> Result<Self, Error> {
+        let configured = Configured::read(resources, &options.resources)?;
+        let mut leftovers = Leftovers {
+            old: Vec::new(),
+            marked_old: options.log.count(Outcome::MarkedOld),
+            unmatched: Vec::new(),
+            old_dirs: Vec::new(),
+            list,
+            sources: options.sources.clone(),
+            old_suffix: options.old_suffix.clone(),
+        };
+        for dir in dirs {
+            let old = remigrate::old_files(dir, &FromOld::All, options)?;
+            if !old.is_empty() {
+                leftovers.old_dirs.push(dir.source.clone());
+            }
+            leftovers.old.extend(old.iter().map(|file| path(&file.0)));
+
+            for target in migrate::collect_rrd_files(&dir.target)? {
+                let resource = target.1.to_string_lossy();
+                if !migrate::is_target_backup(&target.1)
+                    && !configured.contains(dir.kind, &resource)
+                {
+                    leftovers.unmatched.push(path(&target.0));
+                }
+            }
+        }
+        leftovers.old.sort();
+        leftovers.unmatched.sort();
+        Ok(leftovers)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.old.is_empty() && self.marked_old == 0 && self.unmatched.is_empty()
+    }
+
+    /// Print the summary block to stdout, or stderr with 'legacy_output', nothing if nothing is
+    /// left over
+    pub(crate) fn print(&self, legacy_output: bool) {
+        if self.is_empty() {
+            return;
+        }
+        if legacy_output {
+            eprint!("{self}");
+        } else {
+            print!("{self}");
+        }
+    }
+
+    /// The shell command removing the old files
+    fn cleanup_command(&self) -> String {
+        if let SourceHandling::Archive(ref archive) = self.sources {
+            return format!("rm -r -- '{}'", archive.display());
+        }
+        let dirs: Vec<String> = self
+            .old_dirs
+            .iter()
+            .map(|dir| format!("'{}'", dir.display()))
+            .collect();
+        let suffix = &self.old_suffix;
+        format!(
+            "find {} -maxdepth 1 -type f \\( -name '*{suffix}' -o -name '*{suffix}{}' \\) -delete",
+            dirs.join(" "),
+            migrate::COMPRESSED_SUFFIX
+        )
+    }
+}
+
+fn path(file: &CStr) -> PathBuf {
+    Path::new(OsStr::from_bytes(file.to_bytes())).to_owned()
+}
+
+impl fmt::Display for Leftovers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Left over for cleanup:")?;
+        writeln!(f, "  old source files: {}", self.old.len())?;
+        if self.list {
+            for old in &self.old {
+                writeln!(f, "    {}", old.display())?;
+            }
+        }
+        writeln!(
+            f,
+            "  files of removed resources marked as old: {}",
+            self.marked_old
+        )?;
+        writeln!(
+            f,
+            "  targets without a configured resource: {}",
+            self.unmatched.len()
+        )?;
+        if self.list {
+            for target in &self.unmatched {
+                writeln!(f, "    {}", target.display())?;
+            }
+        }
+        if !self.old.is_empty() {
+            writeln!(f, "Once the targets are in use, remove the old files with:")?;
+            writeln!(f, "  {}", self.cleanup_command())?;
+        }
+        if !self.unmatched.is_empty() {
+            writeln!(
+                f,
+                "Check the targets without a resource with --fsck before removing them."
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -3,3 +3,12 @@
 #![allow(non_snake_case)]
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+pub mod backend;
+pub mod definition;
+pub mod error;
+pub mod filesystem;
+pub mod migrate;
+pub mod pmxcfs;
+
+pub use error::MigrationError;
@@ -0,0 +1,349 @@
+//! Console output via tracing
+//!
+//! Only the messages are printed, like plain eprintln! would, all on stderr so that stdout is left
+//! for the final summary. The legacy output prints everything but warnings and errors on stdout,
+//! as earlier versions did. At debug verbosity each line is prefixed with its level and the spans
+//! (phase, file) it was logged in.
+//!
+//! Events can carry a 'status' field, one of the audit log outcomes, to color them on a terminal:
+//! green for migrated files, yellow for skipped ones and red for failures.
+//!
+//! The lines of all threads are written by a single output thread, in the order they were
+//! logged, so lines of parallel workers never get mixed up.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::io::{IsTerminal, Write};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Error};
+use crossbeam_channel::{unbounded, Sender};
+use serde::Deserialize;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::writer::MakeWriter;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::EnvFilter;
+
+/// How much to print, messages for single files are only printed with 'Verbose' or above
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verbosity {
+    /// only warnings and errors
+    Quiet,
+    /// summaries of the phases
+    Normal,
+    /// a line per file
+    Verbose,
+    /// everything, with level and spans
+    Debug,
+}
+
+impl FromStr for Verbosity {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "quiet" => Ok(Verbosity::Quiet),
+            "normal" => Ok(Verbosity::Normal),
+            "verbose" => Ok(Verbosity::Verbose),
+            "debug" => Ok(Verbosity::Debug),
+            _ => bail!("unknown verbosity '{value}', use quiet, normal, verbose or debug"),
+        }
+    }
+}
+
+impl Verbosity {
+    fn filter(self) -> &'static str {
+        match self {
+            Verbosity::Quiet => "warn",
+            Verbosity::Normal => "info",
+            Verbosity::Verbose => "debug",
+            Verbosity::Debug => "trace",
+        }
+    }
+}
+
+struct PlainFormat {
+    verbose: bool,
+    color: bool,
+}
+
+/// The fields of an event, with the status taken out
+#[derive(Default)]
+struct EventFields {
+    message: String,
+    status: Option<String>,
+    other: String,
+}
+
+impl Visit for EventFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "status" => self.status = Some(value.to_string()),
+            "message" => self.message = value.to_string(),
+            _ => self.record_debug(field, &value),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{value:?}"),
+            name => self.other.push_str(&format!(" {name}={value:?}")),
+        }
+    }
+}
+
+/// ANSI color code for the status of a file, or the level if none is given
+fn color_code(status: Option<&str>, level: &Level) -> Option<&'static str> {
+    match status {
+        Some("migrated" | "forced") => Some("32"),
+        Some("skipped" | "marked-old") => Some("33"),
+        Some("failed") => Some("31"),
+        _ => match *level {
+            Level::ERROR => Some("31"),
+            Level::WARN => Some("33"),
+            _ => None,
+        },
+    }
+}
+
+/// Whether to color the output, not if disabled via NO_COLOR or if it is not a terminal
+pub fn use_color(no_color: bool, legacy_output: bool) -> bool {
+    if no_color || std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+        return false;
+    }
+    io::stderr().is_terminal() && (!legacy_output || io::stdout().is_terminal())
+}
+
+impl<S, N> FormatEvent<S, N> for PlainFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        if self.verbose {
+            write!(writer, "{:>5} ", event.metadata().level())?;
+            if let Some(scope) = ctx.event_scope() {
+                for span in scope.from_root() {
+                    write!(writer, "{}", span.name())?;
+                    if let Some(fields) = span.extensions().get::<FormattedFields<N>>() {
+                        if !fields.is_empty() {
+                            write!(writer, "{{{fields}}}")?;
+                        }
+                    }
+                    write!(writer, ": ")?;
+                }
+            }
+        }
+        let mut fields = EventFields::default();
+        event.record(&mut fields);
+        let line = format!("{}{}", fields.message, fields.other);
+        match color_code(fields.status.as_deref(), event.metadata().level()) {
+            Some(code) if self.color => writeln!(writer, "\x1b[{code}m{line}\x1b[0m"),
+            _ => writeln!(writer, "{line}"),
+        }
+    }
+}
+
+/// Keeps the last lines of output in memory instead of printing them, while the terminal is
+/// used otherwise
+#[derive(Clone, Debug)]
+pub struct LogBuffer {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    capacity: usize,
+}
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lines: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// The last 'count' lines, oldest first
+    pub fn last(&self, count: usize) -> Vec<String> {
+        let lines = self.lines.lock().unwrap();
+        lines
+            .iter()
+            .skip(lines.len().saturating_sub(count))
+            .cloned()
+            .collect()
+    }
+
+    fn push(&self, line: &str) {
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line.to_string());
+    }
+}
+
+/// Writer for a single event
+pub struct LogBufferWriter(LogBuffer);
+
+impl io::Write for LogBufferWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for line in String::from_utf8_lossy(buf).lines() {
+            self.0.push(line);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogBuffer {
+    type Writer = LogBufferWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogBufferWriter(self.clone())
+    }
+}
+
+enum Line {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+    /// acknowledge once everything sent before was written
+    Flush(Sender<()>),
+}
+
+/// Hands the formatted lines to the output thread
+#[derive(Clone, Debug)]
+pub struct Console {
+    lines: Sender<Line>,
+    legacy_output: bool,
+}
+
+impl Console {
+    fn start(legacy_output: bool) -> Self {
+        let (lines, receiver) = unbounded();
+        std::thread::Builder::new()
+            .name("output".to_string())
+            .spawn(move || {
+                for line in receiver {
+                    // nothing sensible left to do if the console went away
+                    let _ = match line {
+                        Line::Stdout(line) => io::stdout().lock().write_all(&line),
+                        Line::Stderr(line) => io::stderr().lock().write_all(&line),
+                        Line::Flush(done) => {
+                            let _ = io::stdout().flush();
+                            let _ = done.send(());
+                            Ok(())
+                        }
+                    };
+                }
+            })
+            .expect("failed to spawn output thread");
+        Self {
+            lines,
+            legacy_output,
+        }
+    }
+
+    /// Wait until everything logged so far was written, needed before exiting the process
+    pub fn flush(&self) {
+        let (done, wait) = crossbeam_channel::bounded(1);
+        if self.lines.send(Line::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+/// Collects a single event and sends it as a whole once dropped
+pub struct ConsoleWriter {
+    lines: Sender<Line>,
+    stderr: bool,
+    buffer: Vec<u8>,
+}
+
+impl io::Write for ConsoleWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ConsoleWriter {
+    fn drop(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let buffer = std::mem::take(&mut self.buffer);
+        let _ = self.lines.send(if self.stderr {
+            Line::Stderr(buffer)
+        } else {
+            Line::Stdout(buffer)
+        });
+    }
+}
+
+impl<'a> MakeWriter<'a> for Console {
+    type Writer = ConsoleWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        ConsoleWriter {
+            lines: self.lines.clone(),
+            stderr: true,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        ConsoleWriter {
+            lines: self.lines.clone(),
+            stderr: !self.legacy_output || *meta.level() <= Level::WARN,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+/// Set up console output, honoring RUST_LOG if set, otherwise filtering by 'verbosity'
+///
+/// With 'buffer' set, all output goes there instead of stdout and stderr. Otherwise the returned
+/// console needs to be flushed before exiting.
+pub fn init(
+    verbosity: Verbosity,
+    buffer: Option<LogBuffer>,
+    legacy_output: bool,
+    color: bool,
+) -> Option<Console> {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(verbosity.filter()));
+
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .event_format(PlainFormat {
+            verbose: verbosity == Verbosity::Debug,
+            // the dashboard shows the lines as they are
+            color: color && buffer.is_none(),
+        });
+    match buffer {
+        Some(buffer) => {
+            subscriber.with_writer(buffer).init();
+            None
+        }
+        None => {
+            let console = Console::start(legacy_output);
+            subscriber.with_writer(console.clone()).init();
+            Some(console)
+        }
+    }
+}
@@ -1,19 +1,89 @@
 use std::{
-    ffi::{CStr, CString, OsString},
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
     fs,
-    io::ErrorKind,
-    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    io::IsTerminal,
+    os::unix::ffi::OsStrExt,
     path::{Path, PathBuf},
-    sync::Arc,
+    str::FromStr,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
 };
 
-use anyhow::{bail, Context, Error, Result};
-
-use proxmox_rrd_migration_tool::{rrd_clear_error, rrd_create_r2, rrd_get_context, rrd_get_error};
-
-use crate::parallel_handler::ParallelHandler;
-
+use anyhow::{bail, Error};
+use serde::Deserialize;
+
+use proxmox_rrd_migration_tool::backend::{Librrd, RrdBackend};
+use proxmox_rrd_migration_tool::filesystem::{Filesystem, StdFilesystem};
+use proxmox_rrd_migration_tool::migrate::{self, RRDFile, ResourceType};
+use proxmox_rrd_migration_tool::{pmxcfs, MigrationError};
+
+use tracing::{debug, error, info, warn};
+
+use crate::args::{Args, ProgressInterval, Threads};
+use crate::audit::{AuditLog, RunAudit};
+use crate::completion::{Completion, Summary};
+use crate::control::Control;
+use crate::journal::Journal;
+use crate::leftovers::Leftovers;
+use crate::logging::Verbosity;
+use crate::notify::Notifier;
+use crate::pattern::PathPattern;
+use crate::plan::OutputFormat;
+use crate::progress::Progress;
+use crate::report::ErrorReport;
+use crate::run_report::RunReport;
+use crate::service::Service;
+use crate::status::StatusFile;
+use crate::symlinks::SymlinkPolicy;
+
+pub mod args;
+pub mod audit;
+pub mod backup;
+pub mod benchmark;
+pub mod canary;
+pub mod cluster;
+pub mod completion;
+pub mod config;
+pub mod control;
+pub mod create;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub mod diff;
+pub mod file;
+pub mod fsck;
+pub mod guest_pool;
+pub mod inspect;
+pub mod journal;
+pub mod leftovers;
+pub mod logging;
+pub mod marker;
+pub mod metrics;
+pub mod notify;
 pub mod parallel_handler;
+pub mod pattern;
+pub mod phase;
+pub mod plan;
+pub mod preflight;
+pub mod progress;
+#[cfg(feature = "rayon")]
+pub mod rayon_pool;
+pub mod reconcile;
+pub mod remigrate;
+#[cfg(not(feature = "static-rrd"))]
+pub mod render;
+pub mod report;
+pub mod restore;
+pub mod run_report;
+pub mod sample;
+pub mod schema;
+pub mod service;
+pub mod status;
+pub mod symlinks;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod twins;
+pub mod verify;
 
 const BASE_DIR: &str = "/var/lib/rrdcached/db";
 const SOURCE_SUBDIR_NODE: &str = "pve2-node";
@@ -23,203 +93,291 @@ const TARGET_SUBDIR_NODE: &str = "pve-node-9.0";
 const TARGET_SUBDIR_GUEST: &str = "pve-vm-9.0";
 const TARGET_SUBDIR_STORAGE: &str = "pve-storage-9.0";
 const RESOURCE_BASE_DIR: &str = "/etc/pve";
+const STORAGE_CONFIG: &str = "storage.cfg";
+const QUARANTINE_SUBDIR: &str = "corrupt";
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
 const MAX_AUTO_THREADS: usize = 6;
-const RRD_STEP_SIZE: usize = 60;
-
-type RRDFile = (CString, OsString);
-
-// RRAs are defined in the following way:
-//
-// RRA:CF:xff:step:rows
-// CF: AVERAGE or MAX
-// xff: 0.5
-// steps: stepsize is defined on rrd file creation! example: with a 60 secondu step size, one step
-//    means 60 sec, 30 steps means 1800 seconds or 30 min
-// rows: how many aggregated rows are kept, as in how far back in time we store data
-//
-// how many seconds are aggregated per RRA: steps * stepsize * rows
-// how many hours are aggregated per RRA: steps * stepsize * rows / 3600
-// how many days are aggregated per RRA: steps * stepsize * rows / 3600 / 24
-// https://oss.oetiker.ch/rrdtool/tut/rrd-beginners.en.html#Understanding_by_an_example
-
-const RRD_VM_DEF: [&CStr; 25] = [
-    c"DS:maxcpu:GAUGE:120:0:U",
-    c"DS:cpu:GAUGE:120:0:U",
-    c"DS:maxmem:GAUGE:120:0:U",
-    c"DS:mem:GAUGE:120:0:U",
-    c"DS:maxdisk:GAUGE:120:0:U",
-    c"DS:disk:GAUGE:120:0:U",
-    c"DS:netin:DERIVE:120:0:U",
-    c"DS:netout:DERIVE:120:0:U",
-    c"DS:diskread:DERIVE:120:0:U",
-    c"DS:diskwrite:DERIVE:120:0:U",
-    c"DS:memhost:GAUGE:120:0:U",
-    c"DS:pressurecpusome:GAUGE:120:0:U",
-    c"DS:pressurecpufull:GAUGE:120:0:U",
-    c"DS:pressureiosome:GAUGE:120:0:U",
-    c"DS:pressureiofull:GAUGE:120:0:U",
-    c"DS:pressurememorysome:GAUGE:120:0:U",
-    c"DS:pressurememoryfull:GAUGE:120:0:U",
-    c"RRA:AVERAGE:0.5:1:1440",    // 1 min * 1440 => 1 day
-    c"RRA:AVERAGE:0.5:30:1440",   // 30 min * 1440 => 30 day
-    c"RRA:AVERAGE:0.5:360:1440",  // 6 hours * 1440 => 360 day ~1 year
-    c"RRA:AVERAGE:0.5:10080:570", // 1 week * 570 => ~10 years
-    c"RRA:MAX:0.5:1:1440",        // 1 min * 1440 => 1 day
-    c"RRA:MAX:0.5:30:1440",       // 30 min * 1440 => 30 day
-    c"RRA:MAX:0.5:360:1440",      // 6 hours * 1440 => 360 day ~1 year
-    c"RRA:MAX:0.5:10080:570",     // 1 week * 570 => ~10 years
-];
-
-const RRD_NODE_DEF: [&CStr; 27] = [
-    c"DS:loadavg:GAUGE:120:0:U",
-    c"DS:maxcpu:GAUGE:120:0:U",
-    c"DS:cpu:GAUGE:120:0:U",
-    c"DS:iowait:GAUGE:120:0:U",
-    c"DS:memtotal:GAUGE:120:0:U",
-    c"DS:memused:GAUGE:120:0:U",
-    c"DS:swaptotal:GAUGE:120:0:U",
-    c"DS:swapused:GAUGE:120:0:U",
-    c"DS:roottotal:GAUGE:120:0:U",
-    c"DS:rootused:GAUGE:120:0:U",
-    c"DS:netin:DERIVE:120:0:U",
-    c"DS:netout:DERIVE:120:0:U",
-    c"DS:memavailable:GAUGE:120:0:U",
-    c"DS:arcsize:GAUGE:120:0:U",
-    c"DS:pressurecpusome:GAUGE:120:0:U",
-    c"DS:pressureiosome:GAUGE:120:0:U",
-    c"DS:pressureiofull:GAUGE:120:0:U",
-    c"DS:pressurememorysome:GAUGE:120:0:U",
-    c"DS:pressurememoryfull:GAUGE:120:0:U",
-    c"RRA:AVERAGE:0.5:1:1440",    // 1 min * 1440 => 1 day
-    c"RRA:AVERAGE:0.5:30:1440",   // 30 min * 1440 => 30 day
-    c"RRA:AVERAGE:0.5:360:1440",  // 6 hours * 1440 => 360 day ~1 year
-    c"RRA:AVERAGE:0.5:10080:570", // 1 week * 570 => ~10 years
-    c"RRA:MAX:0.5:1:1440",        // 1 min * 1440 => 1 day
-    c"RRA:MAX:0.5:30:1440",       // 30 min * 1440 => 30 day
-    c"RRA:MAX:0.5:360:1440",      // 6 hours * 1440 => 360 day ~1 year
-    c"RRA:MAX:0.5:10080:570",     // 1 week * 570 => ~10 years
-];
-
-const RRD_STORAGE_DEF: [&CStr; 10] = [
-    c"DS:total:GAUGE:120:0:U",
-    c"DS:used:GAUGE:120:0:U",
-    c"RRA:AVERAGE:0.5:1:1440",    // 1 min * 1440 => 1 day
-    c"RRA:AVERAGE:0.5:30:1440",   // 30 min * 1440 => 30 day
-    c"RRA:AVERAGE:0.5:360:1440",  // 6 hours * 1440 => 360 day ~1 year
-    c"RRA:AVERAGE:0.5:10080:570", // 1 week * 570 => ~10 years
-    c"RRA:MAX:0.5:1:1440",        // 1 min * 1440 => 1 day
-    c"RRA:MAX:0.5:30:1440",       // 30 min * 1440 => 30 day
-    c"RRA:MAX:0.5:360:1440",      // 6 hours * 1440 => 360 day ~1 year
-    c"RRA:MAX:0.5:10080:570",     // 1 week * 570 => ~10 years
-];
-
-const HELP: &str = "\
-proxmox-rrd-migration tool
-
-Migrates existing RRD metrics data to the new format.
-
-Use this only in the process of upgrading from Proxmox VE 8 to 9 according to the upgrade guide!
-
-USAGE:
-    proxmox-rrd-migration [OPTIONS]
-
-    FLAGS:
-        -h, --help              Prints this help information
-
-    OPTIONS:
-        --migrate               Start the migration. Without it, only a dry run will be done.
-
-        --force                 Migrate, even if the target already exists.
-                                This will overwrite any migrated RRD files!
-
-        --threads THREADS       Number of paralell threads.
-
-        --source <SOURCE DIR>   Source base directory. Mainly for tests!
-                                Default: /var/lib/rrdcached/db
-
-        --target <TARGET DIR>   Target base directory. Mainly for tests!
-                                Default: /var/lib/rrdcached/db
-
-        --resources <DIR>       Directory that contains .vmlist and .member files. Mainly for tests!
-                                Default: /etc/pve
-
-";
-
-#[derive(Debug)]
-struct Args {
+const DEFAULT_STALL_TIMEOUT: u64 = 300;
+const RETRY_BACKOFF: Duration = Duration::from_secs(2);
+const DEFAULT_PROGRESS_INTERVAL: ProgressInterval = ProgressInterval::Files(10);
+/// Output lines kept in memory for the dashboard
+const TUI_LOG_LINES: usize = 1000;
+const DEFAULT_BENCHMARK_FILES: usize = 20;
+/// Most threads of each kind with --low-memory
+const LOW_MEMORY_MAX_THREADS: usize = 2;
+/// Most guest files sent to the threads and not done yet with --low-memory
+const LOW_MEMORY_QUEUE_DEPTH: usize = 8;
+
+/// All files were migrated, or there were none
+const EXIT_SUCCESS: i32 = 0;
+/// A migration phase failed or was aborted
+const EXIT_FAILURE: i32 = 1;
+/// The command line could not be parsed
+const EXIT_USAGE: i32 = 2;
+/// Some files could not be migrated, the others were
+const EXIT_PARTIAL: i32 = 3;
+/// Checks before the migration failed, nothing was touched
+const EXIT_PREFLIGHT: i32 = 4;
+/// All files were migrated by earlier runs, nothing was left to do
+const EXIT_NOTHING_TO_DO: i32 = 5;
+/// --fsck found anomalies
+const EXIT_ANOMALIES: i32 = 11;
+/// --verify found targets that do not match their source
+const EXIT_VERIFY_FAILED: i32 = 12;
+/// --estimate found that the target does not have enough space left
+const EXIT_NO_SPACE: i32 = 13;
+
+/// Settings shared by all migration phases
+#[derive(Clone, Debug)]
+struct MigrationOptions {
+    /// Actually migrate, otherwise only do a dry run
     migrate: bool,
+    /// Overwrite already existing target files
     force: bool,
-    threads: Option<usize>,
-    source: Option<String>,
-    target: Option<String>,
-    resources: Option<String>,
+    /// Only overwrite the target files the source was modified after
+    incremental: bool,
+    /// Number of threads for the guest migration
+    threads: usize,
+    /// Upper limit when scaling the guest migration threads automatically
+    max_threads: Option<usize>,
+    /// Threads reading the guest files ahead of the conversion, if set
+    io_threads: Option<usize>,
+    /// Limit the files queued at once and list the storage files node by node
+    low_memory: bool,
+    /// Number of guest files sent to the threads at once
+    batch_size: usize,
+    /// Warn about guest files that take longer than this to migrate
+    stall_timeout: Duration,
+    /// Give up on files that take longer than this to migrate
+    file_timeout: Option<Duration>,
+    /// How often to retry migrating a file that failed
+    retries: u32,
+    /// Failed files over all phases, to abort once too many failed
+    errors: ErrorCounter,
+    /// Files that were not migrated over all phases, printed at the end
+    report: ErrorReport,
+    /// Only migrate these source files, if set
+    files_from: Option<Arc<HashSet<PathBuf>>>,
+    /// Only migrate the source files whose relative path matches, if set
+    path_match: Option<PathPattern>,
+    /// Records the decision taken for every file
+    log: AuditLog,
+    /// Machine-readable progress of the phases
+    progress: Progress,
+    /// Status and watchdog of the systemd service, if running as one
+    notifier: Notifier,
+    /// Pausing and aborting the run from outside
+    control: Control,
+    /// How often to print how many files of a phase were migrated
+    progress_every: ProgressInterval,
+    /// Base directory of the source files
+    source_base: PathBuf,
+    /// What to do with the source files once migrated
+    sources: SourceHandling,
+    /// Appended to old source files, unless moved to an archive directory
+    old_suffix: String,
+    /// Compress migrated source files once the target was verified
+    compress_old: bool,
+    /// Verify each target right after creating it, before dealing with its source
+    verify_after_migrate: bool,
+    /// Migrate the files of guests missing from .vmlist instead of marking them as old
+    migrate_orphans: bool,
+    /// What to do with the files of guests hosted on another node
+    remote_guests: RemoteGuests,
+    /// Mark the files of storages missing from storage.cfg as old instead of migrating them
+    prune_removed_storages: bool,
+    /// Where corrupt source files are moved to
+    quarantine: PathBuf,
+    /// Try to repair corrupt source files instead of only moving them to the quarantine
+    try_repair: bool,
+    /// Mark source files not updated for longer than this as old instead of migrating them
+    skip_stale: Option<Duration>,
+    /// What to do with source files that are symbolic links
+    symlinks: SymlinkPolicy,
+    /// Copy source files with other hard links before marking them as old
+    break_hardlinks: bool,
+    /// The configured guests and nodes
+    resources: Arc<ResourceLists>,
+    /// Migrate even if the cluster is not quorate
+    ignore_quorum: bool,
+    /// The node whose storage files are migrated, [`None`] for all of them
+    storage_node: Option<StorageNode>,
+    /// Only migrate the files of these storages, if set
+    storages: Option<HashSet<String>>,
+    /// Reads and writes the files of a single migration
+    backend: Arc<dyn RrdBackend>,
+    /// Lists the source directories, marks files as old and creates the target directories
+    fs: Arc<dyn Filesystem>,
 }
 
-fn parse_args() -> Result<Args, Error> {
-    let mut pargs = pico_args::Arguments::from_env();
-
-    // Help has a higher priority and should be handled separately.
-    if pargs.contains(["-h", "--help"]) {
-        print!("{HELP}");
-        std::process::exit(0);
-    }
-
-    let mut args = Args {
-        migrate: false,
-        threads: pargs
-            .opt_value_from_str("--threads")
-            .expect("Could not parse --threads parameter"),
-        force: false,
-        source: pargs
-            .opt_value_from_str("--source")
-            .expect("Could not parse --source parameter"),
-        target: pargs
-            .opt_value_from_str("--target")
-            .expect("Could not parse --target parameter"),
-        resources: pargs
-            .opt_value_from_str("--resources")
-            .expect("Could not parse --resources parameter"),
-    };
+/// What to do with the files of guests that .vmlist lists on another node
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RemoteGuests {
+    /// migrate them like those of the local guests
+    #[default]
+    All,
+    /// leave them alone
+    Local,
+    /// mark them as old
+    Archive,
+}
+
+impl FromStr for RemoteGuests {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "all" => Ok(RemoteGuests::All),
+            "local" => Ok(RemoteGuests::Local),
+            "archive" => Ok(RemoteGuests::Archive),
+            _ => bail!("unknown policy '{value}', use all, local or archive"),
+        }
+    }
+}
+
+/// The node whose storage files are migrated
+#[derive(Clone, Debug)]
+enum StorageNode {
+    Name(String),
+    /// this node, as named in .members
+    Local,
+}
 
-    if pargs.contains("--migrate") {
-        args.migrate = true;
+/// What to do with a source file once it was migrated, and with those of resources that are gone
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum SourceHandling {
+    /// rename it, appending the old suffix
+    MarkOld,
+    /// move it below the directory, see [`migrate::mv_archive`]
+    Archive(PathBuf),
+    /// leave it alone
+    Keep,
+    /// delete it once the target was verified, the files of resources that are gone are still
+    /// marked as old
+    Delete,
+}
+
+impl MigrationOptions {
+    /// Whether the file was selected for migration, old source files never are
+    fn is_selected(&self, file: &RRDFile) -> bool {
+        let compressed = format!("{}{}", self.old_suffix, migrate::COMPRESSED_SUFFIX);
+        if file.1.as_bytes().ends_with(self.old_suffix.as_bytes())
+            || file.1.as_bytes().ends_with(compressed.as_bytes())
+        {
+            return false;
+        }
+        let path = Path::new(OsStr::from_bytes(file.0.as_bytes()));
+        if let Some(pattern) = &self.path_match {
+            let relative = path.strip_prefix(&self.source_base).unwrap_or(path);
+            if !pattern.is_match(&relative.to_string_lossy()) {
+                return false;
+            }
+        }
+        match &self.files_from {
+            Some(files) => files.contains(path),
+            None => true,
+        }
     }
-    if pargs.contains("--force") {
-        args.force = true;
+
+    /// Whether the storage files of 'node' are migrated
+    fn is_storage_node_selected(&self, node: &OsStr) -> bool {
+        match &self.storage_node {
+            None => true,
+            Some(StorageNode::Name(name)) => node == name.as_str(),
+            Some(StorageNode::Local) => node == self.resources.local_node().as_str(),
+        }
     }
 
-    // It's up to the caller what to do with the remaining arguments.
-    let remaining = pargs.finish();
-    if !remaining.is_empty() {
-        bail!(format!("Warning: unused arguments left: {:?}", remaining));
+    /// Whether the files of the storage 'id' are migrated
+    fn is_storage_selected(&self, id: &OsStr) -> bool {
+        self.storages
+            .as_ref()
+            .is_none_or(|storages| storages.contains(&*id.to_string_lossy()))
     }
+}
 
-    Ok(args)
+/// Counts the files that failed to migrate, shared over all phases and threads
+#[derive(Clone, Debug, Default)]
+struct ErrorCounter {
+    /// abort once this many files failed
+    max_errors: Option<usize>,
+    /// the files that failed so far, a file failing again on a retry counts once
+    failed: Arc<Mutex<HashSet<Vec<u8>>>>,
+}
+
+impl ErrorCounter {
+    /// Record the failed file 'file', returns an error if the migration should be aborted
+    fn record(&self, file: &[u8]) -> Result<(), Error> {
+        let errors = {
+            let mut failed = self.failed.lock().unwrap();
+            failed.insert(file.to_vec());
+            failed.len()
+        };
+        match self.max_errors {
+            Some(max_errors) if errors >= max_errors => {
+                bail!("aborting migration after {errors} failed file(s)")
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 fn main() {
-    let args = match parse_args() {
+    let started = Instant::now();
+    let start_time = audit::timestamp();
+    match std::env::args().nth(1).as_deref() {
+        Some("restore") => std::process::exit(restore::run()),
+        Some("create") => std::process::exit(create::run()),
+        Some("inspect") => std::process::exit(inspect::run()),
+        Some("diff") => std::process::exit(diff::run()),
+        _ => {}
+    }
+    let args = match args::parse_args() {
         Ok(v) => v,
         Err(err) => {
             eprintln!("Error: {err}.");
-            std::process::exit(1);
+            std::process::exit(EXIT_USAGE);
         }
     };
+    if args.dump_schema {
+        schema::dump();
+        std::process::exit(EXIT_SUCCESS);
+    }
+    if let Err(err) = args.check() {
+        eprintln!("Error: {err}.");
+        std::process::exit(EXIT_USAGE);
+    }
+    // the dashboard shows the latest output itself
+    let log_buffer = args.tui.then(|| logging::LogBuffer::new(TUI_LOG_LINES));
+    let console = logging::init(
+        args.verbosity.unwrap_or(Verbosity::Normal),
+        log_buffer.clone(),
+        args.legacy_output,
+        logging::use_color(args.no_color, args.legacy_output),
+    );
 
-    let source_base_dir = match args.source {
-        Some(ref v) => v.as_str(),
-        None => BASE_DIR,
-    };
-
-    let target_base_dir = match args.target {
-        Some(ref v) => v.as_str(),
-        None => BASE_DIR,
-    };
-
-    let resource_base_dir = match args.resources {
-        Some(ref v) => v.as_str(),
-        None => RESOURCE_BASE_DIR,
+    let base_dir = |option, value: &Option<String>, default| match preflight::resolve_base_dir(
+        option,
+        value.as_deref(),
+        default,
+    ) {
+        Ok(dir) => dir,
+        Err(err) => {
+            error!("Error: {err}");
+            std::process::exit(EXIT_USAGE);
+        }
     };
+    #[cfg(not(feature = "rayon"))]
+    if let Err(err) = guest_pool::register_thread_signals() {
+        warn!("could not register the handlers of SIGUSR1 and SIGUSR2 - {err}");
+    }
+    let source_base_dir = base_dir("--source", &args.source, BASE_DIR);
+    let source_base_dir = source_base_dir.as_str();
+    let target_base_dir = base_dir("--target", &args.target, BASE_DIR);
+    let target_base_dir = target_base_dir.as_str();
+    let resource_base_dir = base_dir("--resources", &args.resources, RESOURCE_BASE_DIR);
+    let resource_base_dir = resource_base_dir.as_str();
 
     let source_dir_guests: PathBuf = [source_base_dir, SOURCE_SUBDIR_GUEST].iter().collect();
     let target_dir_guests: PathBuf = [target_base_dir, TARGET_SUBDIR_GUEST].iter().collect();
@@ -228,43 +386,585 @@ fn main() {
     let source_dir_storage: PathBuf = [source_base_dir, SOURCE_SUBDIR_STORAGE].iter().collect();
     let target_dir_storage: PathBuf = [target_base_dir, TARGET_SUBDIR_STORAGE].iter().collect();
 
-    if !args.migrate {
-        println!("DRYRUN! Use the --migrate parameter to start the migration.");
+    if let Err(overlap) = preflight::check_overlap(
+        &[
+            (ResourceType::Guest, &source_dir_guests),
+            (ResourceType::Node, &source_dir_nodes),
+            (ResourceType::Storage, &source_dir_storage),
+        ],
+        &[
+            (ResourceType::Guest, &target_dir_guests),
+            (ResourceType::Node, &target_dir_nodes),
+            (ResourceType::Storage, &target_dir_storage),
+        ],
+    ) {
+        error!("Error: {overlap}, check --source and --target.");
+        std::process::exit(EXIT_USAGE);
+    }
+
+    if !args.migrate && !args.plan && !args.needs_migration && !args.fsck && !args.verify {
+        info!("DRYRUN! Use the --migrate parameter to start the migration.");
     }
     if args.force {
-        println!("Force mode! Will overwrite existing target RRD files!");
+        info!("Force mode! Will overwrite existing target RRD files!");
     }
 
-    if let Err(err) = migrate_nodes(
-        source_dir_nodes,
-        target_dir_nodes,
-        resource_base_dir,
-        args.migrate,
-        args.force,
-    ) {
-        eprintln!("Error migrating nodes: {err}");
-        std::process::exit(1);
-    }
-    if let Err(err) = migrate_storage(
-        source_dir_storage,
-        target_dir_storage,
-        args.migrate,
-        args.force,
-    ) {
-        eprintln!("Error migrating storage: {err}");
-        std::process::exit(1);
-    }
-    if let Err(err) = migrate_guests(
-        source_dir_guests,
-        target_dir_guests,
-        resource_base_dir,
-        set_threads(&args),
-        args.migrate,
-        args.force,
-    ) {
-        eprintln!("Error migrating guests: {err}");
-        std::process::exit(1);
+    let mut options = migration_options(&args, source_base_dir, resource_base_dir);
+    options.log.set_progress(options.progress.clone());
+    let run_id = audit::new_run_id();
+    options.log.set_run_id(&run_id);
+    let audit_dir = args
+        .audit_dir
+        .as_deref()
+        .unwrap_or(Path::new(audit::AUDIT_DIR));
+    let mut audit = RunAudit::start(audit_dir, &run_id);
+    let service = args
+        .service
+        .then(|| match Service::start(&run_id) {
+            Ok(service) => Some(service),
+            Err(err) => {
+                warn!("could not keep the state of the run - {err:#}");
+                None
+            }
+        })
+        .flatten();
+    #[cfg(feature = "tui")]
+    let dashboard = log_buffer.map(|buffer| {
+        tui::Dashboard::start(
+            &run_id,
+            options.progress.clone(),
+            buffer,
+            options.control.clone(),
+        )
+    });
+    debug!("run ID {run_id}");
+
+    // only set once all the phases ran
+    let mut leftovers = None;
+    let mut status_file = None;
+    let exit_code = 'run: {
+        let mut dirs = migration_dirs(
+            (&source_dir_nodes, &target_dir_nodes),
+            (&source_dir_guests, &target_dir_guests),
+            (&source_dir_storage, &target_dir_storage),
+        );
+        dirs.retain(|dir| {
+            dir.kind != ResourceType::Storage
+                || dir
+                    .source
+                    .file_name()
+                    .is_some_and(|node| options.is_storage_node_selected(node))
+        });
+
+        if args.needs_migration {
+            if let Some(ref console) = console {
+                console.flush();
+            }
+            break 'run plan::needs_migration(&dirs, resource_base_dir, &options);
+        }
+        if args.fsck {
+            if let Some(ref console) = console {
+                console.flush();
+            }
+            let format = args.fsck_format.unwrap_or(OutputFormat::Text);
+            break 'run fsck::run(&dirs, resource_base_dir, format, &options);
+        }
+        if args.cluster {
+            if let Some(ref console) = console {
+                console.flush();
+            }
+            let args = std::env::args_os()
+                .skip(1)
+                .filter(|arg| arg != "--cluster")
+                .collect();
+            break 'run cluster::run(args, &options);
+        }
+        if let Some(fd) = args.progress_fd {
+            if let Err(err) = options.progress.set_fd(fd) {
+                error!("Error: cannot use file descriptor {fd} for progress events: {err}");
+                break 'run EXIT_USAGE;
+            }
+        }
+        if let Some(addr) = args.metrics_listen {
+            let source = metrics::Source {
+                progress: options.progress.clone(),
+                log: options.log.clone(),
+                threads: options.threads,
+            };
+            if let Err(err) = metrics::start(addr, source) {
+                error!("Error: cannot serve metrics on {addr}: {err}");
+                break 'run EXIT_PREFLIGHT;
+            }
+        }
+        let status_path = match args.status_file {
+            Some(ref path) => Some(path.clone()),
+            None if args.service => Some(PathBuf::from(status::STATUS_FILE)),
+            None => None,
+        };
+        if let Some(path) = status_path {
+            match StatusFile::start(&path, &run_id, options.progress.clone()) {
+                Ok(started) => status_file = Some(started),
+                Err(err) => warn!("could not write the status file - {err:#}"),
+            }
+        }
+        #[cfg(feature = "dbus")]
+        let _dbus = if args.dbus {
+            let control = options.control.clone();
+            match dbus::start(&run_id, options.progress.clone(), control) {
+                Ok(connection) => Some(connection),
+                Err(err) => {
+                    error!("Error: {err:#}");
+                    break 'run EXIT_PREFLIGHT;
+                }
+            }
+        } else {
+            None
+        };
+        match Notifier::from_env() {
+            Ok(notifier) => {
+                options.progress.set_notifier(notifier.clone());
+                options.notifier = notifier;
+            }
+            Err(err) => warn!("failed to connect to the systemd notify socket - {err}"),
+        }
+        if let Some(ref log_file) = args.log_file {
+            if let Err(err) = options.log.open_file(log_file) {
+                error!("Error: cannot open log file {log_file:?}: {err}");
+                break 'run EXIT_PREFLIGHT;
+            }
+        }
+        let use_journal = match args.log_target.as_deref() {
+            None | Some("auto") => Journal::is_output_connected(),
+            Some("journald") => true,
+            Some("console") => false,
+            Some(other) => {
+                error!("Error: unknown log target '{other}', use auto, console or journald.");
+                break 'run EXIT_USAGE;
+            }
+        };
+        if use_journal {
+            if let Err(err) = options.log.connect_journal() {
+                warn!("failed to connect to the journal, not logging to it - {err}");
+            }
+        }
+        if let Some(ref files_from) = args.files_from {
+            match report::read_file_list(files_from) {
+                Ok(files) => {
+                    info!(
+                        "Only migrating the {} file(s) listed in {files_from:?}",
+                        files.len()
+                    );
+                    options.files_from = Some(Arc::new(files.into_iter().collect()));
+                }
+                Err(err) => {
+                    error!("Error: cannot read file list {files_from:?}: {err}");
+                    break 'run EXIT_PREFLIGHT;
+                }
+            }
+        }
+
+        if let Err(err) = preflight::check(resource_base_dir, Path::new(target_base_dir), &options)
+        {
+            error!("Error: {err:#}");
+            break 'run EXIT_PREFLIGHT;
+        }
+
+        if args.plan {
+            // the plan must not end up in the middle of the messages
+            if let Some(ref console) = console {
+                console.flush();
+            }
+            let format = args.plan_format.unwrap_or(OutputFormat::Text);
+            break 'run plan::run(&dirs, resource_base_dir, format, &options);
+        }
+        if args.estimate {
+            break 'run sample::estimate(Path::new(target_base_dir), &dirs, &run_id, &options);
+        }
+        if args.benchmark {
+            break 'run benchmark::run(
+                args.benchmark_files.unwrap_or(DEFAULT_BENCHMARK_FILES),
+                &source_dir_guests,
+                &run_id,
+                &options,
+            );
+        }
+        if let Some(count) = args.sample {
+            break 'run sample::run(count, args.sample_dir.as_deref(), &dirs, &run_id, &options);
+        }
+        if let Some(ref selection) = args.from_old {
+            break 'run remigrate::run(&dirs, selection, &options);
+        }
+        if args.reconcile {
+            break 'run reconcile::run(&dirs, &options);
+        }
+        if args.verify {
+            let threads = cap_threads(args.verify_threads.unwrap_or(options.threads), &args);
+            break 'run verify::run(&dirs, threads, &options);
+        }
+
+        if options.force && options.migrate && !args.yes && std::io::stdin().is_terminal() {
+            let existing = count_existing_targets(&dirs, &options);
+            if existing > 0 {
+                if let Some(ref console) = console {
+                    console.flush();
+                }
+                if !confirm(&format!(
+                    "{existing} already migrated target file(s) will be overwritten, continue?"
+                )) {
+                    error!("Aborted, nothing was overwritten.");
+                    break 'run EXIT_FAILURE;
+                }
+            }
+        }
+
+        let marked_dirs = [
+            source_dir_nodes.clone(),
+            source_dir_guests.clone(),
+            source_dir_storage.clone(),
+        ];
+        let marked_dirs: Vec<&Path> = marked_dirs.iter().map(PathBuf::as_path).collect();
+        if let Err(err) = preflight::check_markers(&marked_dirs, &options) {
+            error!("Error: {err}");
+            break 'run EXIT_PREFLIGHT;
+        }
+
+        if let Some(ref backup) = args.backup {
+            info!("Backing up the source directories to {backup:?}…");
+            if let Err(err) = backup::create(backup, Path::new(source_base_dir)) {
+                error!("Error: backup failed, nothing was changed: {err:#}");
+                break 'run EXIT_PREFLIGHT;
+            }
+            audit.backup(backup);
+        }
+
+        if args.threads == Some(Threads::AutoTune) && options.migrate {
+            match benchmark::calibrate(&source_dir_guests, &run_id, &options) {
+                Ok(threads) => {
+                    options.threads = cap_threads(threads, &args);
+                    info!("Using {} thread(s), as calibrated", options.threads);
+                }
+                Err(err) => warn!(
+                    "could not calibrate the number of threads, using {} - {err}",
+                    options.threads
+                ),
+            }
+        }
+
+        if args.canary {
+            let files = match canary::files(&dirs, &options) {
+                Ok(files) => files,
+                Err(err) => {
+                    error!("Error selecting canary files: {err}");
+                    break 'run EXIT_FAILURE;
+                }
+            };
+            if files.is_empty() {
+                info!("No canary files left to migrate.");
+            } else {
+                if !canary::run(files, &options) {
+                    error!("Canary migration failed, not migrating the other files.");
+                    break 'run EXIT_FAILURE;
+                }
+                if let Some(ref console) = console {
+                    console.flush();
+                }
+                if !args.yes
+                    && !confirm("Canary files migrated and verified, go on with the others?")
+                {
+                    info!("Stopped after the canary files, run again to migrate the others.");
+                    break 'run EXIT_SUCCESS;
+                }
+            }
+        }
+
+        // nothing would be overwritten without --force, no need to go through all the files,
+        // a dry run still reports them
+        if options.migrate && !options.force {
+            match plan::fully_migrated(&dirs, resource_base_dir, &options) {
+                Ok(Some(migrated)) if migrated > 0 => {
+                    info!("Nothing to do, {migrated} RRD file(s) already migrated.");
+                    options.progress.already_migrated(migrated);
+                    break 'run EXIT_NOTHING_TO_DO;
+                }
+                Ok(_) => {}
+                Err(err) => debug!("could not check whether everything is migrated - {err}"),
+            }
+        }
+
+        let failed = match phase::migrate_all(
+            (source_dir_nodes, target_dir_nodes.clone()),
+            (source_dir_storage, target_dir_storage),
+            (source_dir_guests, target_dir_guests.clone()),
+            resource_base_dir,
+            &options,
+        ) {
+            Ok(failed) => failed,
+            Err(err) => {
+                error!("Error {err:#}");
+                break 'run EXIT_FAILURE;
+            }
+        };
+        if options.migrate {
+            for dir in &marked_dirs {
+                if let Err(err) = marker::write(dir, &run_id) {
+                    warn!("could not record that this host migrated the sources - {err:#}");
+                }
+            }
+        }
+
+        #[cfg(not(feature = "static-rrd"))]
+        if let Some(count) = args.render_samples {
+            let dir = args
+                .render_dir
+                .clone()
+                .unwrap_or_else(|| audit_dir.join(format!("graphs-{run_id}")));
+            render::run(count, &dir, &target_dir_guests, &target_dir_nodes);
+        }
+
+        match Leftovers::collect(&dirs, resource_base_dir, args.list_leftovers, &options) {
+            Ok(found) => leftovers = Some(found),
+            Err(err) => warn!("could not collect the files left over: {err}"),
+        }
+
+        if failed > 0 {
+            EXIT_PARTIAL
+        } else {
+            EXIT_SUCCESS
+        }
+    };
+
+    #[cfg(feature = "tui")]
+    if let Some(dashboard) = dashboard {
+        dashboard.stop();
+    }
+    // messages must not end up in the middle of the summary
+    if let Some(ref console) = console {
+        console.flush();
+    }
+    options.report.print(args.legacy_output);
+    if let Some(ref leftovers) = leftovers {
+        leftovers.print(args.legacy_output);
+    }
+    // keep the JSON plan and findings and the --needs-migration line parseable
+    let json_plan = args.plan && args.plan_format == Some(OutputFormat::Json);
+    let json_fsck = args.fsck && args.fsck_format == Some(OutputFormat::Json);
+    if !args.legacy_output && !json_plan && !json_fsck && !args.needs_migration && !args.cluster {
+        println!("Result: exit={exit_code} {}", options.log.counts());
+    }
+    let failed_files = args
+        .failed_files
+        .clone()
+        .or_else(|| service.as_ref().map(Service::failed_files));
+    if let Some(ref failed_files) = failed_files {
+        write_failed_files(&options.report, failed_files, &run_id);
+    }
+    options.progress.finished(&run_id, exit_code);
+    let summary = Summary::new(
+        &run_id,
+        exit_code,
+        &options.log,
+        options.report.len(),
+        started.elapsed(),
+    );
+    if args.low_memory {
+        info!("Peak memory usage: {} MiB", summary.peak_memory / 1024);
+    }
+    let completion = Completion {
+        webhook: args.notify_webhook.clone(),
+        email: args.notify_email.clone(),
+    };
+    completion.send(&summary);
+    let report_path = match args.report {
+        Some(ref path) => Some(path.clone()),
+        None if options.migrate && !args.cluster => {
+            Some(Path::new(target_base_dir).join(run_report::REPORT_FILE))
+        }
+        None => None,
+    };
+    if let Some(path) = report_path {
+        let report = RunReport::new(summary, start_time, &options.progress, &options.report);
+        if let Err(err) = report.write(&path) {
+            warn!("could not write the report of the run - {err:#}");
+        }
+    }
+    audit.finish(exit_code, &options.log, &options.report);
+    if let Some(status_file) = status_file {
+        status_file.finish(exit_code);
+    }
+    if let Some(service) = service {
+        service.finish(exit_code);
+    }
+    if let Some(ref console) = console {
+        console.flush();
+    }
+    if args.service {
+        std::process::exit(service::exit_code(exit_code));
     }
+    std::process::exit(exit_code);
+}
+
+/// The options of the migration phases from the command line 'args'
+fn migration_options(
+    args: &Args,
+    source_base_dir: &str,
+    resource_base_dir: &str,
+) -> MigrationOptions {
+    MigrationOptions {
+        migrate: args.migrate,
+        force: args.force,
+        incremental: args.incremental,
+        threads: cap_threads(set_threads(args), args),
+        max_threads: args.max_threads.map(|threads| cap_threads(threads, args)),
+        io_threads: args.io_threads.map(|threads| cap_threads(threads, args)),
+        low_memory: args.low_memory,
+        batch_size: args.batch_size.unwrap_or(1),
+        stall_timeout: Duration::from_secs(args.stall_timeout.unwrap_or(DEFAULT_STALL_TIMEOUT)),
+        file_timeout: args.file_timeout.map(Duration::from_secs),
+        retries: args.retries.unwrap_or(0),
+        errors: ErrorCounter {
+            max_errors: if args.fail_fast {
+                Some(1)
+            } else {
+                args.max_errors
+            },
+            ..Default::default()
+        },
+        report: ErrorReport::default(),
+        files_from: None,
+        path_match: args.path_match.clone(),
+        log: AuditLog::default(),
+        progress: Progress::default(),
+        notifier: Notifier::default(),
+        control: Control::default(),
+        progress_every: args.progress_every.unwrap_or(DEFAULT_PROGRESS_INTERVAL),
+        source_base: PathBuf::from(source_base_dir),
+        sources: match (&args.archive_dir, args.keep_source, args.delete_source) {
+            (Some(archive), _, _) => SourceHandling::Archive(archive.clone()),
+            (None, true, _) => SourceHandling::Keep,
+            (None, _, true) => SourceHandling::Delete,
+            (None, false, false) => SourceHandling::MarkOld,
+        },
+        old_suffix: args
+            .old_suffix
+            .clone()
+            .unwrap_or_else(|| migrate::OLD_SUFFIX.to_string()),
+        compress_old: args.compress_old,
+        verify_after_migrate: args.verify_after_migrate,
+        migrate_orphans: args.migrate_orphans,
+        remote_guests: args.remote_guests.unwrap_or_default(),
+        prune_removed_storages: args.prune_removed_storages,
+        quarantine: args
+            .quarantine_dir
+            .clone()
+            .unwrap_or_else(|| Path::new(source_base_dir).join(QUARANTINE_SUBDIR)),
+        try_repair: args.try_repair,
+        skip_stale: args
+            .skip_stale
+            .map(|days| Duration::from_secs(days * SECONDS_PER_DAY)),
+        symlinks: args.symlinks.unwrap_or_default(),
+        break_hardlinks: args.break_hardlinks,
+        resources: Arc::new(ResourceLists::new(
+            resource_base_dir,
+            args.resources_from_ipc,
+        )),
+        ignore_quorum: args.ignore_quorum,
+        storage_node: match args.node {
+            _ if args.all_nodes => None,
+            Some(ref node) => Some(StorageNode::Name(node.clone())),
+            None => Some(StorageNode::Local),
+        },
+        storages: args
+            .storage
+            .as_deref()
+            .map(|ids| ids.split(',').map(str::to_string).collect()),
+        backend: Arc::new(Librrd {
+            start: args.timestamp.map(|timestamp| timestamp.0),
+        }),
+        fs: Arc::new(StdFilesystem),
+    }
+}
+
+/// Write the list of files to retry, if there are any
+fn write_failed_files(report: &ErrorReport, path: &Path, run_id: &str) {
+    if report.retryable() == 0 {
+        return;
+    }
+    match report.write_failed_files(path, run_id) {
+        Ok(()) => info!(
+            "Wrote {} failed file(s) to {path:?}, retry them with --files-from",
+            report.retryable()
+        ),
+        Err(err) => error!("failed to write list of failed files to {path:?}: {err}"),
+    }
+}
+
+/// Source and target directory of the files of a resource type, storages have one per node
+#[derive(Clone, Debug)]
+struct MigrationDir {
+    kind: ResourceType,
+    source: PathBuf,
+    target: PathBuf,
+}
+
+/// All directories containing files to migrate, from the (source, target) pairs of each type
+fn migration_dirs(
+    nodes: (&Path, &Path),
+    guests: (&Path, &Path),
+    storage: (&Path, &Path),
+) -> Vec<MigrationDir> {
+    let mut dirs = vec![
+        MigrationDir {
+            kind: ResourceType::Node,
+            source: nodes.0.to_owned(),
+            target: nodes.1.to_owned(),
+        },
+        MigrationDir {
+            kind: ResourceType::Guest,
+            source: guests.0.to_owned(),
+            target: guests.1.to_owned(),
+        },
+    ];
+    if let Ok(nodes) = fs::read_dir(storage.0) {
+        let mut nodes: Vec<_> = nodes
+            .filter_map(|node| node.ok())
+            .filter(|node| node.path().is_dir())
+            .collect();
+        nodes.sort_by_key(|node| node.file_name());
+        for node in nodes {
+            dirs.push(MigrationDir {
+                kind: ResourceType::Storage,
+                source: node.path(),
+                target: storage.1.join(node.file_name()),
+            });
+        }
+    }
+    dirs
+}
+
+/// Number of selected source files in the directories whose target exists
+fn count_existing_targets(dirs: &[MigrationDir], options: &MigrationOptions) -> usize {
+    dirs.iter()
+        .filter_map(|dir| {
+            let files =
+                migrate::collect_rrd_files_with(&*options.fs, &dir.source, &options.old_suffix)
+                    .ok()?;
+            let existing = files
+                .iter()
+                .filter(|file| options.is_selected(file) && dir.target.join(&file.1).exists())
+                .count();
+            Some(existing)
+        })
+        .sum()
+}
+
+/// Ask a yes/no question on the terminal, anything but yes counts as no
+fn confirm(question: &str) -> bool {
+    eprint!("{question} [y/N] ");
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
 }
 
 /// Set number of threads
@@ -272,7 +972,7 @@ fn main() {
 /// Either a fixed parameter or determining a range between 1 to 4 threads
 ///  based on the number of CPU cores available in the system.
 fn set_threads(args: &Args) -> usize {
-    if let Some(threads) = args.threads {
+    if let Some(Threads::Count(threads)) = args.threads {
         return threads;
     }
 
@@ -283,13 +983,13 @@ fn set_threads(args: &Args) -> usize {
             match String::from_utf8_lossy(nproc_output).parse::<usize>() {
                 Ok(cpus) => cpus,
                 Err(err) => {
-                    eprintln!("failed to parse nproc output, falling back to single CPU – {err}");
+                    warn!("failed to parse nproc output, falling back to single CPU – {err}");
                     1
                 }
             }
         }
         Err(err) => {
-            eprintln!("failed run nproc, falling back to single CPU – {err}");
+            warn!("failed run nproc, falling back to single CPU – {err}");
             1
         }
     };
@@ -304,337 +1004,126 @@ fn set_threads(args: &Args) -> usize {
     MAX_AUTO_THREADS
 }
 
-/// Check if a VMID is currently configured
-fn resource_present(path: &str, resource: &str) -> Result<bool> {
-    let resourcelist = fs::read_to_string(path).context(format!("failed to read {path:?}"))?;
-    Ok(resourcelist.contains(format!("\"{resource}\"").as_str()))
+/// Limit 'threads' to [`LOW_MEMORY_MAX_THREADS`] with --low-memory
+fn cap_threads(threads: usize, args: &Args) -> usize {
+    if args.low_memory {
+        threads.min(LOW_MEMORY_MAX_THREADS)
+    } else {
+        threads
+    }
 }
 
-/// Rename file to old, when migrated or resource not present at all -> old RRD file
-fn mv_old(file: &str) -> Result<()> {
-    let old = format!("{file}.old");
-    fs::rename(file, old)?;
-    Ok(())
+/// The guests in .vmlist and the nodes in .members, each list read once when first looked up
+#[derive(Debug)]
+struct ResourceLists {
+    dir: String,
+    /// query them from pmxcfs instead of reading the files in 'dir'
+    ipc: bool,
+    /// the VMIDs with the node hosting each guest
+    guests: OnceLock<HashMap<String, Option<String>>>,
+    members: OnceLock<migrate::Membership>,
 }
 
-/// Colllect all RRD files in the provided directory
-fn collect_rrd_files(location: &PathBuf) -> Result<Vec<(CString, OsString)>> {
-    let mut files: Vec<(CString, OsString)> = Vec::new();
-
-    let contents = match fs::read_dir(location) {
-        Ok(contents) => contents,
-        Err(e) if e.kind() == ErrorKind::NotFound => {
-            return Ok(files);
+impl ResourceLists {
+    fn new(dir: &str, ipc: bool) -> Self {
+        Self {
+            dir: dir.to_string(),
+            ipc,
+            guests: OnceLock::new(),
+            members: OnceLock::new(),
         }
-        Err(e) => return Err(e.into()),
-    };
-
-    contents
-        .filter(|f| f.is_ok())
-        .map(|f| f.unwrap().path())
-        .filter(|f| f.is_file() && f.extension().is_none_or(|ext| ext != "old"))
-        .for_each(|file| {
-            let path = CString::new(file.as_path().as_os_str().as_bytes())
-                .expect("Could not convert path to CString.");
-            let fname = file
-                .file_name()
-                .map(|v| v.to_os_string())
-                .expect("Could not convert fname to OsString.");
-            files.push((path, fname))
-        });
-    Ok(files)
-}
-
-/// Does the actual migration for the given file
-fn do_rrd_migration(
-    file: RRDFile,
-    target_location: &Path,
-    rrd_def: &[&CStr],
-    migrate: bool,
-    force: bool,
-) -> Result<()> {
-    let resource = file.1;
-    let mut target_path = target_location.to_path_buf();
-    target_path.push(&resource);
-
-    if target_path.exists() && !force {
-        println!(
-            "already migrated, use --force to overwrite target file: {}",
-            target_path.display()
-        );
     }
 
-    if !migrate {
-        bail!("skipping migration of metrics for {resource:?} - dry-run mode");
-    } else if target_path.exists() && !force {
-        bail!("refusing to migrate metrics for {resource:?} - target already exists and 'force' not set!");
-    }
-
-    let mut source: [*const i8; 2] = [std::ptr::null(); 2];
-    source[0] = file.0.as_ptr();
-
-    let target_path = CString::new(target_path.to_str().unwrap()).unwrap();
-
-    unsafe {
-        rrd_get_context();
-        rrd_clear_error();
-        let res = rrd_create_r2(
-            target_path.as_ptr(),
-            RRD_STEP_SIZE as u64,
-            0,
-            0,
-            source.as_mut_ptr(),
-            std::ptr::null(),
-            rrd_def.len() as i32,
-            rrd_def
-                .iter()
-                .map(|v| v.as_ptr())
-                .collect::<Vec<_>>()
-                .as_mut_ptr(),
-        );
-        if res != 0 {
-            bail!(
-                "RRD create-migrated error: {}",
-                CStr::from_ptr(rrd_get_error()).to_string_lossy()
-            );
+    fn guests(&self) -> Result<&HashMap<String, Option<String>>, MigrationError> {
+        if let Some(guests) = self.guests.get() {
+            return Ok(guests);
         }
+        let guests = if self.ipc {
+            migrate::parse_guests(&pmxcfs::guest_list()?)?
+        } else {
+            migrate::read_guests(&format!("{}/.vmlist", self.dir))?
+        };
+        Ok(self.guests.get_or_init(|| guests))
     }
-    Ok(())
-}
 
-/// Migrate guest RRD files
-///
-/// In parallel to speed up the process as most time is spent on converting the
-/// data to the new format.
-fn migrate_guests(
-    source_dir_guests: PathBuf,
-    target_dir_guests: PathBuf,
-    resources: &str,
-    threads: usize,
-    migrate: bool,
-    force: bool,
-) -> Result<(), Error> {
-    println!("Migrating RRD metrics data for virtual guests…");
-    println!("Using {threads} thread(s)");
-
-    let guest_source_files = collect_rrd_files(&source_dir_guests)?;
-
-    if guest_source_files.is_empty() {
-        println!("No guest metrics to migrate");
-        return Ok(());
-    }
-
-    if !target_dir_guests.exists() && migrate {
-        println!("Creating new directory: '{}'", target_dir_guests.display());
-        std::fs::create_dir(&target_dir_guests)?;
-    }
-
-    let total_guests = guest_source_files.len();
-    let guests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
-    let guests2 = guests.clone();
-    let failed_guests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
-    let failed_guests2 = failed_guests.clone();
-    let start_time = std::time::SystemTime::now();
-
-    let migration_pool = ParallelHandler::new(
-        "guest rrd migration",
-        threads,
-        move |file: (CString, OsString)| {
-            let full_path = file.0.clone().into_string().unwrap();
-
-            match do_rrd_migration(
-                file,
-                &target_dir_guests,
-                RRD_VM_DEF.as_slice(),
-                migrate,
-                force,
-            ) {
-                Ok(()) => {
-                    mv_old(full_path.as_str())?;
-                    let current_guests = guests2.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                    if current_guests > 0 && current_guests % 10 == 0 {
-                        println!(
-                            "migrated metrics for {current_guests} out of {total_guests} guests."
-                        );
-                    }
-                }
-                Err(err) => {
-                    eprintln!("{err}"); // includes information messages, so just print.
-                    failed_guests2.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                }
-            }
-            Ok(())
-        },
-    );
-    let migration_channel = migration_pool.channel();
-
-    for file in guest_source_files {
-        let guest = file.1.clone().into_string().unwrap();
-        if !resource_present(format!("{resources}/.vmlist").as_str(), guest.as_str())? {
-            if migrate {
-                println!("VMID: '{guest}' not present. Skip and mark as old.");
-                mv_old(format!("{}", file.0.to_string_lossy()).as_str())?;
-            } else {
-                println!("VMID: '{guest}' not present. Would mark as old, but in dry-run mode, so just skip.");
-            }
-            continue;
+    fn members(&self) -> Result<&migrate::Membership, MigrationError> {
+        if let Some(members) = self.members.get() {
+            return Ok(members);
         }
-        let migration_channel = migration_channel.clone();
-        migration_channel.send(file)?;
+        let members = if self.ipc {
+            migrate::parse_members(&pmxcfs::cluster_info()?)?
+        } else {
+            migrate::read_members(&format!("{}/.members", self.dir))?
+        };
+        Ok(self.members.get_or_init(|| members))
     }
 
-    drop(migration_channel);
-    migration_pool.complete()?;
-
-    let elapsed = start_time.elapsed()?.as_secs_f64();
-    let guests = guests.load(std::sync::atomic::Ordering::SeqCst);
-
-    let failed_guests = failed_guests.load(std::sync::atomic::Ordering::SeqCst);
-    if failed_guests == 0 {
-        println!("Migrated metrics data of all {guests} guests to new format in {elapsed:.2}s");
-    } else {
-        println!(
-            "Tried to migrated metrics of all guests to new format in {elapsed:.2}s, but did not \
-            finish {failed_guests} guests - see output above for details."
-        );
+    /// The configured nodes and whether they are online
+    fn nodes(&self) -> Result<&HashMap<String, bool>, MigrationError> {
+        Ok(&self.members()?.nodes)
     }
 
-    Ok(())
-}
+    /// Check if a VMID or node is currently configured, storages are not in these lists
+    fn contains(&self, kind: ResourceType, resource: &str) -> Result<bool, MigrationError> {
+        match kind {
+            ResourceType::Guest => Ok(self.guests()?.contains_key(resource)),
+            ResourceType::Node => Ok(self.nodes()?.contains_key(resource)),
+            ResourceType::Storage => Ok(true),
+        }
+    }
 
-/// Migrate node RRD files
-///
-/// In serial as the number of nodes will not be high.
-fn migrate_nodes(
-    source_dir_nodes: PathBuf,
-    target_dir_nodes: PathBuf,
-    resources: &str,
-    migrate: bool,
-    force: bool,
-) -> Result<(), Error> {
-    println!("Migrating RRD metrics data for nodes…");
+    /// The node hosting guest 'vmid' according to .vmlist, if it is listed with one
+    fn guest_node(&self, vmid: &str) -> Result<Option<&str>, MigrationError> {
+        Ok(self.guests()?.get(vmid).and_then(|node| node.as_deref()))
+    }
 
-    if !target_dir_nodes.exists() && migrate {
-        println!("Creating new directory: '{}'", target_dir_nodes.display());
-        std::fs::create_dir(&target_dir_nodes)?;
+    /// Check if a configured node is online, according to .members
+    fn is_online(&self, node: &str) -> Result<bool, MigrationError> {
+        Ok(self.nodes()?.get(node).copied().unwrap_or(false))
     }
 
-    let node_source_files = collect_rrd_files(&source_dir_nodes)?;
+    /// Check if the cluster is quorate, otherwise the lists may be stale
+    fn is_quorate(&self) -> Result<bool, MigrationError> {
+        Ok(self.members()?.quorate)
+    }
 
-    let mut no_migration_err = true;
-    for file in node_source_files {
-        let node = file.1.clone().into_string().unwrap();
-        let full_path = file.0.clone().into_string().unwrap();
-        println!("Node: '{node}'");
-        if !resource_present(format!("{resources}/.members").as_str(), node.as_str())? {
-            if migrate {
-                println!("Node: '{node}' not present. Skip and mark as old.");
-                mv_old(full_path.as_str())?;
-            } else {
-                println!("Node: '{node}' not present. Would mark as old, but in dry-run mode, so just skip.");
-            }
-            continue;
-        }
-        match do_rrd_migration(
-            file,
-            &target_dir_nodes,
-            RRD_NODE_DEF.as_slice(),
-            migrate,
-            force,
-        ) {
-            Ok(()) => {
-                mv_old(full_path.as_str())?;
-            }
-            Err(err) => {
-                eprintln!("{err}"); // includes information messages, so just print.
-                no_migration_err = false;
+    /// The name of this node in .members, or its short host name if it cannot be read
+    fn local_node(&self) -> String {
+        match self
+            .members()
+            .ok()
+            .and_then(|members| members.nodename.clone())
+        {
+            Some(nodename) => nodename,
+            None => {
+                let hostname = marker::hostname();
+                hostname.split('.').next().unwrap_or_default().to_string()
             }
         }
     }
-
-    if no_migration_err {
-        println!("Migrated metrics of all nodes to new format");
-    } else {
-        println!(
-            "Tried to migrated metrics of all nodes to new format - see output above for details."
-        );
-    }
-
-    Ok(())
 }
 
-/// Migrate storage RRD files
-///
-/// In serial as the number of storage will not be that high.
-fn migrate_storage(
-    source_dir_storage: PathBuf,
-    target_dir_storage: PathBuf,
-    migrate: bool,
-    force: bool,
-) -> Result<(), Error> {
-    println!("Migrating RRD metrics data for storages…");
-
-    if !target_dir_storage.exists() && migrate {
-        println!("Creating new directory: '{}'", target_dir_storage.display());
-        std::fs::create_dir(&target_dir_storage)?;
-    }
-
-    let mut no_migration_err = true;
-    // storage has another layer of directories per node over which we need to iterate
-    fs::read_dir(&source_dir_storage)?
-        .filter(|f| f.is_ok())
-        .map(|f| f.unwrap().path())
-        .filter(|f| f.is_dir())
-        .try_for_each(|node| {
-            let mut source_storage_subdir = source_dir_storage.clone();
-            source_storage_subdir.push(node.file_name().unwrap());
-
-            let mut target_storage_subdir = target_dir_storage.clone();
-            target_storage_subdir.push(node.file_name().unwrap());
-
-            if !target_storage_subdir.exists() && migrate {
-                fs::create_dir(target_storage_subdir.as_path())?;
-                let metadata = target_storage_subdir.metadata()?;
-                let mut permissions = metadata.permissions();
-                permissions.set_mode(0o755);
-                fs::set_permissions(&target_storage_subdir, permissions)?;
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            let storage_source_files = collect_rrd_files(&source_storage_subdir)?;
-            for file in storage_source_files {
-                println!(
-                    "Migrating metrics for storage '{}/{}'",
-                    node.file_name()
-                        .expect("no file name present")
-                        .to_string_lossy(),
-                    PathBuf::from(file.1.clone()).display()
-                );
-
-                let full_path = file.0.clone().into_string().unwrap();
-                match do_rrd_migration(
-                    file,
-                    &target_storage_subdir,
-                    RRD_STORAGE_DEF.as_slice(),
-                    migrate,
-                    force,
-                ) {
-                    Ok(()) => {
-                        mv_old(full_path.as_str())?;
-                    }
-                    Err(err) => {
-                        eprintln!("{err}"); // includes information messages, so just print.
-                        no_migration_err = false;
-                    }
-                }
-            }
-            Ok::<(), Error>(())
-        })?;
+    #[test]
+    fn error_counter() {
+        let unlimited = ErrorCounter::default();
+        for file in 0..100u8 {
+            assert!(unlimited.record(&[file]).is_ok());
+        }
 
-    if no_migration_err {
-        println!("Migrated metrics of all storages to new format");
-    } else {
-        println!("Tried to migrated metrics of all storages to new format - see output above for details.");
+        let counter = ErrorCounter {
+            max_errors: Some(3),
+            ..Default::default()
+        };
+        assert!(counter.record(b"pve2-vm/100").is_ok());
+        assert!(counter.record(b"pve2-vm/101").is_ok());
+        // a file failing again on a retry counts once
+        assert!(counter.record(b"pve2-vm/100").is_ok());
+        // shared between clones, as between the threads and phases
+        assert!(counter.clone().record(b"pve2-vm/102").is_err());
+        assert!(counter.record(b"pve2-vm/103").is_err());
     }
-
-    Ok(())
 }
@@ -0,0 +1,74 @@
+//! Marker recording which host migrated a source directory, for directories on shared storage
+//! or copied between nodes, which another node must not migrate a second time
+
+use std::ffi::CStr;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error};
+use serde::{Deserialize, Serialize};
+
+use proxmox_rrd_migration_tool::migrate::MIGRATION_MARKER;
+
+use crate::audit;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Marker {
+    pub(crate) host: String,
+    pub(crate) run_id: String,
+    pub(crate) time: String,
+}
+
+/// Name of this host, empty if it cannot be determined
+pub(crate) fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if res != 0 {
+        return String::new();
+    }
+    CStr::from_bytes_until_nul(&buf)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn read(dir: &Path) -> Result<Option<Marker>, Error> {
+    let path = dir.join(MIGRATION_MARKER);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(Error::from(err).context(format!("cannot read {path:?}"))),
+    };
+    let marker = serde_json::from_str(&content).context(format!("cannot parse {path:?}"))?;
+    Ok(Some(marker))
+}
+
+/// The markers of other hosts in 'dirs'
+pub(crate) fn foreign(dirs: &[&Path]) -> Result<Vec<(PathBuf, Marker)>, Error> {
+    let host = hostname();
+    let mut markers = Vec::new();
+    for dir in dirs {
+        if let Some(marker) = read(dir)? {
+            if marker.host != host {
+                markers.push((dir.to_path_buf(), marker));
+            }
+        }
+    }
+    Ok(markers)
+}
+
+/// Record that this host migrated 'dir' in the run 'run_id', if it exists
+pub(crate) fn write(dir: &Path, run_id: &str) -> Result<(), Error> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    let marker = Marker {
+        host: hostname(),
+        run_id: run_id.to_string(),
+        time: audit::timestamp(),
+    };
+    let path = dir.join(MIGRATION_MARKER);
+    let content = serde_json::to_string(&marker)?;
+    fs::write(&path, content + "\n").context(format!("cannot write {path:?}"))
+}
@@ -0,0 +1,170 @@
+//! Minimal HTTP endpoint serving the progress of the run in the Prometheus text format, so that
+//! long migrations can be watched with the existing monitoring
+//!
+//! Only 'GET /metrics' is answered, one request per connection, on a thread of its own that lives
+//! as long as the process.
+
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+use tracing::debug;
+
+use crate::audit::{AuditLog, Outcome};
+use crate::progress::{PhaseSnapshot, Progress};
+
+const METRIC_PREFIX: &str = "proxmox_rrd_migration";
+/// Time a client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the metrics are taken from
+#[derive(Clone, Debug)]
+pub struct Source {
+    pub progress: Progress,
+    pub log: AuditLog,
+    /// configured number of guest migration threads
+    pub threads: usize,
+}
+
+/// Listen on 'addr' and serve the metrics from 'source' in the background
+pub fn start(addr: SocketAddr, source: Source) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    std::thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| serve(stream, &source));
+                if let Err(err) = result {
+                    debug!("metrics request failed - {err}");
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn serve(mut stream: TcpStream, source: &Source) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(source)),
+        (Some("GET"), Some(_)) => ("404 Not Found", "not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "only GET is supported\n".to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\n\
+        Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        \r\n\
+        {body}",
+        body.len()
+    )
+}
+
+/// Escape a label value as the text format needs it
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Append the HELP and TYPE lines of metric 'name'
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {METRIC_PREFIX}_{name} {help}");
+    let _ = writeln!(out, "# TYPE {METRIC_PREFIX}_{name} {kind}");
+}
+
+/// The current metrics in the Prometheus text format
+fn render(source: &Source) -> String {
+    let snapshot = source.progress.snapshot();
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "files_total",
+        "counter",
+        "RRD files handled, by outcome",
+    );
+    for outcome in Outcome::ALL {
+        let _ = writeln!(
+            out,
+            "{METRIC_PREFIX}_files_total{{outcome=\"{}\"}} {}",
+            outcome.as_str(),
+            source.log.count(outcome)
+        );
+    }
+    header(
+        &mut out,
+        "bytes_processed_total",
+        "counter",
+        "Size of the source files migrated",
+    );
+    let _ = writeln!(
+        out,
+        "{METRIC_PREFIX}_bytes_processed_total {}",
+        snapshot.bytes
+    );
+
+    let phase_metrics: [(&str, &str, fn(&PhaseSnapshot) -> f64); 5] = [
+        ("phase_files", "Files to handle in the phase", |phase| {
+            phase.total as f64
+        }),
+        (
+            "phase_files_done",
+            "Files of the phase handled so far",
+            |phase| phase.done as f64,
+        ),
+        (
+            "phase_files_failed",
+            "Files of the phase that failed",
+            |phase| phase.failed as f64,
+        ),
+        (
+            "phase_seconds",
+            "Time the phase is running or ran",
+            |phase| phase.elapsed.as_secs_f64(),
+        ),
+        ("phase_finished", "Whether the phase is finished", |phase| {
+            f64::from(u8::from(phase.finished))
+        }),
+    ];
+    for (name, help, value) in phase_metrics {
+        header(&mut out, name, "gauge", help);
+        for phase in &snapshot.phases {
+            let _ = writeln!(
+                out,
+                "{METRIC_PREFIX}_{name}{{phase=\"{}\"}} {}",
+                label(&phase.name),
+                value(phase)
+            );
+        }
+    }
+
+    header(
+        &mut out,
+        "worker_threads",
+        "gauge",
+        "Configured number of guest migration threads",
+    );
+    let _ = writeln!(out, "{METRIC_PREFIX}_worker_threads {}", source.threads);
+    header(
+        &mut out,
+        "workers_busy",
+        "gauge",
+        "Threads migrating a file right now",
+    );
+    let _ = writeln!(
+        out,
+        "{METRIC_PREFIX}_workers_busy {}",
+        snapshot.current.len()
+    );
+    out
+}
@@ -0,0 +1,1873 @@
+//! Migration of single RRD files to the new format
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io::{ErrorKind, Read};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::backend::{Librrd, RrdBackend};
+use crate::definition::{self, Definition};
+use crate::error::MigrationError;
+use crate::filesystem::{Filesystem, StdFilesystem};
+#[cfg(not(feature = "static-rrd"))]
+use crate::rrd_graph;
+use crate::{
+    rrd_clear_error, rrd_create_r2, rrd_dump_r, rrd_fetch_r, rrd_freemem, rrd_get_context,
+    rrd_get_error, rrd_info_free, rrd_info_r, rrd_info_type_RD_I_CNT, rrd_info_type_RD_I_STR,
+    rrd_info_type_RD_I_VAL, rrd_last_r, rrd_lastupdate_r, rrd_restore, rrd_strversion,
+    rrd_update_r,
+};
+
+/// Step size of the migrated RRD files in seconds
+pub const RRD_STEP_SIZE: usize = 60;
+
+/// Full path of an RRD file and its file name, which is the name of the resource
+pub type RRDFile = (CString, OsString);
+
+// RRAs are defined in the following way:
+//
+// RRA:CF:xff:step:rows
+// CF: AVERAGE or MAX
+// xff: 0.5
+// steps: stepsize is defined on rrd file creation! example: with a 60 secondu step size, one step
+//    means 60 sec, 30 steps means 1800 seconds or 30 min
+// rows: how many aggregated rows are kept, as in how far back in time we store data
+//
+// how many seconds are aggregated per RRA: steps * stepsize * rows
+// how many hours are aggregated per RRA: steps * stepsize * rows / 3600
+// how many days are aggregated per RRA: steps * stepsize * rows / 3600 / 24
+// https://oss.oetiker.ch/rrdtool/tut/rrd-beginners.en.html#Understanding_by_an_example
+
+pub const RRD_VM_DEF: [&CStr; 25] = [
+    c"DS:maxcpu:GAUGE:120:0:U",
+    c"DS:cpu:GAUGE:120:0:U",
+    c"DS:maxmem:GAUGE:120:0:U",
+    c"DS:mem:GAUGE:120:0:U",
+    c"DS:maxdisk:GAUGE:120:0:U",
+    c"DS:disk:GAUGE:120:0:U",
+    c"DS:netin:DERIVE:120:0:U",
+    c"DS:netout:DERIVE:120:0:U",
+    c"DS:diskread:DERIVE:120:0:U",
+    c"DS:diskwrite:DERIVE:120:0:U",
+    c"DS:memhost:GAUGE:120:0:U",
+    c"DS:pressurecpusome:GAUGE:120:0:U",
+    c"DS:pressurecpufull:GAUGE:120:0:U",
+    c"DS:pressureiosome:GAUGE:120:0:U",
+    c"DS:pressureiofull:GAUGE:120:0:U",
+    c"DS:pressurememorysome:GAUGE:120:0:U",
+    c"DS:pressurememoryfull:GAUGE:120:0:U",
+    c"RRA:AVERAGE:0.5:1:1440",    // 1 min * 1440 => 1 day
+    c"RRA:AVERAGE:0.5:30:1440",   // 30 min * 1440 => 30 day
+    c"RRA:AVERAGE:0.5:360:1440",  // 6 hours * 1440 => 360 day ~1 year
+    c"RRA:AVERAGE:0.5:10080:570", // 1 week * 570 => ~10 years
+    c"RRA:MAX:0.5:1:1440",        // 1 min * 1440 => 1 day
+    c"RRA:MAX:0.5:30:1440",       // 30 min * 1440 => 30 day
+    c"RRA:MAX:0.5:360:1440",      // 6 hours * 1440 => 360 day ~1 year
+    c"RRA:MAX:0.5:10080:570",     // 1 week * 570 => ~10 years
+];
+
+pub const RRD_NODE_DEF: [&CStr; 27] = [
+    c"DS:loadavg:GAUGE:120:0:U",
+    c"DS:maxcpu:GAUGE:120:0:U",
+    c"DS:cpu:GAUGE:120:0:U",
+    c"DS:iowait:GAUGE:120:0:U",
+    c"DS:memtotal:GAUGE:120:0:U",
+    c"DS:memused:GAUGE:120:0:U",
+    c"DS:swaptotal:GAUGE:120:0:U",
+    c"DS:swapused:GAUGE:120:0:U",
+    c"DS:roottotal:GAUGE:120:0:U",
+    c"DS:rootused:GAUGE:120:0:U",
+    c"DS:netin:DERIVE:120:0:U",
+    c"DS:netout:DERIVE:120:0:U",
+    c"DS:memavailable:GAUGE:120:0:U",
+    c"DS:arcsize:GAUGE:120:0:U",
+    c"DS:pressurecpusome:GAUGE:120:0:U",
+    c"DS:pressureiosome:GAUGE:120:0:U",
+    c"DS:pressureiofull:GAUGE:120:0:U",
+    c"DS:pressurememorysome:GAUGE:120:0:U",
+    c"DS:pressurememoryfull:GAUGE:120:0:U",
+    c"RRA:AVERAGE:0.5:1:1440",    // 1 min * 1440 => 1 day
+    c"RRA:AVERAGE:0.5:30:1440",   // 30 min * 1440 => 30 day
+    c"RRA:AVERAGE:0.5:360:1440",  // 6 hours * 1440 => 360 day ~1 year
+    c"RRA:AVERAGE:0.5:10080:570", // 1 week * 570 => ~10 years
+    c"RRA:MAX:0.5:1:1440",        // 1 min * 1440 => 1 day
+    c"RRA:MAX:0.5:30:1440",       // 30 min * 1440 => 30 day
+    c"RRA:MAX:0.5:360:1440",      // 6 hours * 1440 => 360 day ~1 year
+    c"RRA:MAX:0.5:10080:570",     // 1 week * 570 => ~10 years
+];
+
+pub const RRD_STORAGE_DEF: [&CStr; 10] = [
+    c"DS:total:GAUGE:120:0:U",
+    c"DS:used:GAUGE:120:0:U",
+    c"RRA:AVERAGE:0.5:1:1440",    // 1 min * 1440 => 1 day
+    c"RRA:AVERAGE:0.5:30:1440",   // 30 min * 1440 => 30 day
+    c"RRA:AVERAGE:0.5:360:1440",  // 6 hours * 1440 => 360 day ~1 year
+    c"RRA:AVERAGE:0.5:10080:570", // 1 week * 570 => ~10 years
+    c"RRA:MAX:0.5:1:1440",        // 1 min * 1440 => 1 day
+    c"RRA:MAX:0.5:30:1440",       // 30 min * 1440 => 30 day
+    c"RRA:MAX:0.5:360:1440",      // 6 hours * 1440 => 360 day ~1 year
+    c"RRA:MAX:0.5:10080:570",     // 1 week * 570 => ~10 years
+];
+
+// The schemas of the pve2 source files, as pmxcfs of Proxmox VE 8 creates them
+
+pub const RRD_LEGACY_VM_DEF: [&CStr; 20] = [
+    c"DS:maxcpu:GAUGE:120:0:U",
+    c"DS:cpu:GAUGE:120:0:U",
+    c"DS:maxmem:GAUGE:120:0:U",
+    c"DS:mem:GAUGE:120:0:U",
+    c"DS:maxdisk:GAUGE:120:0:U",
+    c"DS:disk:GAUGE:120:0:U",
+    c"DS:netin:DERIVE:120:0:U",
+    c"DS:netout:DERIVE:120:0:U",
+    c"DS:diskread:DERIVE:120:0:U",
+    c"DS:diskwrite:DERIVE:120:0:U",
+    c"RRA:AVERAGE:0.5:1:70",     // 1 min * 70 => 1 hour
+    c"RRA:AVERAGE:0.5:30:70",    // 30 min * 70 => 1 day
+    c"RRA:AVERAGE:0.5:180:70",   // 3 hours * 70 => 1 week
+    c"RRA:AVERAGE:0.5:720:70",   // 12 hours * 70 => 1 month
+    c"RRA:AVERAGE:0.5:10080:70", // 1 week * 70 => 1 year
+    c"RRA:MAX:0.5:1:70",         // 1 min * 70 => 1 hour
+    c"RRA:MAX:0.5:30:70",        // 30 min * 70 => 1 day
+    c"RRA:MAX:0.5:180:70",       // 3 hours * 70 => 1 week
+    c"RRA:MAX:0.5:720:70",       // 12 hours * 70 => 1 month
+    c"RRA:MAX:0.5:10080:70",     // 1 week * 70 => 1 year
+];
+
+pub const RRD_LEGACY_NODE_DEF: [&CStr; 22] = [
+    c"DS:loadavg:GAUGE:120:0:U",
+    c"DS:maxcpu:GAUGE:120:0:U",
+    c"DS:cpu:GAUGE:120:0:U",
+    c"DS:iowait:GAUGE:120:0:U",
+    c"DS:memtotal:GAUGE:120:0:U",
+    c"DS:memused:GAUGE:120:0:U",
+    c"DS:swaptotal:GAUGE:120:0:U",
+    c"DS:swapused:GAUGE:120:0:U",
+    c"DS:roottotal:GAUGE:120:0:U",
+    c"DS:rootused:GAUGE:120:0:U",
+    c"DS:netin:DERIVE:120:0:U",
+    c"DS:netout:DERIVE:120:0:U",
+    c"RRA:AVERAGE:0.5:1:70",     // 1 min * 70 => 1 hour
+    c"RRA:AVERAGE:0.5:30:70",    // 30 min * 70 => 1 day
+    c"RRA:AVERAGE:0.5:180:70",   // 3 hours * 70 => 1 week
+    c"RRA:AVERAGE:0.5:720:70",   // 12 hours * 70 => 1 month
+    c"RRA:AVERAGE:0.5:10080:70", // 1 week * 70 => 1 year
+    c"RRA:MAX:0.5:1:70",         // 1 min * 70 => 1 hour
+    c"RRA:MAX:0.5:30:70",        // 30 min * 70 => 1 day
+    c"RRA:MAX:0.5:180:70",       // 3 hours * 70 => 1 week
+    c"RRA:MAX:0.5:720:70",       // 12 hours * 70 => 1 month
+    c"RRA:MAX:0.5:10080:70",     // 1 week * 70 => 1 year
+];
+
+pub const RRD_LEGACY_STORAGE_DEF: [&CStr; 12] = [
+    c"DS:total:GAUGE:120:0:U",
+    c"DS:used:GAUGE:120:0:U",
+    c"RRA:AVERAGE:0.5:1:70",     // 1 min * 70 => 1 hour
+    c"RRA:AVERAGE:0.5:30:70",    // 30 min * 70 => 1 day
+    c"RRA:AVERAGE:0.5:180:70",   // 3 hours * 70 => 1 week
+    c"RRA:AVERAGE:0.5:720:70",   // 12 hours * 70 => 1 month
+    c"RRA:AVERAGE:0.5:10080:70", // 1 week * 70 => 1 year
+    c"RRA:MAX:0.5:1:70",         // 1 min * 70 => 1 hour
+    c"RRA:MAX:0.5:30:70",        // 30 min * 70 => 1 day
+    c"RRA:MAX:0.5:180:70",       // 3 hours * 70 => 1 week
+    c"RRA:MAX:0.5:720:70",       // 12 hours * 70 => 1 month
+    c"RRA:MAX:0.5:10080:70",     // 1 week * 70 => 1 year
+];
+
+/// Kind of resource an RRD file holds the metrics of
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResourceType {
+    Node,
+    Storage,
+    Guest,
+}
+
+impl ResourceType {
+    /// Schema of the migrated RRD files
+    pub fn rrd_def(self) -> &'static [&'static CStr] {
+        match self {
+            ResourceType::Node => &RRD_NODE_DEF,
+            ResourceType::Storage => &RRD_STORAGE_DEF,
+            ResourceType::Guest => &RRD_VM_DEF,
+        }
+    }
+
+    /// The schema of the pve2 source files of this type
+    pub fn legacy_rrd_def(self) -> &'static [&'static CStr] {
+        match self {
+            ResourceType::Node => &RRD_LEGACY_NODE_DEF,
+            ResourceType::Storage => &RRD_LEGACY_STORAGE_DEF,
+            ResourceType::Guest => &RRD_LEGACY_VM_DEF,
+        }
+    }
+}
+
+impl fmt::Display for ResourceType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ResourceType::Node => "node",
+            ResourceType::Storage => "storage",
+            ResourceType::Guest => "guest",
+        })
+    }
+}
+
+/// Whether 'name' can be the file name of a source of the given type: a numeric VMID for guests,
+/// a host name for nodes and a storage ID for storages
+///
+/// Anything else, like editor backups or notes, is not an RRD file of pmxcfs.
+pub fn is_valid_resource_name(kind: ResourceType, name: &OsStr) -> bool {
+    let name = name.as_bytes();
+    let (Some(first), Some(last)) = (name.first(), name.last()) else {
+        return false;
+    };
+    match kind {
+        ResourceType::Guest => name.iter().all(u8::is_ascii_digit),
+        ResourceType::Node => {
+            first.is_ascii_alphanumeric()
+                && last.is_ascii_alphanumeric()
+                && name
+                    .iter()
+                    .all(|byte| byte.is_ascii_alphanumeric() || *byte == b'-')
+        }
+        ResourceType::Storage => {
+            name.len() > 1
+                && first.is_ascii_alphabetic()
+                && last.is_ascii_alphanumeric()
+                && name
+                    .iter()
+                    .all(|byte| byte.is_ascii_alphanumeric() || b"-_.".contains(byte))
+        }
+    }
+}
+
+/// 'content' without the commas before a closing brace or bracket, outside of strings
+fn strip_trailing_commas(content: &str) -> String {
+    let mut stripped = String::with_capacity(content.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, c) in content.char_indices() {
+        if in_string {
+            in_string = escaped || c != '"';
+            escaped = !escaped && c == '\\';
+        } else if c == '"' {
+            in_string = true;
+        } else if c == ',' && content[index + 1..].trim_start().starts_with(['}', ']']) {
+            continue;
+        }
+        stripped.push(c);
+    }
+    stripped
+}
+
+#[derive(Deserialize)]
+struct VmList {
+    ids: HashMap<String, VmListEntry>,
+}
+
+#[derive(Deserialize)]
+struct VmListEntry {
+    node: Option<String>,
+}
+
+/// VMIDs of the guests in the .vmlist at 'path'
+pub fn read_guest_ids(path: &str) -> Result<HashSet<String>, MigrationError> {
+    Ok(read_guests(path)?.into_keys().collect())
+}
+
+/// VMIDs of the guests in 'vmlist', in the format of .vmlist
+pub fn parse_guest_ids(vmlist: &str) -> Result<HashSet<String>, MigrationError> {
+    Ok(parse_guests(vmlist)?.into_keys().collect())
+}
+
+/// The guests in the .vmlist at 'path' with the node each is hosted on, see [`parse_guests`]
+pub fn read_guests(path: &str) -> Result<HashMap<String, Option<String>>, MigrationError> {
+    let vmlist = fs::read_to_string(path).map_err(|err| MigrationError::io(path, err))?;
+    parse_guests(&vmlist)
+}
+
+/// The VMIDs of the guests in 'vmlist', in the format of .vmlist, with the node each is hosted
+/// on, if it is given
+///
+/// Read as JSON, once the trailing commas pmxcfs writes are removed. A list that still does not
+/// parse is an error, guessing the guests from it could mark those that exist as old.
+pub fn parse_guests(vmlist: &str) -> Result<HashMap<String, Option<String>>, MigrationError> {
+    let parsed = serde_json::from_str::<VmList>(&strip_trailing_commas(vmlist)).map_err(|err| {
+        MigrationError::InvalidResourceList {
+            list: ".vmlist",
+            message: err.to_string(),
+        }
+    })?;
+    Ok(parsed
+        .ids
+        .into_iter()
+        .map(|(vmid, entry)| (vmid, entry.node))
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct Members {
+    nodename: Option<String>,
+    cluster: Option<Cluster>,
+    #[serde(default)]
+    nodelist: HashMap<String, Member>,
+}
+
+#[derive(Deserialize)]
+struct Cluster {
+    quorate: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct Member {
+    #[serde(default)]
+    online: u64,
+}
+
+/// The cluster membership as seen by this node
+#[derive(Clone, Debug)]
+pub struct Membership {
+    /// the cluster nodes and whether they are online, or the node itself if not clustered
+    pub nodes: HashMap<String, bool>,
+    /// a node that is not clustered always is
+    pub quorate: bool,
+    /// the name of this node
+    pub nodename: Option<String>,
+}
+
+/// The cluster membership in the .members at 'path'
+pub fn read_members(path: &str) -> Result<Membership, MigrationError> {
+    let members = fs::read_to_string(path).map_err(|err| MigrationError::io(path, err))?;
+    parse_members(&members)
+}
+
+/// The cluster membership in 'members', in the format of .members, see [`read_members`]
+///
+/// Read as JSON like the .vmlist, see [`parse_guests`], and just as much an error if it does not
+/// parse.
+pub fn parse_members(members: &str) -> Result<Membership, MigrationError> {
+    let parsed =
+        serde_json::from_str::<Members>(&strip_trailing_commas(members)).map_err(|err| {
+            MigrationError::InvalidResourceList {
+                list: ".members",
+                message: err.to_string(),
+            }
+        })?;
+    let mut nodes: HashMap<String, bool> = parsed
+        .nodelist
+        .into_iter()
+        .map(|(name, member)| (name, member.online != 0))
+        .collect();
+    if nodes.is_empty() {
+        nodes.extend(parsed.nodename.clone().map(|name| (name, true)));
+    }
+    Ok(Membership {
+        nodes,
+        quorate: parsed
+            .cluster
+            .and_then(|cluster| cluster.quorate)
+            .is_none_or(|quorate| quorate != 0),
+        nodename: parsed.nodename,
+    })
+}
+
+/// Names of the cluster nodes in the .members at 'path', or of the node itself if not clustered
+pub fn read_node_names(path: &str) -> Result<HashSet<String>, MigrationError> {
+    Ok(read_members(path)?.nodes.into_keys().collect())
+}
+
+/// IDs of the storages in the storage configuration at 'path', like /etc/pve/storage.cfg
+///
+/// Each section starts with an unindented `<type>: <id>` line.
+pub fn read_storage_ids(path: &str) -> Result<HashSet<String>, MigrationError> {
+    let config = fs::read_to_string(path).map_err(|err| MigrationError::io(path, err))?;
+    Ok(config
+        .lines()
+        .filter(|line| !line.starts_with(char::is_whitespace) && !line.starts_with('#'))
+        .filter_map(|line| Some(line.split_once(':')?.1.trim().to_string()))
+        .filter(|id| !id.is_empty())
+        .collect())
+}
+
+/// Appended to old RRD files by default
+pub const OLD_SUFFIX: &str = ".old";
+/// Appended to the older of a source file and its old twin when both exist, so that it is kept
+/// out of the way of the migration
+pub const SUPERSEDED_SUFFIX: &str = ".superseded";
+
+/// Rename 'from' to 'to', or copy it and remove 'from' if they are on different file systems,
+/// like with a --target, archive or quarantine directory on another mount
+///
+/// The copy keeps the modification time and is synced to disk, with its directory, before
+/// 'from' is removed.
+pub(crate) fn move_file(from: &Path, to: &Path) -> Result<(), MigrationError> {
+    match fs::rename(from, to) {
+        Ok(()) => return Ok(()),
+        Err(err) if err.raw_os_error() == Some(libc::EXDEV) => {}
+        Err(err) => return Err(MigrationError::io(from, err)),
+    }
+    let copy = || -> std::io::Result<()> {
+        let modified = fs::metadata(from)?.modified()?;
+        fs::copy(from, to)?;
+        let copy = fs::File::open(to)?;
+        copy.set_modified(modified)?;
+        copy.sync_all()?;
+        if let Some(dir) = to.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    };
+    if let Err(err) = copy() {
+        let _ = fs::remove_file(to);
+        return Err(MigrationError::io(to, err));
+    }
+    fs::remove_file(from).map_err(|err| MigrationError::io(from, err))
+}
+
+/// Rename file to old by appending 'suffix', when migrated or resource not present at all -> old
+/// RRD file
+///
+/// Returns the new path.
+pub fn mv_old(file: &Path, suffix: &str) -> Result<PathBuf, MigrationError> {
+    mv_old_with(&StdFilesystem, file, suffix)
+}
+
+/// [`mv_old`] on the file system 'fs'
+pub fn mv_old_with(
+    fs: &dyn Filesystem,
+    file: &Path,
+    suffix: &str,
+) -> Result<PathBuf, MigrationError> {
+    let mut old = file.as_os_str().to_os_string();
+    old.push(suffix);
+    let old = PathBuf::from(old);
+    fs.rename(file, &old)?;
+    Ok(old)
+}
+
+/// Create the directory 'dir' unless it exists, returns whether it was created
+pub fn create_missing_dir(fs: &dyn Filesystem, dir: &Path) -> Result<bool, MigrationError> {
+    match fs.metadata(dir) {
+        Ok(_) => Ok(false),
+        Err(err) if err.is_not_found() => fs.create_dir(dir).map(|()| true),
+        Err(err) => Err(err),
+    }
+}
+
+/// Create the directory 'dir' and its missing parents, like [`fs::create_dir_all`]
+pub fn create_dir_all_with(fs: &dyn Filesystem, dir: &Path) -> Result<(), MigrationError> {
+    if dir.as_os_str().is_empty() || fs.metadata(dir).is_ok_and(|metadata| metadata.is_dir) {
+        return Ok(());
+    }
+    if let Some(parent) = dir.parent() {
+        create_dir_all_with(fs, parent)?;
+    }
+    match fs.create_dir(dir) {
+        // created concurrently
+        Err(MigrationError::Io { source, .. }) if source.kind() == ErrorKind::AlreadyExists => {
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Read all of 'path' once, so that its conversion finds it in the page cache
+///
+/// Returns the number of bytes read.
+pub fn read_ahead(path: &Path) -> Result<u64, MigrationError> {
+    let mut file = fs::File::open(path).map_err(|err| MigrationError::io(path, err))?;
+    std::io::copy(&mut file, &mut std::io::sink()).map_err(|err| MigrationError::io(path, err))
+}
+
+/// Number of names the inode of 'file' has
+pub fn hard_links(file: &Path) -> Result<u64, MigrationError> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = fs::metadata(file).map_err(|err| MigrationError::io(file, err))?;
+    Ok(metadata.nlink())
+}
+
+/// Replace 'file' by a copy of itself, so that it no longer shares its inode with other hard
+/// links
+pub fn break_hardlink(file: &Path) -> Result<(), MigrationError> {
+    let mut copy = file.as_os_str().to_os_string();
+    copy.push(".unlinked");
+    let copy = PathBuf::from(copy);
+    let result = fs::copy(file, &copy).and_then(|_| fs::rename(&copy, file));
+    if let Err(err) = result {
+        let _ = fs::remove_file(&copy);
+        return Err(MigrationError::io(file, err));
+    }
+    Ok(())
+}
+
+/// Move 'file' into 'archive', at the same path it has below 'source_base', instead of renaming
+/// it to old next to the other source files
+///
+/// On another file system, the file is copied and removed afterwards. Returns the new path.
+pub fn mv_archive(
+    file: &Path,
+    source_base: &Path,
+    archive: &Path,
+) -> Result<PathBuf, MigrationError> {
+    mv_archive_with(&StdFilesystem, file, source_base, archive)
+}
+
+/// [`mv_archive`] on the file system 'fs'
+pub fn mv_archive_with(
+    fs: &dyn Filesystem,
+    file: &Path,
+    source_base: &Path,
+    archive: &Path,
+) -> Result<PathBuf, MigrationError> {
+    let relative = file.strip_prefix(source_base).map_err(|_| {
+        MigrationError::io(
+            file,
+            std::io::Error::other(format!("not below {}", source_base.display())),
+        )
+    })?;
+    let archived = archive.join(relative);
+    if let Some(parent) = archived.parent() {
+        create_dir_all_with(fs, parent)?;
+    }
+    fs.rename(file, &archived)?;
+    Ok(archived)
+}
+
+/// Appended to old RRD files compressed with [`compress`]
+pub const COMPRESSED_SUFFIX: &str = ".zst";
+
+/// Compress 'file' with zstd(1), replacing it by `<file>.zst`, which is returned
+pub fn compress(file: &Path) -> Result<PathBuf, MigrationError> {
+    zstd(file, |command| command.arg("--rm").arg(file))?;
+    let mut compressed = file.as_os_str().to_os_string();
+    compressed.push(COMPRESSED_SUFFIX);
+    Ok(PathBuf::from(compressed))
+}
+
+/// Run zstd(1) quietly on 'file' with the arguments added by 'args'
+fn zstd(
+    file: &Path,
+    args: impl FnOnce(&mut std::process::Command) -> &mut std::process::Command,
+) -> Result<(), MigrationError> {
+    let mut command = std::process::Command::new("zstd");
+    command.arg("--quiet");
+    let output = args(&mut command)
+        .output()
+        .map_err(|err| MigrationError::io(file, err))?;
+    if !output.status.success() {
+        let message = format!(
+            "zstd failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return Err(MigrationError::io(file, std::io::Error::other(message)));
+    }
+    Ok(())
+}
+
+/// Create a new directory only accessible by the current user below 'parent', named 'prefix'
+/// followed by random characters, with mkdtemp(3)
+///
+/// Unlike a predictable name, nothing can be there already, like a symlink placed by another user.
+pub fn create_private_dir(parent: &Path, prefix: &str) -> Result<PathBuf, MigrationError> {
+    let template = parent.join(format!("{prefix}XXXXXX"));
+    let template = CString::new(template.into_os_string().into_vec())
+        .map_err(|err| MigrationError::io(parent, std::io::Error::other(err)))?;
+    let raw = template.into_raw();
+    let created = unsafe { libc::mkdtemp(raw) };
+    let error = std::io::Error::last_os_error();
+    let path = unsafe { CString::from_raw(raw) };
+    if created.is_null() {
+        return Err(MigrationError::io(parent, error));
+    }
+    Ok(PathBuf::from(OsString::from_vec(path.into_bytes())))
+}
+
+/// An old file librrd can read: the file itself, or for one compressed with [`compress`] an
+/// uncompressed copy in a private directory, which is removed again once this is dropped
+///
+/// The directory is created below $RUNTIME_DIRECTORY if systemd provides one, else below the
+/// temporary directory.
+#[derive(Debug)]
+pub struct Uncompressed {
+    path: PathBuf,
+    dir: Option<PathBuf>,
+}
+
+impl Uncompressed {
+    pub fn of(file: &Path) -> Result<Self, MigrationError> {
+        if !file
+            .as_os_str()
+            .as_bytes()
+            .ends_with(COMPRESSED_SUFFIX.as_bytes())
+        {
+            return Ok(Self {
+                path: file.to_path_buf(),
+                dir: None,
+            });
+        }
+        let parent = std::env::var_os("RUNTIME_DIRECTORY")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir);
+        let dir = create_private_dir(&parent, "proxmox-rrd-migration-")?;
+        let copy = Self {
+            path: dir.join("uncompressed.rrd"),
+            dir: Some(dir),
+        };
+        zstd(file, |command| {
+            command
+                .args(["--decompress", "-o"])
+                .arg(&copy.path)
+                .arg(file)
+        })?;
+        Ok(copy)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Uncompressed {
+    fn drop(&mut self) {
+        if let Some(ref dir) = self.dir {
+            let _ = fs::remove_file(&self.path);
+            let _ = fs::remove_dir(dir);
+        }
+    }
+}
+
+/// Marks the copies of targets overwritten with --force, followed by the time in seconds
+pub const TARGET_BACKUP_INFIX: &str = ".bak.";
+
+/// The path [`mv_bak`] keeps 'target' at, `<name>.bak.<timestamp>`
+pub fn backup_path(target: &Path) -> PathBuf {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut backup = target.as_os_str().to_os_string();
+    backup.push(format!("{TARGET_BACKUP_INFIX}{now}"));
+    PathBuf::from(backup)
+}
+
+/// Keep an existing target that is about to be overwritten as `<name>.bak.<timestamp>`
+pub fn mv_bak(target: &Path) -> Result<PathBuf, MigrationError> {
+    let backup = backup_path(target);
+    move_file(target, &backup)?;
+    Ok(backup)
+}
+
+/// Whether 'target' was modified after 'source', so that migrating it again would not add anything
+pub fn is_up_to_date(source: &Path, target: &Path) -> Result<bool, MigrationError> {
+    is_up_to_date_with(&Librrd::default(), source, target)
+}
+
+/// [`is_up_to_date`] with the files of 'backend'
+pub fn is_up_to_date_with(
+    backend: &dyn RrdBackend,
+    source: &Path,
+    target: &Path,
+) -> Result<bool, MigrationError> {
+    Ok(backend.modified(target)? >= backend.modified(source)?)
+}
+
+/// Whether 'name' is that of a target kept by [`mv_bak`]
+pub fn is_target_backup(name: &OsStr) -> bool {
+    name.to_string_lossy().contains(TARGET_BACKUP_INFIX)
+}
+
+/// Size of the static header every RRD file starts with, smaller files cannot be RRD files
+pub const MIN_RRD_SIZE: u64 = 128;
+
+fn file_len(fs: &dyn Filesystem, file: &Path) -> u64 {
+    fs.metadata(file).map(|metadata| metadata.len).unwrap_or(0)
+}
+
+/// The path and file name of 'file' as [`RRDFile`], if it has a file name
+fn rrd_file(file: &Path) -> Option<RRDFile> {
+    let path = CString::new(file.as_os_str().as_bytes()).ok()?;
+    Some((path, file.file_name()?.to_os_string()))
+}
+
+/// Name of the file recording which host migrated a source directory, it is not an RRD file
+pub const MIGRATION_MARKER: &str = ".migrated-by";
+
+/// Whether 'file' can be a current RRD file, not the migration marker, a superseded file or an
+/// old one marked with 'old_suffix', compressed or not
+fn is_candidate(fs: &dyn Filesystem, file: &Path, old_suffix: &str) -> bool {
+    let Some(name) = file.file_name() else {
+        return false;
+    };
+    let name = name.as_bytes();
+    let compressed = format!("{old_suffix}{COMPRESSED_SUFFIX}");
+    is_file(fs, file)
+        && name != MIGRATION_MARKER.as_bytes()
+        && !name.ends_with(old_suffix.as_bytes())
+        && !name.ends_with(compressed.as_bytes())
+        && !name.ends_with(b".superseded")
+}
+
+fn is_file(fs: &dyn Filesystem, file: &Path) -> bool {
+    fs.metadata(file).is_ok_and(|metadata| metadata.is_file)
+}
+
+/// The entries of 'location', none if it does not exist
+fn read_dir(fs: &dyn Filesystem, location: &Path) -> Result<Vec<PathBuf>, MigrationError> {
+    match fs.read_dir(location) {
+        Err(err) if err.is_not_found() => Ok(Vec::new()),
+        result => result,
+    }
+}
+
+/// Colllect the files in the provided directory that [`collect_rrd_files`] skips because they
+/// are smaller than [`MIN_RRD_SIZE`], with their size
+pub fn collect_unusable_rrd_files(location: &Path) -> Result<Vec<(RRDFile, u64)>, MigrationError> {
+    collect_unusable_rrd_files_with(&StdFilesystem, location, OLD_SUFFIX)
+}
+
+/// [`collect_unusable_rrd_files`] on the file system 'fs', for old files marked with 'old_suffix'
+pub fn collect_unusable_rrd_files_with(
+    fs: &dyn Filesystem,
+    location: &Path,
+    old_suffix: &str,
+) -> Result<Vec<(RRDFile, u64)>, MigrationError> {
+    Ok(read_dir(fs, location)?
+        .into_iter()
+        .filter(|f| is_candidate(fs, f, old_suffix))
+        .map(|f| (file_len(fs, &f), f))
+        .filter(|(len, _)| *len < MIN_RRD_SIZE)
+        .filter_map(|(len, f)| Some((rrd_file(&f)?, len)))
+        .collect())
+}
+
+/// Colllect all RRD files in the provided directory
+///
+/// Files too small to be RRD files are skipped, see [`collect_unusable_rrd_files`], as are old
+/// files marked with [`OLD_SUFFIX`].
+pub fn collect_rrd_files(location: &Path) -> Result<Vec<RRDFile>, MigrationError> {
+    collect_rrd_files_with(&StdFilesystem, location, OLD_SUFFIX)
+}
+
+/// [`collect_rrd_files`] on the file system 'fs', for old files marked with 'old_suffix'
+pub fn collect_rrd_files_with(
+    fs: &dyn Filesystem,
+    location: &Path,
+    old_suffix: &str,
+) -> Result<Vec<RRDFile>, MigrationError> {
+    Ok(read_dir(fs, location)?
+        .into_iter()
+        .filter(|f| is_candidate(fs, f, old_suffix))
+        .filter(|f| file_len(fs, f) >= MIN_RRD_SIZE)
+        .filter_map(|file| rrd_file(&file))
+        .collect())
+}
+
+/// Collect the RRD files in the provided directory that were renamed to old by appending
+/// 'suffix', with the suffix stripped from their resource name
+pub fn collect_old_rrd_files(
+    location: &Path,
+    suffix: &str,
+) -> Result<Vec<RRDFile>, MigrationError> {
+    collect_old_rrd_files_with(&StdFilesystem, location, suffix)
+}
+
+/// [`collect_old_rrd_files`] on the file system 'fs'
+pub fn collect_old_rrd_files_with(
+    fs: &dyn Filesystem,
+    location: &Path,
+    suffix: &str,
+) -> Result<Vec<RRDFile>, MigrationError> {
+    let mut files = Vec::new();
+    for file in read_dir(fs, location)? {
+        let Some(name) = file.file_name() else {
+            continue;
+        };
+        let Some(resource) = name.as_bytes().strip_suffix(suffix.as_bytes()) else {
+            continue;
+        };
+        if resource.is_empty() || !is_file(fs, &file) {
+            continue;
+        }
+        let Ok(path) = CString::new(file.as_os_str().as_bytes()) else {
+            continue;
+        };
+        files.push((path, OsStr::from_bytes(resource).to_os_string()));
+    }
+    Ok(files)
+}
+
+/// The first bytes of every RRD file
+const RRD_MAGIC: &[u8; 4] = b"RRD\0";
+
+/// Check that 'path' is an RRD file whose header librrd can read and that is as long as the
+/// header says
+pub(crate) fn check_file(path: &Path, resource: &OsStr) -> Result<(), MigrationError> {
+    let corrupt = |message: String| MigrationError::Corrupt {
+        resource: resource.to_os_string(),
+        message,
+    };
+    let mut magic = [0u8; 4];
+    let len = match fs::File::open(path).and_then(|mut file| {
+        file.read_exact(&mut magic)?;
+        Ok(file.metadata()?.len())
+    }) {
+        Ok(len) => len,
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+            return Err(corrupt("too short for an RRD file".to_string()));
+        }
+        Err(err) => return Err(MigrationError::io(path, err)),
+    };
+    if &magic != RRD_MAGIC {
+        return Err(corrupt("not an RRD file".to_string()));
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let mut header_size = 0;
+    let mut data_sources = 0;
+    let mut rows = 0;
+    unsafe {
+        clear_rrd_error();
+        let info = rrd_info_r(c_path.as_ptr());
+        if info.is_null() {
+            return Err(corrupt(rrd_error()));
+        }
+        let mut entry = info;
+        while !entry.is_null() {
+            let key = CStr::from_ptr((*entry).key).to_string_lossy();
+            let count = ((*entry).type_ == rrd_info_type_RD_I_CNT).then(|| (*entry).value.u_cnt);
+            if key == "header_size" {
+                header_size = count.unwrap_or_default();
+            } else if key.starts_with("ds[") && key.ends_with("].type") {
+                data_sources += 1;
+            } else if key.starts_with("rra[") && key.ends_with("].rows") {
+                rows += count.unwrap_or_default();
+            }
+            entry = (*entry).next;
+        }
+        rrd_info_free(info);
+    }
+    // one double per data source and row follows the header
+    let expected = header_size + rows * data_sources * 8;
+    if len < expected {
+        return Err(corrupt(format!("truncated, {len} of {expected} bytes")));
+    }
+    Ok(())
+}
+
+/// Check that 'file' is an RRD file librrd can read completely, before migrating it
+pub fn check_source(file: &RRDFile) -> Result<(), MigrationError> {
+    check_file(Path::new(OsStr::from_bytes(file.0.as_bytes())), &file.1)
+}
+
+/// Check that the existing 'target' was written completely, and not left behind half-written
+pub fn check_target(target: &Path) -> Result<(), MigrationError> {
+    check_file(target, target.file_name().unwrap_or_default())
+}
+
+/// Version of the librrd in use, like 1.7.2
+pub fn librrd_version() -> String {
+    unsafe { CStr::from_ptr(rrd_strversion()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Time of the last update of the RRD file 'path', in seconds since the epoch
+pub fn last_update(path: &Path) -> Result<i64, MigrationError> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let mut last_update = 0;
+    let mut ds_count = 0;
+    let mut ds_names = std::ptr::null_mut();
+    let mut last_ds = std::ptr::null_mut();
+    unsafe {
+        clear_rrd_error();
+        let res = rrd_lastupdate_r(
+            c_path.as_ptr(),
+            &mut last_update,
+            &mut ds_count,
+            &mut ds_names,
+            &mut last_ds,
+        );
+        if res != 0 {
+            return Err(MigrationError::Rrd {
+                resource: path.file_name().unwrap_or_default().to_os_string(),
+                message: rrd_error(),
+            });
+        }
+        for index in 0..ds_count as usize {
+            rrd_freemem((*ds_names.add(index)).cast());
+            rrd_freemem((*last_ds.add(index)).cast());
+        }
+        rrd_freemem(ds_names.cast());
+        rrd_freemem(last_ds.cast());
+    }
+    Ok(last_update)
+}
+
+thread_local! {
+    /// Whether [`init_rrd_thread`] set up the librrd context of the current thread
+    static RRD_CONTEXT: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Serializes the librrd calls without a thread-safe `_r` variant, like rrd_restore
+static RRD_NOT_REENTRANT: Mutex<()> = Mutex::new(());
+
+/// Set up the librrd context of the calling thread, once
+///
+/// The thread-safe `_r` functions of librrd keep their error message in a context per thread,
+/// which [`rrd_get_context`] creates on first use. The migration relies on every thread only
+/// ever reading the error of its own calls, so the context is set up before a thread's first
+/// call and the error cleared before each one, never shared or freed while the thread runs.
+/// Worker pools call this when their threads start, all other threads get it from
+/// [`clear_rrd_error`].
+pub fn init_rrd_thread() {
+    RRD_CONTEXT.with(|ready| {
+        if !ready.get() {
+            unsafe { rrd_get_context() };
+            ready.set(true);
+        }
+    });
+}
+
+/// Clear the error of the calling thread before a librrd call
+pub(crate) fn clear_rrd_error() {
+    init_rrd_thread();
+    unsafe { rrd_clear_error() };
+}
+
+/// The error librrd set for the last call in this thread
+unsafe fn rrd_error() -> String {
+    CStr::from_ptr(rrd_get_error())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Make a dump of a damaged file with 'rras' RRAs loadable again
+///
+/// A dump that ends in the middle of its last RRA is cut after the last complete row and its open
+/// elements are closed, dropping the rows that came after. Fails if whole RRAs would be lost
+/// that way, or if not a single row is left.
+fn sanitize_dump(xml: &[u8], rras: usize) -> Result<String, String> {
+    let xml: Vec<u8> = xml.iter().copied().filter(|byte| *byte != 0).collect();
+    let xml = String::from_utf8_lossy(&xml);
+    if xml.trim_end().ends_with("</rrd>") {
+        return Ok(xml.into_owned());
+    }
+    let Some(end) = xml.rfind("</row>") else {
+        return Err("no complete row left in the dump".to_string());
+    };
+    let mut sanitized = xml[..end + "</row>".len()].to_string();
+    let kept = sanitized.matches("<rra>").count();
+    if kept < rras {
+        return Err(format!(
+            "the dump ends in RRA {kept} of {rras}, the {} after it would be lost",
+            rras - kept
+        ));
+    }
+    sanitized.push_str("\n</database>\n</rra>\n</rrd>\n");
+    Ok(sanitized)
+}
+
+/// Dump 'source' to 'xml', sanitize the dump and restore it as 'target'
+fn dump_and_restore(
+    source: &CStr,
+    xml: &Path,
+    target: &CStr,
+    resource: &OsStr,
+) -> Result<(), MigrationError> {
+    let failed = |message: String| MigrationError::Rrd {
+        resource: resource.to_os_string(),
+        message,
+    };
+    let rras = inspect_file(Path::new(OsStr::from_bytes(source.to_bytes())))
+        .map_err(|err| failed(format!("cannot read its RRAs: {err}")))?
+        .rras
+        .len();
+    let xml_path = CString::new(xml.as_os_str().as_bytes()).unwrap();
+    unsafe {
+        clear_rrd_error();
+        // a dump cut short by a read error is still worth sanitizing
+        if rrd_dump_r(source.as_ptr(), xml_path.as_ptr().cast_mut()) != 0 && !xml.exists() {
+            return Err(failed(format!("dump failed: {}", rrd_error())));
+        }
+    }
+    let dump = fs::read(xml).map_err(|err| MigrationError::io(xml, err))?;
+    let sanitized = sanitize_dump(&dump, rras).map_err(failed)?;
+    fs::write(xml, sanitized).map_err(|err| MigrationError::io(xml, err))?;
+
+    let command = CString::new("restore").unwrap();
+    let mut argv = [
+        command.as_ptr().cast_mut(),
+        xml_path.as_ptr().cast_mut(),
+        target.as_ptr().cast_mut(),
+    ];
+    // librrd does not promise that rrd_restore is thread-safe
+    let _guard = RRD_NOT_REENTRANT
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    unsafe {
+        clear_rrd_error();
+        if rrd_restore(argv.len() as i32, argv.as_mut_ptr()) != 0 {
+            return Err(failed(format!("restore failed: {}", rrd_error())));
+        }
+    }
+    Ok(())
+}
+
+/// Recreate the RRD file 'damaged' as 'repaired', from what rrd_dump can still read of it
+///
+/// The intermediate XML dump is written next to 'damaged' and removed again.
+pub fn repair(damaged: &Path, repaired: &Path) -> Result<(), MigrationError> {
+    let resource = repaired.file_name().unwrap_or_default();
+    let mut xml = damaged.as_os_str().to_os_string();
+    xml.push(".xml");
+    let xml = PathBuf::from(xml);
+    let source = CString::new(damaged.as_os_str().as_bytes()).unwrap();
+    let target = CString::new(repaired.as_os_str().as_bytes()).unwrap();
+
+    let result = dump_and_restore(&source, &xml, &target, resource);
+    let _ = fs::remove_file(&xml);
+    result
+}
+
+/// Migrate a single RRD file into 'target_location', using the schema 'rrd_def'
+///
+/// Nothing is changed unless 'migrate' is set. An existing target is only overwritten with
+/// 'force'.
+pub fn migrate_file(
+    file: &RRDFile,
+    target_location: &Path,
+    rrd_def: &[&CStr],
+    migrate: bool,
+    force: bool,
+) -> Result<(), MigrationError> {
+    migrate_file_with(
+        &Librrd::default(),
+        file,
+        target_location,
+        rrd_def,
+        migrate,
+        force,
+    )
+}
+
+/// [`migrate_file`] with the files of 'backend'
+pub fn migrate_file_with(
+    backend: &dyn RrdBackend,
+    file: &RRDFile,
+    target_location: &Path,
+    rrd_def: &[&CStr],
+    migrate: bool,
+    force: bool,
+) -> Result<(), MigrationError> {
+    let resource = &file.1;
+    let target_path = target_location.join(resource);
+
+    if !migrate {
+        return Err(MigrationError::DryRun {
+            resource: resource.clone(),
+        });
+    } else if backend.exists(&target_path) && !force {
+        return Err(MigrationError::AlreadyMigrated {
+            resource: resource.clone(),
+        });
+    }
+
+    let source = Path::new(OsStr::from_bytes(file.0.as_bytes()));
+    backend.create(source, &target_path, rrd_def)
+}
+
+/// Migrate 'file' like [`migrate_file_with`], keeping an existing target until 'accept' accepted
+/// the new one
+///
+/// An existing target is only replaced with 'overwrite', it is moved to `<name>.bak.<timestamp>`
+/// meanwhile. If migrating fails or 'accept' returns an error, the new target is removed and the
+/// existing one moved back. Otherwise the backup is removed, it is returned with 'keep_backup' or
+/// if removing it failed.
+pub fn replace_file_with(
+    backend: &dyn RrdBackend,
+    file: &RRDFile,
+    target_location: &Path,
+    rrd_def: &[&CStr],
+    overwrite: bool,
+    keep_backup: bool,
+    accept: impl FnOnce(&Path) -> Result<(), MigrationError>,
+) -> Result<Option<PathBuf>, MigrationError> {
+    let target = target_location.join(&file.1);
+    let backup = if overwrite && backend.exists(&target) {
+        let backup = backup_path(&target);
+        backend.rename(&target, &backup)?;
+        Some(backup)
+    } else {
+        None
+    };
+    let result = migrate_file_with(backend, file, target_location, rrd_def, true, overwrite)
+        .and_then(|()| {
+            accept(&target).inspect_err(|_| {
+                let _ = backend.remove(&target);
+            })
+        });
+    match (result, backup) {
+        (Err(err), Some(backup)) => {
+            // the backup is the only copy left then, its path tells where it is
+            backend.rename(&backup, &target)?;
+            Err(err)
+        }
+        (Err(err), None) => Err(err),
+        (Ok(()), Some(backup)) if !keep_backup && backend.remove(&backup).is_ok() => Ok(None),
+        (Ok(()), backup) => Ok(backup),
+    }
+}
+
+/// Create the empty RRD file 'path' with the schema 'rrd_def', last updated at 'start'
+///
+/// For generating source files, see the generate-fixtures example.
+pub fn create_file(path: &Path, rrd_def: &[&CStr], start: i64) -> Result<(), MigrationError> {
+    definition::validate(rrd_def)?;
+    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    unsafe {
+        clear_rrd_error();
+        let res = rrd_create_r2(
+            c_path.as_ptr(),
+            RRD_STEP_SIZE as u64,
+            start,
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            rrd_def.len() as i32,
+            rrd_def
+                .iter()
+                .map(|v| v.as_ptr())
+                .collect::<Vec<_>>()
+                .as_mut_ptr(),
+        );
+        if res != 0 {
+            return Err(MigrationError::Rrd {
+                resource: path.file_name().unwrap_or_default().to_os_string(),
+                message: rrd_error(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Add the 'updates' to the RRD file 'path', each like `<time>:<value>:<value>...` with the
+/// values in the order of its data sources
+pub fn update_file(path: &Path, updates: &[String]) -> Result<(), MigrationError> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let updates: Vec<CString> = updates
+        .iter()
+        .map(|update| CString::new(update.as_str()).unwrap())
+        .collect();
+    let mut argv: Vec<*const i8> = updates.iter().map(|update| update.as_ptr()).collect();
+    unsafe {
+        clear_rrd_error();
+        let res = rrd_update_r(
+            c_path.as_ptr(),
+            std::ptr::null(),
+            argv.len() as i32,
+            argv.as_mut_ptr(),
+        );
+        if res != 0 {
+            return Err(MigrationError::Rrd {
+                resource: path.file_name().unwrap_or_default().to_os_string(),
+                message: rrd_error(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Check that the migrated file 'target' has the step size, the data sources and the RRAs with the
+/// rows of 'rrd_def', and holds the data of 'source' up to its last update
+pub fn verify_file(source: &Path, target: &Path, rrd_def: &[&CStr]) -> Result<(), MigrationError> {
+    let resource = target.file_name().unwrap_or_default().to_os_string();
+    let invalid = |message: String| MigrationError::Verification {
+        resource: resource.clone(),
+        message,
+    };
+    let source = CString::new(source.as_os_str().as_bytes()).unwrap();
+    let target = CString::new(target.as_os_str().as_bytes()).unwrap();
+
+    let mut step = None;
+    let mut data_sources = BTreeSet::new();
+    // the rows of each RRA by its index
+    let mut rras = BTreeMap::new();
+    let (source_last, target_last) = unsafe {
+        clear_rrd_error();
+        let info = rrd_info_r(target.as_ptr());
+        if info.is_null() {
+            return Err(MigrationError::Rrd {
+                resource,
+                message: CStr::from_ptr(rrd_get_error())
+                    .to_string_lossy()
+                    .into_owned(),
+            });
+        }
+        let mut entry = info;
+        while !entry.is_null() {
+            let key = CStr::from_ptr((*entry).key).to_string_lossy();
+            if key == "step" && (*entry).type_ == rrd_info_type_RD_I_CNT {
+                step = Some((*entry).value.u_cnt as usize);
+            } else if let Some((name, "type")) =
+                key.strip_prefix("ds[").and_then(|key| key.split_once("]."))
+            {
+                data_sources.insert(name.to_string());
+            } else if let Some((index, field)) = key
+                .strip_prefix("rra[")
+                .and_then(|key| key.split_once("]."))
+                .and_then(|(index, field)| Some((index.parse::<usize>().ok()?, field)))
+            {
+                let rows = rras.entry(index).or_insert(0);
+                if field == "rows" && (*entry).type_ == rrd_info_type_RD_I_CNT {
+                    *rows = (*entry).value.u_cnt;
+                }
+            }
+            entry = (*entry).next;
+        }
+        rrd_info_free(info);
+        (rrd_last_r(source.as_ptr()), rrd_last_r(target.as_ptr()))
+    };
+
+    if step != Some(RRD_STEP_SIZE) {
+        return Err(invalid(format!(
+            "step size is {step:?} instead of {RRD_STEP_SIZE}"
+        )));
+    }
+    let expected: BTreeSet<String> = rrd_def
+        .iter()
+        .filter_map(|def| def.to_str().ok()?.strip_prefix("DS:"))
+        .filter_map(|def| Some(def.split_once(':')?.0.to_string()))
+        .collect();
+    if data_sources != expected {
+        let missing: Vec<_> = expected.difference(&data_sources).collect();
+        let unexpected: Vec<_> = data_sources.difference(&expected).collect();
+        return Err(invalid(format!(
+            "data sources differ, missing {missing:?}, unexpected {unexpected:?}"
+        )));
+    }
+    let (_, expected_rras) = def_schema(rrd_def);
+    if rras.len() != expected_rras.len() {
+        return Err(invalid(format!(
+            "{} RRAs instead of {}",
+            rras.len(),
+            expected_rras.len()
+        )));
+    }
+    for ((index, rows), expected) in rras.iter().zip(&expected_rras) {
+        if *rows != expected.rows {
+            return Err(invalid(format!(
+                "RRA {index} has {rows} rows instead of {}",
+                expected.rows
+            )));
+        }
+    }
+    if source_last < 0 || target_last < source_last {
+        return Err(invalid(format!(
+            "last update at {target_last}, but the source was last updated at {source_last}"
+        )));
+    }
+    Ok(())
+}
+
+/// A data source of an RRD file, see [`inspect_file`]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DataSourceInfo {
+    pub name: String,
+    /// GAUGE, DERIVE, COUNTER or ABSOLUTE
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub heartbeat: u64,
+    /// [`None`] for U, no limit
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+/// An RRA of an RRD file, see [`inspect_file`]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RraInfo {
+    /// AVERAGE, MAX, MIN or LAST
+    pub cf: String,
+    pub pdp_per_row: u64,
+    pub rows: u64,
+    pub xff: f64,
+}
+
+/// The metadata of an RRD file, what rrdtool info prints without the values of the data sources
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RrdInfo {
+    pub version: String,
+    /// in seconds
+    pub step: u64,
+    /// in seconds since the epoch
+    pub last_update: i64,
+    pub header_size: u64,
+    /// in the order of their index
+    pub data_sources: Vec<DataSourceInfo>,
+    pub rras: Vec<RraInfo>,
+}
+
+/// Read the step, data sources, RRAs and last update of the RRD file 'path'
+pub fn inspect_file(path: &Path) -> Result<RrdInfo, MigrationError> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let mut info = RrdInfo {
+        version: String::new(),
+        step: 0,
+        last_update: 0,
+        header_size: 0,
+        data_sources: Vec::new(),
+        rras: Vec::new(),
+    };
+    unsafe {
+        clear_rrd_error();
+        let entries = rrd_info_r(c_path.as_ptr());
+        if entries.is_null() {
+            return Err(MigrationError::Rrd {
+                resource: path.file_name().unwrap_or_default().to_os_string(),
+                message: rrd_error(),
+            });
+        }
+        let mut entry = entries;
+        while !entry.is_null() {
+            let key = CStr::from_ptr((*entry).key).to_string_lossy();
+            let value = &(*entry).value;
+            let (count, number, string) = match (*entry).type_ {
+                rrd_info_type_RD_I_CNT => (value.u_cnt, None, None),
+                rrd_info_type_RD_I_VAL => (0, Some(value.u_val).filter(|v| !v.is_nan()), None),
+                rrd_info_type_RD_I_STR => {
+                    let string = CStr::from_ptr(value.u_str).to_string_lossy();
+                    (0, None, Some(string.into_owned()))
+                }
+                _ => (0, None, None),
+            };
+            match key.as_ref() {
+                "rrd_version" => info.version = string.clone().unwrap_or_default(),
+                "step" => info.step = count,
+                "last_update" => info.last_update = count as i64,
+                "header_size" => info.header_size = count,
+                _ => {}
+            }
+            if let Some((name, field)) =
+                key.strip_prefix("ds[").and_then(|key| key.split_once("]."))
+            {
+                if info.data_sources.last().is_none_or(|ds| ds.name != name) {
+                    info.data_sources.push(DataSourceInfo {
+                        name: name.to_string(),
+                        kind: String::new(),
+                        heartbeat: 0,
+                        min: None,
+                        max: None,
+                    });
+                }
+                let ds = info.data_sources.last_mut().unwrap();
+                match field {
+                    "type" => ds.kind = string.unwrap_or_default(),
+                    "minimal_heartbeat" => ds.heartbeat = count,
+                    "min" => ds.min = number,
+                    "max" => ds.max = number,
+                    _ => {}
+                }
+            } else if let Some((index, field)) = key
+                .strip_prefix("rra[")
+                .and_then(|key| key.split_once("]."))
+                .and_then(|(index, field)| Some((index.parse::<usize>().ok()?, field)))
+            {
+                if info.rras.len() <= index {
+                    info.rras.resize(
+                        index + 1,
+                        RraInfo {
+                            cf: String::new(),
+                            pdp_per_row: 0,
+                            rows: 0,
+                            xff: 0.0,
+                        },
+                    );
+                }
+                let rra = &mut info.rras[index];
+                match field {
+                    "cf" => rra.cf = string.unwrap_or_default(),
+                    "pdp_per_row" => rra.pdp_per_row = count,
+                    "rows" => rra.rows = count,
+                    "xff" => rra.xff = number.unwrap_or_default(),
+                    _ => {}
+                }
+            }
+            entry = (*entry).next;
+        }
+        rrd_info_free(entries);
+    }
+    Ok(info)
+}
+
+/// The data sources and RRAs of the schema 'rrd_def', like [`inspect_file`] reads them from a file
+/// created with it
+pub fn def_schema(rrd_def: &[&CStr]) -> (Vec<DataSourceInfo>, Vec<RraInfo>) {
+    let mut data_sources = Vec::new();
+    let mut rras = Vec::new();
+    for def in rrd_def {
+        match definition::parse(&def.to_string_lossy()) {
+            Ok(Definition::DataSource(ds)) => data_sources.push(ds),
+            Ok(Definition::Rra(rra)) => rras.push(rra),
+            Err(_) => {}
+        }
+    }
+    (data_sources, rras)
+}
+
+/// Values of an RRD file, see [`fetch_file`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct FetchedData {
+    /// in seconds since the epoch, the first row holds the values of the step after it
+    pub start: i64,
+    /// in seconds
+    pub step: u64,
+    pub data_sources: Vec<String>,
+    /// a value for each of the data sources per step, NaN if unknown
+    pub rows: Vec<Vec<f64>>,
+}
+
+impl FetchedData {
+    /// The row with the values of the step that ends at or after 'time'
+    pub fn row_at(&self, time: i64) -> Option<&[f64]> {
+        if time <= self.start || self.step == 0 {
+            return None;
+        }
+        let index = (time - self.start - 1) as u64 / self.step;
+        self.rows.get(index as usize).map(Vec::as_slice)
+    }
+}
+
+/// Read the values consolidated with 'cf' between 'start' and 'end' from the RRD file 'path'
+///
+/// librrd picks the RRA with the finest resolution that covers the time range, and widens the
+/// range to whole steps of it.
+pub fn fetch_file(
+    path: &Path,
+    cf: &str,
+    start: i64,
+    end: i64,
+) -> Result<FetchedData, MigrationError> {
+    let resource = path.file_name().unwrap_or_default().to_os_string();
+    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let Ok(c_cf) = CString::new(cf) else {
+        return Err(MigrationError::Rrd {
+            resource,
+            message: format!("invalid consolidation function {cf:?}"),
+        });
+    };
+    let mut start = start;
+    let mut end = end;
+    let mut step = 0;
+    let mut ds_count = 0;
+    let mut ds_names = std::ptr::null_mut();
+    let mut data = std::ptr::null_mut();
+    unsafe {
+        clear_rrd_error();
+        let res = rrd_fetch_r(
+            c_path.as_ptr(),
+            c_cf.as_ptr(),
+            &mut start,
+            &mut end,
+            &mut step,
+            &mut ds_count,
+            &mut ds_names,
+            &mut data,
+        );
+        if res != 0 {
+            return Err(MigrationError::Rrd {
+                resource,
+                message: rrd_error(),
+            });
+        }
+        let ds_count = ds_count as usize;
+        let mut data_sources = Vec::with_capacity(ds_count);
+        for index in 0..ds_count {
+            let name = *ds_names.add(index);
+            data_sources.push(CStr::from_ptr(name).to_string_lossy().into_owned());
+            rrd_freemem(name.cast());
+        }
+        rrd_freemem(ds_names.cast());
+        let row_count = if step == 0 {
+            0
+        } else {
+            (end - start) as usize / step as usize
+        };
+        let mut rows = Vec::with_capacity(row_count);
+        if !data.is_null() {
+            if ds_count > 0 {
+                let values = std::slice::from_raw_parts(data, row_count * ds_count);
+                rows.extend(values.chunks(ds_count).map(<[f64]>::to_vec));
+            }
+            rrd_freemem(data.cast());
+        }
+        Ok(FetchedData {
+            start,
+            step,
+            data_sources,
+            rows,
+        })
+    }
+}
+
+/// Colors of the lines of [`graph_file`], one per data source
+#[cfg(not(feature = "static-rrd"))]
+const GRAPH_COLORS: [&str; 4] = ["#e57000", "#0070c0", "#00a000", "#a000a0"];
+
+/// Render the values of 'data_sources' of the RRD file 'path' between 'start' and 'end' as a
+/// small PNG graph into 'image', averaged like in the web interface
+///
+/// Not available with the static-rrd feature, that librrd is built without rrd_graph.
+#[cfg(not(feature = "static-rrd"))]
+pub fn graph_file(
+    path: &Path,
+    image: &Path,
+    title: &str,
+    data_sources: &[&str],
+    start: i64,
+    end: i64,
+) -> Result<(), MigrationError> {
+    let resource = path.file_name().unwrap_or_default().to_os_string();
+    // colons separate the fields of a DEF
+    let rrd = path.to_string_lossy().replace(':', "\\:");
+    let mut args = vec![
+        "graph".to_string(),
+        image.to_string_lossy().into_owned(),
+        "--imgformat".to_string(),
+        "PNG".to_string(),
+        "--start".to_string(),
+        start.to_string(),
+        "--end".to_string(),
+        end.to_string(),
+        "--width".to_string(),
+        "600".to_string(),
+        "--height".to_string(),
+        "150".to_string(),
+        "--title".to_string(),
+        title.to_string(),
+    ];
+    for (index, ds) in data_sources.iter().enumerate() {
+        let color = GRAPH_COLORS[index % GRAPH_COLORS.len()];
+        args.push(format!("DEF:v{index}={rrd}:{ds}:AVERAGE"));
+        args.push(format!("LINE1:v{index}{color}:{ds}"));
+    }
+    let Ok(args) = args
+        .into_iter()
+        .map(CString::new)
+        .collect::<Result<Vec<_>, _>>()
+    else {
+        return Err(MigrationError::Rrd {
+            resource,
+            message: "graph arguments contain a NUL byte".to_string(),
+        });
+    };
+    let mut argv: Vec<*mut std::os::raw::c_char> =
+        args.iter().map(|arg| arg.as_ptr().cast_mut()).collect();
+
+    let mut printed: *mut *mut std::os::raw::c_char = std::ptr::null_mut();
+    let (mut width, mut height) = (0, 0);
+    let (mut min, mut max) = (0.0, 0.0);
+    // librrd has no thread-safe variant of rrd_graph
+    let _guard = RRD_NOT_REENTRANT
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    unsafe {
+        clear_rrd_error();
+        let res = rrd_graph(
+            argv.len() as i32,
+            argv.as_mut_ptr(),
+            &mut printed,
+            &mut width,
+            &mut height,
+            std::ptr::null_mut(),
+            &mut min,
+            &mut max,
+        );
+        // the output of PRINT elements, there are none
+        if !printed.is_null() {
+            let mut line = printed;
+            while !(*line).is_null() {
+                rrd_freemem((*line).cast());
+                line = line.add(1);
+            }
+            rrd_freemem(printed.cast());
+        }
+        if res != 0 {
+            return Err(MigrationError::Rrd {
+                resource,
+                message: rrd_error(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A dump like rrd_dump writes it, with two RRAs of two rows each
+    const DUMP: &str = "\
+<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<rrd>
+\t<version>0003</version>
+\t<step>60</step> <!-- Seconds -->
+\t<lastupdate>1754000000</lastupdate> <!-- 2025-07-31 22:13:20 UTC -->
+\t<ds>
+\t\t<name> cpu </name>
+\t\t<type> GAUGE </type>
+\t\t<minimal_heartbeat>120</minimal_heartbeat>
+\t\t<last_ds>0.5</last_ds>
+\t</ds>
+\t<!-- Round Robin Archives -->
+\t<rra>
+\t\t<cf>AVERAGE</cf>
+\t\t<pdp_per_row>1</pdp_per_row> <!-- 60 seconds -->
+\t\t<params>
+\t\t<xff>5.0000000000e-01</xff>
+\t\t</params>
+\t\t<cdp_prep>
+\t\t\t<ds>
+\t\t\t<primary_value>5.0000000000e-01</primary_value>
+\t\t\t<unknown_datapoints>0</unknown_datapoints>
+\t\t\t</ds>
+\t\t</cdp_prep>
+\t\t<database>
+\t\t\t<!-- 2025-07-31 22:12:00 UTC / 1753999920 --> <row><v>1.0000000000e-01</v></row>
+\t\t\t<!-- 2025-07-31 22:13:00 UTC / 1753999980 --> <row><v>2.0000000000e-01</v></row>
+\t\t</database>
+\t</rra>
+\t<rra>
+\t\t<cf>MAX</cf>
+\t\t<pdp_per_row>1</pdp_per_row> <!-- 60 seconds -->
+\t\t<params>
+\t\t<xff>5.0000000000e-01</xff>
+\t\t</params>
+\t\t<cdp_prep>
+\t\t\t<ds>
+\t\t\t<primary_value>5.0000000000e-01</primary_value>
+\t\t\t<unknown_datapoints>0</unknown_datapoints>
+\t\t\t</ds>
+\t\t</cdp_prep>
+\t\t<database>
+\t\t\t<!-- 2025-07-31 22:12:00 UTC / 1753999920 --> <row><v>3.0000000000e-01</v></row>
+\t\t\t<!-- 2025-07-31 22:13:00 UTC / 1753999980 --> <row><v>4.0000000000e-01</v></row>
+\t\t</database>
+\t</rra>
+</rrd>
+";
+
+    /// 'DUMP' cut right after the 'nth' occurrence of 'marker'
+    fn cut_after(marker: &str, nth: usize) -> &'static str {
+        let (index, _) = DUMP
+            .match_indices(marker)
+            .nth(nth)
+            .expect("marker in the dump");
+        &DUMP[..index + marker.len()]
+    }
+
+    /// Whether each element the sanitizing closes is closed as often as it is opened
+    fn assert_balanced(xml: &str) {
+        for element in ["rrd", "rra", "params", "cdp_prep", "database", "row"] {
+            assert_eq!(
+                xml.matches(&format!("<{element}>")).count(),
+                xml.matches(&format!("</{element}>")).count(),
+                "<{element}> in {xml}"
+            );
+        }
+    }
+
+    #[test]
+    fn sanitize_complete_dump() {
+        assert_eq!(sanitize_dump(DUMP.as_bytes(), 2).as_deref(), Ok(DUMP));
+        // as rrd_dump leaves it after a read error
+        let padded = [DUMP.as_bytes(), &[0; 16]].concat();
+        assert_eq!(sanitize_dump(&padded, 2).as_deref(), Ok(DUMP));
+    }
+
+    #[test]
+    fn sanitize_cut_in_last_database() {
+        for cut in [
+            // inside a comment, a row and right after one, the first comment is of lastupdate
+            cut_after("<!-- 2025-07-31 22:13", 2),
+            cut_after("<v>4.00", 0),
+            cut_after("</row>", 3),
+            // after the database or the RRA
+            cut_after("</database>", 1),
+            cut_after("</rra>", 1),
+        ] {
+            let sanitized = sanitize_dump(cut.as_bytes(), 2).expect("sanitized");
+            assert_balanced(&sanitized);
+            assert!(sanitized.contains("<v>3.0000000000e-01</v>"));
+            assert_eq!(
+                sanitized.contains("<v>4.0000000000e-01</v>"),
+                cut.matches("</row>").count() == 4,
+                "{cut}"
+            );
+        }
+    }
+
+    #[test]
+    fn sanitize_cut_before_last_database() {
+        for cut in [
+            cut_after("<rra>", 1),
+            cut_after("<xff>5.0", 1),
+            cut_after("</params>", 1),
+            cut_after("<primary_value>", 1),
+            cut_after("</cdp_prep>", 1),
+            cut_after("<database>", 1),
+            cut_after("<!-- 2025-07-31 22:12", 1),
+        ] {
+            let err = sanitize_dump(cut.as_bytes(), 2).expect_err("whole RRA lost");
+            assert!(err.contains("RRA 1 of 2"), "{err}");
+        }
+    }
+
+    #[test]
+    fn sanitize_cut_before_any_row() {
+        for cut in [
+            cut_after("<ds>", 0),
+            cut_after("<params>", 0),
+            cut_after("<unknown_datapoints>", 0),
+            cut_after("<row><v>1.0", 0),
+        ] {
+            let err = sanitize_dump(cut.as_bytes(), 2).expect_err("no row left");
+            assert_eq!(err, "no complete row left in the dump");
+        }
+    }
+
+    #[test]
+    fn strip_trailing_commas_outside_strings() {
+        assert_eq!(
+            strip_trailing_commas("{\"a\": [1, 2,],\n}"),
+            "{\"a\": [1, 2]\n}"
+        );
+        assert_eq!(strip_trailing_commas("[1 ,\t\n ]"), "[1 \t\n ]");
+        // within strings, also after an escaped quote, they stay
+        assert_eq!(
+            strip_trailing_commas(r#"["a,}", "b\",]",]"#),
+            r#"["a,}", "b\",]"]"#
+        );
+        assert_eq!(strip_trailing_commas(r#"["a\\",]"#), r#"["a\\"]"#);
+        assert_eq!(strip_trailing_commas("[1, 2]"), "[1, 2]");
+    }
+
+    #[test]
+    fn parse_guests_as_pmxcfs_writes_them() {
+        let vmlist = "{\n\"version\": 7,\n\"ids\": {\n\
+            \"100\": { \"node\": \"pve1\", \"type\": \"qemu\", \"version\": 61 },\n\
+            \"1000\": { \"type\": \"lxc\", \"version\": 3 },\n\n}\n}\n";
+        let guests = parse_guests(vmlist).expect("parse .vmlist");
+        assert_eq!(guests.len(), 2);
+        assert_eq!(guests["100"].as_deref(), Some("pve1"));
+        assert_eq!(guests["1000"], None);
+        // a VMID that is only part of another one is not a guest
+        assert!(!guests.contains_key("10"));
+
+        let ids = parse_guest_ids("{\"version\": 1, \"ids\": {}}").expect("parse empty .vmlist");
+        assert!(ids.is_empty());
+    }
+
+    #[test]
+    fn parse_guests_fails_on_broken_list() {
+        for vmlist in ["", "{\"ids\": {\"100\": {}", "{\"version\": 7}", "100 101"] {
+            assert!(
+                matches!(
+                    parse_guests(vmlist),
+                    Err(MigrationError::InvalidResourceList {
+                        list: ".vmlist",
+                        ..
+                    })
+                ),
+                "{vmlist}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_members_of_cluster() {
+        let members = "{\n\"nodename\": \"pve1\",\n\"version\": 5,\n\
+            \"cluster\": { \"name\": \"test\", \"version\": 3, \"nodes\": 2, \"quorate\": 0 },\n\
+            \"nodelist\": {\n\
+            \"pve1\": { \"id\": 1, \"online\": 1, \"ip\": \"10.0.0.1\"},\n\
+            \"pve2\": { \"id\": 2, \"online\": 0, \"ip\": \"10.0.0.2\"},\n\
+            }\n}\n";
+        let membership = parse_members(members).expect("parse .members");
+        assert_eq!(membership.nodes.len(), 2);
+        assert!(membership.nodes["pve1"]);
+        assert!(!membership.nodes["pve2"]);
+        assert!(!membership.quorate);
+        assert_eq!(membership.nodename.as_deref(), Some("pve1"));
+    }
+
+    #[test]
+    fn parse_members_of_single_node() {
+        let membership =
+            parse_members("{ \"nodename\": \"pve1\", \"version\": 0 }").expect("parse .members");
+        assert_eq!(membership.nodes.len(), 1);
+        assert!(membership.nodes["pve1"]);
+        assert!(membership.quorate);
+    }
+
+    #[test]
+    fn parse_members_fails_on_broken_list() {
+        for members in ["", "{\"nodelist\": {\"pve1\": {}", "{\"nodelist\": []}"] {
+            assert!(
+                matches!(
+                    parse_members(members),
+                    Err(MigrationError::InvalidResourceList {
+                        list: ".members",
+                        ..
+                    })
+                ),
+                "{members}"
+            );
+        }
+    }
+
+    #[test]
+    fn valid_resource_names() {
+        let valid = |kind, name: &[u8]| is_valid_resource_name(kind, OsStr::from_bytes(name));
+        assert!(valid(ResourceType::Guest, b"100"));
+        assert!(valid(ResourceType::Guest, b"999999999"));
+        for name in [&b""[..], b"100.old", b"100~", b"vm-100", b" 100", b"10\xff"] {
+            assert!(!valid(ResourceType::Guest, name), "{name:?}");
+        }
+
+        assert!(valid(ResourceType::Node, b"pve1"));
+        assert!(valid(ResourceType::Node, b"node-a-1"));
+        assert!(valid(ResourceType::Node, b"1"));
+        for name in [
+            &b""[..],
+            b"-pve",
+            b"pve-",
+            b"pve_1",
+            b"pve.example.com",
+            b"pv\xc3\xa9",
+        ] {
+            assert!(!valid(ResourceType::Node, name), "{name:?}");
+        }
+
+        assert!(valid(ResourceType::Storage, b"local"));
+        assert!(valid(ResourceType::Storage, b"local-lvm"));
+        assert!(valid(ResourceType::Storage, b"nfs_backup.2"));
+        for name in [
+            &b""[..],
+            b"a",
+            b"1local",
+            b"local-",
+            b"local.",
+            b"lo cal",
+            b".hidden",
+        ] {
+            assert!(!valid(ResourceType::Storage, name), "{name:?}");
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! Minimal sd_notify implementation, to report the status to systemd and serve its watchdog
+
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Inner {
+    socket: UnixDatagram,
+    /// interval systemd expects a ping in, if its watchdog is enabled for us
+    watchdog: Option<Duration>,
+    last_ping: Mutex<Instant>,
+}
+
+/// Notifies systemd about our status, does nothing if not started by systemd with a notify socket
+#[derive(Clone, Debug, Default)]
+pub struct Notifier {
+    inner: Option<Arc<Inner>>,
+}
+
+impl Notifier {
+    /// Connect to the socket passed by systemd in NOTIFY_SOCKET, if any
+    pub fn from_env() -> io::Result<Self> {
+        let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+            return Ok(Self::default());
+        };
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        let socket = UnixDatagram::unbound()?;
+        socket.connect_addr(&addr)?;
+
+        Ok(Self {
+            inner: Some(Arc::new(Inner {
+                socket,
+                watchdog: watchdog_interval(),
+                last_ping: Mutex::new(Instant::now()),
+            })),
+        })
+    }
+
+    /// Set the free-form status shown by 'systemctl status'
+    pub fn status(&self, status: &str) {
+        self.send(&format!("STATUS={status}"));
+    }
+
+    /// Ping the watchdog if enabled and due, cheap enough to be called often
+    pub fn watchdog_ping(&self) {
+        let Some(inner) = &self.inner else {
+            return;
+        };
+        let Some(interval) = inner.watchdog else {
+            return;
+        };
+        let mut last_ping = inner.last_ping.lock().unwrap();
+        if last_ping.elapsed() >= interval / 2 {
+            *last_ping = Instant::now();
+            let _ = inner.socket.send(b"WATCHDOG=1");
+        }
+    }
+
+    fn send(&self, message: &str) {
+        if let Some(inner) = &self.inner {
+            let _ = inner.socket.send(message.as_bytes());
+        }
+    }
+}
+
+/// The watchdog interval from WATCHDOG_USEC, if it is meant for this process
+fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = std::env::var_os("WATCHDOG_PID") {
+        if pid.to_str()?.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec))
+}
@@ -1,10 +1,98 @@
 //! A thread pool which run a closure in parallel.
 
-use std::sync::{Arc, Mutex};
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, format_err, Error};
-use crossbeam_channel::{bounded, Sender};
+use crossbeam_channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender};
+
+/// How often idle workers check whether they should retire
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+/// How often the automatic scaling re-evaluates the number of workers
+const AUTOSCALE_INTERVAL: Duration = Duration::from_secs(5);
+
+thread_local! {
+    /// The state of the item the current worker processes
+    static CURRENT_ITEM: RefCell<Option<Arc<ItemState>>> = const { RefCell::new(None) };
+}
+
+const ITEM_RUNNING: u8 = 0;
+const ITEM_COMMITTED: u8 = 1;
+const ITEM_ABANDONED: u8 = 2;
+
+/// Whether an item is still being processed, was committed to, or was given up on
+///
+/// Committing and giving up exclude each other, so that a handler whose item was given up on
+/// can undo its side effects, and one that committed is not reported as given up on.
+#[derive(Debug, Default)]
+pub struct ItemState(AtomicU8);
+
+impl ItemState {
+    /// Give up on the item, returns false if it was committed to already
+    pub fn abandon(&self) -> bool {
+        self.0
+            .compare_exchange(
+                ITEM_RUNNING,
+                ITEM_ABANDONED,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_ok()
+    }
+
+    /// Commit to the outcome of the item, returns false if it was given up on already
+    pub fn commit(&self) -> bool {
+        match self.0.compare_exchange(
+            ITEM_RUNNING,
+            ITEM_COMMITTED,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => true,
+            Err(current) => current == ITEM_COMMITTED,
+        }
+    }
+
+    pub fn is_abandoned(&self) -> bool {
+        self.0.load(Ordering::SeqCst) == ITEM_ABANDONED
+    }
+
+    /// Start over with the next item of a batch, unless the batch was given up on
+    fn next_item(&self) {
+        let _ = self.0.compare_exchange(
+            ITEM_COMMITTED,
+            ITEM_RUNNING,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+    }
+}
+
+fn with_current_item<T>(f: impl FnOnce(&ItemState) -> T) -> Option<T> {
+    CURRENT_ITEM.with(|current| current.borrow().as_deref().map(f))
+}
+
+/// Returns true if the watchdog skipped the item the calling worker is processing
+///
+/// Handlers can use this after a long running call returned, to avoid
+/// side effects for an item that was already given up on.
+pub fn item_abandoned() -> bool {
+    with_current_item(ItemState::is_abandoned).unwrap_or(false)
+}
+
+/// Commit to the outcome of the item the calling worker is processing, see [`ItemState::commit`]
+///
+/// Returns false if the watchdog skipped it already, also outside of a pool true.
+pub fn commit_item() -> bool {
+    with_current_item(ItemState::commit).unwrap_or(true)
+}
 
 /// A handle to send data to the worker thread (implements clone)
 pub struct SendHandle<I> {
@@ -12,6 +100,58 @@ pub struct SendHandle<I> {
     abort: Arc<Mutex<Option<String>>>,
 }
 
+/// Items sent to the workers together, which cuts the synchronization per item when there are
+/// many small ones, see [`ParallelHandler::with_batched_results`]
+pub struct Batch<I>(pub Vec<I>);
+
+/// A batch of a single item looks like the item itself, in stall reports and panic messages
+impl<I: fmt::Debug> fmt::Debug for Batch<I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0[..] {
+            [item] => item.fmt(f),
+            items => f.debug_list().entries(items).finish(),
+        }
+    }
+}
+
+/// Collects items into batches of a fixed size before sending them to the worker threads
+pub struct BatchSender<I> {
+    handle: SendHandle<Batch<I>>,
+    size: usize,
+    batch: Vec<I>,
+}
+
+impl<I: Send> BatchSender<I> {
+    /// Send batches of 'size' items over 'handle'
+    pub fn new(handle: SendHandle<Batch<I>>, size: usize) -> Self {
+        Self {
+            handle,
+            size: size.max(1),
+            batch: Vec::new(),
+        }
+    }
+
+    /// Add 'input' to the current batch, which is sent once it is full
+    pub fn send(&mut self, input: I) -> Result<(), Error> {
+        self.batch.push(input);
+        if self.batch.len() >= self.size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Send the current batch, even if it is not full yet
+    ///
+    /// Needs to be called before waiting for the results of the items sent so far.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(self.size));
+        self.handle.send(Batch(batch))
+    }
+}
+
 /// Returns the first error happened, if any
 pub fn check_abort(abort: &Mutex<Option<String>>) -> Result<(), Error> {
     let guard = abort.lock().unwrap();
@@ -21,6 +161,291 @@ pub fn check_abort(abort: &Mutex<Option<String>>) -> Result<(), Error> {
     Ok(())
 }
 
+/// Error reported for an item whose handler panicked
+#[derive(Debug)]
+pub struct PanicError {
+    /// Debug representation of the item that was processed
+    pub item: String,
+    /// The panic message, if it could be extracted
+    pub message: Option<String>,
+}
+
+impl fmt::Display for PanicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.message {
+            Some(message) => write!(f, "processing {} panicked: {message}", self.item),
+            None => write!(f, "processing {} panicked", self.item),
+        }
+    }
+}
+
+impl std::error::Error for PanicError {}
+
+fn panic_message(panic: &(dyn Any + Send)) -> Option<String> {
+    if let Some(panic_msg) = panic.downcast_ref::<&str>() {
+        Some(panic_msg.to_string())
+    } else {
+        panic.downcast_ref::<String>().cloned()
+    }
+}
+
+/// Snapshot of the utilization of a thread pool
+#[derive(Clone, Copy, Debug)]
+pub struct PoolStats {
+    /// Number of worker threads the pool is currently scaled to
+    pub threads: usize,
+    /// Number of items waiting in the queue
+    pub queued: usize,
+    /// Number of items processed so far
+    pub completed: usize,
+    /// Time spent in the handler, summed up over all items
+    pub busy: Duration,
+}
+
+/// Item that is taking longer than the watchdog timeout
+#[derive(Clone, Debug)]
+pub struct StalledItem {
+    /// Name of the worker thread processing the item
+    pub thread: String,
+    /// Debug representation of the item
+    pub item: String,
+    /// Time spent on the item so far
+    pub elapsed: Duration,
+    /// Whether the pool gave up on the item and continues without it
+    pub skipped: bool,
+}
+
+/// Item a worker is currently processing
+struct WorkerSlot {
+    thread: String,
+    item: String,
+    started: Instant,
+    reported: bool,
+    state: Arc<ItemState>,
+}
+
+type SpawnFn<I> = dyn Fn(Arc<PoolState<I>>, usize) -> JoinHandle<()> + Send;
+
+/// State shared between the pool handle, the workers and the helper threads
+struct PoolState<I> {
+    name: String,
+    input: Receiver<I>,
+    spawn_fn: Mutex<Box<SpawnFn<I>>>,
+    handles: Mutex<Vec<(usize, JoinHandle<()>)>>,
+    slots: Mutex<HashMap<usize, WorkerSlot>>,
+    panics: Mutex<Vec<String>>,
+    /// number of workers the pool should be running
+    target: AtomicUsize,
+    /// number of workers that have not retired yet
+    running: AtomicUsize,
+    /// notified under 'running_lock' whenever a worker retired, see 'wait_for_workers()'
+    running_changed: Condvar,
+    running_lock: Mutex<()>,
+    next_id: AtomicUsize,
+    completed: AtomicUsize,
+    busy_nanos: AtomicU64,
+    finished: AtomicBool,
+    /// run by each worker before its first item, see 'thread_init()'
+    thread_init: Mutex<Option<fn()>>,
+}
+
+impl<I> PoolState<I> {
+    fn spawn_worker(self: &Arc<Self>) {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.running.fetch_add(1, Ordering::SeqCst);
+        let handle = (self.spawn_fn.lock().unwrap())(Arc::clone(self), id);
+        self.handles.lock().unwrap().push((id, handle));
+    }
+
+    /// Report items exceeding 'timeout' and give up on the ones exceeding 'skip_after'
+    ///
+    /// A worker processing a skipped item is detached from the pool and a
+    /// replacement is started, so the remaining items are not held up.
+    fn check_stalled(
+        self: &Arc<Self>,
+        timeout: Duration,
+        skip_after: Option<Duration>,
+    ) -> Vec<StalledItem> {
+        let mut stalled = Vec::new();
+        let mut skipped_workers = Vec::new();
+
+        let mut slots = self.slots.lock().unwrap();
+        for (id, slot) in slots.iter_mut() {
+            let elapsed = slot.started.elapsed();
+            // an item its handler committed to already is waited for
+            let skip =
+                skip_after.is_some_and(|skip_after| elapsed > skip_after) && slot.state.abandon();
+            if skip {
+                skipped_workers.push(*id);
+            } else if slot.reported || elapsed <= timeout {
+                continue;
+            }
+            slot.reported = true;
+            stalled.push(StalledItem {
+                thread: slot.thread.clone(),
+                item: slot.item.clone(),
+                elapsed,
+                skipped: skip,
+            });
+        }
+        for id in skipped_workers.iter() {
+            slots.remove(id);
+        }
+        drop(slots);
+
+        if !skipped_workers.is_empty() {
+            // dropping the join handle detaches the stuck thread
+            self.handles
+                .lock()
+                .unwrap()
+                .retain(|(id, _)| !skipped_workers.contains(id));
+            self.running
+                .fetch_sub(skipped_workers.len(), Ordering::SeqCst);
+            self.notify_running();
+            self.set_threads(self.target.load(Ordering::SeqCst));
+        }
+        stalled
+    }
+
+    fn set_threads(self: &Arc<Self>, threads: usize) {
+        let threads = threads.max(1);
+        self.target.store(threads, Ordering::SeqCst);
+        while self.running.load(Ordering::SeqCst) < threads {
+            self.spawn_worker();
+        }
+    }
+
+    /// Returns true if the calling worker should exit because the pool got scaled down
+    fn retire_surplus_worker(&self) -> bool {
+        let mut running = self.running.load(Ordering::SeqCst);
+        loop {
+            if running <= self.target.load(Ordering::SeqCst) {
+                return false;
+            }
+            match self.running.compare_exchange(
+                running,
+                running - 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => {
+                    self.notify_running();
+                    return true;
+                }
+                Err(current) => running = current,
+            }
+        }
+    }
+
+    /// Wake up 'wait_for_workers()' after the number of running workers went down
+    fn notify_running(&self) {
+        let _running = self.running_lock.lock().unwrap();
+        self.running_changed.notify_all();
+    }
+
+    fn stats(&self) -> PoolStats {
+        PoolStats {
+            threads: self.target.load(Ordering::SeqCst),
+            queued: self.input.len(),
+            completed: self.completed.load(Ordering::SeqCst),
+            busy: Duration::from_nanos(self.busy_nanos.load(Ordering::SeqCst)),
+        }
+    }
+}
+
+/// Keeps the number of running workers up to date, also if a worker dies unexpectedly
+struct RunningGuard<'a, I> {
+    state: &'a PoolState<I>,
+    armed: bool,
+}
+
+impl<I> Drop for RunningGuard<'_, I> {
+    fn drop(&mut self) {
+        if self.armed {
+            self.state.running.fetch_sub(1, Ordering::SeqCst);
+            self.state.notify_running();
+        }
+    }
+}
+
+fn worker_loop<I, F>(
+    state: &PoolState<I>,
+    id: usize,
+    abort: &Mutex<Option<String>>,
+    name: &str,
+    handler_fn: F,
+) where
+    I: fmt::Debug,
+    F: Fn(I) -> Result<(), Error>,
+{
+    let mut running = RunningGuard { state, armed: true };
+    let mut initialized = false;
+    loop {
+        if state.retire_surplus_worker() {
+            running.armed = false;
+            return;
+        }
+        let data = match state.input.recv_timeout(IDLE_CHECK_INTERVAL) {
+            Ok(data) => data,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if !initialized {
+            if let Some(init) = *state.thread_init.lock().unwrap() {
+                init();
+            }
+            initialized = true;
+        }
+        let item = format!("{data:?}");
+        let start = Instant::now();
+
+        let item_state = Arc::new(ItemState::default());
+        let slot = WorkerSlot {
+            thread: name.to_string(),
+            item: item.clone(),
+            started: start,
+            reported: false,
+            state: Arc::clone(&item_state),
+        };
+        state.slots.lock().unwrap().insert(id, slot);
+        CURRENT_ITEM.with(|current| *current.borrow_mut() = Some(Arc::clone(&item_state)));
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| (handler_fn)(data)));
+
+        state.slots.lock().unwrap().remove(&id);
+        if item_state.is_abandoned() {
+            // the watchdog already replaced this worker and reported the item
+            running.armed = false;
+            return;
+        }
+
+        let busy = start.elapsed().as_nanos() as u64;
+        state.busy_nanos.fetch_add(busy, Ordering::SeqCst);
+        state.completed.fetch_add(1, Ordering::SeqCst);
+
+        match result {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => {
+                let mut guard = abort.lock().unwrap();
+                if guard.is_none() {
+                    *guard = Some(err.to_string());
+                }
+            }
+            Err(panic) => {
+                let err = PanicError {
+                    item,
+                    message: panic_message(&*panic),
+                };
+                state
+                    .panics
+                    .lock()
+                    .unwrap()
+                    .push(format!("thread {name}: {err}"));
+            }
+        }
+    }
+}
+
 impl<I: Send> SendHandle<I> {
     /// Send data to the worker threads
     pub fn send(&self, input: I) -> Result<(), Error> {
@@ -32,18 +457,57 @@ impl<I: Send> SendHandle<I> {
     }
 }
 
+/// The sending side of the results channel, shared by all workers instead of cloned into each
+///
+/// A worker the watchdog detached may never return, closing the channel must not depend on it.
+struct ResultSender<T>(Arc<Mutex<Option<Sender<T>>>>);
+
+impl<T> Clone for ResultSender<T> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<T> ResultSender<T> {
+    fn new() -> (Self, Receiver<T>) {
+        let (sender, receiver) = unbounded();
+        (Self(Arc::new(Mutex::new(Some(sender)))), receiver)
+    }
+
+    /// Send 'result' unless the channel was closed already
+    fn send(&self, result: T) {
+        if let Some(sender) = &*self.0.lock().unwrap() {
+            // the receiver only goes away if nobody is interested in the results anymore
+            let _ = sender.send(result);
+        }
+    }
+
+    fn close(&self) {
+        self.0.lock().unwrap().take();
+    }
+}
+
 /// A thread pool which run the supplied closure
 ///
 /// The send command sends data to the worker threads. If one handler
 /// returns an error, we mark the channel as failed and it is no
 /// longer possible to send data.
 ///
+/// A panic while processing an item is caught and recorded, the
+/// worker thread then continues with the next item.
+///
+/// The number of worker threads can be changed while the pool is
+/// running, either explicitly or automatically via 'autoscale()'.
+///
 /// When done, the 'complete()' method needs to be called to check for
 /// outstanding errors.
 pub struct ParallelHandler<I> {
-    handles: Vec<JoinHandle<()>>,
-    name: String,
+    state: Arc<PoolState<I>>,
     input: Option<SendHandle<I>>,
+    scaler: Option<JoinHandle<()>>,
+    watchdog: Option<JoinHandle<()>>,
+    /// drops the sender of the results channel, see 'with_results()'
+    close_results: Option<Box<dyn FnOnce() + Send>>,
 }
 
 impl<I> Clone for SendHandle<I> {
@@ -55,51 +519,113 @@ impl<I> Clone for SendHandle<I> {
     }
 }
 
-impl<I: Send + 'static> ParallelHandler<I> {
+impl<I: Send + fmt::Debug + 'static> ParallelHandler<I> {
     /// Create a new thread pool, each thread processing incoming data
     /// with 'handler_fn'.
     pub fn new<F>(name: &str, threads: usize, handler_fn: F) -> Self
     where
         F: Fn(I) -> Result<(), Error> + Send + Clone + 'static,
     {
-        let mut handles = Vec::new();
         let (input_tx, input_rx) = bounded::<I>(threads);
 
         let abort = Arc::new(Mutex::new(None));
 
-        for i in 0..threads {
-            let input_rx = input_rx.clone();
+        let spawn_fn = {
+            let name = name.to_string();
             let abort = Arc::clone(&abort);
-            let handler_fn = handler_fn.clone();
+            move |state: Arc<PoolState<I>>, id: usize| {
+                let abort = Arc::clone(&abort);
+                let handler_fn = handler_fn.clone();
+                let thread_name = format!("{} ({})", name, id);
 
-            handles.push(
                 std::thread::Builder::new()
-                    .name(format!("{} ({})", name, i))
-                    .spawn(move || loop {
-                        let data = match input_rx.recv() {
-                            Ok(data) => data,
-                            Err(_) => return,
-                        };
-                        if let Err(err) = (handler_fn)(data) {
-                            let mut guard = abort.lock().unwrap();
-                            if guard.is_none() {
-                                *guard = Some(err.to_string());
-                            }
-                        }
-                    })
-                    .unwrap(),
-            );
-        }
-        Self {
-            handles,
+                    .name(thread_name.clone())
+                    .spawn(move || worker_loop(&state, id, &abort, &thread_name, handler_fn))
+                    .unwrap()
+            }
+        };
+
+        let state = Arc::new(PoolState {
             name: name.to_string(),
+            input: input_rx,
+            spawn_fn: Mutex::new(Box::new(spawn_fn)),
+            handles: Mutex::new(Vec::new()),
+            slots: Mutex::new(HashMap::new()),
+            panics: Mutex::new(Vec::new()),
+            target: AtomicUsize::new(0),
+            running: AtomicUsize::new(0),
+            running_changed: Condvar::new(),
+            running_lock: Mutex::new(()),
+            next_id: AtomicUsize::new(0),
+            completed: AtomicUsize::new(0),
+            busy_nanos: AtomicU64::new(0),
+            finished: AtomicBool::new(false),
+            thread_init: Mutex::new(None),
+        });
+        state.set_threads(threads);
+
+        Self {
+            state,
             input: Some(SendHandle {
                 input: input_tx,
                 abort,
             }),
+            scaler: None,
+            watchdog: None,
+            close_results: None,
         }
     }
 
+    /// Create a new thread pool, each thread processing incoming data
+    /// with 'handler_fn' and sending its result back over the returned
+    /// channel.
+    ///
+    /// Contrary to 'new()', an error returned by 'handler_fn' does not
+    /// abort the pool, it is passed on to the results channel like any
+    /// other outcome. A panic is sent as [`PanicError`] for the item, the
+    /// worker continues with the next one and 'complete()' does not report it
+    /// again. The channel is closed once the pool is completed.
+    pub fn with_results<F, R, E>(
+        name: &str,
+        threads: usize,
+        handler_fn: F,
+    ) -> (Self, Receiver<Result<R, E>>)
+    where
+        F: Fn(I) -> Result<R, E> + Send + Clone + 'static,
+        R: Send + 'static,
+        E: From<PanicError> + Send + 'static,
+    {
+        let (result_tx, result_rx) = ResultSender::new();
+
+        let mut pool = Self::new(name, threads, {
+            let result_tx = result_tx.clone();
+            move |data| {
+                let item = format!("{data:?}");
+                let result = panic::catch_unwind(AssertUnwindSafe(|| (handler_fn)(data)))
+                    .unwrap_or_else(|panic| {
+                        let message = panic_message(&*panic);
+                        Err(PanicError { item, message }.into())
+                    });
+                if !item_abandoned() {
+                    result_tx.send(result);
+                }
+                Ok(())
+            }
+        });
+        pool.close_results = Some(Box::new(move || result_tx.close()));
+
+        (pool, result_rx)
+    }
+
+    /// Run 'init' in each worker thread before it processes its first item, also in the workers
+    /// started later by scaling or to replace a stuck one
+    ///
+    /// Meant for per-thread state of C libraries, like the librrd context. Needs to be set
+    /// before the first item is sent.
+    pub fn thread_init(&self, init: fn()) {
+        *self.state.thread_init.lock().unwrap() = Some(init);
+    }
+
     /// Returns a cloneable channel to send data to the worker threads
     pub fn channel(&self) -> SendHandle<I> {
         self.input.as_ref().unwrap().clone()
@@ -111,18 +637,119 @@ impl<I: Send + 'static> ParallelHandler<I> {
         Ok(())
     }
 
+    /// Returns the number of worker threads the pool is scaled to
+    pub fn threads(&self) -> usize {
+        self.state.target.load(Ordering::SeqCst)
+    }
+
+    /// Scale the pool to 'threads' worker threads
+    ///
+    /// New workers are started right away, surplus workers exit once
+    /// they finished their current item.
+    pub fn set_threads(&self, threads: usize) {
+        self.state.set_threads(threads);
+    }
+
+    /// Returns the current utilization of the pool
+    pub fn stats(&self) -> PoolStats {
+        self.state.stats()
+    }
+
+    /// Automatically scale the pool between 'min' and 'max' worker threads
+    ///
+    /// The average time per item is periodically compared to the one
+    /// observed at the start. If items take a lot longer, the host is
+    /// likely busy and a worker is removed. If items are waiting in the
+    /// queue and the latency is still fine, a worker is added.
+    pub fn autoscale(&mut self, min: usize, max: usize) {
+        if self.scaler.is_some() {
+            return;
+        }
+        let state = Arc::clone(&self.state);
+
+        let scaler = std::thread::Builder::new()
+            .name(format!("{} (scaler)", state.name))
+            .spawn(move || {
+                let mut baseline: Option<f64> = None;
+                let mut last = state.stats();
+                let mut last_check = Instant::now();
+
+                while !state.finished.load(Ordering::SeqCst) {
+                    std::thread::sleep(IDLE_CHECK_INTERVAL);
+                    if last_check.elapsed() < AUTOSCALE_INTERVAL {
+                        continue;
+                    }
+                    last_check = Instant::now();
+
+                    let current = state.stats();
+                    let completed = current.completed - last.completed;
+                    if completed == 0 {
+                        continue;
+                    }
+                    let latency = (current.busy - last.busy).as_secs_f64() / completed as f64;
+                    last = current;
+
+                    let baseline = *baseline.get_or_insert(latency);
+                    let threads = autoscale_step(current, latency / baseline, min, max);
+                    if threads != current.threads {
+                        state.set_threads(threads);
+                    }
+                }
+            })
+            .unwrap();
+        self.scaler = Some(scaler);
+    }
+
+    /// Watch for items that take longer than 'timeout' to process
+    ///
+    /// 'on_stall' is called once for each such item. If 'skip_after' is
+    /// set, items exceeding it are given up on, unless their handler
+    /// committed to them with [`commit_item`] already: the pool stops waiting
+    /// for them, no result is sent for them, and a replacement worker takes
+    /// over the remaining items. 'on_stall' is called again for them with
+    /// 'skipped' set.
+    pub fn watchdog<F>(&mut self, timeout: Duration, skip_after: Option<Duration>, on_stall: F)
+    where
+        F: Fn(&StalledItem) + Send + 'static,
+    {
+        if self.watchdog.is_some() {
+            return;
+        }
+        let state = Arc::clone(&self.state);
+
+        let watchdog = std::thread::Builder::new()
+            .name(format!("{} (watchdog)", state.name))
+            .spawn(move || {
+                while !state.finished.load(Ordering::SeqCst) {
+                    std::thread::sleep(IDLE_CHECK_INTERVAL);
+                    for stalled in state.check_stalled(timeout, skip_after) {
+                        on_stall(&stalled);
+                    }
+                }
+            })
+            .unwrap();
+        self.watchdog = Some(watchdog);
+    }
+
     /// Wait for worker threads to complete and check for errors
+    ///
+    /// All panics caught while processing items are reported together.
     pub fn complete(mut self) -> Result<(), Error> {
         let input = self.input.take().unwrap();
         let abort = Arc::clone(&input.abort);
         check_abort(&abort)?;
         drop(input);
 
-        let msg_list = self.join_threads();
+        self.wait_for_workers();
+        self.close_results();
+        self.stop_helpers();
+        let mut msg_list = self.join_threads();
 
         // an error might be encountered while waiting for the join
         check_abort(&abort)?;
 
+        msg_list.append(&mut self.state.panics.lock().unwrap());
+
         if msg_list.is_empty() {
             return Ok(());
         }
@@ -133,14 +760,15 @@ impl<I: Send + 'static> ParallelHandler<I> {
         let mut msg_list = Vec::new();
 
         let mut i = 0;
-        while let Some(handle) = self.handles.pop() {
+        // don't hold the lock while joining
+        while let Some((_, handle)) = self.pop_handle() {
             if let Err(panic) = handle.join() {
-                if let Some(panic_msg) = panic.downcast_ref::<&str>() {
-                    msg_list.push(format!("thread {} ({i}) panicked: {panic_msg}", self.name));
-                } else if let Some(panic_msg) = panic.downcast_ref::<String>() {
-                    msg_list.push(format!("thread {} ({i}) panicked: {panic_msg}", self.name));
-                } else {
-                    msg_list.push(format!("thread {} ({i}) panicked", self.name));
+                let name = &self.state.name;
+                match panic_message(&*panic) {
+                    Some(panic_msg) => {
+                        msg_list.push(format!("thread {name} ({i}) panicked: {panic_msg}"))
+                    }
+                    None => msg_list.push(format!("thread {name} ({i}) panicked")),
                 }
             }
             i += 1;
@@ -149,12 +777,218 @@ impl<I: Send + 'static> ParallelHandler<I> {
     }
 }
 
+impl<I: Send + fmt::Debug + 'static> ParallelHandler<Batch<I>> {
+    /// Like 'with_results()', for items sent in batches with a [`BatchSender`]
+    ///
+    /// 'handler_fn' is called for each item of a batch and its result is sent on its own. A
+    /// panic is sent as [`PanicError`] for its item, the rest of the batch is still processed.
+    /// Once the watchdog gave up on a batch, no results are sent for its remaining items.
+    pub fn with_batched_results<F, R, E>(
+        name: &str,
+        threads: usize,
+        handler_fn: F,
+    ) -> (Self, Receiver<Result<R, E>>)
+    where
+        F: Fn(I) -> Result<R, E> + Send + Clone + 'static,
+        R: Send + 'static,
+        E: From<PanicError> + Send + 'static,
+    {
+        let (result_tx, result_rx) = ResultSender::new();
+
+        let mut pool = Self::new(name, threads, {
+            let result_tx = result_tx.clone();
+            move |batch: Batch<I>| {
+                for data in batch.0 {
+                    if item_abandoned() {
+                        break;
+                    }
+                    with_current_item(ItemState::next_item);
+                    let item = format!("{data:?}");
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| (handler_fn)(data)))
+                        .unwrap_or_else(|panic| {
+                            let message = panic_message(&*panic);
+                            Err(PanicError { item, message }.into())
+                        });
+                    if !item_abandoned() {
+                        result_tx.send(result);
+                    }
+                }
+                Ok(())
+            }
+        });
+        pool.close_results = Some(Box::new(move || result_tx.close()));
+
+        (pool, result_rx)
+    }
+}
+
+/// The number of workers to scale to from the 'current' ones, with items taking 'slowdown' times
+/// as long as at the start
+fn autoscale_step(current: PoolStats, slowdown: f64, min: usize, max: usize) -> usize {
+    if slowdown > 2.0 && current.threads > min {
+        current.threads - 1
+    } else if slowdown < 1.25 && current.queued > 0 && current.threads < max {
+        current.threads + 1
+    } else {
+        current.threads
+    }
+}
+
+impl<I> ParallelHandler<I> {
+    fn pop_handle(&self) -> Option<(usize, JoinHandle<()>)> {
+        self.state.handles.lock().unwrap().pop()
+    }
+
+    /// Wait until all workers ran out of items
+    ///
+    /// The workers are not joined directly, as the watchdog still needs to
+    /// be able to detach a worker that got stuck on one of the last items.
+    fn wait_for_workers(&self) {
+        let mut running = self.state.running_lock.lock().unwrap();
+        while self.state.running.load(Ordering::SeqCst) > 0 {
+            running = self.state.running_changed.wait(running).unwrap();
+        }
+    }
+
+    /// Close the results channel, also if a detached worker is still stuck on its item
+    fn close_results(&mut self) {
+        if let Some(close) = self.close_results.take() {
+            close();
+        }
+    }
+
+    /// Stop the scaler and watchdog threads, if any
+    ///
+    /// Must be called before joining the workers, so that the set of
+    /// workers does not change anymore.
+    fn stop_helpers(&mut self) {
+        self.state.finished.store(true, Ordering::SeqCst);
+        if let Some(scaler) = self.scaler.take() {
+            let _ = scaler.join();
+        }
+        if let Some(watchdog) = self.watchdog.take() {
+            let _ = watchdog.join();
+        }
+    }
+}
+
 // Note: We make sure that all threads will be joined
 impl<I> Drop for ParallelHandler<I> {
     fn drop(&mut self) {
         drop(self.input.take());
-        while let Some(handle) = self.handles.pop() {
+        self.wait_for_workers();
+        self.close_results();
+        self.stop_helpers();
+        while let Some((_, handle)) = self.pop_handle() {
             let _ = handle.join();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct ItemError(String);
+
+    impl From<PanicError> for ItemError {
+        fn from(err: PanicError) -> Self {
+            ItemError(err.to_string())
+        }
+    }
+
+    #[test]
+    fn with_results_panic() {
+        let (pool, results) = ParallelHandler::with_results("test", 2, |item: u32| {
+            if item == 3 {
+                panic!("broken item");
+            }
+            Ok::<u32, ItemError>(item * 2)
+        });
+        for item in 0..6 {
+            pool.send(item).expect("send item");
+        }
+        // the panic is the outcome of its item, not a failure of the pool
+        pool.complete().expect("complete pool");
+
+        let (mut migrated, mut failed) = (Vec::new(), Vec::new());
+        for result in results.iter() {
+            match result {
+                Ok(value) => migrated.push(value),
+                Err(ItemError(message)) => failed.push(message),
+            }
+        }
+        migrated.sort();
+        assert_eq!(migrated, [0, 2, 4, 8, 10]);
+        assert_eq!(failed, ["processing 3 panicked: broken item"]);
+    }
+
+    #[test]
+    fn with_results_detached_worker() {
+        let stuck = Duration::from_secs(5);
+        let (mut pool, results) = ParallelHandler::with_results("test", 2, move |item: u32| {
+            if item == 0 {
+                std::thread::sleep(stuck);
+            }
+            Ok::<u32, ItemError>(item)
+        });
+        let skipped = Arc::new(Mutex::new(Vec::new()));
+        let on_stall = {
+            let skipped = Arc::clone(&skipped);
+            move |stalled: &StalledItem| {
+                if stalled.skipped {
+                    skipped.lock().unwrap().push(stalled.item.clone());
+                }
+            }
+        };
+        let timeout = Duration::from_millis(50);
+        pool.watchdog(timeout, Some(timeout * 2), on_stall);
+        let start = Instant::now();
+        for item in 0..4 {
+            pool.send(item).expect("send item");
+        }
+        pool.complete().expect("complete pool");
+
+        // the channel closes although the detached worker still holds on to its item
+        let mut migrated: Vec<u32> = results.iter().map(|result| result.unwrap()).collect();
+        migrated.sort();
+        assert_eq!(migrated, [1, 2, 3]);
+        assert_eq!(*skipped.lock().unwrap(), ["0"]);
+        assert!(start.elapsed() < stuck);
+    }
+
+    #[test]
+    fn set_threads() {
+        let pool = ParallelHandler::new("test", 2, |_: u32| Ok(()));
+        assert_eq!(pool.threads(), 2);
+        pool.set_threads(4);
+        assert_eq!(pool.threads(), 4);
+        assert_eq!(pool.state.running.load(Ordering::SeqCst), 4);
+        // a pool without workers would never finish
+        pool.set_threads(0);
+        assert_eq!(pool.threads(), 1);
+        for item in 0..8 {
+            pool.send(item).expect("send item");
+        }
+        pool.complete().expect("complete pool");
+    }
+
+    #[test]
+    fn autoscale() {
+        let stats = |threads, queued| PoolStats {
+            threads,
+            queued,
+            completed: 0,
+            busy: Duration::ZERO,
+        };
+        // slower items remove a worker, but not below the minimum
+        assert_eq!(autoscale_step(stats(4, 10), 2.5, 2, 8), 3);
+        assert_eq!(autoscale_step(stats(2, 10), 2.5, 2, 8), 2);
+        // waiting items add one as long as the items are not slower, up to the maximum
+        assert_eq!(autoscale_step(stats(4, 10), 1.0, 2, 8), 5);
+        assert_eq!(autoscale_step(stats(8, 10), 1.0, 2, 8), 8);
+        assert_eq!(autoscale_step(stats(4, 0), 1.0, 2, 8), 4);
+        assert_eq!(autoscale_step(stats(4, 10), 1.5, 2, 8), 4);
+    }
+}
@@ -0,0 +1,144 @@
+//! Selecting source files by a glob or regular expression on their path relative to the source
+//! directory, like pve2-vm/1?? or pve2-storage/*/local-zfs
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Error};
+use regex::Regex;
+use serde::Deserialize;
+
+/// Prefix of patterns that are regular expressions instead of globs
+const REGEX_PREFIX: &str = "re:";
+
+/// A glob, which has to match the whole path, or a regular expression, which has to match
+/// somewhere in it
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PathPattern {
+    pattern: String,
+    regex: Regex,
+}
+
+impl PathPattern {
+    pub fn is_match(&self, path: &str) -> bool {
+        self.regex.is_match(path)
+    }
+}
+
+impl fmt::Display for PathPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+/// The regular expression of 'glob', * and ? do not match a /, ** does
+fn glob_to_regex(glob: &str) -> Result<String, Error> {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.next_if(|&c| c == '!' || c == '^').is_some() {
+                    regex.push('^');
+                }
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        closed = true;
+                        break;
+                    }
+                    if c == '[' || c == '\\' {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+                if !closed {
+                    bail!("unclosed '[' in glob '{glob}'");
+                }
+                regex.push(']');
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Ok(regex)
+}
+
+impl FromStr for PathPattern {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let regex = match value.strip_prefix(REGEX_PREFIX) {
+            Some(regex) => regex.to_string(),
+            None => glob_to_regex(value)?,
+        };
+        Ok(Self {
+            pattern: value.to_string(),
+            regex: Regex::new(&regex)?,
+        })
+    }
+}
+
+impl TryFrom<String> for PathPattern {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(value: &str) -> PathPattern {
+        value.parse().expect("valid pattern")
+    }
+
+    #[test]
+    fn glob_matches_whole_path() {
+        let guests = pattern("pve2-vm/1??");
+        assert!(guests.is_match("pve2-vm/100"));
+        assert!(!guests.is_match("pve2-vm/10"));
+        assert!(!guests.is_match("pve2-vm/1000"));
+        assert!(!guests.is_match("old/pve2-vm/100"));
+
+        let storage = pattern("pve2-storage/*/local-zfs");
+        assert!(storage.is_match("pve2-storage/pve1/local-zfs"));
+        assert!(!storage.is_match("pve2-storage/a/b/local-zfs"));
+        assert!(pattern("pve2-storage/**/local-zfs").is_match("pve2-storage/a/b/local-zfs"));
+        assert!(!pattern("pve2-vm/*").is_match("pve2-node/pve1"));
+    }
+
+    #[test]
+    fn glob_brackets_and_escaping() {
+        let range = pattern("pve2-vm/[12]0[!0]");
+        assert!(range.is_match("pve2-vm/101"));
+        assert!(range.is_match("pve2-vm/209"));
+        assert!(!range.is_match("pve2-vm/100"));
+        assert!(!range.is_match("pve2-vm/301"));
+        // regex characters in a glob only match themselves
+        let dotted = pattern("pve2-storage/pve1/nfs.backup+1");
+        assert!(dotted.is_match("pve2-storage/pve1/nfs.backup+1"));
+        assert!(!dotted.is_match("pve2-storage/pve1/nfsXbackup1"));
+        assert!("pve2-vm/[12".parse::<PathPattern>().is_err());
+    }
+
+    #[test]
+    fn regex_matches_anywhere() {
+        let regex = pattern("re:/1\\d{2}$");
+        assert!(regex.is_match("pve2-vm/100"));
+        assert!(!regex.is_match("pve2-vm/1000"));
+        assert!(pattern("re:local").is_match("pve2-storage/pve1/local-lvm"));
+        assert!("re:(".parse::<PathPattern>().is_err());
+        assert_eq!(regex.to_string(), "re:/1\\d{2}$");
+    }
+}
@@ -0,0 +1,483 @@
+//! The migration phases of the nodes, storages and guests
+
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error};
+use tracing::{debug, info, info_span, warn};
+
+use proxmox_rrd_migration_tool::migrate::{self, RRDFile, ResourceType};
+
+use crate::file::{
+    do_rrd_migration_with_timeout, finish_source, is_retryable, is_skip, log_file_error,
+    mark_not_present, report_failure, report_unusable, retry_failed_files, skip_stale, source_path,
+    take_invalid_names,
+};
+#[cfg(not(feature = "rayon"))]
+use crate::guest_pool;
+use crate::guest_pool::GuestResults;
+use crate::progress;
+#[cfg(feature = "rayon")]
+use crate::rayon_pool;
+use crate::report::ErrorCause;
+use crate::symlinks::{self, SymlinkPolicy};
+use crate::twins;
+use crate::{MigrationOptions, STORAGE_CONFIG};
+
+/// The estimated time left for the phase of 'kind' for the progress messages, if known
+pub(crate) fn eta_suffix(kind: ResourceType, options: &MigrationOptions) -> String {
+    match options.progress.eta(kind) {
+        Some(eta) => format!(", about {} left", progress::format_duration(eta)),
+        None => String::new(),
+    }
+}
+
+/// Run the node, storage and guest phases, each from source to target, returns the failed count
+pub(crate) fn migrate_all(
+    nodes: (PathBuf, PathBuf),
+    storage: (PathBuf, PathBuf),
+    guests: (PathBuf, PathBuf),
+    resource_base_dir: &str,
+    options: &MigrationOptions,
+) -> Result<usize, Error> {
+    let mut failed = migrate_nodes(nodes.0, nodes.1, options).context("migrating nodes")?;
+    failed += migrate_storage(storage.0, storage.1, resource_base_dir, options)
+        .context("migrating storage")?;
+    failed += migrate_guests(guests.0, guests.1, options).context("migrating guests")?;
+    Ok(failed)
+}
+
+/// Migrate guest RRD files in parallel, returns the number of guests that failed
+pub(crate) fn migrate_guests(
+    source_dir_guests: PathBuf,
+    target_dir_guests: PathBuf,
+    options: &MigrationOptions,
+) -> Result<usize, Error> {
+    let _phase = info_span!("phase", name = "guests").entered();
+    info!("Migrating RRD metrics data for virtual guests…");
+    info!("Using {} thread(s)", options.threads);
+    if let Some(max_threads) = options.max_threads {
+        info!("Scaling automatically up to {max_threads} thread(s)");
+    }
+    if let Some(io_threads) = options.io_threads {
+        info!("Reading ahead with {io_threads} thread(s)");
+    }
+
+    report_unusable(&source_dir_guests, ResourceType::Guest, options)?;
+    let mut guest_source_files =
+        migrate::collect_rrd_files_with(&*options.fs, &source_dir_guests, &options.old_suffix)?;
+    guest_source_files.retain(|file| options.is_selected(file));
+    take_invalid_names(&mut guest_source_files, ResourceType::Guest, options);
+    twins::resolve_all(&guest_source_files, ResourceType::Guest, options);
+    options
+        .progress
+        .phase_start(ResourceType::Guest, guest_source_files.len());
+
+    if guest_source_files.is_empty() {
+        options.progress.phase_end(ResourceType::Guest);
+        info!("No guest metrics to migrate");
+        return Ok(0);
+    }
+
+    if options.migrate && migrate::create_missing_dir(&*options.fs, &target_dir_guests)? {
+        info!("Created new directory: '{}'", target_dir_guests.display());
+    }
+
+    let links = symlinks::take_links(&mut guest_source_files, ResourceType::Guest, options);
+    let links_target = target_dir_guests.clone();
+    let start_time = std::time::SystemTime::now();
+
+    let mut results = GuestResults::new(options.errors.clone(), options.notifier.clone());
+    // panics and timeouts only know the item they happened for
+    let mut dispatched = HashMap::new();
+    #[cfg(not(feature = "rayon"))]
+    guest_pool::run(
+        guest_source_files,
+        target_dir_guests,
+        options,
+        &mut results,
+        &mut dispatched,
+    )?;
+    #[cfg(feature = "rayon")]
+    rayon_pool::run_guests(
+        guest_source_files,
+        &target_dir_guests,
+        options,
+        &mut results,
+        &mut dispatched,
+    )?;
+
+    let elapsed = start_time.elapsed()?.as_secs_f64();
+    let guests = results.migrated.len();
+
+    options.progress.phase_end(ResourceType::Guest);
+    let failed_guests = results.failed.len();
+    if failed_guests == 0 {
+        info!("Migrated metrics data of all {guests} guests to new format in {elapsed:.2}s");
+    } else {
+        info!(
+            "Tried to migrated metrics of all guests to new format in {elapsed:.2}s, but did not \
+            finish {failed_guests} guests - see output above for details."
+        );
+    }
+
+    for err in &results.failed {
+        match err.file.as_ref().or_else(|| dispatched.get(&err.resource)) {
+            Some(file) => report_failure(
+                &options.report,
+                file.1.to_string_lossy(),
+                &file.0,
+                &err.error,
+            ),
+            None => options.report.add(
+                ErrorCause::Other,
+                err.resource.as_str(),
+                None,
+                Some(format!("{:#}", err.error)),
+            ),
+        }
+    }
+
+    if let Some(abort) = results.aborted {
+        return Err(abort);
+    }
+    results.completion?;
+
+    let failed_links =
+        symlinks::replicate_links(links, &links_target, ResourceType::Guest, options);
+    Ok(results
+        .failed
+        .iter()
+        .filter(|err| !is_skip(&err.error))
+        .count()
+        + failed_links)
+}
+
+/// Migrate node RRD files in serial, returns the number of nodes that failed
+pub(crate) fn migrate_nodes(
+    source_dir_nodes: PathBuf,
+    target_dir_nodes: PathBuf,
+    options: &MigrationOptions,
+) -> Result<usize, Error> {
+    let _phase = info_span!("phase", name = "nodes").entered();
+    info!("Migrating RRD metrics data for nodes…");
+
+    if options.migrate && migrate::create_missing_dir(&*options.fs, &target_dir_nodes)? {
+        info!("Created new directory: '{}'", target_dir_nodes.display());
+    }
+
+    report_unusable(&source_dir_nodes, ResourceType::Node, options)?;
+    let mut node_source_files =
+        migrate::collect_rrd_files_with(&*options.fs, &source_dir_nodes, &options.old_suffix)?;
+    node_source_files.retain(|file| options.is_selected(file));
+    take_invalid_names(&mut node_source_files, ResourceType::Node, options);
+    twins::resolve_all(&node_source_files, ResourceType::Node, options);
+    let links = symlinks::take_links(&mut node_source_files, ResourceType::Node, options);
+    options
+        .progress
+        .phase_start(ResourceType::Node, node_source_files.len());
+
+    let mut no_migration_err = true;
+    // failed files that cannot be retried, like corrupt sources
+    let mut failed = 0;
+    let mut retry = Vec::new();
+    for file in node_source_files {
+        options.control.checkpoint(&options.notifier)?;
+        options.notifier.watchdog_ping();
+        let node = file.1.to_string_lossy().into_owned();
+        debug!("Node: '{node}'");
+        if !options.resources.contains(ResourceType::Node, &node)? {
+            options
+                .report
+                .add(ErrorCause::NotPresent, node.as_str(), None, None);
+            if options.migrate {
+                debug!(
+                    status = "marked-old",
+                    "Node: '{node}' not present. Skip and mark as old."
+                );
+            } else {
+                debug!(status = "skipped", "Node: '{node}' not present. Would mark as old, but in dry-run mode, so just skip.");
+            }
+            mark_not_present(source_path(&file), ".members", ResourceType::Node, options)?;
+            continue;
+        }
+        if !options.resources.is_online(&node)? {
+            warn!("Node: '{node}' is configured, but offline, migrating its metrics anyway");
+        }
+        if skip_stale(&file, ResourceType::Node, options)? {
+            continue;
+        }
+        // a source that cannot be dealt with fails like its migration, not the whole phase
+        let target = target_dir_nodes.join(&file.1);
+        match do_rrd_migration_with_timeout(
+            file.clone(),
+            &target_dir_nodes,
+            ResourceType::Node,
+            options,
+        )
+        .and_then(|()| finish_source(source_path(&file), &target, ResourceType::Node, options))
+        {
+            Ok(_) => {}
+            Err(err) => {
+                log_file_error(&err);
+                if is_retryable(&err) {
+                    options.errors.record(file.0.as_bytes())?;
+                    retry.push((file, target_dir_nodes.clone(), err));
+                } else {
+                    if !is_skip(&err) {
+                        options.errors.record(file.0.as_bytes())?;
+                        failed += 1;
+                    }
+                    report_failure(&options.report, node.as_str(), &file.0, &err);
+                    no_migration_err = false;
+                }
+            }
+        }
+    }
+    let failed = failed
+        + retry_failed_files(retry, ResourceType::Node, options)
+        + symlinks::replicate_links(links, &target_dir_nodes, ResourceType::Node, options);
+    if failed > 0 {
+        no_migration_err = false;
+    }
+
+    options.progress.phase_end(ResourceType::Node);
+    if no_migration_err {
+        info!("Migrated metrics of all nodes to new format");
+    } else {
+        info!(
+            "Tried to migrated metrics of all nodes to new format - see output above for details."
+        );
+    }
+
+    Ok(failed)
+}
+
+/// Migrate storage RRD files in serial, returns the number of storages that failed
+pub(crate) fn migrate_storage(
+    source_dir_storage: PathBuf,
+    target_dir_storage: PathBuf,
+    resources: &str,
+    options: &MigrationOptions,
+) -> Result<usize, Error> {
+    let _phase = info_span!("phase", name = "storages").entered();
+    info!("Migrating RRD metrics data for storages…");
+
+    if options.migrate && migrate::create_missing_dir(&*options.fs, &target_dir_storage)? {
+        info!("Created new directory: '{}'", target_dir_storage.display());
+    }
+
+    // storage has another layer of directories per node over which we need to iterate
+    let nodes: Vec<PathBuf> = options
+        .fs
+        .read_dir(&source_dir_storage)?
+        .into_iter()
+        .filter(|f| options.fs.metadata(f).is_ok_and(|metadata| metadata.is_dir))
+        .filter(|node| {
+            let name = node.file_name().unwrap_or_default();
+            let selected = options.is_storage_node_selected(name);
+            if !selected {
+                debug!(
+                    "Skipping the storage metrics of node '{}', use --all-nodes to migrate them",
+                    name.to_string_lossy()
+                );
+            }
+            selected
+        })
+        .collect();
+    let mut storage_links = Vec::new();
+    // the number of files of a node to migrate, without listing or reporting them like list_node
+    let count_node = |node: &Path| -> Result<usize, Error> {
+        let source_storage_subdir = source_dir_storage.join(node.file_name().unwrap());
+        let files = migrate::collect_rrd_files_with(
+            &*options.fs,
+            &source_storage_subdir,
+            &options.old_suffix,
+        )?;
+        let count = files
+            .iter()
+            .filter(|file| {
+                options.is_selected(file)
+                    && options.is_storage_selected(&file.1)
+                    && migrate::is_valid_resource_name(ResourceType::Storage, &file.1)
+                    && (options.symlinks == SymlinkPolicy::Follow || !symlinks::is_symlink(file))
+            })
+            .count();
+        Ok(count)
+    };
+    // the files of a node to migrate, with the target directory they are migrated to
+    let mut list_node = |node: &Path| -> Result<(PathBuf, Vec<RRDFile>), Error> {
+        let mut source_storage_subdir = source_dir_storage.clone();
+        source_storage_subdir.push(node.file_name().unwrap());
+
+        let mut target_storage_subdir = target_dir_storage.clone();
+        target_storage_subdir.push(node.file_name().unwrap());
+
+        if options.migrate && migrate::create_missing_dir(&*options.fs, &target_storage_subdir)? {
+            let metadata = target_storage_subdir.metadata()?;
+            let mut permissions = metadata.permissions();
+            permissions.set_mode(0o755);
+            fs::set_permissions(&target_storage_subdir, permissions)?;
+        }
+
+        report_unusable(&source_storage_subdir, ResourceType::Storage, options)?;
+        let mut files = migrate::collect_rrd_files_with(
+            &*options.fs,
+            &source_storage_subdir,
+            &options.old_suffix,
+        )?;
+        files.retain(|file| options.is_selected(file) && options.is_storage_selected(&file.1));
+        take_invalid_names(&mut files, ResourceType::Storage, options);
+        twins::resolve_all(&files, ResourceType::Storage, options);
+        for link in symlinks::take_links(&mut files, ResourceType::Storage, options) {
+            storage_links.push((target_storage_subdir.clone(), link));
+        }
+        Ok((target_storage_subdir, files))
+    };
+    let configured = if options.prune_removed_storages {
+        Some(migrate::read_storage_ids(&format!(
+            "{resources}/{STORAGE_CONFIG}"
+        ))?)
+    } else {
+        None
+    };
+
+    let mut no_migration_err = true;
+    // failed files that cannot be retried, like corrupt sources
+    let mut failed = 0;
+    let mut retry = Vec::new();
+    let mut done = 0;
+    let mut migrate_file = |node: &Path,
+                            target_storage_subdir: &Path,
+                            file: RRDFile,
+                            total_storages: usize|
+     -> Result<(), Error> {
+        options.control.checkpoint(&options.notifier)?;
+        done += 1;
+        options.notifier.watchdog_ping();
+        let storage = format!(
+            "{}/{}",
+            node.file_name()
+                .expect("no file name present")
+                .to_string_lossy(),
+            PathBuf::from(file.1.clone()).display()
+        );
+        debug!("Migrating metrics for storage '{storage}'");
+
+        if configured
+            .as_ref()
+            .is_some_and(|ids| !ids.contains(file.1.to_string_lossy().as_ref()))
+        {
+            options
+                .report
+                .add(ErrorCause::NotPresent, storage.as_str(), None, None);
+            if options.migrate {
+                debug!(
+                    status = "marked-old",
+                    "Storage: '{storage}' not configured. Skip and mark as old."
+                );
+            } else {
+                debug!(status = "skipped", "Storage: '{storage}' not configured. Would mark as old, but in dry-run mode, so just skip.");
+            }
+            mark_not_present(
+                source_path(&file),
+                STORAGE_CONFIG,
+                ResourceType::Storage,
+                options,
+            )?;
+            return Ok(());
+        }
+        if skip_stale(&file, ResourceType::Storage, options)? {
+            return Ok(());
+        }
+        // a source that cannot be dealt with fails like its migration, not the whole phase
+        let target = target_storage_subdir.join(&file.1);
+        match do_rrd_migration_with_timeout(
+            file.clone(),
+            target_storage_subdir,
+            ResourceType::Storage,
+            options,
+        )
+        .and_then(|()| finish_source(source_path(&file), &target, ResourceType::Storage, options))
+        {
+            Ok(_) => {}
+            Err(err) => {
+                log_file_error(&err);
+                if is_retryable(&err) {
+                    options.errors.record(file.0.as_bytes())?;
+                    retry.push((file, target_storage_subdir.to_path_buf(), err));
+                } else {
+                    if !is_skip(&err) {
+                        options.errors.record(file.0.as_bytes())?;
+                        failed += 1;
+                    }
+                    report_failure(&options.report, storage.as_str(), &file.0, &err);
+                    no_migration_err = false;
+                }
+            }
+        }
+        if options.progress_every.is_due(done, total_storages) {
+            info!(
+                "processed metrics for {done} out of {total_storages} storages{}.",
+                eta_suffix(ResourceType::Storage, options)
+            );
+        }
+        Ok(())
+    };
+
+    if options.low_memory {
+        // list the files node by node while migrating them, instead of those of all nodes at once,
+        // after only counting them for the progress
+        let mut total_storages = 0;
+        for node in &nodes {
+            total_storages += count_node(node)?;
+        }
+        options
+            .progress
+            .phase_start(ResourceType::Storage, total_storages);
+        for node in &nodes {
+            let (target_storage_subdir, files) = list_node(node)?;
+            for file in files {
+                migrate_file(node, &target_storage_subdir, file, total_storages)?;
+            }
+        }
+    } else {
+        // collect the files of all nodes first to know their total
+        let listed = nodes
+            .iter()
+            .map(|node| Ok((node, list_node(node)?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let total_storages: usize = listed.iter().map(|(_, (_, files))| files.len()).sum();
+        options
+            .progress
+            .phase_start(ResourceType::Storage, total_storages);
+        for (node, (target_storage_subdir, files)) in listed {
+            for file in files {
+                migrate_file(node, &target_storage_subdir, file, total_storages)?;
+            }
+        }
+    }
+    let mut failed = failed + retry_failed_files(retry, ResourceType::Storage, options);
+    for (target_storage_subdir, link) in storage_links {
+        failed += symlinks::replicate_links(
+            vec![link],
+            &target_storage_subdir,
+            ResourceType::Storage,
+            options,
+        );
+    }
+    if failed > 0 {
+        no_migration_err = false;
+    }
+
+    options.progress.phase_end(ResourceType::Storage);
+    if no_migration_err {
+        info!("Migrated metrics of all storages to new format");
+    } else {
+        info!("Tried to migrated metrics of all storages to new format - see output above for details.");
+    }
+
+    Ok(failed)
+}
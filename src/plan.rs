@@ -0,0 +1,344 @@
+//! Plan of what a migration would do with each file, instead of only skipping them in a dry run
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{bail, Error};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use proxmox_rrd_migration_tool::migrate::{self, ResourceType};
+
+use crate::file::stale_for;
+use crate::guest_pool::remote_guest_node;
+use crate::{
+    MigrationDir, MigrationOptions, RemoteGuests, EXIT_FAILURE, EXIT_SUCCESS, STORAGE_CONFIG,
+};
+
+/// Exit code of --needs-migration if all files were migrated already, or there are none
+pub const NOT_NEEDED: i32 = EXIT_SUCCESS;
+/// Exit code of --needs-migration if there are files left to migrate, unlike any other one
+pub const NEEDED: i32 = 10;
+/// Exit code of --needs-migration if the files or resource lists could not be read
+pub const UNKNOWN: i32 = EXIT_FAILURE;
+
+/// How to print the plan or the findings of --fsck
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => bail!("unknown format '{value}', use text or json"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Action {
+    Migrate,
+    /// the target exists and --force is set
+    Overwrite,
+    /// the source was modified after the target and --incremental is set
+    Update,
+    /// the target was left incomplete by an interrupted run
+    Replace,
+    /// the target exists already, or the guest is hosted on another node
+    Skip,
+    /// the resource is gone, hosted on another node with --remote-guests archive or the file is
+    /// stale, it is renamed to .old
+    MarkOld,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Action::Migrate => "migrate",
+            Action::Overwrite => "overwrite",
+            Action::Update => "update",
+            Action::Replace => "replace",
+            Action::Skip => "skip",
+            Action::MarkOld => "mark-old",
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct PlannedFile {
+    resource: String,
+    source: PathBuf,
+    target: PathBuf,
+    action: Action,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct TypePlan {
+    resource_type: String,
+    schema: Vec<String>,
+    files: Vec<PlannedFile>,
+}
+
+impl TypePlan {
+    fn count(&self, action: Action) -> usize {
+        self.files
+            .iter()
+            .filter(|file| file.action == action)
+            .count()
+    }
+
+    /// Files that are not migrated yet, not counting existing targets that --force overwrites
+    fn left(&self) -> usize {
+        self.count(Action::Migrate)
+            + self.count(Action::Update)
+            + self.count(Action::Replace)
+            + self.count(Action::MarkOld)
+    }
+}
+
+/// Decide for every selected file what a migration would do with it
+fn plan(
+    dirs: &[MigrationDir],
+    resources: &str,
+    options: &MigrationOptions,
+) -> Result<Vec<TypePlan>, Error> {
+    let storages = if options.prune_removed_storages {
+        Some(migrate::read_storage_ids(&format!(
+            "{resources}/{STORAGE_CONFIG}"
+        ))?)
+    } else {
+        None
+    };
+    let mut plans: Vec<TypePlan> = Vec::new();
+    for dir in dirs {
+        let index = match plans
+            .iter()
+            .position(|plan| plan.resource_type == dir.kind.to_string())
+        {
+            Some(index) => index,
+            None => {
+                plans.push(TypePlan {
+                    resource_type: dir.kind.to_string(),
+                    schema: dir
+                        .kind
+                        .rrd_def()
+                        .iter()
+                        .map(|def| def.to_string_lossy().into_owned())
+                        .collect(),
+                    files: Vec::new(),
+                });
+                plans.len() - 1
+            }
+        };
+
+        let mut files =
+            migrate::collect_rrd_files_with(&*options.fs, &dir.source, &options.old_suffix)?;
+        files.retain(|file| {
+            options.is_selected(file)
+                && migrate::is_valid_resource_name(dir.kind, &file.1)
+                && (dir.kind != ResourceType::Storage || options.is_storage_selected(&file.1))
+        });
+        files.sort_by(|a, b| a.1.cmp(&b.1));
+        for file in files {
+            let resource = file.1.to_string_lossy().into_owned();
+            let present = match dir.kind {
+                ResourceType::Node => options.resources.contains(dir.kind, &resource)?,
+                ResourceType::Guest => {
+                    options.migrate_orphans || options.resources.contains(dir.kind, &resource)?
+                }
+                ResourceType::Storage => storages
+                    .as_ref()
+                    .is_none_or(|storages| storages.contains(&resource)),
+            };
+            // decided like the migration does, which only looks at this for present guests
+            let remote = match dir.kind {
+                ResourceType::Guest if present => remote_guest_node(&resource, options)?,
+                _ => None,
+            };
+            let source = PathBuf::from(OsStr::from_bytes(file.0.as_bytes()));
+            let target = dir.target.join(&file.1);
+            let action = if remote.is_some() {
+                match options.remote_guests {
+                    RemoteGuests::Archive => Action::MarkOld,
+                    _ => Action::Skip,
+                }
+            } else if !present || stale_for(&file, options).is_some() {
+                Action::MarkOld
+            } else if target.exists() && options.force {
+                Action::Overwrite
+            } else if target.exists() && migrate::check_target(&target).is_err() {
+                Action::Replace
+            } else if target.exists()
+                && options.incremental
+                && !migrate::is_up_to_date(&source, &target)?
+            {
+                Action::Update
+            } else if target.exists() {
+                Action::Skip
+            } else {
+                Action::Migrate
+            };
+            plans[index].files.push(PlannedFile {
+                resource,
+                source,
+                target,
+                action,
+            });
+        }
+    }
+    Ok(plans)
+}
+
+fn print_text(plans: &[TypePlan]) {
+    for plan in plans {
+        let data_sources: Vec<&str> = plan
+            .schema
+            .iter()
+            .filter_map(|def| def.strip_prefix("DS:")?.split(':').next())
+            .collect();
+        let rras = plan
+            .schema
+            .iter()
+            .filter(|def| def.starts_with("RRA:"))
+            .count();
+        println!(
+            "{}: {} file(s), data sources {}, {rras} RRAs",
+            plan.resource_type,
+            plan.files.len(),
+            data_sources.join(", ")
+        );
+        for file in &plan.files {
+            match file.action {
+                Action::Migrate | Action::Overwrite | Action::Update | Action::Replace => println!(
+                    "  {:<9} {} -> {}",
+                    file.action,
+                    file.source.display(),
+                    file.target.display()
+                ),
+                Action::Skip => println!(
+                    "  {:<9} {} (already migrated to {})",
+                    file.action,
+                    file.source.display(),
+                    file.target.display()
+                ),
+                Action::MarkOld => println!(
+                    "  {:<9} {} (resource not present anymore or stale)",
+                    file.action,
+                    file.source.display()
+                ),
+            }
+        }
+        println!(
+            "  {} to migrate, {} to overwrite, {} to update, {} to replace, {} to skip, {} to mark \
+            as old",
+            plan.count(Action::Migrate),
+            plan.count(Action::Overwrite),
+            plan.count(Action::Update),
+            plan.count(Action::Replace),
+            plan.count(Action::Skip),
+            plan.count(Action::MarkOld),
+        );
+    }
+}
+
+/// Print the plan on stdout, without changing anything
+///
+/// Returns the exit code.
+pub(crate) fn run(
+    dirs: &[MigrationDir],
+    resources: &str,
+    format: OutputFormat,
+    options: &MigrationOptions,
+) -> i32 {
+    let plans = match plan(dirs, resources, options) {
+        Ok(plans) => plans,
+        Err(err) => {
+            error!("Error: cannot plan the migration: {err}");
+            return EXIT_FAILURE;
+        }
+    };
+    match format {
+        OutputFormat::Text => print_text(&plans),
+        OutputFormat::Json => match serde_json::to_string_pretty(&plans) {
+            Ok(json) => println!("{json}"),
+            Err(err) => {
+                error!("Error: cannot serialize the plan: {err}");
+                return EXIT_FAILURE;
+            }
+        },
+    }
+    EXIT_SUCCESS
+}
+
+/// Print a single line on stdout whether files are left to migrate, for the pve8to9 checks
+///
+/// Returns [`NOT_NEEDED`], [`NEEDED`] or [`UNKNOWN`] as exit code.
+pub(crate) fn needs_migration(
+    dirs: &[MigrationDir],
+    resources: &str,
+    options: &MigrationOptions,
+) -> i32 {
+    let plans = match plan(dirs, resources, options) {
+        Ok(plans) => plans,
+        Err(err) => {
+            println!("cannot determine whether the RRD files need a migration: {err}");
+            return UNKNOWN;
+        }
+    };
+    let left: Vec<String> = plans
+        .iter()
+        .map(|plan| (plan, plan.left()))
+        .filter(|(_, left)| *left > 0)
+        .map(|(plan, left)| format!("{left} {}", plan.resource_type))
+        .collect();
+    if left.is_empty() {
+        println!(
+            "no migration needed, {} RRD file(s) already migrated",
+            migrated_targets(dirs)
+        );
+        NOT_NEEDED
+    } else {
+        println!("migration needed, RRD files left: {}", left.join(", "));
+        NEEDED
+    }
+}
+
+/// Number of files in the target directories
+fn migrated_targets(dirs: &[MigrationDir]) -> usize {
+    dirs.iter()
+        .filter_map(|dir| migrate::collect_rrd_files(&dir.target).ok())
+        .map(|files| {
+            files
+                .iter()
+                .filter(|file| !migrate::is_target_backup(&file.1))
+                .count()
+        })
+        .sum()
+}
+
+/// The number of migrated files if there is nothing left to migrate, [`None`] otherwise
+pub(crate) fn fully_migrated(
+    dirs: &[MigrationDir],
+    resources: &str,
+    options: &MigrationOptions,
+) -> Result<Option<usize>, Error> {
+    let plans = plan(dirs, resources, options)?;
+    if plans.iter().all(|plan| plan.left() == 0) {
+        Ok(Some(migrated_targets(dirs)))
+    } else {
+        Ok(None)
+    }
+}
@@ -0,0 +1,107 @@
+//! Querying the live guest and node lists from pmxcfs over its libqb IPC, like the pve-cluster
+//! consumers do, instead of reading the .vmlist and .members files it provides
+
+use std::ffi::{c_char, c_void, CStr};
+use std::io;
+use std::mem::size_of;
+
+use crate::error::MigrationError;
+
+/// Name of the IPC service of pmxcfs
+const SERVICE: &CStr = c"pve2";
+/// Largest response accepted, the guest list of a big cluster needs to fit
+const MAX_MSG_SIZE: usize = 8 * 1024 * 1024;
+const TIMEOUT_MS: i32 = 10_000;
+
+/// Message IDs of the pmxcfs IPC requests, see cfs-ipc-ops.h of pve-cluster
+const CFS_IPC_GET_CLUSTER_INFO: i32 = 2;
+const CFS_IPC_GET_GUEST_LIST: i32 = 3;
+
+#[repr(C, align(8))]
+struct RequestHeader {
+    id: i32,
+    size: i32,
+}
+
+#[repr(C, align(8))]
+struct ResponseHeader {
+    id: i32,
+    size: i32,
+    error: i32,
+}
+
+extern "C" {
+    fn qb_ipcc_connect(name: *const c_char, max_msg_size: usize) -> *mut c_void;
+    fn qb_ipcc_sendv_recv(
+        connection: *mut c_void,
+        iov: *const libc::iovec,
+        iov_len: u32,
+        response: *mut c_void,
+        response_len: usize,
+        ms_timeout: i32,
+    ) -> isize;
+    fn qb_ipcc_disconnect(connection: *mut c_void);
+}
+
+fn ipc_error(err: io::Error) -> MigrationError {
+    MigrationError::Io {
+        path: "pmxcfs IPC".into(),
+        source: err,
+    }
+}
+
+/// Send the request 'id' without data to pmxcfs, returning the data of the response
+fn request(id: i32) -> Result<String, MigrationError> {
+    let connection = unsafe { qb_ipcc_connect(SERVICE.as_ptr(), MAX_MSG_SIZE) };
+    if connection.is_null() {
+        return Err(ipc_error(io::Error::last_os_error()));
+    }
+    let header = RequestHeader {
+        id,
+        size: size_of::<RequestHeader>() as i32,
+    };
+    let iov = libc::iovec {
+        iov_base: std::ptr::addr_of!(header).cast_mut().cast(),
+        iov_len: size_of::<RequestHeader>(),
+    };
+    let mut response = vec![0u8; MAX_MSG_SIZE];
+    let len = unsafe {
+        let len = qb_ipcc_sendv_recv(
+            connection,
+            &iov,
+            1,
+            response.as_mut_ptr().cast(),
+            response.len(),
+            TIMEOUT_MS,
+        );
+        qb_ipcc_disconnect(connection);
+        len
+    };
+    if len < 0 {
+        return Err(ipc_error(io::Error::from_raw_os_error(-len as i32)));
+    }
+    let len = len as usize;
+    if len < size_of::<ResponseHeader>() {
+        return Err(ipc_error(io::Error::other("short response")));
+    }
+    let header: ResponseHeader = unsafe { std::ptr::read_unaligned(response.as_ptr().cast()) };
+    if header.error < 0 {
+        return Err(ipc_error(io::Error::from_raw_os_error(-header.error)));
+    }
+    let end = usize::try_from(header.size).unwrap_or(0).min(len);
+    let data = response
+        .get(size_of::<ResponseHeader>()..end)
+        .unwrap_or_default();
+    let data = data.strip_suffix(b"\0").unwrap_or(data);
+    Ok(String::from_utf8_lossy(data).into_owned())
+}
+
+/// The current guest list, in the format of .vmlist
+pub fn guest_list() -> Result<String, MigrationError> {
+    request(CFS_IPC_GET_GUEST_LIST)
+}
+
+/// The current cluster membership, in the format of .members
+pub fn cluster_info() -> Result<String, MigrationError> {
+    request(CFS_IPC_GET_CLUSTER_INFO)
+}
@@ -0,0 +1,265 @@
+//! Checks before the migration touches any file
+
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Error};
+use tracing::warn;
+
+use proxmox_rrd_migration_tool::migrate::ResourceType;
+
+use crate::{
+    marker, MigrationOptions, SourceHandling, SOURCE_SUBDIR_GUEST, SOURCE_SUBDIR_NODE,
+    SOURCE_SUBDIR_STORAGE, STORAGE_CONFIG, TARGET_SUBDIR_GUEST, TARGET_SUBDIR_NODE,
+    TARGET_SUBDIR_STORAGE,
+};
+
+/// Checks that need to pass before anything is touched
+pub(crate) fn check(
+    resources: &str,
+    target: &Path,
+    options: &MigrationOptions,
+) -> Result<(), Error> {
+    if options.resources.ipc {
+        options
+            .resources
+            .guests()
+            .and_then(|_| options.resources.nodes())
+            .context("cannot query the resource lists from pmxcfs")?;
+    } else {
+        for list in [".vmlist", ".members"] {
+            let path = format!("{resources}/{list}");
+            fs::File::open(&path).context(format!("cannot read resource list {path:?}"))?;
+        }
+        // a list that does not parse must not make the guests and nodes in it look removed
+        options
+            .resources
+            .guests()
+            .and_then(|_| options.resources.nodes())
+            .context(format!("invalid resource list in {resources:?}"))?;
+    }
+    if options.prune_removed_storages {
+        let path = format!("{resources}/{STORAGE_CONFIG}");
+        fs::File::open(&path).context(format!("cannot read storage configuration {path:?}"))?;
+    }
+    let quorate = options
+        .resources
+        .is_quorate()
+        .context("cannot read the quorum state")?;
+    if !quorate && options.ignore_quorum {
+        warn!("The cluster is not quorate, the guest list may be stale, continuing anyway");
+    } else if !quorate && options.migrate {
+        bail!(
+            "the cluster is not quorate, so the guest list may be stale and guests could be \
+            taken for removed ones, use --ignore-quorum to migrate anyway"
+        );
+    } else if !quorate {
+        warn!("The cluster is not quorate, the guest list may be stale");
+    }
+    check_access(resources, target, options)?;
+    if options.migrate {
+        probe_writable(target, "target")?;
+        if options.sources != SourceHandling::Keep {
+            probe_writable(&options.source_base, "source")?;
+        }
+    }
+    Ok(())
+}
+
+/// Warn about source directories another host migrated, refuse migrating them again without force
+pub(crate) fn check_markers(dirs: &[&Path], options: &MigrationOptions) -> Result<(), Error> {
+    let markers = match marker::foreign(dirs) {
+        Ok(markers) => markers,
+        Err(err) => {
+            warn!("could not check which host migrated the sources - {err:#}");
+            return Ok(());
+        }
+    };
+    for (dir, marker) in &markers {
+        warn!(
+            "{} was migrated by host '{}' already, in run {} at {}",
+            dir.display(),
+            marker.host,
+            marker.run_id,
+            marker.time
+        );
+    }
+    if !markers.is_empty() && options.migrate && !options.force {
+        bail!(
+            "the source directories are shared with or were copied from another host, use \
+            --force to migrate them again anyway"
+        );
+    }
+    Ok(())
+}
+
+/// Check that this user may read, and when migrating change, the directories of the migration
+fn check_access(resources: &str, target: &Path, options: &MigrationOptions) -> Result<(), Error> {
+    let read = libc::R_OK | libc::X_OK;
+    let write = if options.migrate { libc::W_OK } else { 0 };
+    let source_write = if options.sources == SourceHandling::Keep {
+        0
+    } else {
+        write
+    };
+    let mut dirs = vec![(PathBuf::from(resources), "resources", read)];
+    for subdir in [
+        SOURCE_SUBDIR_GUEST,
+        SOURCE_SUBDIR_NODE,
+        SOURCE_SUBDIR_STORAGE,
+    ] {
+        dirs.push((
+            options.source_base.join(subdir),
+            "source",
+            read | source_write,
+        ));
+    }
+    dirs.push((target.to_owned(), "target", read | write));
+    for subdir in [
+        TARGET_SUBDIR_GUEST,
+        TARGET_SUBDIR_NODE,
+        TARGET_SUBDIR_STORAGE,
+    ] {
+        dirs.push((target.join(subdir), "target", read | write));
+    }
+
+    let mut missing = Vec::new();
+    for (dir, name, mode) in dirs {
+        let Ok(path) = CString::new(dir.as_os_str().as_bytes()) else {
+            continue;
+        };
+        if unsafe { libc::faccessat(libc::AT_FDCWD, path.as_ptr(), mode, libc::AT_EACCESS) } == 0 {
+            continue;
+        }
+        // missing directories are created or skipped, read-only file systems are probed later
+        let err = std::io::Error::last_os_error();
+        if matches!(err.raw_os_error(), Some(libc::EACCES | libc::EPERM)) {
+            let access = if mode & libc::W_OK != 0 {
+                "read and write"
+            } else {
+                "read"
+            };
+            missing.push(format!("{access} access to the {name} directory {dir:?}"));
+        }
+    }
+    if !missing.is_empty() {
+        let uid = unsafe { libc::geteuid() };
+        bail!(
+            "running as user ID {uid}, which has no {}, run the migration as root",
+            missing.join(", no ")
+        );
+    }
+    Ok(())
+}
+
+/// Check once that files can be created in 'dir', instead of failing for every single file
+fn probe_writable(dir: &Path, name: &str) -> Result<(), Error> {
+    let probe = dir.join(format!(".rrd-migration-probe.{}", std::process::id()));
+    match fs::File::create_new(&probe) {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            Ok(())
+        }
+        Err(err) if err.raw_os_error() == Some(libc::EROFS) => bail!(
+            "the {name} directory {dir:?} is on a read-only file system, remount it read-write \
+            before migrating"
+        ),
+        Err(err) => bail!("cannot write to the {name} directory {dir:?} - {err}"),
+    }
+}
+
+/// The canonical path of the base directory given with 'option', or of 'default' without it
+pub(crate) fn resolve_base_dir(
+    option: &str,
+    value: Option<&str>,
+    default: &str,
+) -> Result<String, String> {
+    let (path, name) = match value {
+        Some(path) => (path, format!("{option} {path:?}")),
+        None => (
+            default,
+            format!("the default {option} directory {default:?}"),
+        ),
+    };
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => return Err(format!("{name} is not a directory")),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Err(format!("{name} does not exist"))
+        }
+        Err(err) => return Err(format!("{name} cannot be accessed - {err}")),
+    }
+    let resolved = Path::new(path)
+        .canonicalize()
+        .map_err(|err| format!("{name} cannot be resolved - {err}"))?;
+    resolved
+        .into_os_string()
+        .into_string()
+        .map_err(|resolved| format!("{name} resolves to the non UTF-8 path {resolved:?}"))
+}
+
+/// 'path' with all symbolic links and . or .. components resolved, also if it does not exist yet
+fn resolve_path(path: &Path) -> PathBuf {
+    if let Ok(resolved) = path.canonicalize() {
+        return resolved;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => {
+            resolve_path(parent).join(name)
+        }
+        _ => std::path::absolute(path).unwrap_or_else(|_| path.to_owned()),
+    }
+}
+
+/// Check that no target directory is inside or around a source directory, whatever links lead there
+pub(crate) fn check_overlap(
+    sources: &[(ResourceType, &Path)],
+    targets: &[(ResourceType, &Path)],
+) -> Result<(), String> {
+    for (target_kind, target) in targets {
+        let resolved_target = resolve_path(target);
+        for (source_kind, source) in sources {
+            let resolved_source = resolve_path(source);
+            if resolved_target.starts_with(&resolved_source)
+                || resolved_source.starts_with(&resolved_target)
+            {
+                return Err(format!(
+                    "the {target_kind} target directory {target:?} ({resolved_target:?}) overlaps \
+                    with the {source_kind} source directory {source:?} ({resolved_source:?})"
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proxmox_rrd_migration_tool::migrate;
+
+    #[test]
+    fn overlapping_directories() {
+        let dir = migrate::create_private_dir(&std::env::temp_dir(), "check-overlap-").unwrap();
+        let source = dir.join("source");
+        std::fs::create_dir_all(source.join("pve2-node")).unwrap();
+        std::fs::create_dir(dir.join("target")).unwrap();
+        std::os::unix::fs::symlink(&source, dir.join("link")).unwrap();
+
+        let sources = [(ResourceType::Node, source.as_path())];
+        let check = |target: &Path| check_overlap(&sources, &[(ResourceType::Node, target)]);
+
+        assert!(check(&dir.join("target/pve-node-9.0")).is_ok());
+        assert!(check(&dir.join("source-new")).is_ok());
+        // a target inside of or containing the source directory
+        assert!(check(&source.join("pve-node-9.0")).is_err());
+        assert!(check(&dir).is_err());
+        // the same, but through a symbolic link, .. or a directory that does not exist yet
+        assert!(check(&dir.join("link/pve-node-9.0")).is_err());
+        assert!(check(&dir.join("target/../source/new/pve-node-9.0")).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
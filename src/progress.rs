@@ -0,0 +1,339 @@
+//! Progress of the migration phases, as machine-readable JSON lines on a file descriptor for
+//! frontends like installers or upgrade checkers, and as status for systemd
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{FromRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::notify::Notifier;
+
+/// Number of the most recently migrated files the ETA is based on
+const ETA_WINDOW: usize = 50;
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+enum Event<'a> {
+    #[serde(rename_all = "kebab-case")]
+    PhaseStart { phase: &'a str, total: usize },
+    #[serde(rename_all = "kebab-case")]
+    File {
+        phase: &'a str,
+        file: &'a str,
+        result: &'a str,
+        done: usize,
+        total: usize,
+        failed: usize,
+    },
+    #[serde(rename_all = "kebab-case")]
+    PhaseEnd {
+        phase: &'a str,
+        done: usize,
+        total: usize,
+        failed: usize,
+    },
+    #[serde(rename_all = "kebab-case")]
+    AlreadyMigrated { files: usize },
+    #[serde(rename_all = "kebab-case")]
+    Finished { run_id: &'a str, exit_code: i32 },
+}
+
+#[derive(Debug, Default)]
+struct PhaseState {
+    total: usize,
+    done: usize,
+    /// files that failed so far, a retry that succeeds takes them out again
+    failed: HashSet<String>,
+    started: Option<Instant>,
+    finished: Option<Instant>,
+    /// size of the source files of the phase migrated so far, and their number
+    bytes: u64,
+    sized: usize,
+    /// when the most recently migrated files were done and their size
+    recent: VecDeque<(Instant, u64)>,
+}
+
+impl PhaseState {
+    /// Bytes per second over the recently migrated files, a stall slows it down
+    fn rate(&self, now: Instant) -> Option<f64> {
+        let (since, _) = self.recent.front()?;
+        let bytes: u64 = self.recent.iter().skip(1).map(|(_, bytes)| bytes).sum();
+        let elapsed = (now - *since).as_secs_f64();
+        (bytes > 0 && elapsed > 0.0).then(|| bytes as f64 / elapsed)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// where to write the JSON events to
+    out: Option<File>,
+    notifier: Notifier,
+    phases: HashMap<String, PhaseState>,
+    /// phase names in the order they started
+    order: Vec<String>,
+    /// files currently being migrated, with the thread and since when
+    current: HashMap<String, (String, Instant)>,
+    /// size of the source files migrated so far
+    bytes: u64,
+}
+
+/// Progress of a single phase at some point in time
+#[derive(Clone, Debug)]
+pub struct PhaseSnapshot {
+    pub name: String,
+    pub total: usize,
+    pub done: usize,
+    pub failed: usize,
+    pub elapsed: Duration,
+    pub finished: bool,
+    /// bytes per second over the most recently migrated files, if known
+    pub rate: Option<f64>,
+    /// average size of the source files migrated so far, if known
+    pub average_size: Option<f64>,
+}
+
+impl PhaseSnapshot {
+    /// Estimated time until all files of the phase are done
+    ///
+    /// Based on how quickly the most recent files were migrated, weighted by their size, or on
+    /// the time per file so far while there are too few of them.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.finished || self.done == 0 || self.done >= self.total {
+            return None;
+        }
+        let remaining = (self.total - self.done) as f64;
+        match (self.rate, self.average_size) {
+            (Some(rate), Some(size)) => Duration::try_from_secs_f64(remaining * size / rate).ok(),
+            _ => Some(self.elapsed.mul_f64(remaining / self.done as f64)),
+        }
+    }
+}
+
+/// 'duration' as hours, minutes and seconds, like 1:02:03
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// A file that is being migrated right now
+#[derive(Clone, Debug)]
+pub struct CurrentFile {
+    pub thread: String,
+    pub file: String,
+    pub elapsed: Duration,
+}
+
+/// Progress of all phases so far
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub phases: Vec<PhaseSnapshot>,
+    pub current: Vec<CurrentFile>,
+    /// size of the source files migrated so far
+    pub bytes: u64,
+}
+
+impl Inner {
+    /// Write one event per line, a reader going away must not break the migration
+    fn emit(&mut self, event: &Event) {
+        let Some(out) = &mut self.out else {
+            return;
+        };
+        if let Ok(mut line) = serde_json::to_vec(event) {
+            line.push(b'\n');
+            let _ = out.write_all(&line);
+        }
+    }
+
+    fn update_status(&self, phase: &str) {
+        let state = &self.phases[phase];
+        self.notifier.status(&format!(
+            "migrating {phase} metrics: {} of {} files, {} failed",
+            state.done,
+            state.total,
+            state.failed.len()
+        ));
+    }
+}
+
+/// Tracks the progress and reports it where requested
+#[derive(Clone, Debug, Default)]
+pub struct Progress {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Progress {
+    /// Write the events to the already open file descriptor 'fd'
+    pub fn set_fd(&self, fd: RawFd) -> std::io::Result<()> {
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        self.inner.lock().unwrap().out = Some(unsafe { File::from_raw_fd(fd) });
+        Ok(())
+    }
+
+    /// Report the status of the current phase to systemd
+    pub fn set_notifier(&self, notifier: Notifier) {
+        self.inner.lock().unwrap().notifier = notifier;
+    }
+
+    /// A phase starts with 'total' files to handle
+    pub fn phase_start(&self, phase: impl ToString, total: usize) {
+        let phase = phase.to_string();
+        self.with_inner(|inner| {
+            let state = inner.phases.entry(phase.clone()).or_default();
+            // files handled before, like canary files, count towards the phase too
+            state.total = state.done + total;
+            state.started = Some(Instant::now());
+            if !inner.order.contains(&phase) {
+                inner.order.push(phase.clone());
+            }
+            let total = state.total;
+            inner.emit(&Event::PhaseStart {
+                phase: &phase,
+                total,
+            });
+            inner.update_status(&phase);
+        });
+    }
+
+    /// The calling thread starts migrating 'file'
+    pub fn file_started(&self, file: &str) {
+        let thread = std::thread::current().name().unwrap_or("main").to_string();
+        self.with_inner(|inner| {
+            inner
+                .current
+                .insert(file.to_string(), (thread, Instant::now()));
+        });
+    }
+
+    /// A file was handled, 'result' is one of the audit log outcomes
+    pub fn file(&self, phase: impl ToString, file: &str, result: &str, failed: bool) {
+        let phase = phase.to_string();
+        self.with_inner(|inner| {
+            inner.current.remove(file);
+            let state = inner.phases.entry(phase.clone()).or_default();
+            let retried = state.failed.contains(file);
+            if !retried {
+                state.done += 1;
+            }
+            if failed {
+                state.failed.insert(file.to_string());
+            } else if retried {
+                state.failed.remove(file);
+            }
+            let event = Event::File {
+                phase: &phase,
+                file,
+                result,
+                done: state.done,
+                total: state.total,
+                failed: state.failed.len(),
+            };
+            inner.emit(&event);
+            inner.update_status(&phase);
+        });
+    }
+
+    pub fn phase_end(&self, phase: impl ToString) {
+        let phase = phase.to_string();
+        self.with_inner(|inner| {
+            let state = inner.phases.entry(phase.clone()).or_default();
+            state.finished = Some(Instant::now());
+            let event = Event::PhaseEnd {
+                phase: &phase,
+                done: state.done,
+                total: state.total,
+                failed: state.failed.len(),
+            };
+            inner.emit(&event);
+        });
+    }
+
+    /// A source file of 'bytes' of the phase was migrated
+    pub fn bytes_processed(&self, phase: impl ToString, bytes: u64) {
+        let phase = phase.to_string();
+        self.with_inner(|inner| {
+            inner.bytes += bytes;
+            let state = inner.phases.entry(phase).or_default();
+            state.bytes += bytes;
+            state.sized += 1;
+            // one more, the first only marks the start of the window
+            if state.recent.len() > ETA_WINDOW {
+                state.recent.pop_front();
+            }
+            state.recent.push_back((Instant::now(), bytes));
+        });
+    }
+
+    /// Estimated time until all files of 'phase' are done, if it is running
+    pub fn eta(&self, phase: impl ToString) -> Option<Duration> {
+        let phase = phase.to_string();
+        self.snapshot()
+            .phases
+            .into_iter()
+            .find(|snapshot| snapshot.name == phase)?
+            .eta()
+    }
+
+    /// Nothing was left to migrate, 'files' were migrated by earlier runs
+    pub fn already_migrated(&self, files: usize) {
+        self.with_inner(|inner| inner.emit(&Event::AlreadyMigrated { files }));
+    }
+
+    pub fn finished(&self, run_id: &str, exit_code: i32) {
+        self.with_inner(|inner| {
+            inner.emit(&Event::Finished { run_id, exit_code });
+            inner
+                .notifier
+                .status(&format!("finished with exit code {exit_code}"));
+        });
+    }
+
+    /// Current progress of all phases that started so far
+    pub fn snapshot(&self) -> Snapshot {
+        let inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let phases = inner
+            .order
+            .iter()
+            .map(|name| {
+                let state = &inner.phases[name];
+                let started = state.started.unwrap_or(now);
+                PhaseSnapshot {
+                    name: name.clone(),
+                    total: state.total,
+                    done: state.done,
+                    failed: state.failed.len(),
+                    elapsed: state.finished.unwrap_or(now) - started,
+                    finished: state.finished.is_some(),
+                    rate: state.rate(now),
+                    average_size: (state.sized > 0)
+                        .then(|| state.bytes as f64 / state.sized as f64),
+                }
+            })
+            .collect();
+        let mut current: Vec<CurrentFile> = inner
+            .current
+            .iter()
+            .map(|(file, (thread, since))| CurrentFile {
+                thread: thread.clone(),
+                file: file.clone(),
+                elapsed: now - *since,
+            })
+            .collect();
+        current.sort_by(|a, b| a.thread.cmp(&b.thread));
+        Snapshot {
+            phases,
+            current,
+            bytes: inner.bytes,
+        }
+    }
+
+    fn with_inner(&self, f: impl FnOnce(&mut Inner)) {
+        f(&mut self.inner.lock().unwrap());
+    }
+}
@@ -0,0 +1,191 @@
+//! Migrating the guest files on a rayon work-stealing pool, instead of the [`ParallelHandler`]
+//!
+//! The cost per file varies a lot with its size and the storage it is on, idle workers steal the
+//! queued files of busy ones. Failures, retries, the abort after too many errors and the
+//! warnings about slow files are handled like with the [`ParallelHandler`], the pool cannot
+//! scale or give up on stuck files though, so --max-threads, --file-timeout, --io-threads and
+//! --batch-size are rejected when built with it.
+//!
+//! [`ParallelHandler`]: crate::parallel_handler::ParallelHandler
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{format_err, Error};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use proxmox_rrd_migration_tool::migrate::{self, RRDFile};
+use tracing::warn;
+
+use crate::file::wait_before_retry;
+use crate::guest_pool::{is_guest_dispatched, migrate_guest, FileError, GuestResults};
+use crate::parallel_handler::PanicError;
+use crate::MigrationOptions;
+
+/// How long to wait for an outcome before pinging the service watchdog again
+const PING_INTERVAL: Duration = Duration::from_millis(500);
+
+type Outcome = Result<OsString, FileError>;
+
+/// A file one of the workers is converting
+struct RunningFile {
+    thread: String,
+    started: Instant,
+    reported: bool,
+}
+
+/// The pool with what its workers need for each file
+struct Workers {
+    pool: rayon::ThreadPool,
+    target_dir: Arc<Path>,
+    /// number of migrated files, out of 'total'
+    done: Arc<AtomicUsize>,
+    total: usize,
+    options: MigrationOptions,
+    sender: Sender<Outcome>,
+    running: Arc<Mutex<HashMap<String, RunningFile>>>,
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> Option<String> {
+    panic
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+}
+
+impl Workers {
+    /// Queue 'file', its outcome is sent to the receiver of 'sender'
+    fn spawn(&self, file: RRDFile) {
+        let target_dir = Arc::clone(&self.target_dir);
+        let done = Arc::clone(&self.done);
+        let total = self.total;
+        let options = self.options.clone();
+        let sender = self.sender.clone();
+        let running = Arc::clone(&self.running);
+        self.pool.spawn(move || {
+            let item = format!("{file:?}");
+            let thread = std::thread::current()
+                .name()
+                .unwrap_or_default()
+                .to_string();
+            let started = Instant::now();
+            let file_state = RunningFile {
+                thread,
+                started,
+                reported: false,
+            };
+            running.lock().unwrap().insert(item.clone(), file_state);
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+                migrate_guest(file, &target_dir, &done, total, &options)
+            }));
+            running.lock().unwrap().remove(&item);
+            let outcome = outcome.unwrap_or_else(|panic| {
+                let message = panic_message(&*panic);
+                Err(PanicError { item, message }.into())
+            });
+            let _ = sender.send(outcome);
+        });
+    }
+
+    /// Warn once about each file that takes longer than the stall timeout
+    fn report_stalled(&self) {
+        let mut running = self.running.lock().unwrap();
+        for (item, file) in running.iter_mut() {
+            let elapsed = file.started.elapsed();
+            if file.reported || elapsed <= self.options.stall_timeout {
+                continue;
+            }
+            file.reported = true;
+            warn!(
+                "migration of {item} is still running after {}s in {}",
+                elapsed.as_secs(),
+                file.thread,
+            );
+        }
+    }
+
+    /// Handle the outcomes until at most 'limit' files are queued or running
+    fn wait(&self, results: &mut GuestResults, receiver: &Receiver<Outcome>, limit: usize) {
+        while results.outstanding > limit {
+            match receiver.recv_timeout(PING_INTERVAL) {
+                Ok(outcome) => results.handle(outcome),
+                Err(RecvTimeoutError::Timeout) => {
+                    results.notifier.watchdog_ping();
+                    self.report_stalled();
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+}
+
+/// Migrate the guest 'files' to 'target_dir' on a rayon pool with the configured threads
+///
+/// The outcomes end up in 'results', the files queued on the pool in 'dispatched'.
+pub(crate) fn run_guests(
+    files: Vec<RRDFile>,
+    target_dir: &Path,
+    options: &MigrationOptions,
+    results: &mut GuestResults,
+    dispatched: &mut HashMap<String, RRDFile>,
+) -> Result<(), Error> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.threads)
+        .thread_name(|id| format!("guest rrd migration ({id})"))
+        .start_handler(|_| migrate::init_rrd_thread())
+        .build()
+        .map_err(|err| format_err!("could not start the guest migration threads - {err}"))?;
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let workers = Workers {
+        pool,
+        target_dir: Arc::from(target_dir),
+        done: Arc::new(AtomicUsize::new(0)),
+        total: files.len(),
+        options: options.clone(),
+        sender,
+        running: Arc::new(Mutex::new(HashMap::new())),
+    };
+    // like the bounded channel of the ParallelHandler, so an abort leaves the rest unqueued
+    let queue = options.threads * 2;
+
+    for file in files {
+        if let Err(abort) = options.control.checkpoint(&options.notifier) {
+            results.aborted = Some(abort);
+            break;
+        }
+        if !is_guest_dispatched(&file, options)? {
+            continue;
+        }
+        dispatched.insert(format!("{file:?}"), file.clone());
+        workers.spawn(file);
+        results.outstanding += 1;
+
+        workers.wait(results, &receiver, queue);
+        if results.aborted.is_some() {
+            break;
+        }
+    }
+
+    for attempt in 1..=options.retries {
+        if results.aborted.is_some() {
+            break;
+        }
+        workers.wait(results, &receiver, 0);
+        let retry = results.take_retryable();
+        if retry.is_empty() {
+            break;
+        }
+        wait_before_retry(retry.len(), attempt, options);
+        for file in results.retry_sources(retry, target_dir, options) {
+            workers.spawn(file);
+            results.outstanding += 1;
+        }
+    }
+
+    workers.wait(results, &receiver, 0);
+    Ok(())
+}
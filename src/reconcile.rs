@@ -0,0 +1,219 @@
+//! Finding and fixing the inconsistent states an interrupted run can leave behind
+//!
+//! For each file, the source, the old source and the target are compared:
+//! - a source next to a target that verifies against it was migrated but not marked as old
+//! - a source next to a target librrd cannot read completely left a partial target behind
+//! - a source next to a complete target with other data is only reported, either may be the one
+//!   that is right
+//! - an old source without a target lost its target
+//! - an old source next to a target that does not verify against it has a broken target
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use anyhow::Error;
+use tracing::{error, info, info_span, warn};
+
+use proxmox_rrd_migration_tool::migrate::{self, RRDFile};
+
+use crate::file::finish_source;
+use crate::remigrate::{self, FromOld};
+use crate::{
+    MigrationDir, MigrationOptions, SourceHandling, EXIT_FAILURE, EXIT_PARTIAL, EXIT_SUCCESS,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Inconsistency {
+    /// fixed by marking the source as old
+    StraySource,
+    /// fixed by deleting the target, so that the next run migrates the source
+    PartialTarget,
+    /// not fixed, the complete target might have been updated since
+    TargetMismatch,
+    /// fixed by migrating the old source again
+    MissingTarget,
+    /// fixed by migrating the old source again, keeping the broken target
+    BrokenTarget,
+}
+
+impl Inconsistency {
+    /// Whether reconciling fixes it, the others are only reported
+    fn fixable(self) -> bool {
+        self != Inconsistency::TargetMismatch
+    }
+
+    fn fix(self) -> &'static str {
+        match self {
+            Inconsistency::StraySource => "mark the source as old",
+            Inconsistency::PartialTarget => "delete the target",
+            Inconsistency::TargetMismatch => {
+                "leave both alone, check them and migrate the source with --force if it is right"
+            }
+            Inconsistency::MissingTarget | Inconsistency::BrokenTarget => {
+                "migrate the old source again"
+            }
+        }
+    }
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Inconsistency::StraySource => "migrated, but the source was not marked as old",
+            Inconsistency::PartialTarget => "partial target next to the source",
+            Inconsistency::TargetMismatch => "the target does not match the source next to it",
+            Inconsistency::MissingTarget => "marked as old, but there is no target",
+            Inconsistency::BrokenTarget => "marked as old, but the target does not verify",
+        })
+    }
+}
+
+fn path(file: &RRDFile) -> &Path {
+    Path::new(OsStr::from_bytes(file.0.as_bytes()))
+}
+
+/// The inconsistent files in 'dir', with the file each fix starts from
+fn find(
+    dir: &MigrationDir,
+    options: &MigrationOptions,
+) -> Result<Vec<(Inconsistency, RRDFile)>, Error> {
+    let mut sources =
+        migrate::collect_rrd_files_with(&*options.fs, &dir.source, &options.old_suffix)?;
+    sources.retain(|file| options.is_selected(file));
+    let old = remigrate::old_files(dir, &FromOld::All, options)?;
+
+    let mut found = Vec::new();
+    for source in sources {
+        let target = dir.target.join(&source.1);
+        // sources next to their targets are expected then
+        if !target.exists() || options.sources == SourceHandling::Keep {
+            continue;
+        }
+        // only a target that is not complete can be deleted, a complete one holds data
+        if migrate::check_target(&target).is_err() {
+            found.push((Inconsistency::PartialTarget, source));
+        } else if migrate::verify_file(path(&source), &target, dir.kind.rrd_def()).is_ok() {
+            found.push((Inconsistency::StraySource, source));
+        } else {
+            found.push((Inconsistency::TargetMismatch, source));
+        }
+    }
+    for old in old {
+        if dir.source.join(&old.1).exists() {
+            // the current source is the one that counts
+            continue;
+        }
+        let target = dir.target.join(&old.1);
+        if !target.exists() {
+            found.push((Inconsistency::MissingTarget, old));
+        } else if migrate::Uncompressed::of(path(&old))
+            .and_then(|old| migrate::verify_file(old.path(), &target, dir.kind.rrd_def()))
+            .is_err()
+        {
+            found.push((Inconsistency::BrokenTarget, old));
+        }
+    }
+    Ok(found)
+}
+
+fn fix(
+    inconsistency: Inconsistency,
+    file: &RRDFile,
+    dir: &MigrationDir,
+    options: &MigrationOptions,
+) -> Result<(), Error> {
+    let target = dir.target.join(&file.1);
+    match inconsistency {
+        Inconsistency::StraySource => {
+            finish_source(path(file), &target, dir.kind, options)?;
+        }
+        Inconsistency::PartialTarget => fs::remove_file(&target)?,
+        Inconsistency::TargetMismatch => {}
+        Inconsistency::MissingTarget | Inconsistency::BrokenTarget => {
+            let options = MigrationOptions {
+                force: true,
+                ..options.clone()
+            };
+            remigrate::remigrate(file, &dir.target, dir.kind, &options)?;
+        }
+    }
+    Ok(())
+}
+
+/// Report the inconsistent files, and fix them unless in dry-run mode
+///
+/// Returns the exit code.
+pub(crate) fn run(dirs: &[MigrationDir], options: &MigrationOptions) -> i32 {
+    let _phase = info_span!("phase", name = "reconcile").entered();
+    let mut inconsistent = 0;
+    let mut failed = 0;
+    let mut left = 0;
+    for dir in dirs {
+        let found = match find(dir, options) {
+            Ok(found) => found,
+            Err(err) => {
+                error!("Error checking the {} files: {err}", dir.kind);
+                return EXIT_FAILURE;
+            }
+        };
+        for (inconsistency, file) in found {
+            options.notifier.watchdog_ping();
+            inconsistent += 1;
+            let resource = file.1.to_string_lossy().into_owned();
+            if !inconsistency.fixable() {
+                warn!(
+                    "{} '{resource}': {inconsistency}, {}",
+                    dir.kind,
+                    inconsistency.fix()
+                );
+                left += 1;
+                continue;
+            }
+            if !options.migrate {
+                info!(
+                    "{} '{resource}': {inconsistency}, would {}",
+                    dir.kind,
+                    inconsistency.fix()
+                );
+                continue;
+            }
+            match fix(inconsistency, &file, dir, options) {
+                Ok(()) => info!(
+                    status = "migrated",
+                    "{} '{resource}': {inconsistency}, did {}",
+                    dir.kind,
+                    inconsistency.fix()
+                ),
+                Err(err) => {
+                    error!(
+                        status = "failed",
+                        "{} '{resource}': {inconsistency}, could not {}: {err}",
+                        dir.kind,
+                        inconsistency.fix()
+                    );
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    if inconsistent == 0 {
+        info!("No inconsistencies found");
+    } else if !options.migrate && inconsistent > left {
+        warn!(
+            "Found {} inconsistent RRD file(s), use --migrate to fix them",
+            inconsistent - left
+        );
+    }
+    if left > 0 {
+        warn!("{left} inconsistent RRD file(s) need to be checked by hand");
+    }
+    if failed > 0 || (left > 0 && options.migrate) {
+        EXIT_PARTIAL
+    } else {
+        EXIT_SUCCESS
+    }
+}
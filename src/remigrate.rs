@@ -0,0 +1,185 @@
+//! Migrating the old source files again, to recreate targets that turned out to be broken after
+//! the sources were already renamed to .old or moved to the archive directory
+//!
+//! The old files are left in place, the broken targets are kept like with --force.
+
+use std::collections::HashSet;
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, Error};
+use serde::Deserialize;
+use tracing::{error, info, info_span};
+
+use proxmox_rrd_migration_tool::migrate::{self, RRDFile, ResourceType};
+
+use crate::file::{do_rrd_migration, log_file_error, report_failure};
+use crate::{
+    MigrationDir, MigrationOptions, SourceHandling, EXIT_FAILURE, EXIT_PARTIAL, EXIT_SUCCESS,
+};
+
+/// Which old files to migrate again
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum FromOld {
+    All,
+    /// only those of these VMIDs, nodes or storages
+    Resources(HashSet<String>),
+}
+
+impl FromStr for FromOld {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value == "all" {
+            return Ok(FromOld::All);
+        }
+        let resources: HashSet<String> = value
+            .split(',')
+            .map(str::trim)
+            .filter(|resource| !resource.is_empty())
+            .map(str::to_string)
+            .collect();
+        if resources.is_empty() {
+            bail!("expected 'all' or a comma separated list of VMIDs, nodes or storages");
+        }
+        Ok(FromOld::Resources(resources))
+    }
+}
+
+impl TryFrom<String> for FromOld {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl FromOld {
+    fn contains(&self, resource: &OsStr) -> bool {
+        match self {
+            FromOld::All => true,
+            FromOld::Resources(resources) => {
+                resources.contains(resource.to_string_lossy().as_ref())
+            }
+        }
+    }
+}
+
+/// The old files of 'dir' wanted by 'selection'
+pub(crate) fn old_files(
+    dir: &MigrationDir,
+    selection: &FromOld,
+    options: &MigrationOptions,
+) -> Result<Vec<RRDFile>, Error> {
+    let mut files = match options.sources {
+        SourceHandling::Archive(ref archive) => {
+            let relative = dir.source.strip_prefix(&options.source_base)?;
+            let mut files = migrate::collect_rrd_files(&archive.join(relative))?;
+            for file in &mut files {
+                if let Some(resource) = file
+                    .1
+                    .as_bytes()
+                    .strip_suffix(migrate::COMPRESSED_SUFFIX.as_bytes())
+                {
+                    file.1 = OsStr::from_bytes(resource).to_os_string();
+                }
+            }
+            files
+        }
+        _ => {
+            let compressed = format!("{}{}", options.old_suffix, migrate::COMPRESSED_SUFFIX);
+            let mut files = migrate::collect_old_rrd_files(&dir.source, &options.old_suffix)?;
+            files.extend(migrate::collect_old_rrd_files(&dir.source, &compressed)?);
+            files
+        }
+    };
+    files.retain(|file| selection.contains(&file.1));
+    // of an old file that is there compressed and uncompressed, the uncompressed one counts
+    files.sort_by_key(|file| (file.1.clone(), is_compressed(file)));
+    files.dedup_by(|file, kept| file.1 == kept.1);
+    Ok(files)
+}
+
+/// Whether the old file was compressed with --compress-old
+fn is_compressed(file: &RRDFile) -> bool {
+    file.0
+        .as_bytes()
+        .ends_with(migrate::COMPRESSED_SUFFIX.as_bytes())
+}
+
+/// Migrate the old file again, overwriting the target with --force
+pub(crate) fn remigrate(
+    file: &RRDFile,
+    target_dir: &Path,
+    kind: ResourceType,
+    options: &MigrationOptions,
+) -> Result<(), Error> {
+    fs::create_dir_all(target_dir)?;
+    let old = migrate::Uncompressed::of(Path::new(OsStr::from_bytes(file.0.as_bytes())))?;
+    let source = (
+        CString::new(old.path().as_os_str().as_bytes())?,
+        file.1.clone(),
+    );
+    do_rrd_migration(source, target_dir, kind, options, &|| true)?;
+    migrate::verify_file(old.path(), &target_dir.join(&file.1), kind.rrd_def())?;
+    Ok(())
+}
+
+/// Migrate the old files selected by 'selection' again
+///
+/// Returns the exit code.
+pub(crate) fn run(dirs: &[MigrationDir], selection: &FromOld, options: &MigrationOptions) -> i32 {
+    let _phase = info_span!("phase", name = "from-old").entered();
+    let options = MigrationOptions {
+        force: true,
+        ..options.clone()
+    };
+
+    let mut found = 0;
+    let mut failed = 0;
+    for dir in dirs {
+        let files = match old_files(dir, selection, &options) {
+            Ok(files) => files,
+            Err(err) => {
+                error!("Error collecting the old {} files: {err}", dir.kind);
+                return EXIT_FAILURE;
+            }
+        };
+        for file in files {
+            found += 1;
+            options.notifier.watchdog_ping();
+            let resource = file.1.to_string_lossy().into_owned();
+            match remigrate(&file, &dir.target, dir.kind, &options) {
+                Ok(()) => info!(
+                    status = "migrated",
+                    "{} '{resource}': migrated again from {}",
+                    dir.kind,
+                    file.0.to_string_lossy()
+                ),
+                Err(err) => {
+                    log_file_error(&err);
+                    if !options.migrate {
+                        continue;
+                    }
+                    report_failure(&options.report, resource, &file.0, &err);
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    if found == 0 {
+        info!("No old RRD files found to migrate again");
+    } else if !options.migrate {
+        info!("Would migrate {found} old RRD file(s) again, use --migrate to do so");
+    }
+    if failed > 0 {
+        EXIT_PARTIAL
+    } else {
+        EXIT_SUCCESS
+    }
+}
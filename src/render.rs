@@ -0,0 +1,128 @@
+//! Rendering small graphs of some of the migrated files for --render-samples, a quick visual check
+//! that their history survived the migration
+//!
+//! Not built with the static-rrd feature, that librrd has no rrd_graph.
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Error};
+use tracing::{info, warn};
+
+use proxmox_rrd_migration_tool::migrate::{self, ResourceType};
+
+use crate::marker;
+
+/// Time range of the graphs before the last update of a file, longer than the finest RRA
+const GRAPH_RANGE: i64 = 30 * 24 * 3600;
+
+/// A graph to render
+#[derive(Debug)]
+struct Graph {
+    rrd: PathBuf,
+    /// file name of the image, without the extension
+    name: String,
+    title: String,
+    data_sources: &'static [&'static str],
+}
+
+/// Pick 'count' of 'items' at random, in no particular order
+///
+/// Only for spreading the choice, a xorshift seeded with the current time is enough.
+fn pick_random<T>(mut items: Vec<T>, count: usize) -> Vec<T> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or_default();
+    let mut state = (nanos ^ u64::from(std::process::id())) | 1;
+    let count = count.min(items.len());
+    for index in 0..count {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let other = index + (state % (items.len() - index) as u64) as usize;
+        items.swap(index, other);
+    }
+    items.truncate(count);
+    items
+}
+
+/// The migrated files of 'kind' in 'dir', sorted by name
+fn targets(dir: &Path, kind: ResourceType) -> Result<Vec<String>, Error> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .context(format!("cannot read {dir:?}"))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_file()))
+        .map(|entry| entry.file_name())
+        .filter(|name| migrate::is_valid_resource_name(kind, name))
+        .filter_map(|name| name.into_string().ok())
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// The CPU graphs of 'count' random guests and the memory graph of the local node, or of the
+/// first node if there is no local one
+fn pick_graphs(count: usize, guests: &Path, nodes: &Path) -> Result<Vec<Graph>, Error> {
+    let mut graphs: Vec<Graph> = pick_random(targets(guests, ResourceType::Guest)?, count)
+        .into_iter()
+        .map(|vmid| Graph {
+            rrd: guests.join(&vmid),
+            name: format!("guest-{vmid}-cpu"),
+            title: format!("CPU of guest {vmid}"),
+            data_sources: &["cpu"],
+        })
+        .collect();
+    let nodes_found = targets(nodes, ResourceType::Node)?;
+    let hostname = marker::hostname();
+    let local = hostname.split('.').next().unwrap_or_default();
+    let node = nodes_found
+        .iter()
+        .find(|node| node.as_str() == local)
+        .or(nodes_found.first());
+    if let Some(node) = node {
+        graphs.push(Graph {
+            rrd: nodes.join(node),
+            name: format!("node-{node}-memory"),
+            title: format!("Memory of node {node}"),
+            data_sources: &["memtotal", "memused"],
+        });
+    }
+    Ok(graphs)
+}
+
+/// Render the graphs for --render-samples into 'dir', failures are only warned about
+///
+/// 'guests' and 'nodes' are the target directories of the guests and nodes.
+pub(crate) fn run(count: usize, dir: &Path, guests: &Path, nodes: &Path) {
+    let graphs = match pick_graphs(count, guests, nodes) {
+        Ok(graphs) => graphs,
+        Err(err) => {
+            warn!("could not pick the files to render graphs of - {err:#}");
+            return;
+        }
+    };
+    if let Err(err) = std::fs::create_dir_all(dir) {
+        warn!("could not create {dir:?} for the graphs - {err}");
+        return;
+    }
+    let mut rendered = 0;
+    for graph in graphs {
+        let image = dir.join(format!("{}.png", graph.name));
+        let result = migrate::last_update(&graph.rrd).and_then(|end| {
+            migrate::graph_file(
+                &graph.rrd,
+                &image,
+                &graph.title,
+                graph.data_sources,
+                end - GRAPH_RANGE,
+                end,
+            )
+        });
+        match result {
+            Ok(()) => rendered += 1,
+            Err(err) => warn!("could not render the graph of {:?} - {err}", graph.rrd),
+        }
+    }
+    info!("Rendered {rendered} graph(s) of migrated files into {dir:?}");
+}
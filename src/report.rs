@@ -0,0 +1,204 @@
+//! Collects the files that could not be migrated over all phases and threads, so that they can
+//! be reported grouped by cause at the end of the run instead of only interleaved with the
+//! progress output.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Why a file was not migrated
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorCause {
+    /// the target already exists and --force is not set
+    TargetExists,
+    /// the guest or node is not in .vmlist or .members anymore
+    NotPresent,
+    /// the source is empty or too small to be an RRD file, it was skipped
+    Unusable,
+    /// the file name is not a VMID, node name or storage ID, it was skipped
+    InvalidName,
+    /// the source was not updated for longer than --skip-stale, it was marked as old
+    Stale,
+    /// the guest is hosted on another node, so --remote-guests left it alone or marked it as old
+    OtherNode,
+    /// librrd cannot read the source file, it was moved to the quarantine directory
+    Corrupt,
+    /// librrd failed to create the new file
+    Librrd,
+    /// the schema to create the new file with is malformed
+    InvalidSchema,
+    /// the new file does not have the expected schema or data
+    Verification,
+    /// reading, writing or renaming a file failed
+    Io,
+    /// the conversion took longer than the file timeout
+    Timeout,
+    /// the worker migrating the file panicked
+    Panic,
+    /// anything else
+    Other,
+}
+
+impl fmt::Display for ErrorCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorCause::TargetExists => "target already exists",
+            ErrorCause::NotPresent => "not in .vmlist or .members",
+            ErrorCause::Unusable => "unusable source",
+            ErrorCause::InvalidName => "unexpected file name",
+            ErrorCause::Stale => "stale",
+            ErrorCause::OtherNode => "hosted on another node",
+            ErrorCause::Corrupt => "corrupt source",
+            ErrorCause::Librrd => "librrd error",
+            ErrorCause::InvalidSchema => "invalid schema",
+            ErrorCause::Verification => "verification failed",
+            ErrorCause::Io => "IO error",
+            ErrorCause::Timeout => "timed out",
+            ErrorCause::Panic => "panicked",
+            ErrorCause::Other => "other error",
+        })
+    }
+}
+
+#[derive(Debug)]
+struct ReportEntry {
+    resource: String,
+    /// the source file, if it is still there and can be migrated again
+    source: Option<PathBuf>,
+    /// more information, if the cause alone does not say enough
+    detail: Option<String>,
+}
+
+/// Shared collection of all files that were not migrated
+#[derive(Clone, Debug, Default)]
+pub struct ErrorReport {
+    entries: Arc<Mutex<BTreeMap<ErrorCause, Vec<ReportEntry>>>>,
+}
+
+impl ErrorReport {
+    /// Record that `resource` was not migrated
+    pub fn add(
+        &self,
+        cause: ErrorCause,
+        resource: impl Into<String>,
+        source: Option<PathBuf>,
+        detail: Option<String>,
+    ) {
+        self.entries
+            .lock()
+            .unwrap()
+            .entry(cause)
+            .or_default()
+            .push(ReportEntry {
+                resource: resource.into(),
+                source,
+                detail,
+            });
+    }
+
+    /// Number of recorded files that can be migrated again
+    pub fn retryable(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap()
+            .values()
+            .flatten()
+            .filter(|entry| entry.source.is_some())
+            .count()
+    }
+
+    /// All recorded files as cause, resource and detail
+    pub fn entries(&self) -> Vec<(ErrorCause, String, Option<String>)> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|(cause, entries)| {
+                entries
+                    .iter()
+                    .map(|entry| (*cause, entry.resource.clone(), entry.detail.clone()))
+            })
+            .collect()
+    }
+
+    /// Write the source paths of the files that can be migrated again to `path`
+    ///
+    /// One path per line, each preceded by a comment with the reason it failed. The file can
+    /// be passed back via --files-from to only retry these files.
+    pub fn write_failed_files(&self, path: &Path, run_id: &str) -> std::io::Result<()> {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        writeln!(out, "# failed files of run {run_id}")?;
+        for (cause, entries) in self.entries.lock().unwrap().iter() {
+            for entry in entries {
+                let Some(source) = &entry.source else {
+                    continue;
+                };
+                match &entry.detail {
+                    // a detail over several lines, like the output of a tool, must not end the
+                    // comment
+                    Some(detail) => {
+                        let detail = detail.lines().collect::<Vec<_>>().join(" ");
+                        writeln!(out, "# {}: {cause}: {detail}", entry.resource)?
+                    }
+                    None => writeln!(out, "# {}: {cause}", entry.resource)?,
+                }
+                writeln!(out, "{}", source.display())?;
+            }
+        }
+        out.flush()
+    }
+
+    /// Number of recorded files
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Print the summary block to stdout, or stderr with 'legacy_output', nothing if no file was
+    /// recorded
+    pub fn print(&self, legacy_output: bool) {
+        if self.is_empty() {
+            return;
+        }
+        if legacy_output {
+            eprint!("{self}");
+        } else {
+            print!("{self}");
+        }
+    }
+}
+
+impl fmt::Display for ErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entries = self.entries.lock().unwrap();
+        let total: usize = entries.values().map(Vec::len).sum();
+        writeln!(f, "Summary of {total} file(s) not migrated:")?;
+        for (cause, entries) in entries.iter() {
+            writeln!(f, "  {cause} ({}):", entries.len())?;
+            for entry in entries {
+                match &entry.detail {
+                    Some(detail) => writeln!(f, "    {}: {detail}", entry.resource)?,
+                    None => writeln!(f, "    {}", entry.resource)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read a list of source paths as written by [`ErrorReport::write_failed_files`]
+///
+/// Empty lines and lines starting with '#' are ignored, the others are taken as they are, since
+/// file names can start or end with spaces.
+pub fn read_file_list(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect())
+}
@@ -0,0 +1,268 @@
+//! The restore subcommand, putting the source files from a --backup archive back into place and
+//! removing their migrated targets, so that the migration can be started over
+
+use std::io::IsTerminal;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, format_err, Context, Error};
+use tracing::{error, info, warn};
+
+use proxmox_rrd_migration_tool::migrate::{COMPRESSED_SUFFIX, OLD_SUFFIX};
+
+use crate::args::HELP;
+use crate::config::{self, Config};
+use crate::logging::{self, Verbosity};
+use crate::{
+    backup, confirm, BASE_DIR, EXIT_FAILURE, EXIT_SUCCESS, EXIT_USAGE, SOURCE_SUBDIR_GUEST,
+    SOURCE_SUBDIR_NODE, SOURCE_SUBDIR_STORAGE, TARGET_SUBDIR_GUEST, TARGET_SUBDIR_NODE,
+    TARGET_SUBDIR_STORAGE,
+};
+
+#[derive(Debug)]
+struct RestoreArgs {
+    from: PathBuf,
+    yes: bool,
+    source: Option<String>,
+    target: Option<String>,
+    /// how the migration marked the old source files
+    old_suffix: String,
+    archive_dir: Option<PathBuf>,
+}
+
+fn parse_args() -> Result<RestoreArgs, Error> {
+    let mut pargs = pico_args::Arguments::from_env();
+    // the subcommand itself
+    let _ = pargs.subcommand()?;
+
+    let mut args = RestoreArgs {
+        from: pargs
+            .value_from_str("--from")
+            .context("Could not parse --from parameter")?,
+        yes: false,
+        source: pargs
+            .opt_value_from_str("--source")
+            .context("Could not parse --source parameter")?,
+        target: pargs
+            .opt_value_from_str("--target")
+            .context("Could not parse --target parameter")?,
+        old_suffix: OLD_SUFFIX.to_string(),
+        archive_dir: pargs
+            .opt_value_from_str("--archive-dir")
+            .context("Could not parse --archive-dir parameter")?,
+    };
+    let old_suffix: Option<String> = pargs
+        .opt_value_from_str("--old-suffix")
+        .context("Could not parse --old-suffix parameter")?;
+    if pargs.contains(["-y", "--yes"]) {
+        args.yes = true;
+    }
+
+    let remaining = pargs.finish();
+    if !remaining.is_empty() {
+        bail!(format!("Warning: unused arguments left: {:?}", remaining));
+    }
+
+    // like the migration that marked the files as old
+    let config =
+        Config::from_env()?.or(Config::load(config::env::<PathBuf>("CONFIG")?.as_deref())?);
+    if let Some(old_suffix) = old_suffix.or(config.old_suffix) {
+        if old_suffix.is_empty() || old_suffix.contains('/') {
+            bail!("--old-suffix must not be empty or contain a '/'");
+        }
+        args.old_suffix = old_suffix;
+    }
+    args.archive_dir = args.archive_dir.or(config.archive_dir);
+    Ok(args)
+}
+
+/// The tar(1) flags to read 'archive'
+fn tar(archive: &Path) -> Command {
+    let mut tar = Command::new("tar");
+    tar.arg("--file").arg(archive);
+    if backup::is_compressed(archive) {
+        tar.arg("--zstd");
+    }
+    tar
+}
+
+/// The files in 'archive', relative to the source base directory
+///
+/// Fails for anything outside of the source directories, that would not come from --backup.
+fn list(archive: &Path) -> Result<Vec<PathBuf>, Error> {
+    let output = tar(archive)
+        .arg("--list")
+        .output()
+        .context("could not run tar")?;
+    if !output.status.success() {
+        bail!(
+            "tar failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let mut files = Vec::new();
+    for entry in String::from_utf8_lossy(&output.stdout).lines() {
+        let path = Path::new(entry.trim_end_matches('/'));
+        let inside = path.components().next().is_some_and(|dir| {
+            backup::SOURCE_SUBDIRS
+                .iter()
+                .any(|subdir| dir.as_os_str() == *subdir)
+        });
+        let relative = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if !inside || !relative {
+            bail!("{entry:?} in {archive:?} is not part of a backup of the source directories");
+        }
+        files.push(path.to_owned());
+    }
+    Ok(files)
+}
+
+/// The migrated target of the source file 'file', both relative to their base directories
+fn target_of(file: &Path) -> Option<PathBuf> {
+    let mut components = file.components();
+    let target_subdir = match components.next()?.as_os_str().to_str()? {
+        SOURCE_SUBDIR_NODE => TARGET_SUBDIR_NODE,
+        SOURCE_SUBDIR_GUEST => TARGET_SUBDIR_GUEST,
+        SOURCE_SUBDIR_STORAGE => TARGET_SUBDIR_STORAGE,
+        _ => return None,
+    };
+    Some(Path::new(target_subdir).join(components.as_path()))
+}
+
+/// Where the migration may have left the source file 'file' once migrated, marked as old or moved
+/// to the archive directory, and compressed or not
+fn old_files(source_base: &Path, file: &Path, args: &RestoreArgs) -> Vec<PathBuf> {
+    let mut marked_old = source_base.join(file).into_os_string();
+    marked_old.push(&args.old_suffix);
+    let mut old = vec![PathBuf::from(marked_old)];
+    if let Some(ref archive) = args.archive_dir {
+        old.push(archive.join(file));
+    }
+    let compressed: Vec<PathBuf> = old
+        .iter()
+        .map(|path| {
+            let mut compressed = path.clone().into_os_string();
+            compressed.push(COMPRESSED_SUFFIX);
+            PathBuf::from(compressed)
+        })
+        .collect();
+    old.extend(compressed);
+    old
+}
+
+/// Remove 'path' if it exists, returns whether it did
+fn remove(path: &Path) -> Result<bool, Error> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(format_err!("could not remove {path:?} - {err}")),
+    }
+}
+
+fn restore(args: &RestoreArgs) -> Result<(), Error> {
+    let source_base = Path::new(args.source.as_deref().unwrap_or(BASE_DIR));
+    let target_base = Path::new(args.target.as_deref().unwrap_or(BASE_DIR));
+
+    let entries = list(&args.from)?;
+    let files: Vec<&PathBuf> = entries
+        .iter()
+        // not the source directories themselves
+        .filter(|entry| entry.components().count() > 1)
+        .collect();
+    info!("{} entries in {:?}", entries.len(), args.from);
+
+    let output = tar(&args.from)
+        .arg("--extract")
+        .arg("--directory")
+        .arg(source_base)
+        .output()
+        .context("could not run tar")?;
+    if !output.status.success() {
+        bail!(
+            "tar failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    info!("Restored the source files to {source_base:?}");
+
+    let mut targets = 0;
+    let mut old = 0;
+    for file in files {
+        let source = source_base.join(file);
+        if source.is_dir() {
+            continue;
+        }
+        if let Some(target) = target_of(file) {
+            if remove(&target_base.join(target))? {
+                targets += 1;
+            }
+        }
+        for path in old_files(source_base, file, args) {
+            if remove(&path)? {
+                old += 1;
+            }
+        }
+    }
+    info!("Removed {targets} migrated target(s) and {old} old source file(s)");
+    Ok(())
+}
+
+/// Run the restore subcommand, returns the exit code
+pub(crate) fn run() -> i32 {
+    if std::env::args().any(|arg| arg == "-h" || arg == "--help") {
+        print!("{HELP}");
+        return EXIT_SUCCESS;
+    }
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("Error: {err}.");
+            return EXIT_USAGE;
+        }
+    };
+    let console = logging::init(
+        Verbosity::Normal,
+        None,
+        false,
+        logging::use_color(false, false),
+    );
+    let flush = || {
+        if let Some(ref console) = console {
+            console.flush();
+        }
+    };
+
+    let exit_code = 'run: {
+        if !args.from.exists() {
+            error!("Error: backup {:?} does not exist", args.from);
+            break 'run EXIT_USAGE;
+        }
+        if !args.yes && !std::io::stdin().is_terminal() {
+            error!("Error: restore removes the migrated targets, confirm it with --yes");
+            break 'run EXIT_USAGE;
+        }
+        if !args.yes {
+            flush();
+            if !confirm(&format!(
+                "Restore the source files from {:?} and remove their migrated targets?",
+                args.from
+            )) {
+                warn!("Aborted, nothing was restored.");
+                break 'run EXIT_FAILURE;
+            }
+        }
+        match restore(&args) {
+            Ok(()) => EXIT_SUCCESS,
+            Err(err) => {
+                error!("Error: restore failed: {err:#}");
+                EXIT_FAILURE
+            }
+        }
+    };
+    flush();
+    exit_code
+}
@@ -0,0 +1,107 @@
+//! Machine-readable report of a run as JSON file, for the automation around the upgrade
+//!
+//! Written to <TARGET>/migration-report.json by every run that migrates, or to --report.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error};
+use serde::Serialize;
+
+use proxmox_rrd_migration_tool::migrate;
+
+use crate::completion::Summary;
+use crate::progress::Progress;
+use crate::report::ErrorReport;
+
+/// Name of the report in the target base directory
+pub const REPORT_FILE: &str = "migration-report.json";
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Versions {
+    tool: &'static str,
+    librrd: String,
+}
+
+/// What a phase, migrating one resource type, did
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Phase {
+    name: String,
+    total: usize,
+    done: usize,
+    failed: usize,
+    /// in seconds
+    duration: f64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Failure {
+    resource: String,
+    cause: String,
+    detail: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RunReport {
+    #[serde(flatten)]
+    summary: Summary,
+    versions: Versions,
+    arguments: Vec<String>,
+    /// local time in RFC 3339 format
+    start: String,
+    end: String,
+    phases: Vec<Phase>,
+    failures: Vec<Failure>,
+}
+
+impl RunReport {
+    /// The report of the run summarized in 'summary', which started at 'start'
+    pub fn new(summary: Summary, start: String, progress: &Progress, report: &ErrorReport) -> Self {
+        Self {
+            summary,
+            versions: Versions {
+                tool: env!("CARGO_PKG_VERSION"),
+                librrd: migrate::librrd_version(),
+            },
+            arguments: std::env::args().skip(1).collect(),
+            start,
+            end: crate::audit::timestamp(),
+            phases: progress
+                .snapshot()
+                .phases
+                .into_iter()
+                .map(|phase| Phase {
+                    name: phase.name,
+                    total: phase.total,
+                    done: phase.done,
+                    failed: phase.failed,
+                    duration: phase.elapsed.as_secs_f64(),
+                })
+                .collect(),
+            failures: report
+                .entries()
+                .into_iter()
+                .map(|(cause, resource, detail)| Failure {
+                    resource,
+                    cause: cause.to_string(),
+                    detail,
+                })
+                .collect(),
+        }
+    }
+
+    /// Write the report to 'path', replacing an earlier one only once complete
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        let mut tmp = PathBuf::from(path).into_os_string();
+        tmp.push(".tmp");
+        let mut file = std::fs::File::create(&tmp).context(format!("cannot create {tmp:?}"))?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path).context(format!("cannot rename {tmp:?} to {path:?}"))
+    }
+}
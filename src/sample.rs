@@ -0,0 +1,326 @@
+//! Converting only the first few files of each resource type into a scratch directory, to try out
+//! librrd on this host and estimate how long the whole migration takes and how much space it needs
+
+use std::ffi::{CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Error};
+use tracing::{error, info, info_span, warn};
+
+use proxmox_rrd_migration_tool::migrate::{self, ResourceType};
+
+use crate::{MigrationDir, MigrationOptions, EXIT_FAILURE, EXIT_NO_SPACE, EXIT_SUCCESS};
+
+/// Files converted per resource type for --estimate
+const ESTIMATE_SAMPLES: usize = 2;
+
+#[derive(Debug)]
+struct Stats {
+    kind: ResourceType,
+    /// files of this type in the whole tree
+    total: usize,
+    sampled: usize,
+    failed: usize,
+    elapsed: Duration,
+    /// size of all source files of this type
+    source_size: u64,
+    /// size of a converted file, the same for all files of a type
+    target_size: Option<u64>,
+}
+
+impl Stats {
+    fn new(kind: ResourceType) -> Self {
+        Self {
+            kind,
+            total: 0,
+            sampled: 0,
+            failed: 0,
+            elapsed: Duration::ZERO,
+            source_size: 0,
+            target_size: None,
+        }
+    }
+
+    /// Estimated time for all files of this type, guests are migrated by multiple threads
+    fn estimate(&self, threads: usize) -> Duration {
+        if self.sampled == 0 {
+            return Duration::ZERO;
+        }
+        let per_file = self.elapsed / self.sampled as u32;
+        match self.kind {
+            ResourceType::Guest => per_file * self.total as u32 / threads.max(1) as u32,
+            _ => per_file * self.total as u32,
+        }
+    }
+}
+
+/// Convert and verify the first 'count' files of each type into 'scratch'
+fn convert_samples(
+    count: usize,
+    scratch: &Path,
+    dirs: &[MigrationDir],
+    options: &MigrationOptions,
+) -> Vec<Stats> {
+    let mut stats: Vec<Stats> = Vec::new();
+    for migration_dir in dirs {
+        let kind = migration_dir.kind;
+        let index = match stats.iter().position(|stats| stats.kind == kind) {
+            Some(index) => index,
+            None => {
+                stats.push(Stats::new(kind));
+                stats.len() - 1
+            }
+        };
+        let stats = &mut stats[index];
+
+        let mut files = match migrate::collect_rrd_files_with(
+            &*options.fs,
+            &migration_dir.source,
+            &options.old_suffix,
+        ) {
+            Ok(files) => files,
+            Err(err) => {
+                error!("Error: {err}");
+                stats.failed += 1;
+                continue;
+            }
+        };
+        files.retain(|file| options.is_selected(file));
+        files.sort_by(|a, b| a.1.cmp(&b.1));
+        stats.total += files.len();
+        stats.source_size += files
+            .iter()
+            .filter_map(|file| std::fs::metadata(OsStr::from_bytes(file.0.as_bytes())).ok())
+            .map(|metadata| metadata.len())
+            .sum::<u64>();
+
+        // storages are kept apart per node, like in the real target
+        let mut target = scratch.join(kind.to_string());
+        if kind == ResourceType::Storage {
+            target.push(migration_dir.source.file_name().unwrap_or_default());
+        }
+        let remaining = count.saturating_sub(stats.sampled);
+        for file in files.into_iter().take(remaining) {
+            options.notifier.watchdog_ping();
+            let resource = file.1.to_string_lossy().into_owned();
+            let target_path = target.join(&file.1);
+            let started = Instant::now();
+            let result = std::fs::create_dir_all(&target)
+                .map_err(Error::from)
+                .and_then(|()| {
+                    migrate::migrate_file(&file, &target, kind.rrd_def(), true, true)?;
+                    let source = Path::new(OsStr::from_bytes(file.0.as_bytes()));
+                    migrate::verify_file(source, &target_path, kind.rrd_def())?;
+                    Ok(())
+                });
+            let elapsed = started.elapsed();
+            stats.sampled += 1;
+            stats.elapsed += elapsed;
+            match result {
+                Ok(()) => {
+                    info!(
+                        status = "migrated",
+                        "{kind} '{resource}': converted and verified in {:.2}s",
+                        elapsed.as_secs_f64()
+                    );
+                    if let Ok(metadata) = std::fs::metadata(&target_path) {
+                        stats.target_size = Some(metadata.len());
+                    }
+                }
+                Err(err) => {
+                    error!(status = "failed", "{kind} '{resource}': {err}");
+                    stats.failed += 1;
+                }
+            }
+        }
+    }
+    stats
+}
+
+/// Print the duration per type and in total
+fn print_durations(stats: &[Stats], options: &MigrationOptions) {
+    let mut estimate = Duration::ZERO;
+    for stats in stats {
+        if stats.sampled == 0 {
+            info!("{}: no files to sample", stats.kind);
+            continue;
+        }
+        info!(
+            "{}: {} of {} file(s) in {:.2}s, about {:.0}s for all of them",
+            stats.kind,
+            stats.sampled,
+            stats.total,
+            stats.elapsed.as_secs_f64(),
+            stats.estimate(options.threads).as_secs_f64(),
+        );
+        estimate += stats.estimate(options.threads);
+    }
+    info!(
+        "Estimated duration of the whole migration: {:.0}s",
+        estimate.as_secs_f64()
+    );
+}
+
+/// Create a new scratch directory for 'name' in the temporary directory, only accessible by the
+/// current user, which must not exist yet
+pub(crate) fn create_scratch(name: &str, run_id: &str) -> Result<PathBuf, Error> {
+    let prefix = format!("proxmox-rrd-migration-{name}-{run_id}-");
+    migrate::create_private_dir(&std::env::temp_dir(), &prefix)
+        .context("could not create scratch directory")
+}
+
+fn remove_scratch(scratch: &Path) {
+    if let Err(err) = std::fs::remove_dir_all(scratch) {
+        warn!("could not remove '{}' - {err}", scratch.display());
+    }
+}
+
+fn exit_code(stats: &[Stats]) -> i32 {
+    if stats.iter().any(|stats| stats.failed > 0) {
+        EXIT_FAILURE
+    } else {
+        EXIT_SUCCESS
+    }
+}
+
+/// Convert and verify the first 'count' files of each type into 'dir', or a temporary directory
+///
+/// Returns the exit code.
+pub(crate) fn run(
+    count: usize,
+    dir: Option<&Path>,
+    dirs: &[MigrationDir],
+    run_id: &str,
+    options: &MigrationOptions,
+) -> i32 {
+    let _phase = info_span!("phase", name = "sample").entered();
+    let scratch = match dir {
+        Some(dir) => dir.to_owned(),
+        None => match create_scratch("sample", run_id) {
+            Ok(scratch) => scratch,
+            Err(err) => {
+                error!("Error: {err:#}");
+                return EXIT_FAILURE;
+            }
+        },
+    };
+    info!(
+        "Converting up to {count} file(s) per resource type into '{}'…",
+        scratch.display()
+    );
+
+    let stats = convert_samples(count, &scratch, dirs, options);
+    print_durations(&stats, options);
+    if dir.is_none() {
+        remove_scratch(&scratch);
+    }
+    exit_code(&stats)
+}
+
+/// Report the space the migrated files will need in 'target' and how long the migration takes,
+/// from converting a few sample files into a temporary directory
+///
+/// Returns the exit code, [`EXIT_NO_SPACE`] if there is not enough space left.
+pub(crate) fn estimate(
+    target: &Path,
+    dirs: &[MigrationDir],
+    run_id: &str,
+    options: &MigrationOptions,
+) -> i32 {
+    let _phase = info_span!("phase", name = "estimate").entered();
+    let scratch = match create_scratch("estimate", run_id) {
+        Ok(scratch) => scratch,
+        Err(err) => {
+            error!("Error: {err:#}");
+            return EXIT_FAILURE;
+        }
+    };
+    let stats = convert_samples(ESTIMATE_SAMPLES, &scratch, dirs, options);
+    remove_scratch(&scratch);
+
+    let mut needed = 0;
+    for stats in &stats {
+        match stats.target_size {
+            Some(size) => {
+                let target_size = size * stats.total as u64;
+                needed += target_size;
+                info!(
+                    "{}: {} file(s), {} now, {} after the migration",
+                    stats.kind,
+                    stats.total,
+                    format_size(stats.source_size),
+                    format_size(target_size),
+                );
+            }
+            None if stats.total == 0 => info!("{}: no files to migrate", stats.kind),
+            None => warn!(
+                "{}: {} file(s), {} now, unknown size after the migration",
+                stats.kind,
+                stats.total,
+                format_size(stats.source_size),
+            ),
+        }
+    }
+    let sources: u64 = stats.iter().map(|stats| stats.source_size).sum();
+    info!(
+        "The migrated files need {} of additional space, {} in total with the old sources kept",
+        format_size(needed),
+        format_size(sources + needed),
+    );
+    print_durations(&stats, options);
+
+    match available_space(target) {
+        Ok(available) if available < needed => {
+            error!(
+                "Error: only {} available for the {} needed in '{}'",
+                format_size(available),
+                format_size(needed),
+                target.display()
+            );
+            return EXIT_NO_SPACE;
+        }
+        Ok(available) => info!(
+            "{} available in '{}'",
+            format_size(available),
+            target.display()
+        ),
+        Err(err) => warn!(
+            "could not check the space available in '{}' - {err}",
+            target.display()
+        ),
+    }
+    exit_code(&stats)
+}
+
+/// Space available to unprivileged users on the file system of 'path', or of its closest
+/// existing parent if it does not exist yet
+fn available_space(path: &Path) -> std::io::Result<u64> {
+    let path = path
+        .ancestors()
+        .find(|path| path.exists())
+        .unwrap_or(Path::new("/"));
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Human readable size in binary units
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
@@ -0,0 +1,86 @@
+//! Printing the schemas the migration creates the RRD files with, for --dump-schema, as arguments
+//! to rrdtool create and as tables of their data sources and RRAs
+
+use std::ffi::CStr;
+
+use proxmox_rrd_migration_tool::migrate::{self, ResourceType, RRD_STEP_SIZE};
+
+use crate::inspect::limit;
+use crate::{TARGET_SUBDIR_GUEST, TARGET_SUBDIR_NODE, TARGET_SUBDIR_STORAGE};
+
+/// 'seconds' in the largest unit that divides it, like 30m or 6h
+fn format_span(seconds: u64) -> String {
+    for (unit, size) in [
+        ("w", 7 * 24 * 3600),
+        ("d", 24 * 3600),
+        ("h", 3600),
+        ("m", 60),
+    ] {
+        if seconds >= size && seconds % size == 0 {
+            return format!("{}{unit}", seconds / size);
+        }
+    }
+    format!("{seconds}s")
+}
+
+fn print_schema(kind: ResourceType, subdir: &str, rrd_def: &[&CStr]) {
+    println!("{kind} ({subdir}/):");
+    println!();
+    println!("    rrdtool create <FILE> --step {RRD_STEP_SIZE} \\");
+    let defs: Vec<String> = rrd_def
+        .iter()
+        .map(|def| def.to_string_lossy().into_owned())
+        .collect();
+    for (index, def) in defs.iter().enumerate() {
+        let end = if index + 1 < defs.len() { " \\" } else { "" };
+        println!("        {def}{end}");
+    }
+    println!();
+
+    let (data_sources, rras) = migrate::def_schema(rrd_def);
+    println!("    Data sources:");
+    println!(
+        "        {:<20}{:<10}{:<11}{:<6}MAX",
+        "NAME", "TYPE", "HEARTBEAT", "MIN"
+    );
+    for ds in &data_sources {
+        let heartbeat = format!("{}s", ds.heartbeat);
+        println!(
+            "        {:<20}{:<10}{heartbeat:<11}{:<6}{}",
+            ds.name,
+            ds.kind,
+            limit(ds.min),
+            limit(ds.max)
+        );
+    }
+    println!();
+    println!("    RRAs:");
+    println!(
+        "        {:<9}{:>10}{:>7}{:>6}{:>12}{:>11}",
+        "CF", "STEPS/ROW", "ROWS", "XFF", "RESOLUTION", "RETENTION"
+    );
+    for rra in &rras {
+        let resolution = RRD_STEP_SIZE as u64 * rra.pdp_per_row;
+        println!(
+            "        {:<9}{:>10}{:>7}{:>6}{:>12}{:>11}",
+            rra.cf,
+            rra.pdp_per_row,
+            rra.rows,
+            rra.xff,
+            format_span(resolution),
+            format_span(resolution * rra.rows)
+        );
+    }
+    println!();
+}
+
+/// Print the schema of each resource type
+pub(crate) fn dump() {
+    for (kind, subdir) in [
+        (ResourceType::Guest, TARGET_SUBDIR_GUEST),
+        (ResourceType::Node, TARGET_SUBDIR_NODE),
+        (ResourceType::Storage, TARGET_SUBDIR_STORAGE),
+    ] {
+        print_schema(kind, subdir, kind.rrd_def());
+    }
+}
@@ -0,0 +1,133 @@
+//! Running as systemd oneshot service with --service, from the shipped unit file
+//!
+//! The state of each run is kept in the state directory systemd sets up for the unit, so that a
+//! run interrupted by a reboot or timeout is picked up by the next start of the unit. Files
+//! migrated before are skipped by that run anyway, only the rest is migrated.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{EXIT_NOTHING_TO_DO, EXIT_PARTIAL, EXIT_PREFLIGHT, EXIT_SUCCESS};
+
+/// State directory if systemd does not pass one in STATE_DIRECTORY
+pub const STATE_DIR: &str = "/var/lib/proxmox-rrd-migration";
+const STATE_FILE: &str = "state.json";
+const FAILED_FILES: &str = "failed-files";
+
+/// Generic failure, by the conventions systemd follows for exit codes
+const SYSTEMD_EXIT_FAILURE: i32 = 1;
+/// The program is not configured, like for checks before the migration that failed
+const SYSTEMD_EXIT_NOTCONFIGURED: i32 = 6;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct State {
+    run_id: String,
+    pid: u32,
+    /// local time in RFC 3339 format
+    start: String,
+    end: Option<String>,
+    exit_code: Option<i32>,
+}
+
+/// The state of the run of the service
+#[derive(Debug)]
+pub(crate) struct Service {
+    dir: PathBuf,
+    state: State,
+}
+
+/// The first directory systemd passes in STATE_DIRECTORY, or [`STATE_DIR`]
+fn state_dir() -> PathBuf {
+    std::env::var_os("STATE_DIRECTORY")
+        .and_then(|dirs| {
+            let dirs = dirs.to_string_lossy().into_owned();
+            dirs.split(':').next().map(PathBuf::from)
+        })
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| PathBuf::from(STATE_DIR))
+}
+
+fn read_state(dir: &Path) -> Result<Option<State>, Error> {
+    let path = dir.join(STATE_FILE);
+    match std::fs::read(&path) {
+        Ok(data) => Ok(Some(
+            serde_json::from_slice(&data).context(format!("cannot parse {path:?}"))?,
+        )),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).context(format!("cannot read {path:?}")),
+    }
+}
+
+impl Service {
+    /// Record the start of the run 'run_id', telling whether it resumes an interrupted one
+    pub(crate) fn start(run_id: &str) -> Result<Self, Error> {
+        let dir = state_dir();
+        std::fs::create_dir_all(&dir).context(format!("cannot create {dir:?}"))?;
+        match read_state(&dir) {
+            Ok(Some(previous)) if previous.end.is_none() => info!(
+                "Resuming the migration of run {} started at {}, which was interrupted",
+                previous.run_id, previous.start
+            ),
+            Ok(_) => {}
+            Err(err) => warn!("could not read the state of the previous run - {err:#}"),
+        }
+        // the list of an earlier run must not be retried once more
+        if let Err(err) = std::fs::remove_file(dir.join(FAILED_FILES)) {
+            if err.kind() != ErrorKind::NotFound {
+                warn!("could not remove the failed files of the previous run - {err}");
+            }
+        }
+        let service = Self {
+            dir,
+            state: State {
+                run_id: run_id.to_string(),
+                pid: std::process::id(),
+                start: crate::audit::timestamp(),
+                end: None,
+                exit_code: None,
+            },
+        };
+        service.write()?;
+        Ok(service)
+    }
+
+    /// Where the files not migrated are listed, for --files-from
+    pub(crate) fn failed_files(&self) -> PathBuf {
+        self.dir.join(FAILED_FILES)
+    }
+
+    /// Record the end of the run, so that the next one does not count as resuming it
+    pub(crate) fn finish(mut self, exit_code: i32) {
+        self.state.end = Some(crate::audit::timestamp());
+        self.state.exit_code = Some(exit_code);
+        if let Err(err) = self.write() {
+            warn!("could not record the end of the run - {err:#}");
+        }
+    }
+
+    /// Write the state, replacing the earlier one only once complete
+    fn write(&self) -> Result<(), Error> {
+        let path = self.dir.join(STATE_FILE);
+        let tmp = self.dir.join(format!("{STATE_FILE}.tmp"));
+        let data = serde_json::to_vec_pretty(&self.state)?;
+        std::fs::write(&tmp, data).context(format!("cannot write {tmp:?}"))?;
+        std::fs::rename(&tmp, &path).context(format!("cannot rename {tmp:?} to {path:?}"))
+    }
+}
+
+/// The exit code for systemd: success also if there was nothing to do, so that the unit does not
+/// fail on later boots, and the failures by its conventions
+pub(crate) fn exit_code(exit_code: i32) -> i32 {
+    match exit_code {
+        EXIT_SUCCESS | EXIT_NOTHING_TO_DO => EXIT_SUCCESS,
+        EXIT_PREFLIGHT => SYSTEMD_EXIT_NOTCONFIGURED,
+        EXIT_PARTIAL => SYSTEMD_EXIT_FAILURE,
+        // the usage error 2 means the same for systemd
+        other => other,
+    }
+}
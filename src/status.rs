@@ -0,0 +1,154 @@
+//! Small JSON status file of the running migration, for pve-manager to show its progress instead
+//! of graphs that just look broken meanwhile
+//!
+//! Written to /run/proxmox-rrd-migration/status.json by the service, or to --status-file, and
+//! rewritten every few seconds on a thread of its own. Its last version says how the run ended, a
+//! 'running' one of a PID that is gone was interrupted.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::{Context, Error};
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::progress::Progress;
+
+/// Default path of the status file
+pub const STATUS_FILE: &str = "/run/proxmox-rrd-migration/status.json";
+/// How often the status file is rewritten
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Phase {
+    name: String,
+    total: usize,
+    done: usize,
+    failed: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Status {
+    run_id: String,
+    pid: u32,
+    /// 'running' or 'finished'
+    state: &'static str,
+    /// the phase that started last, if any
+    phase: Option<String>,
+    /// percentage of the files of the phases started so far that are done
+    percent: f64,
+    done: usize,
+    total: usize,
+    failed: usize,
+    phases: Vec<Phase>,
+    exit_code: Option<i32>,
+    /// local time in RFC 3339 format
+    updated: String,
+}
+
+impl Status {
+    fn new(run_id: &str, progress: &Progress, exit_code: Option<i32>) -> Self {
+        let phases: Vec<Phase> = progress
+            .snapshot()
+            .phases
+            .into_iter()
+            .map(|phase| Phase {
+                name: phase.name,
+                total: phase.total,
+                done: phase.done,
+                failed: phase.failed,
+            })
+            .collect();
+        let done = phases.iter().map(|phase| phase.done).sum();
+        let total = phases.iter().map(|phase| phase.total).sum();
+        Self {
+            run_id: run_id.to_string(),
+            pid: std::process::id(),
+            state: if exit_code.is_some() {
+                "finished"
+            } else {
+                "running"
+            },
+            phase: phases.last().map(|phase| phase.name.clone()),
+            percent: match total {
+                0 => 0.0,
+                total => done as f64 * 100.0 / total as f64,
+            },
+            done,
+            total,
+            failed: phases.iter().map(|phase| phase.failed).sum(),
+            phases,
+            exit_code,
+            updated: crate::audit::timestamp(),
+        }
+    }
+
+    /// Write the status to 'path', replacing the earlier one only once complete
+    fn write(&self, path: &Path) -> Result<(), Error> {
+        let mut tmp = PathBuf::from(path).into_os_string();
+        tmp.push(".tmp");
+        let mut file = std::fs::File::create(&tmp).context(format!("cannot create {tmp:?}"))?;
+        serde_json::to_writer(&mut file, self)?;
+        file.write_all(b"\n")?;
+        std::fs::rename(&tmp, path).context(format!("cannot rename {tmp:?} to {path:?}"))
+    }
+}
+
+/// The status file of the run, kept up to date until finished
+pub struct StatusFile {
+    path: PathBuf,
+    run_id: String,
+    progress: Progress,
+    stop: Sender<()>,
+    refresh: JoinHandle<()>,
+}
+
+impl StatusFile {
+    /// Write the status of run 'run_id' to 'path' and keep rewriting it in the background
+    pub fn start(path: &Path, run_id: &str, progress: Progress) -> Result<Self, Error> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).context(format!("cannot create {dir:?}"))?;
+        }
+        Status::new(run_id, &progress, None).write(path)?;
+
+        let (stop, stopped) = bounded(0);
+        let refresh = {
+            let path = path.to_path_buf();
+            let run_id = run_id.to_string();
+            let progress = progress.clone();
+            std::thread::Builder::new()
+                .name("status file".to_string())
+                .spawn(move || {
+                    while let Err(RecvTimeoutError::Timeout) =
+                        stopped.recv_timeout(REFRESH_INTERVAL)
+                    {
+                        if let Err(err) = Status::new(&run_id, &progress, None).write(&path) {
+                            warn!("could not update the status file - {err:#}");
+                        }
+                    }
+                })?
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            run_id: run_id.to_string(),
+            progress,
+            stop,
+            refresh,
+        })
+    }
+
+    /// Stop the updates and record how the run ended
+    pub fn finish(self, exit_code: i32) {
+        drop(self.stop);
+        let _ = self.refresh.join();
+        let status = Status::new(&self.run_id, &self.progress, Some(exit_code));
+        if let Err(err) = status.write(&self.path) {
+            warn!("could not write the final status file - {err:#}");
+        }
+    }
+}
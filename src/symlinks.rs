@@ -0,0 +1,178 @@
+//! Handling of source files that are symbolic links to other RRD files
+//!
+//! Followed links are migrated like any other source, and the link is what ends up renamed to
+//! old. Replicated links become the same link in the target directory instead.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Error};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use proxmox_rrd_migration_tool::migrate::{RRDFile, ResourceType};
+
+use crate::audit::Outcome;
+use crate::file::{
+    do_rrd_migration_with_timeout, finish_source, is_skip, log_file_error, mv_old, report_failure,
+};
+use crate::{MigrationOptions, SourceHandling};
+
+/// What to do with source files that are symbolic links
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkPolicy {
+    /// migrate the file the link points to, like any other source
+    #[default]
+    Follow,
+    /// leave the link alone
+    Skip,
+    /// create the same link in the target directory, if it points to a file next to it
+    Replicate,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            "replicate" => Ok(SymlinkPolicy::Replicate),
+            _ => bail!("unknown policy '{value}', use follow, skip or replicate"),
+        }
+    }
+}
+
+fn path(file: &RRDFile) -> PathBuf {
+    PathBuf::from(OsStr::from_bytes(file.0.as_bytes()))
+}
+
+pub(crate) fn is_symlink(file: &RRDFile) -> bool {
+    path(file)
+        .symlink_metadata()
+        .is_ok_and(|metadata| metadata.file_type().is_symlink())
+}
+
+/// Take the symbolic links out of 'files' unless they are followed, recording the skipped ones
+///
+/// Returns the links to replicate, once the files they point to were migrated.
+pub(crate) fn take_links(
+    files: &mut Vec<RRDFile>,
+    kind: ResourceType,
+    options: &MigrationOptions,
+) -> Vec<RRDFile> {
+    if options.symlinks == SymlinkPolicy::Follow {
+        return Vec::new();
+    }
+    let (links, regular): (Vec<RRDFile>, Vec<RRDFile>) = files.drain(..).partition(is_symlink);
+    *files = regular;
+    if options.symlinks == SymlinkPolicy::Skip {
+        for link in &links {
+            let source = link.0.to_string_lossy();
+            debug!(
+                status = "skipped",
+                "{kind} '{source}' is a symbolic link, skipping it"
+            );
+            options
+                .log
+                .record(kind, &source, Outcome::Skipped, "symbolic link, skipped");
+        }
+        return Vec::new();
+    }
+    links
+}
+
+/// The file name the link 'source' points to, if it is in the same directory
+fn link_name(source: &Path) -> Result<Option<PathBuf>, Error> {
+    let destination = fs::read_link(source)?;
+    let dir = source.parent().unwrap_or(Path::new(""));
+    let relative = destination.strip_prefix(dir).unwrap_or(&destination);
+    let mut components = relative
+        .components()
+        .filter(|component| *component != Component::CurDir);
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => Ok(Some(PathBuf::from(name))),
+        _ => Ok(None),
+    }
+}
+
+/// Create the link in 'target_dir' and handle the source link like a migrated source
+fn replicate(
+    link: &RRDFile,
+    name: &Path,
+    target_dir: &Path,
+    kind: ResourceType,
+    options: &MigrationOptions,
+) -> Result<(), Error> {
+    let source = link.0.to_string_lossy();
+    let target = target_dir.join(&link.1);
+    let message = format!("symbolic link to {}", name.display());
+    if !options.migrate {
+        let message = format!("{message} - would replicate it, but in dry-run mode");
+        options
+            .log
+            .record(kind, &source, Outcome::Skipped, &message);
+        return Ok(());
+    }
+    if target.symlink_metadata().is_ok() {
+        if !options.force {
+            let message = format!("{message} - target already exists and 'force' not set");
+            options
+                .log
+                .record(kind, &source, Outcome::Skipped, &message);
+            return Ok(());
+        }
+        fs::remove_file(&target)?;
+    }
+    std::os::unix::fs::symlink(name, &target)?;
+    match options.sources {
+        SourceHandling::Delete => fs::remove_file(path(link))?,
+        _ => {
+            mv_old(&path(link), kind, options)?;
+        }
+    }
+    let message = format!("replicated {message} as {}", target.display());
+    options
+        .log
+        .record(kind, &source, Outcome::Migrated, &message);
+    Ok(())
+}
+
+/// Replicate the links in 'target_dir', following those that point to another directory
+///
+/// Returns the number of links that failed.
+pub(crate) fn replicate_links(
+    links: Vec<RRDFile>,
+    target_dir: &Path,
+    kind: ResourceType,
+    options: &MigrationOptions,
+) -> usize {
+    let mut failed = 0;
+    for link in links {
+        options.notifier.watchdog_ping();
+        let resource = link.1.to_string_lossy().into_owned();
+        let result = match link_name(&path(&link)) {
+            Ok(Some(name)) => replicate(&link, &name, target_dir, kind, options),
+            Ok(None) => {
+                warn!("{kind} '{resource}' links to another directory, following it instead");
+                let source = path(&link);
+                do_rrd_migration_with_timeout(link.clone(), target_dir, kind, options)
+                    .and_then(|()| finish_source(&source, &target_dir.join(&link.1), kind, options))
+                    .map(|_| ())
+            }
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            log_file_error(&err);
+            if !is_skip(&err) {
+                report_failure(&options.report, resource, &link.0, &err);
+                failed += 1;
+            }
+        }
+    }
+    failed
+}
@@ -0,0 +1,167 @@
+//! Interactive dashboard showing the progress of the phases, the files being migrated right now
+//! and the latest output
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::widgets::{Block, Gauge, List, Paragraph};
+use ratatui::Frame;
+
+use crate::control::Control;
+use crate::logging::LogBuffer;
+use crate::progress::{format_duration, Progress, Snapshot};
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+const LOG_LINES: usize = 10;
+
+/// Runs the dashboard in its own thread until stopped
+pub struct Dashboard {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Dashboard {
+    /// Take over the terminal and start drawing, quitting it aborts the run through 'control'
+    pub fn start(run_id: &str, progress: Progress, log: LogBuffer, control: Control) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop2 = Arc::clone(&stop);
+        let title = format!(" proxmox-rrd-migration - run {run_id} ");
+
+        let handle = std::thread::Builder::new()
+            .name("dashboard".to_string())
+            .spawn(move || {
+                let mut terminal = ratatui::init();
+                let started = Instant::now();
+                while !stop2.load(Ordering::SeqCst) {
+                    let snapshot = progress.snapshot();
+                    let log = log.last(LOG_LINES);
+                    let aborted = control.is_aborted();
+                    let _ = terminal.draw(|frame| {
+                        draw(frame, &title, started.elapsed(), aborted, &snapshot, &log)
+                    });
+                    // the phases let the files being migrated finish and end the run
+                    if quit_requested() {
+                        control.abort();
+                    }
+                }
+                ratatui::restore();
+            })
+            .expect("failed to spawn dashboard thread");
+
+        Self {
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    /// Stop drawing and give the terminal back
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Wait for input until the next refresh, the terminal is in raw mode so Ctrl-C arrives here
+fn quit_requested() -> bool {
+    if !event::poll(REFRESH_INTERVAL).unwrap_or(false) {
+        return false;
+    }
+    match event::read() {
+        Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+            key.code == KeyCode::Char('q')
+                || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL))
+        }
+        _ => false,
+    }
+}
+
+fn draw(
+    frame: &mut Frame,
+    title: &str,
+    elapsed: Duration,
+    aborted: bool,
+    snapshot: &Snapshot,
+    log: &[String],
+) {
+    let failed: usize = snapshot.phases.iter().map(|phase| phase.failed).sum();
+    let [header, phases, workers, output] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(3 * snapshot.phases.len().max(1) as u16),
+        Constraint::Min(3),
+        Constraint::Length(LOG_LINES as u16 + 2),
+    ])
+    .areas(frame.area());
+
+    let hint = if aborted {
+        "aborting once the files being migrated are done"
+    } else {
+        "press q to abort"
+    };
+    frame.render_widget(
+        Paragraph::new(format!(
+            "elapsed {}, {failed} file(s) failed - {hint}",
+            format_duration(elapsed)
+        ))
+        .block(Block::bordered().title(title)),
+        header,
+    );
+
+    let rows =
+        Layout::vertical(snapshot.phases.iter().map(|_| Constraint::Length(3))).split(phases);
+    for (phase, area) in snapshot.phases.iter().zip(rows.iter()) {
+        let ratio = if phase.total == 0 {
+            1.0
+        } else {
+            phase.done as f64 / phase.total as f64
+        };
+        let eta = match phase.eta() {
+            Some(eta) => format!(", ETA {}", format_duration(eta)),
+            None => String::new(),
+        };
+        let label = format!(
+            "{} of {} files, {} failed{eta}",
+            phase.done, phase.total, phase.failed
+        );
+        frame.render_widget(
+            Gauge::default()
+                .block(Block::bordered().title(format!(" {} ", phase.name)))
+                .ratio(ratio.min(1.0))
+                .label(label),
+            *area,
+        );
+    }
+
+    let current = snapshot.current.iter().map(|file| {
+        format!(
+            "{}: {} ({}s)",
+            file.thread,
+            file.file,
+            file.elapsed.as_secs()
+        )
+    });
+    frame.render_widget(
+        List::new(current).block(Block::bordered().title(" current files ")),
+        workers,
+    );
+
+    frame.render_widget(
+        Paragraph::new(log.join("\n")).block(Block::bordered().title(" output ")),
+        output,
+    );
+}
@@ -0,0 +1,101 @@
+//! Handling of source files that have an old twin, like '100' next to '100.old', which a rerun
+//! after manual changes to the source directories can leave behind
+//!
+//! The one updated last is migrated, the other one is renamed out of the way by appending
+//! [`SUPERSEDED_SUFFIX`], instead of the old twin being overwritten once the source is marked as
+//! old.
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Error};
+use tracing::{debug, info, warn};
+
+use proxmox_rrd_migration_tool::migrate::{self, RRDFile, ResourceType, SUPERSEDED_SUFFIX};
+
+use crate::audit::Outcome;
+use crate::MigrationOptions;
+
+/// 'path' with 'suffix' appended
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Whether the twin is to be migrated instead of the source, if it was updated later or the
+/// source cannot be read at all
+fn twin_is_newer(source: &Path, twin: &Path) -> bool {
+    match (migrate::last_update(source), migrate::last_update(twin)) {
+        (Ok(source), Ok(twin)) => twin > source,
+        (Err(_), Ok(_)) => true,
+        (_, Err(_)) => false,
+    }
+}
+
+/// Rename 'from' to 'to' unless in dry-run mode
+fn rename(from: &Path, to: &Path, options: &MigrationOptions) -> Result<(), Error> {
+    if options.migrate {
+        options.fs.rename(from, to)?;
+    }
+    Ok(())
+}
+
+/// Resolve the conflict between the source 'file' and its old twin, if it has one
+fn resolve(file: &RRDFile, kind: ResourceType, options: &MigrationOptions) -> Result<(), Error> {
+    let source = Path::new(OsStr::from_bytes(file.0.as_bytes()));
+    let twin = with_suffix(source, &options.old_suffix);
+    if !options
+        .fs
+        .metadata(&twin)
+        .is_ok_and(|metadata| metadata.is_file)
+    {
+        return Ok(());
+    }
+    let resource = file.1.to_string_lossy();
+    let twin_is_newer = twin_is_newer(source, &twin);
+    let older = if twin_is_newer { source } else { &twin };
+    let superseded = with_suffix(older, SUPERSEDED_SUFFIX);
+    if options.fs.metadata(&superseded).is_ok() {
+        bail!("{} already exists", superseded.display());
+    }
+    rename(older, &superseded, options)?;
+    let message = if twin_is_newer {
+        // the twin takes the place of the source, to be migrated and marked as old like it
+        rename(&twin, source, options)?;
+        format!("superseded by the newer {}", twin.display())
+    } else {
+        format!("superseded by the newer {}", source.display())
+    };
+    if options.migrate {
+        info!(
+            "{kind} '{resource}' has an old twin, moved the older of them to {}",
+            superseded.display()
+        );
+    } else {
+        debug!(
+            "{kind} '{resource}' has an old twin, would move the older of them to {}, but in \
+            dry-run mode",
+            superseded.display()
+        );
+    }
+    let superseded = superseded.to_string_lossy();
+    options
+        .log
+        .record(kind, &superseded, Outcome::Skipped, &message);
+    Ok(())
+}
+
+/// Resolve the conflicts between the source files and their old twins, so that each resource is
+/// migrated from the file updated last
+///
+/// A conflict that cannot be resolved is warned about and the source is migrated as before.
+pub(crate) fn resolve_all(files: &[RRDFile], kind: ResourceType, options: &MigrationOptions) {
+    for file in files {
+        if let Err(err) = resolve(file, kind, options) {
+            let source = file.0.to_string_lossy();
+            warn!("could not resolve the conflict of {source} with its old twin - {err}");
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Verifying the migrated targets against the files they were migrated from, for --verify
+//!
+//! Each target is checked against its source, or against its old source once that was marked as
+//! old, in a pool of its own so that tens of thousands of files do not take as long as the
+//! migration did.
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+use anyhow::{format_err, Error};
+use tracing::{error, info, info_span, warn};
+
+use proxmox_rrd_migration_tool::migrate::{self, RRDFile, ResourceType};
+
+use crate::parallel_handler::ParallelHandler;
+use crate::remigrate::{self, FromOld};
+use crate::{MigrationDir, MigrationOptions, EXIT_FAILURE, EXIT_SUCCESS, EXIT_VERIFY_FAILED};
+
+/// A target and the file it is checked against
+#[derive(Debug)]
+struct Check {
+    kind: ResourceType,
+    source: PathBuf,
+    target: PathBuf,
+}
+
+/// The targets of 'dir' with the file each is checked against, and how many targets have neither
+/// a source nor an old source
+fn collect(dir: &MigrationDir, options: &MigrationOptions) -> Result<(Vec<Check>, usize), Error> {
+    let mut sources: BTreeMap<OsString, RRDFile> = BTreeMap::new();
+    for old in remigrate::old_files(dir, &FromOld::All, options)? {
+        sources.insert(old.1.clone(), old);
+    }
+    let mut current =
+        migrate::collect_rrd_files_with(&*options.fs, &dir.source, &options.old_suffix)?;
+    current.retain(|file| options.is_selected(file));
+    // the current source is the one that counts
+    for source in current {
+        sources.insert(source.1.clone(), source);
+    }
+
+    let mut checks = Vec::new();
+    for (name, source) in sources {
+        let target = dir.target.join(&name);
+        if target.exists() {
+            checks.push(Check {
+                kind: dir.kind,
+                source: PathBuf::from(OsStr::from_bytes(source.0.as_bytes())),
+                target,
+            });
+        }
+    }
+    let targets = migrate::collect_rrd_files(&dir.target)
+        .map(|targets| targets.len())
+        .unwrap_or(0);
+    let without_source = targets.saturating_sub(checks.len());
+    Ok((checks, without_source))
+}
+
+/// Verify every migrated target against its source with 'threads' threads
+///
+/// Returns the exit code.
+pub(crate) fn run(dirs: &[MigrationDir], threads: usize, options: &MigrationOptions) -> i32 {
+    let _phase = info_span!("phase", name = "verify").entered();
+    let mut checks = Vec::new();
+    let mut without_source = 0;
+    for dir in dirs {
+        match collect(dir, options) {
+            Ok((found, missing)) => {
+                checks.extend(found);
+                without_source += missing;
+            }
+            Err(err) => {
+                error!("Error collecting the {} files: {err}", dir.kind);
+                return EXIT_FAILURE;
+            }
+        }
+    }
+    if checks.is_empty() {
+        info!("No migrated RRD files found to verify");
+        return EXIT_SUCCESS;
+    }
+    info!(
+        "Verifying {} migrated RRD file(s) with {threads} thread(s)…",
+        checks.len()
+    );
+
+    let (pool, results) = ParallelHandler::with_results("verify", threads, |check: Check| {
+        migrate::Uncompressed::of(&check.source)
+            .and_then(|source| {
+                migrate::verify_file(source.path(), &check.target, check.kind.rrd_def())
+            })
+            .map_err(|err| format_err!("{} {}: {err}", check.kind, check.target.display()))
+    });
+    pool.thread_init(migrate::init_rrd_thread);
+
+    let total = checks.len();
+    for check in checks {
+        options.notifier.watchdog_ping();
+        if let Err(err) = pool.send(check) {
+            error!("Error: {err}");
+            break;
+        }
+    }
+    if let Err(err) = pool.complete() {
+        error!("Error: {err}");
+    }
+
+    let mut verified = 0;
+    for result in results.iter() {
+        match result {
+            Ok(()) => verified += 1,
+            Err(err) => error!(status = "failed", "{err}"),
+        }
+    }
+    // files not sent or lost with their worker count as failed too
+    let failed = total - verified;
+
+    info!("Verified {verified} of {total} migrated RRD file(s), {failed} failed");
+    if without_source > 0 {
+        warn!("{without_source} target(s) without a source to verify them against");
+    }
+    if failed > 0 {
+        EXIT_VERIFY_FAILED
+    } else {
+        EXIT_SUCCESS
+    }
+}
@@ -1,18 +1,31 @@
 use anyhow::Error;
 use pretty_assertions::assert_eq;
 use std::{
+    collections::HashSet,
+    ffi::{CStr, CString, OsString},
     fs,
+    io::ErrorKind,
     path::{Path, PathBuf},
     process::Command,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime},
 };
 
+use proxmox_rrd_migration_tool::backend::{FakeBackend, Librrd, RrdBackend};
+use proxmox_rrd_migration_tool::definition;
+use proxmox_rrd_migration_tool::filesystem::{MemoryFilesystem, Operation};
+use proxmox_rrd_migration_tool::migrate::{self, ResourceType, MIGRATION_MARKER, OLD_SUFFIX};
+use proxmox_rrd_migration_tool::MigrationError;
+
 mod utils;
 
-use utils::{TMPDIR, TMPDIR_RESOURCELISTS, TMPDIR_SOURCE_BASEDIR, TMPDIR_TARGET};
+use utils::{TMPDIR, TMPDIR_AUDIT, TMPDIR_RESOURCELISTS, TMPDIR_SOURCE_BASEDIR, TMPDIR_TARGET};
 
 const TARGET_SUBDIR_NODE: &str = "pve-node-9.0";
 const TARGET_SUBDIR_GUEST: &str = "pve-vm-9.0";
 const TARGET_SUBDIR_STORAGE: &str = "pve-storage-9.0";
+/// Last update of the targets in the compare files, created under faketime
+const COMPARE_START: i64 = 1753999190;
 
 #[test]
 fn migration() {
@@ -33,6 +46,8 @@ fn migration() {
         .arg(TMPDIR_TARGET)
         .arg("--resources")
         .arg(TMPDIR_RESOURCELISTS)
+        .arg("--audit-dir")
+        .arg(TMPDIR_AUDIT)
         .output()
         .expect("failed to execute proxmox-rrd-migration-tool");
 
@@ -49,6 +64,15 @@ fn migration() {
     assert!(Path::new(format!("{TMPDIR_SOURCE_BASEDIR}/pve2-vm/100.old").as_str()).exists());
     assert!(Path::new(format!("{TMPDIR_SOURCE_BASEDIR}/pve2-vm/400.old").as_str()).exists());
 
+    let report = fs::read_to_string(Path::new(TMPDIR_TARGET).join("migration-report.json"))
+        .expect("read run report");
+    let report: serde_json::Value = serde_json::from_str(&report).expect("parse run report");
+    assert_eq!(report["versions"]["tool"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(report["arguments"][0], "--migrate");
+    assert!(report["phases"]
+        .as_array()
+        .is_some_and(|phases| !phases.is_empty()));
+
     // compare
     utils::compare_results("node", &target_dir_nodes, &TARGET_SUBDIR_NODE);
 
@@ -95,15 +119,18 @@ fn migration_second_empty_run() {
         .arg(TMPDIR_TARGET)
         .arg("--resources")
         .arg(TMPDIR_RESOURCELISTS)
+        .arg("--audit-dir")
+        .arg(TMPDIR_AUDIT)
         .output()
         .expect("failed to execute proxmox-rrd-migration-tool");
 
-    // check if output skips all currently existing files
+    // check that the second run stops early, as all currently existing files are migrated
     let output = Command::new("faketime")
         .arg("2025-08-01 00:00:00")
         .arg(utils::migration_tool_path())
         .arg("--threads")
         .arg("2")
+        .arg("--legacy-output")
         .arg("--migrate")
         .arg("--source")
         .arg(TMPDIR_SOURCE_BASEDIR)
@@ -111,6 +138,8 @@ fn migration_second_empty_run() {
         .arg(TMPDIR_TARGET)
         .arg("--resources")
         .arg(TMPDIR_RESOURCELISTS)
+        .arg("--audit-dir")
+        .arg(TMPDIR_AUDIT)
         .output()
         .expect("failed to execute proxmox-rrd-migration-tool");
     let expected_path: PathBuf = [TMPDIR, "resources", "compare", "second_empty_run"]
@@ -120,6 +149,7 @@ fn migration_second_empty_run() {
     let expected =
         fs::read_to_string(expected_path).expect("could not read compare file for skip all");
 
+    assert_eq!(output.status.code(), Some(5), "{output:?}");
     assert_eq!(
         expected,
         String::from_utf8(output.stdout).expect("could not parse output")
@@ -141,6 +171,8 @@ fn migration_second_run_with_missed_files() {
         .arg(TMPDIR_TARGET)
         .arg("--resources")
         .arg(TMPDIR_RESOURCELISTS)
+        .arg("--audit-dir")
+        .arg(TMPDIR_AUDIT)
         .output()
         .expect("failed to execute proxmox-rrd-migration-tool");
 
@@ -152,12 +184,13 @@ fn migration_second_run_with_missed_files() {
         .output()
         .expect("copy 101 rrd file");
 
-    // check if output skips all currently existing files
+    // check that the second run stops early, as all currently existing files are migrated
     let output = Command::new("faketime")
         .arg("2025-08-01 00:00:00")
         .arg(utils::migration_tool_path())
         .arg("--threads")
         .arg("2")
+        .arg("--legacy-output")
         .arg("--migrate")
         .arg("--source")
         .arg(TMPDIR_SOURCE_BASEDIR)
@@ -165,6 +198,8 @@ fn migration_second_run_with_missed_files() {
         .arg(TMPDIR_TARGET)
         .arg("--resources")
         .arg(TMPDIR_RESOURCELISTS)
+        .arg("--audit-dir")
+        .arg(TMPDIR_AUDIT)
         .output()
         .expect("failed to execute proxmox-rrd-migration-tool");
 
@@ -183,3 +218,1020 @@ fn migration_second_run_with_missed_files() {
 
     assert_eq!(expected, output);
 }
+
+#[test]
+fn migration_in_process() {
+    let dir = utils::temp_fixture("in-process");
+    let source = dir.join("resources/source");
+    let target = dir.join("target");
+    let backend = Librrd {
+        start: Some(COMPARE_START),
+    };
+
+    for (kind, source_dir, target_dir, resource, compare) in [
+        (
+            ResourceType::Node,
+            source.join("pve2-node"),
+            target.join(TARGET_SUBDIR_NODE),
+            "testnode",
+            format!("{TARGET_SUBDIR_NODE}_testnode"),
+        ),
+        (
+            ResourceType::Guest,
+            source.join("pve2-vm"),
+            target.join(TARGET_SUBDIR_GUEST),
+            "100",
+            format!("{TARGET_SUBDIR_GUEST}_100"),
+        ),
+        (
+            ResourceType::Storage,
+            source.join("pve2-storage/testnode"),
+            target.join(TARGET_SUBDIR_STORAGE).join("testnode"),
+            "iso",
+            format!("{TARGET_SUBDIR_STORAGE}_testnode_iso"),
+        ),
+    ] {
+        fs::create_dir_all(&target_dir).expect("create target dir");
+        let file = migrate::collect_rrd_files(&source_dir)
+            .expect("collect source files")
+            .into_iter()
+            .find(|file| file.1 == resource)
+            .expect("source file");
+        migrate::migrate_file_with(&backend, &file, &target_dir, kind.rrd_def(), true, false)
+            .expect("migrate file");
+
+        // without force, the target of an earlier run is left alone
+        let again =
+            migrate::migrate_file_with(&backend, &file, &target_dir, kind.rrd_def(), true, false);
+        assert!(matches!(again, Err(MigrationError::AlreadyMigrated { .. })));
+
+        let expected = fs::read_to_string(dir.join("resources/compare").join(compare))
+            .expect("read compare file")
+            .replacen(
+                &format!("\"{TMPDIR_TARGET}"),
+                &format!("\"{}", target.display()),
+                1,
+            );
+        utils::compare_rrdinfo_output(utils::rrdinfo(&target_dir.join(resource)), expected);
+    }
+    fs::remove_dir_all(dir).expect("remove temporary fixture");
+}
+
+#[test]
+fn migration_fake_backend() {
+    let backend = FakeBackend::new();
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(COMPARE_START as u64);
+    backend.add_source("/source/pve2-vm/100", modified);
+    backend.add_corrupt("/source/pve2-vm/101");
+    let file = |resource: &str| {
+        (
+            CString::new(format!("/source/pve2-vm/{resource}")).unwrap(),
+            OsString::from(resource),
+        )
+    };
+    let target_dir = Path::new("/target/pve-vm-9.0");
+    let target = target_dir.join("100");
+    let rrd_def = ResourceType::Guest.rrd_def();
+    let run = |resource, migrate, force| {
+        migrate::migrate_file_with(
+            &backend,
+            &file(resource),
+            target_dir,
+            rrd_def,
+            migrate,
+            force,
+        )
+    };
+
+    assert!(matches!(
+        run("100", false, false),
+        Err(MigrationError::DryRun { .. })
+    ));
+    assert!(!backend.exists(&target));
+
+    run("100", true, false).expect("migrate file");
+    let created = backend.file(&target).expect("target file");
+    assert_eq!(created.rrd_def.map(|def| def.len()), Some(rrd_def.len()));
+    assert!(
+        migrate::is_up_to_date_with(&backend, Path::new("/source/pve2-vm/100"), &target)
+            .expect("compare modification times")
+    );
+
+    assert!(matches!(
+        run("100", true, false),
+        Err(MigrationError::AlreadyMigrated { .. })
+    ));
+    run("100", true, true).expect("overwrite target");
+
+    assert!(matches!(
+        run("101", true, false),
+        Err(MigrationError::Rrd { .. })
+    ));
+    assert!(!backend.exists(&target_dir.join("101")));
+}
+
+#[test]
+fn migration_abandoned_keeps_target() {
+    let old = SystemTime::UNIX_EPOCH + Duration::from_secs(COMPARE_START as u64);
+    let source = Path::new("/source/pve2-vm/100");
+    let target_dir = Path::new("/target/pve-vm-9.0");
+    let target = target_dir.join("100");
+    let file = (
+        CString::new("/source/pve2-vm/100").unwrap(),
+        OsString::from("100"),
+    );
+    // like --force, which keeps a backup, and like an update, which does not
+    for keep_backup in [true, false] {
+        let backend = FakeBackend::new();
+        backend.add_source(source, SystemTime::now());
+        backend.add_source(&target, old);
+        backend.slow_create(Duration::from_millis(200));
+        let abandoned = AtomicBool::new(false);
+
+        let result = std::thread::scope(|scope| {
+            let conversion = scope.spawn(|| {
+                migrate::replace_file_with(
+                    &backend,
+                    &file,
+                    target_dir,
+                    ResourceType::Guest.rrd_def(),
+                    true,
+                    keep_backup,
+                    |_| {
+                        if abandoned.load(Ordering::SeqCst) {
+                            return Err(MigrationError::Abandoned {
+                                resource: file.1.clone(),
+                            });
+                        }
+                        Ok(())
+                    },
+                )
+            });
+            // the timeout gives up on it while it is still converting
+            std::thread::sleep(Duration::from_millis(50));
+            abandoned.store(true, Ordering::SeqCst);
+            conversion.join().unwrap()
+        });
+
+        assert!(matches!(result, Err(MigrationError::Abandoned { .. })));
+        let kept = backend.file(&target).expect("existing target");
+        assert_eq!(kept.modified, old);
+        assert!(kept.rrd_def.is_none());
+        assert_eq!(backend.paths(), [source.to_path_buf(), target.clone()]);
+    }
+}
+
+fn resources(files: Vec<migrate::RRDFile>) -> Vec<String> {
+    let mut resources: Vec<String> = files
+        .into_iter()
+        .map(|file| file.1.to_string_lossy().into_owned())
+        .collect();
+    resources.sort();
+    resources
+}
+
+#[test]
+fn memory_filesystem_orphans() {
+    let fs = MemoryFilesystem::new();
+    let source = Path::new("/source/pve2-vm");
+    for resource in ["100", "101", "102"] {
+        fs.add_file(source.join(resource), 4096);
+    }
+    fs.add_file(source.join("103"), migrate::MIN_RRD_SIZE - 1);
+    fs.add_file(source.join(format!("104{OLD_SUFFIX}")), 4096);
+    fs.add_file(source.join(MIGRATION_MARKER), 4096);
+    fs.add_dir(source.join("105"));
+
+    let files =
+        migrate::collect_rrd_files_with(&fs, source, OLD_SUFFIX).expect("collect source files");
+    let unusable = migrate::collect_unusable_rrd_files_with(&fs, source, OLD_SUFFIX)
+        .expect("collect unusable files");
+    assert_eq!(resources(files.clone()), ["100", "101", "102"]);
+    assert_eq!(
+        unusable.iter().map(|(_, len)| *len).collect::<Vec<_>>(),
+        [migrate::MIN_RRD_SIZE - 1]
+    );
+
+    // the guests that are gone from .vmlist are marked as old, like the migration does
+    let present: HashSet<&str> = ["100"].into();
+    for file in files {
+        if present.contains(file.1.to_str().unwrap()) {
+            continue;
+        }
+        let path = Path::new(file.0.to_str().unwrap());
+        let old = migrate::mv_old_with(&fs, path, OLD_SUFFIX).expect("mark as old");
+        assert!(fs.is_file(&old));
+        assert!(!fs.is_file(path));
+    }
+    let old = migrate::collect_old_rrd_files_with(&fs, source, OLD_SUFFIX).expect("collect old");
+    assert_eq!(resources(old), ["101", "102", "104"]);
+    let files =
+        migrate::collect_rrd_files_with(&fs, source, OLD_SUFFIX).expect("collect source files");
+    assert_eq!(resources(files), ["100"]);
+
+    let missing = Path::new("/source/pve2-node");
+    assert!(migrate::collect_rrd_files_with(&fs, missing, OLD_SUFFIX)
+        .expect("collect missing directory")
+        .is_empty());
+}
+
+#[test]
+fn collect_with_old_suffix() {
+    let fs = MemoryFilesystem::new();
+    let source = Path::new("/source/pve2-storage/testnode");
+    for name in ["local", "nfs.old", "backup.pre-9", "iso.pre-9.zst"] {
+        fs.add_file(source.join(name), 4096);
+    }
+
+    // a storage may well be called like that, if it is not the suffix in use
+    let files = migrate::collect_rrd_files_with(&fs, source, ".pre-9").expect("collect files");
+    assert_eq!(resources(files), ["local", "nfs.old"]);
+    let files = migrate::collect_rrd_files_with(&fs, source, OLD_SUFFIX).expect("collect files");
+    assert_eq!(resources(files), ["backup.pre-9", "iso.pre-9.zst", "local"]);
+}
+
+#[test]
+fn memory_filesystem_failures() {
+    let fs = MemoryFilesystem::new();
+    let source = Path::new("/source/pve2-vm");
+    fs.add_file(source.join("100"), 4096);
+    fs.add_file(source.join("101"), 4096);
+
+    // a failed rename leaves the source where it is
+    fs.fail(
+        Operation::Rename,
+        source.join("100"),
+        ErrorKind::PermissionDenied,
+    );
+    let err = migrate::mv_old_with(&fs, &source.join("100"), OLD_SUFFIX).unwrap_err();
+    assert!(matches!(
+        err,
+        MigrationError::Io { ref path, ref source } if path.ends_with("100")
+            && source.kind() == ErrorKind::PermissionDenied
+    ));
+    assert!(fs.is_file(&source.join("100")));
+    assert!(!fs.is_file(&source.join(format!("100{OLD_SUFFIX}"))));
+
+    // files whose metadata cannot be read are left out
+    fs.fail(Operation::Metadata, source.join("101"), ErrorKind::Other);
+    let files =
+        migrate::collect_rrd_files_with(&fs, source, OLD_SUFFIX).expect("collect source files");
+    assert_eq!(resources(files), ["100"]);
+
+    fs.fail(Operation::ReadDir, source, ErrorKind::PermissionDenied);
+    assert!(!migrate::collect_rrd_files_with(&fs, source, OLD_SUFFIX)
+        .unwrap_err()
+        .is_not_found());
+    assert!(migrate::collect_old_rrd_files_with(&fs, source, OLD_SUFFIX).is_err());
+
+    let target = Path::new("/target/pve-vm-9.0");
+    assert!(migrate::create_missing_dir(&fs, target)
+        .unwrap_err()
+        .is_not_found());
+    fs.add_dir("/target");
+    assert!(migrate::create_missing_dir(&fs, target).expect("create target directory"));
+    assert!(fs.is_dir(target));
+    assert!(!migrate::create_missing_dir(&fs, target).expect("keep target directory"));
+    let nodes = Path::new("/target/pve-node-9.0");
+    fs.fail(Operation::CreateDir, nodes, ErrorKind::PermissionDenied);
+    assert!(migrate::create_missing_dir(&fs, nodes).is_err());
+    assert!(!fs.is_dir(nodes));
+
+    // the rename of 100 still fails, archiving 102 creates the directories below the archive
+    let archived = migrate::mv_archive_with(
+        &fs,
+        &source.join("100"),
+        Path::new("/source"),
+        Path::new("/archive"),
+    )
+    .unwrap_err();
+    assert!(!archived.is_not_found());
+    fs.add_file(source.join("102"), 4096);
+    let archived = migrate::mv_archive_with(
+        &fs,
+        &source.join("102"),
+        Path::new("/source"),
+        Path::new("/archive"),
+    )
+    .expect("archive file");
+    assert_eq!(archived, Path::new("/archive/pve2-vm/102"));
+    assert!(fs.is_dir(Path::new("/archive/pve2-vm")));
+    assert!(!fs.is_file(&source.join("102")));
+}
+
+/// The lines of rrdtool info output that describe the schema, not the data
+fn rrd_schema(info: &str) -> Vec<String> {
+    info.lines()
+        .filter(|line| {
+            let key = line.split(" = ").next().unwrap_or_default();
+            let field = key.rsplit('.').next().unwrap_or_default();
+            match key.split('[').next().unwrap_or_default() {
+                "ds" => ["index", "type", "minimal_heartbeat", "min", "max"].contains(&field),
+                "rra" => ["cf", "rows", "pdp_per_row", "xff"].contains(&field),
+                _ => ["rrd_version", "step", "header_size"].contains(&key),
+            }
+        })
+        .map(String::from)
+        .collect()
+}
+
+#[test]
+fn create_subcommand() {
+    let dir = utils::temp_fixture("create");
+    let target = dir.join("target");
+    let create = |args: &[&str]| {
+        Command::new(utils::migration_tool_path())
+            .arg("create")
+            .args(args)
+            .arg("--target")
+            .arg(&target)
+            .output()
+            .expect("failed to execute proxmox-rrd-migration-tool")
+    };
+
+    let output = create(&["guest", "100", "--timestamp", &COMPARE_START.to_string()]);
+    assert!(output.status.success(), "{output:?}");
+    let created = target.join(TARGET_SUBDIR_GUEST).join("100");
+    let info = utils::rrdinfo(&created);
+    assert!(info.contains(&format!("last_update = {COMPARE_START}\n")));
+    let expected = fs::read_to_string(dir.join("resources/compare/pve-vm-9.0_100"))
+        .expect("read compare file");
+    assert_eq!(rrd_schema(&info), rrd_schema(&expected));
+
+    // an existing file is only replaced with --force
+    let output = create(&["guest", "100"]);
+    assert_eq!(output.status.code(), Some(1));
+    let output = create(&["guest", "100", "--force"]);
+    assert!(output.status.success(), "{output:?}");
+    let backups = fs::read_dir(target.join(TARGET_SUBDIR_GUEST))
+        .expect("read target dir")
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| migrate::is_target_backup(&entry.file_name()))
+        .count();
+    assert_eq!(backups, 1);
+
+    let output = create(&["storage", "iso", "--node", "testnode"]);
+    assert!(output.status.success(), "{output:?}");
+    let created = target.join(TARGET_SUBDIR_STORAGE).join("testnode/iso");
+    let expected = fs::read_to_string(dir.join("resources/compare/pve-storage-9.0_testnode_iso"))
+        .expect("read compare file");
+    assert_eq!(rrd_schema(&utils::rrdinfo(&created)), rrd_schema(&expected));
+
+    for args in [
+        &["container", "100"][..],
+        &["guest", "../100"],
+        &["node", "testnode", "--node", "testnode"],
+    ] {
+        assert_eq!(create(args).status.code(), Some(2), "{args:?}");
+    }
+    fs::remove_dir_all(dir).expect("remove temporary fixture");
+}
+
+#[test]
+fn inspect_subcommand() {
+    let dir = utils::temp_fixture("inspect");
+    let target = dir.join("target");
+    let output = Command::new(utils::migration_tool_path())
+        .args(["create", "guest", "100", "--timestamp"])
+        .arg(COMPARE_START.to_string())
+        .arg("--target")
+        .arg(&target)
+        .output()
+        .expect("failed to execute proxmox-rrd-migration-tool");
+    assert!(output.status.success(), "{output:?}");
+    let created = target.join(TARGET_SUBDIR_GUEST).join("100");
+
+    let inspect = |args: &[&str]| {
+        Command::new(utils::migration_tool_path())
+            .arg("inspect")
+            .args(args)
+            .output()
+            .expect("failed to execute proxmox-rrd-migration-tool")
+    };
+    let output = inspect(&[created.to_str().unwrap(), "--json"]);
+    assert!(output.status.success(), "{output:?}");
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).expect("parse JSON");
+    assert_eq!(json["step"], 60);
+    assert_eq!(json["last_update"], COMPARE_START);
+
+    // the same data sources and RRAs as in the rrdtool info of the compare file
+    let expected = fs::read_to_string(dir.join("resources/compare/pve-vm-9.0_100"))
+        .expect("read compare file");
+    let expected_ds: Vec<&str> = expected
+        .lines()
+        .filter_map(|line| Some(line.strip_prefix("ds[")?.split_once("].index = ")?.0))
+        .collect();
+    let data_sources: Vec<&str> = json["data_sources"]
+        .as_array()
+        .expect("data sources")
+        .iter()
+        .map(|ds| ds["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(data_sources, expected_ds);
+    let expected_cfs: Vec<&str> = expected
+        .lines()
+        .filter(|line| line.starts_with("rra[") && line.contains("].cf = "))
+        .map(|line| line.rsplit('"').nth(1).unwrap())
+        .collect();
+    let cfs: Vec<&str> = json["rras"]
+        .as_array()
+        .expect("RRAs")
+        .iter()
+        .map(|rra| rra["cf"].as_str().unwrap())
+        .collect();
+    assert_eq!(cfs, expected_cfs);
+    assert_eq!(json["data_sources"][0]["min"], 0.0);
+    assert!(json["data_sources"][0]["max"].is_null());
+
+    let output = inspect(&[created.to_str().unwrap()]);
+    assert!(output.status.success(), "{output:?}");
+    let text = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        text.contains(&format!("Last update:  {COMPARE_START} (")),
+        "{text}"
+    );
+
+    let output = inspect(&[dir.join("missing").to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(inspect(&[]).status.code(), Some(2));
+    fs::remove_dir_all(dir).expect("remove temporary fixture");
+}
+
+#[test]
+fn diff_subcommand() {
+    let dir = utils::temp_fixture("diff");
+    let target = dir.join("target");
+    for args in [&["guest", "100"][..], &["node", "testnode"]] {
+        let output = Command::new(utils::migration_tool_path())
+            .arg("create")
+            .args(args)
+            .arg("--timestamp")
+            .arg(COMPARE_START.to_string())
+            .arg("--target")
+            .arg(&target)
+            .output()
+            .expect("failed to execute proxmox-rrd-migration-tool");
+        assert!(output.status.success(), "{output:?}");
+    }
+    let guest = target.join(TARGET_SUBDIR_GUEST).join("100");
+    let copy = target.join(TARGET_SUBDIR_GUEST).join("101");
+    fs::copy(&guest, &copy).expect("copy guest file");
+    let node = target.join(TARGET_SUBDIR_NODE).join("testnode");
+
+    let diff = |args: &[&Path]| {
+        Command::new(utils::migration_tool_path())
+            .arg("diff")
+            .args(args)
+            .output()
+            .expect("failed to execute proxmox-rrd-migration-tool")
+    };
+    let output = diff(&[&guest, &copy]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(output.stdout.is_empty());
+    let output = diff(&[&guest, &copy, Path::new("--data")]);
+    assert!(output.status.success(), "{output:?}");
+
+    // the guest file has data sources the node file does not have, and the other way around
+    let output = diff(&[&guest, &node]);
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.starts_with(&format!("--- {}\n", guest.display())),
+        "{stdout}"
+    );
+    assert!(stdout.contains("\n- data source maxmem: GAUGE"), "{stdout}");
+    assert!(
+        stdout.contains("\n+ data source memtotal: GAUGE"),
+        "{stdout}"
+    );
+    assert!(!stdout.contains("step"), "{stdout}");
+
+    let output = diff(&[&guest, &dir.join("missing")]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(diff(&[&guest]).status.code(), Some(2));
+    let output = diff(&[&guest, &copy, Path::new("--samples"), Path::new("3")]);
+    assert_eq!(output.status.code(), Some(2));
+    fs::remove_dir_all(dir).expect("remove temporary fixture");
+}
+
+#[cfg(not(feature = "static-rrd"))]
+#[test]
+fn graph_file() {
+    let dir = utils::temp_fixture("graph");
+    let target = dir.join("target");
+    let output = Command::new(utils::migration_tool_path())
+        .args(["create", "guest", "100", "--timestamp"])
+        .arg(COMPARE_START.to_string())
+        .arg("--target")
+        .arg(&target)
+        .output()
+        .expect("failed to execute proxmox-rrd-migration-tool");
+    assert!(output.status.success(), "{output:?}");
+    let guest = target.join(TARGET_SUBDIR_GUEST).join("100");
+    let image = dir.join("guest-100-cpu.png");
+
+    migrate::graph_file(
+        &guest,
+        &image,
+        "CPU of guest 100",
+        &["cpu", "maxcpu"],
+        COMPARE_START - 24 * 3600,
+        COMPARE_START,
+    )
+    .expect("render graph");
+    let png = fs::read(&image).expect("read graph");
+    assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+
+    let missing = migrate::graph_file(
+        &dir.join("missing"),
+        &image,
+        "missing",
+        &["cpu"],
+        COMPARE_START - 24 * 3600,
+        COMPARE_START,
+    );
+    assert!(matches!(missing, Err(MigrationError::Rrd { .. })));
+    fs::remove_dir_all(dir).expect("remove temporary fixture");
+}
+
+#[test]
+fn dump_schema() {
+    let output = Command::new(utils::migration_tool_path())
+        .arg("--dump-schema")
+        .output()
+        .expect("failed to execute proxmox-rrd-migration-tool");
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for kind in [
+        "guest (pve-vm-9.0/)",
+        "node (pve-node-9.0/)",
+        "storage (pve-storage-9.0/)",
+    ] {
+        assert!(stdout.contains(kind), "{stdout}");
+    }
+    assert!(
+        stdout.contains("DS:pressurememoryfull:GAUGE:120:0:U \\\n"),
+        "{stdout}"
+    );
+    // the last RRA of each type ends the rrdtool command
+    assert!(stdout.contains("RRA:MAX:0.5:10080:570\n"), "{stdout}");
+    assert!(
+        stdout.contains("        AVERAGE          30   1440   0.5         30m        30d\n"),
+        "{stdout}"
+    );
+}
+
+#[test]
+fn schema_definitions() {
+    for kind in [
+        ResourceType::Guest,
+        ResourceType::Node,
+        ResourceType::Storage,
+    ] {
+        definition::validate(kind.rrd_def()).expect("valid schema");
+        definition::validate(kind.legacy_rrd_def()).expect("valid legacy schema");
+    }
+    let invalid: [(&[&CStr], &str); 11] = [
+        (
+            &[c"DS:cpu:GAUGE:120:0", c"RRA:AVERAGE:0.5:1:70"],
+            "'DS:cpu:GAUGE:120:0': expected 5 fields after DS",
+        ),
+        (
+            &[c"DS:cpu:GAUGE:0:0:U", c"RRA:AVERAGE:0.5:1:70"],
+            "'DS:cpu:GAUGE:0:0:U': heartbeat must be at least 1",
+        ),
+        (
+            &[c"DS:cpu:GAGE:120:0:U", c"RRA:AVERAGE:0.5:1:70"],
+            "unknown data source type 'GAGE' of 'cpu'",
+        ),
+        (
+            &[c"DS:cpu-load:GAUGE:120:0:U", c"RRA:AVERAGE:0.5:1:70"],
+            "data source name 'cpu-load' may only contain",
+        ),
+        (
+            &[c"DS:cpu:GAUGE:120:1:0", c"RRA:AVERAGE:0.5:1:70"],
+            "minimum 1 of 'cpu' is larger than its maximum 0",
+        ),
+        (
+            &[c"DS:cpu:GAUGE:120:0:U", c"RRA:AVG:0.5:1:70"],
+            "unknown consolidation function 'AVG'",
+        ),
+        (
+            &[c"DS:cpu:GAUGE:120:0:U", c"RRA:AVERAGE:1.5:1:70"],
+            "xff '1.5' must be a number from 0 to below 1",
+        ),
+        (
+            &[c"DS:cpu:GAUGE:120:0:U", c"RRA:AVERAGE:0.5:1:x"],
+            "rows 'x' is not a positive integer",
+        ),
+        (
+            &[
+                c"DS:cpu:GAUGE:120:0:U",
+                c"DS:cpu:GAUGE:120:0:U",
+                c"RRA:MAX:0.5:1:70",
+            ],
+            "data source 'cpu' is defined twice",
+        ),
+        (&[c"DS:cpu:GAUGE:120:0:U"], "no RRA defined"),
+        (
+            &[c"CDEF:x=cpu", c"RRA:MAX:0.5:1:70"],
+            "does not start with DS: or RRA:",
+        ),
+    ];
+    for (defs, expected) in invalid {
+        let err = definition::validate(defs).expect_err(expected);
+        assert!(matches!(err, MigrationError::InvalidSchema { .. }));
+        assert!(err.to_string().contains(expected), "{err}");
+    }
+}
+
+#[test]
+fn guest_nodes() {
+    let vmlist = fs::read_to_string("tests/resources/resourcelists/.vmlist").expect("read .vmlist");
+    let guests = migrate::parse_guests(&vmlist).expect("parse .vmlist");
+    assert_eq!(guests.len(), 2);
+    assert_eq!(guests["100"].as_deref(), Some("testnode"));
+    assert_eq!(
+        migrate::parse_guest_ids(&vmlist).expect("parse .vmlist"),
+        HashSet::from(["100".to_string(), "101".to_string()])
+    );
+
+    // not JSON anymore, the guests must not be guessed from the lines
+    let vmlist = "{\n\"ids\": {\n\"100\": { \"node\": \"node1\", \"type\": \"qemu\" },\n\
+        \"101\": { \"type\": \"lxc\" }\n\"102\": { \"node\": \"node2\" },\n}";
+    let err = migrate::parse_guests(vmlist).expect_err("truncated .vmlist");
+    assert!(matches!(err, MigrationError::InvalidResourceList { .. }));
+}
+
+#[test]
+fn cluster_members() {
+    let members =
+        fs::read_to_string("tests/resources/resourcelists/.members").expect("read .members");
+    let membership = migrate::parse_members(&members).expect("parse .members");
+    assert_eq!(membership.nodes.len(), 3);
+    assert!(membership.quorate);
+    assert_eq!(membership.nodename.as_deref(), Some("testnode"));
+
+    // cut off in the middle, the nodes must not be guessed from the lines
+    let members = &members[..members.find("\"thirdnode\"").expect("third node")];
+    let err = migrate::parse_members(members).expect_err("truncated .members");
+    assert!(matches!(err, MigrationError::InvalidResourceList { .. }));
+}
+
+#[test]
+fn old_twin() {
+    let dir = utils::temp_fixture("old-twin");
+    let source = dir.join("resources/source/pve2-vm");
+    let target = dir.join("target");
+    // a twin updated at the same time is not newer, so the source is migrated
+    fs::copy(source.join("100"), source.join("100.old")).expect("create old twin");
+
+    let output = Command::new(utils::migration_tool_path())
+        .arg("--migrate")
+        .arg("--source")
+        .arg(dir.join("resources/source"))
+        .arg("--target")
+        .arg(&target)
+        .arg("--resources")
+        .arg(dir.join("resources/resourcelists"))
+        .arg("--audit-dir")
+        .arg(dir.join("audit"))
+        .output()
+        .expect("failed to execute proxmox-rrd-migration-tool");
+    assert!(output.status.success(), "{output:?}");
+
+    assert!(source.join("100.old.superseded").is_file());
+    assert!(source.join("100.old").is_file());
+    assert!(!source.join("100").exists());
+    assert!(target.join(TARGET_SUBDIR_GUEST).join("100").is_file());
+    fs::remove_dir_all(dir).expect("remove temporary fixture");
+}
+
+#[test]
+fn verify_targets() {
+    let dir = utils::temp_fixture("verify");
+    let target = dir.join("target");
+    let run = |args: &[&str]| {
+        Command::new(utils::migration_tool_path())
+            .args(args)
+            .arg("--source")
+            .arg(dir.join("resources/source"))
+            .arg("--target")
+            .arg(&target)
+            .arg("--resources")
+            .arg(dir.join("resources/resourcelists"))
+            .arg("--audit-dir")
+            .arg(dir.join("audit"))
+            .output()
+            .expect("failed to execute proxmox-rrd-migration-tool")
+    };
+
+    let output = run(&["--migrate"]);
+    assert!(output.status.success(), "{output:?}");
+    let output = run(&["--verify", "--verify-threads", "2"]);
+    assert!(output.status.success(), "{output:?}");
+
+    fs::write(target.join(TARGET_SUBDIR_GUEST).join("100"), b"broken").expect("break target");
+    let output = run(&["--verify", "--verify-threads", "2"]);
+    assert_eq!(output.status.code(), Some(12), "{output:?}");
+    fs::remove_dir_all(dir).expect("remove temporary fixture");
+}
+
+#[test]
+fn verify_compressed_old() {
+    let dir = utils::temp_fixture("compressed-old");
+    let source = dir.join("resources/source/pve2-vm");
+    let run = |args: &[&str]| {
+        Command::new(utils::migration_tool_path())
+            .args(args)
+            .arg("--source")
+            .arg(dir.join("resources/source"))
+            .arg("--target")
+            .arg(dir.join("target"))
+            .arg("--resources")
+            .arg(dir.join("resources/resourcelists"))
+            .arg("--audit-dir")
+            .arg(dir.join("audit"))
+            .output()
+            .expect("failed to execute proxmox-rrd-migration-tool")
+    };
+
+    let output = run(&["--migrate", "--compress-old"]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(source.join("100.old.zst").is_file());
+    assert!(!source.join("100.old").exists());
+    // the targets are verified against the compressed old files
+    let output = run(&["--verify"]);
+    assert!(output.status.success(), "{output:?}");
+    fs::remove_dir_all(dir).expect("remove temporary fixture");
+}
+
+#[test]
+fn config_switch_off() {
+    let dir = utils::temp_fixture("config-switch-off");
+    let source = dir.join("resources/source/pve2-vm");
+    let config = dir.join("migration.conf");
+    fs::write(&config, "keep-source = true\n").expect("write config file");
+
+    let output = Command::new(utils::migration_tool_path())
+        .arg("--migrate")
+        .arg("--config")
+        .arg(&config)
+        .arg("--no-keep-source")
+        .arg("--source")
+        .arg(dir.join("resources/source"))
+        .arg("--target")
+        .arg(dir.join("target"))
+        .arg("--resources")
+        .arg(dir.join("resources/resourcelists"))
+        .arg("--audit-dir")
+        .arg(dir.join("audit"))
+        .env("PROXMOX_RRD_MIGRATION_KEEP_SOURCE", "1")
+        .output()
+        .expect("failed to execute proxmox-rrd-migration-tool");
+    assert!(output.status.success(), "{output:?}");
+    // turned off on the command line, the sources are marked as old again
+    assert!(source.join("100.old").is_file());
+    assert!(!source.join("100").exists());
+    fs::remove_dir_all(dir).expect("remove temporary fixture");
+}
+
+#[test]
+fn service_mode() {
+    let dir = utils::temp_fixture("service");
+    let state = dir.join("state");
+    let target = dir.join("target");
+    let run = || {
+        Command::new(utils::migration_tool_path())
+            .arg("--service")
+            .arg("--status-file")
+            .arg(dir.join("run/status.json"))
+            .arg("--source")
+            .arg(dir.join("resources/source"))
+            .arg("--target")
+            .arg(&target)
+            .arg("--resources")
+            .arg(dir.join("resources/resourcelists"))
+            .arg("--audit-dir")
+            .arg(dir.join("audit"))
+            .env("STATE_DIRECTORY", &state)
+            .output()
+            .expect("failed to execute proxmox-rrd-migration-tool")
+    };
+
+    let output = run();
+    assert!(output.status.success(), "{output:?}");
+    assert!(target.join(TARGET_SUBDIR_GUEST).join("100").is_file());
+    // nothing left to do does not fail the unit
+    let output = run();
+    assert_eq!(output.status.code(), Some(0), "{output:?}");
+
+    let state = fs::read_to_string(state.join("state.json")).expect("read service state");
+    let state: serde_json::Value = serde_json::from_str(&state).expect("parse service state");
+    assert_eq!(state["exit-code"], 5);
+    assert!(state["end"].is_string());
+    fs::remove_dir_all(dir).expect("remove temporary fixture");
+}
+
+#[test]
+fn status_file() {
+    let dir = utils::temp_fixture("status-file");
+    let status = dir.join("run/status.json");
+
+    let output = Command::new(utils::migration_tool_path())
+        .arg("--migrate")
+        .arg("--status-file")
+        .arg(&status)
+        .arg("--source")
+        .arg(dir.join("resources/source"))
+        .arg("--target")
+        .arg(dir.join("target"))
+        .arg("--resources")
+        .arg(dir.join("resources/resourcelists"))
+        .arg("--audit-dir")
+        .arg(dir.join("audit"))
+        .output()
+        .expect("failed to execute proxmox-rrd-migration-tool");
+    assert!(output.status.success(), "{output:?}");
+
+    let status = fs::read_to_string(status).expect("read status file");
+    let status: serde_json::Value = serde_json::from_str(&status).expect("parse status file");
+    assert_eq!(status["state"], "finished");
+    assert_eq!(status["exit-code"], 0);
+    assert!(status["phases"]
+        .as_array()
+        .is_some_and(|phases| !phases.is_empty()));
+    assert!(status["pid"].is_u64());
+    fs::remove_dir_all(dir).expect("remove temporary fixture");
+}
+
+#[test]
+fn restore_subcommand() {
+    let dir = utils::temp_fixture("restore");
+    let source = dir.join("resources/source/pve2-vm");
+    let target = dir.join("target").join(TARGET_SUBDIR_GUEST);
+    let backup = dir.join("backup.tar");
+    let migrate = |args: &[&str]| {
+        Command::new(utils::migration_tool_path())
+            .args(args)
+            .arg("--backup")
+            .arg(&backup)
+            .arg("--source")
+            .arg(dir.join("resources/source"))
+            .arg("--target")
+            .arg(dir.join("target"))
+            .arg("--resources")
+            .arg(dir.join("resources/resourcelists"))
+            .arg("--audit-dir")
+            .arg(dir.join("audit"))
+            .output()
+            .expect("failed to execute proxmox-rrd-migration-tool")
+    };
+    let restore = |args: &[&str]| {
+        Command::new(utils::migration_tool_path())
+            .arg("restore")
+            .arg("--from")
+            .arg(&backup)
+            .args(args)
+            .arg("--source")
+            .arg(dir.join("resources/source"))
+            .arg("--target")
+            .arg(dir.join("target"))
+            .output()
+            .expect("failed to execute proxmox-rrd-migration-tool")
+    };
+
+    let output = migrate(&["--migrate", "--old-suffix", ".pre-9"]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(source.join("100.pre-9").is_file());
+    assert!(target.join("100").is_file());
+
+    // not on a terminal, only with --yes
+    let output = restore(&["--old-suffix", ".pre-9"]);
+    assert_eq!(output.status.code(), Some(2), "{output:?}");
+    assert!(source.join("100.pre-9").is_file());
+
+    let output = restore(&["--yes", "--old-suffix", ".pre-9"]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(source.join("100").is_file());
+    assert!(!source.join("100.pre-9").exists());
+    assert!(!target.join("100").exists());
+
+    // and once more, with the old files compressed
+    fs::remove_file(&backup).expect("remove backup");
+    let output = migrate(&["--migrate", "--compress-old"]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(source.join("100.old.zst").is_file());
+
+    let output = restore(&["--yes"]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(source.join("100").is_file());
+    assert!(!source.join("100.old.zst").exists());
+    assert!(!target.join("100").exists());
+    fs::remove_dir_all(dir).expect("remove temporary fixture");
+}
+
+#[test]
+fn env_cannot_migrate() {
+    let dir = utils::temp_fixture("env-migrate");
+    let source = dir.join("resources/source/pve2-vm");
+
+    let output = Command::new(utils::migration_tool_path())
+        .arg("--source")
+        .arg(dir.join("resources/source"))
+        .arg("--target")
+        .arg(dir.join("target"))
+        .arg("--resources")
+        .arg(dir.join("resources/resourcelists"))
+        .arg("--audit-dir")
+        .arg(dir.join("audit"))
+        .env("PROXMOX_RRD_MIGRATION_MIGRATE", "1")
+        .env("PROXMOX_RRD_MIGRATION_FORCE", "1")
+        .env("PROXMOX_RRD_MIGRATION_ALL_NODES", "1")
+        .arg("--no-all-nodes")
+        .output()
+        .expect("failed to execute proxmox-rrd-migration-tool");
+    assert!(output.status.success(), "{output:?}");
+    // only a dry run, the sources are left alone
+    assert!(source.join("100").is_file());
+    assert!(!source.join("100.old").exists());
+    assert!(!dir
+        .join("target")
+        .join(TARGET_SUBDIR_GUEST)
+        .join("100")
+        .exists());
+    fs::remove_dir_all(dir).expect("remove temporary fixture");
+}
+
+#[test]
+fn nothing_to_do() {
+    let dir = utils::temp_fixture("nothing-to-do");
+    let backup = dir.join("backup.tar");
+    let run = |args: &[&str]| {
+        Command::new(utils::migration_tool_path())
+            .args(args)
+            .arg("--source")
+            .arg(dir.join("resources/source"))
+            .arg("--target")
+            .arg(dir.join("target"))
+            .arg("--resources")
+            .arg(dir.join("resources/resourcelists"))
+            .arg("--audit-dir")
+            .arg(dir.join("audit"))
+            .output()
+            .expect("failed to execute proxmox-rrd-migration-tool")
+    };
+
+    let output = run(&["--migrate"]);
+    assert!(output.status.success(), "{output:?}");
+
+    // the dry run reports as usual
+    let output = run(&[]);
+    assert_eq!(output.status.code(), Some(0), "{output:?}");
+
+    // only decided once the backup was made
+    let output = run(&["--migrate", "--backup", backup.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(5), "{output:?}");
+    assert!(backup.is_file());
+    fs::remove_dir_all(dir).expect("remove temporary fixture");
+}
+
+#[test]
+fn reconcile_partial_target() {
+    let dir = utils::temp_fixture("reconcile");
+    let target = dir.join("target").join(TARGET_SUBDIR_GUEST).join("100");
+    let run = |args: &[&str]| {
+        Command::new(utils::migration_tool_path())
+            .args(args)
+            .arg("--source")
+            .arg(dir.join("resources/source"))
+            .arg("--target")
+            .arg(dir.join("target"))
+            .arg("--resources")
+            .arg(dir.join("resources/resourcelists"))
+            .arg("--audit-dir")
+            .arg(dir.join("audit"))
+            .output()
+            .expect("failed to execute proxmox-rrd-migration-tool")
+    };
+
+    // as left behind by a run interrupted while writing the target
+    fs::create_dir_all(target.parent().unwrap()).expect("create target dir");
+    fs::write(&target, b"partial").expect("write partial target");
+
+    // the dry run only reports it
+    let output = run(&["--reconcile"]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(target.is_file());
+
+    let output = run(&["--reconcile", "--migrate"]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(!target.exists());
+    assert!(dir.join("resources/source/pve2-vm/100").is_file());
+
+    // so that the next run migrates the source
+    let output = run(&["--migrate"]);
+    assert!(output.status.success(), "{output:?}");
+    migrate::check_target(&target).expect("complete target");
+    fs::remove_dir_all(dir).expect("remove temporary fixture");
+}
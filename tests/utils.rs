@@ -11,6 +11,7 @@ pub const TMPDIR_SOURCE_BASEDIR: &str = "tmp_tests/resources/source";
 pub const TMPDIR_TARGET: &str = "tmp_tests/target";
 pub const TMPDIR_COMPARE: &str = "tmp_tests/resources/compare";
 pub const TMPDIR_RESOURCELISTS: &str = "tmp_tests/resources/resourcelists";
+pub const TMPDIR_AUDIT: &str = "tmp_tests/audit";
 pub const TEST_RESOURCE_DIR: &str = "tests/resources";
 
 fn get_target_dir() -> PathBuf {
@@ -43,6 +44,38 @@ pub fn test_prepare() {
         .expect("copy test resource files");
 }
 
+/// Copy the test resources to a directory of their own below the temporary directory, for tests
+/// that can run in parallel to the others
+pub fn temp_fixture(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!(
+        "proxmox-rrd-migration-tool-{name}-{}",
+        std::process::id()
+    ));
+    if dir.exists() {
+        fs::remove_dir_all(&dir).expect("remove temporary fixture");
+    }
+    fs::create_dir_all(&dir).expect("create temporary fixture");
+    Command::new("cp")
+        .args(["-ra", TEST_RESOURCE_DIR])
+        .arg(&dir)
+        .output()
+        .expect("copy test resource files");
+    dir
+}
+
+/// The output of rrdtool info for the RRD file 'path'
+pub fn rrdinfo(path: &Path) -> String {
+    String::from_utf8(
+        Command::new("rrdtool")
+            .arg("info")
+            .arg(path)
+            .output()
+            .expect("execute rrdtool info")
+            .stdout,
+    )
+    .expect("rrdtool into to string")
+}
+
 /// Loop over directories to compare results
 ///
 /// type:               type of test, node, guest, storage
@@ -69,15 +102,7 @@ pub fn compare_results(migrationtype: &str, target_path: &PathBuf, comp_subdir_p
             .iter()
             .collect();
             let expected = fs::read_to_string(expected_path).expect("read compare file");
-            let testcase = String::from_utf8(
-                Command::new("rrdtool")
-                    .args(["info", path.to_str().unwrap()])
-                    .output()
-                    .expect("execute rrdtool info")
-                    .stdout,
-            )
-            .expect("rrdtool into to string");
-            compare_rrdinfo_output(testcase, expected);
+            compare_rrdinfo_output(rrdinfo(path), expected);
         });
 }
 